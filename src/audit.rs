@@ -0,0 +1,354 @@
+use serde::Serialize;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{fs, io};
+
+use crate::validator::Verdict;
+
+/// The size an audit log may grow to before it is rotated if `audit_log_max_size` isn't set.
+pub const DEFAULT_MAX_SIZE: u64 = 100 * 1024 * 1024;
+
+/// The number of rotated audit logs kept if `audit_log_max_files` isn't set.
+pub const DEFAULT_MAX_FILES: u32 = 5;
+
+/// What was done with a checked query.
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// The query wasn't detected as SQL injection and was forwarded
+    Allowed,
+    /// The query was detected as SQL injection and blocked
+    Blocked,
+    /// The query was detected as SQL injection, but forwarded anyway (in monitor mode)
+    Flagged,
+}
+
+/// A single line of the audit log, describing the decision made for one query.
+#[derive(Serialize)]
+pub struct AuditRecord<'a> {
+    pub timestamp: String,
+    /// The name of the proxy instance that checked the query
+    pub instance: &'a str,
+    pub client: &'a str,
+    pub user: Option<&'a str>,
+    pub database: Option<&'a str>,
+    pub query: &'a str,
+    pub action: Action,
+    /// The ID reported to the client in the error for a blocked query (if the error includes one)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<&'a str>,
+    /// Whether the client's address was quarantined when the query was checked (only recorded if so)
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub quarantined: bool,
+    /// How the decision was reached (recorded as the `path`, `prefix_index`, `suffix_index`, `suspect_tokens`
+    /// and `detection` fields)
+    #[serde(flatten)]
+    pub verdict: &'a Verdict,
+}
+
+/// A JSON-lines file that records every detection decision, rotated once it reaches a given size.
+/// When rotated, `<path>` is renamed to `<path>.1`, `<path>.1` to `<path>.2` and so on.
+pub struct AuditLog {
+    path: PathBuf,
+    max_size: u64,
+    /// The number of rotated files kept (older files are deleted)
+    max_files: u32,
+    /// The open log file and its current size, opened upon the first write
+    file: Option<(fs::File, u64)>,
+}
+
+/// An audit log that can be written to by every proxy instance configured with its path.
+pub type SharedAuditLog = Arc<Mutex<AuditLog>>;
+
+/// Writes the audit records of a single proxy instance.
+#[derive(Clone)]
+pub struct Auditor {
+    /// The name of the proxy instance, recorded in each of its records
+    pub instance: String,
+    pub log: SharedAuditLog,
+}
+
+impl Auditor {
+    pub fn record(&self, record: &AuditRecord) {
+        match self.log.lock() {
+            Ok(mut audit_log) => audit_log.write(record),
+            Err(_) => log::error!("Audit log unusable after a thread panicked while writing to it"), // Invariant: should never happen (panics terminate the process)
+        }
+    }
+}
+
+impl AuditLog {
+    pub fn new(path: PathBuf, max_size: u64, max_files: u32) -> Self {
+        AuditLog {
+            path,
+            max_size,
+            max_files,
+            file: None,
+        }
+    }
+
+    pub fn get_path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    pub fn get_max_size(&self) -> u64 {
+        self.max_size
+    }
+
+    pub fn get_max_files(&self) -> u32 {
+        self.max_files
+    }
+
+    /// Appends the record to the log. Failures are logged rather than returned, as they shouldn't interrupt proxying.
+    pub fn write(&mut self, record: &AuditRecord) {
+        let mut line = match serde_json::to_vec(record) {
+            Ok(l) => l,
+            Err(e) => {
+                log::error!("Failed to serialize audit record: {}", e);
+                return;
+            }
+        };
+        line.push(b'\n');
+
+        match self.write_line(&line) {
+            Ok(()) => (),
+            Err(e) => {
+                log::error!(
+                    "Failed to write to audit log {}: {}",
+                    self.path.display(),
+                    e
+                );
+                self.file = None; // Reopen the file on the next write in case it was removed or replaced
+            }
+        }
+    }
+
+    fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        if let Some((_, size)) = self.file.as_ref() {
+            if *size > 0 && size + line.len() as u64 > self.max_size {
+                self.file = None;
+                self.rotate()?;
+            }
+        }
+
+        let (file, size) = match self.file.as_mut() {
+            Some(f) => f,
+            None => {
+                // Only readable by the proxy's user, as the log records every query in full
+                let file = fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .mode(0o600)
+                    .open(&self.path)?;
+                let size = file.metadata()?.len();
+                self.file.insert((file, size))
+            }
+        };
+
+        file.write_all(line)?;
+        *size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let rotated_path = |index: u32| {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{}", index));
+            PathBuf::from(name)
+        };
+
+        if self.max_files == 0 {
+            return fs::remove_file(&self.path);
+        }
+
+        for index in (1..self.max_files).rev() {
+            match fs::rename(rotated_path(index), rotated_path(index + 1)) {
+                Ok(()) => (),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e),
+            }
+        }
+
+        fs::rename(&self.path, rotated_path(1))
+    }
+}
+
+/// Formats the current time as an RFC 3339 timestamp in UTC (e.g. `2023-04-01T12:00:00.123Z`).
+pub fn timestamp_now() -> String {
+    format_timestamp(SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default())
+}
+
+/// Formats the time at the given offset from the Unix epoch as an RFC 3339 timestamp in UTC.
+fn format_timestamp(since_epoch: Duration) -> String {
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+
+    // Converts days since the epoch into a civil date (see http://howardhinnant.github.io/date_algorithms.html#civil_from_days)
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        (secs_of_day % 3600) / 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validator::MatchPath;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+
+    /// Returns the path of an audit log in a fresh directory of its own.
+    fn test_path(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("sqlfortify-audit-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("audit.log")
+    }
+
+    fn write_query(audit_log: &mut AuditLog, query: &str) {
+        let verdict = Verdict {
+            path: MatchPath::NoPattern,
+            prefix_index: None,
+            suffix_index: None,
+            suspect_tokens: None,
+            detection: None,
+        };
+        audit_log.write(&AuditRecord {
+            timestamp: timestamp_now(),
+            instance: "test",
+            client: "127.0.0.1:5000",
+            user: Some("app"),
+            database: None,
+            query,
+            action: Action::Allowed,
+            correlation_id: None,
+            quarantined: false,
+            verdict: &verdict,
+        });
+    }
+
+    /// Returns the queries recorded in the log file at the path.
+    fn logged_queries(path: &Path) -> Vec<String> {
+        fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                let record: serde_json::Value = serde_json::from_str(line).unwrap();
+                record["query"].as_str().unwrap().to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn log_is_only_readable_by_its_owner() {
+        let path = test_path("mode");
+        let mut audit_log = AuditLog::new(path.clone(), DEFAULT_MAX_SIZE, DEFAULT_MAX_FILES);
+        write_query(&mut audit_log, "SELECT 1");
+
+        assert_eq!(logged_queries(&path), ["SELECT 1"]);
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn rotates_once_full() {
+        let path = test_path("rotate");
+        let rotated = |index: u32| PathBuf::from(format!("{}.{}", path.display(), index));
+        write_query(
+            &mut AuditLog::new(path.clone(), DEFAULT_MAX_SIZE, 2),
+            "SELECT 0",
+        );
+        let line_size = fs::metadata(&path).unwrap().len();
+        fs::remove_file(&path).unwrap();
+
+        // Each file has room for two records (all of which are the same size)
+        let mut audit_log = AuditLog::new(path.clone(), line_size * 2, 2);
+        for i in 1..=7 {
+            write_query(&mut audit_log, &format!("SELECT {}", i));
+        }
+
+        assert_eq!(logged_queries(&path), ["SELECT 7"]);
+        assert_eq!(logged_queries(&rotated(1)), ["SELECT 5", "SELECT 6"]);
+        assert_eq!(logged_queries(&rotated(2)), ["SELECT 3", "SELECT 4"]);
+        assert!(!rotated(3).exists()); // Only two rotated files are kept
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn rotation_without_kept_files_starts_afresh() {
+        let path = test_path("no-kept-files");
+        let mut audit_log = AuditLog::new(path.clone(), 1, 0);
+        write_query(&mut audit_log, "SELECT 1");
+        write_query(&mut audit_log, "SELECT 2");
+
+        assert_eq!(logged_queries(&path), ["SELECT 2"]);
+        assert!(!PathBuf::from(format!("{}.1", path.display())).exists());
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn appends_to_an_existing_log() {
+        let path = test_path("append");
+        write_query(
+            &mut AuditLog::new(path.clone(), DEFAULT_MAX_SIZE, 1),
+            "SELECT 1",
+        );
+        write_query(
+            &mut AuditLog::new(path.clone(), DEFAULT_MAX_SIZE, 1),
+            "SELECT 2",
+        );
+
+        assert_eq!(logged_queries(&path), ["SELECT 1", "SELECT 2"]);
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(format_timestamp(Duration::ZERO), "1970-01-01T00:00:00.000Z");
+        assert_eq!(
+            format_timestamp(Duration::from_millis(951_782_400_123)), // Leap day of a year divisible by 400
+            "2000-02-29T00:00:00.123Z"
+        );
+        assert_eq!(
+            format_timestamp(Duration::from_secs(1_680_350_399)),
+            "2023-04-01T11:59:59.000Z"
+        );
+        assert_eq!(
+            format_timestamp(Duration::from_secs(4_107_542_400 - 1)), // The end of a century year that isn't a leap year
+            "2100-02-28T23:59:59.000Z"
+        );
+        assert_eq!(
+            format_timestamp(Duration::from_secs(4_107_542_400)),
+            "2100-03-01T00:00:00.000Z"
+        );
+
+        let now = timestamp_now();
+        assert_eq!(now.len(), 24);
+        assert!(now.ends_with('Z'));
+    }
+}
@@ -0,0 +1,232 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::sql;
+use crate::sql_wire::ErrorFields;
+
+/// How long the error for a tarpitted query is delayed if `tarpit_seconds` isn't set.
+pub const DEFAULT_TARPIT_DELAY: Duration = Duration::from_secs(5);
+
+/// How long a client is quarantined for if `quarantine_seconds` isn't set.
+pub const DEFAULT_QUARANTINE_DURATION: Duration = Duration::from_secs(300);
+
+/// Replaced with why a query was blocked wherever it appears in the blocked error's message, detail or hint.
+pub const REASON_PLACEHOLDER: &str = "{reason}";
+
+/// Replaced with an ID unique to each blocked query (which is also recorded in the audit log) wherever it appears
+/// in the blocked error's message, detail or hint.
+pub const CORRELATION_ID_PLACEHOLDER: &str = "{correlation_id}";
+
+/// Distinguishes correlation IDs generated within the same millisecond.
+static CORRELATION_SEQUENCE: AtomicU32 = AtomicU32::new(0);
+
+/// What is done with a query detected as SQL injection (in enforce mode).
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// The client is sent an error in place of the query's result
+    Error,
+    /// The client's connection is closed without a response
+    Disconnect,
+    /// The client is sent an error, but only once a delay has passed (slowing down automated tools)
+    Tarpit,
+    /// The client is sent an error, and every query from the same address is rejected for a period
+    Quarantine,
+}
+
+/// How a proxy instance responds to the queries it blocks.
+#[derive(Clone)]
+pub struct Policy {
+    pub error: ErrorTemplate,
+    /// The action taken for queries matching rules that have no action of their own
+    pub default_action: Action,
+    pub actions: HashMap<sql::Rule, Action>,
+    /// How long the `Tarpit` action delays the error
+    pub tarpit_delay: Duration,
+    /// How long the `Quarantine` action rejects queries from the client's address for
+    pub quarantine_duration: Duration,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy {
+            error: ErrorTemplate::default(),
+            default_action: Action::Error,
+            actions: HashMap::new(),
+            tarpit_delay: DEFAULT_TARPIT_DELAY,
+            quarantine_duration: DEFAULT_QUARANTINE_DURATION,
+        }
+    }
+}
+
+impl Policy {
+    /// Returns the action taken for queries that match the given rule.
+    pub fn action_for(&self, rule: sql::Rule) -> Action {
+        match self.actions.get(&rule) {
+            Some(action) => *action,
+            None => self.default_action,
+        }
+    }
+}
+
+/// The client addresses that have been quarantined, and until when. Shared by every connection of a proxy instance,
+/// so that a quarantined client can't get around it by reconnecting.
+#[derive(Default)]
+pub struct Quarantine {
+    until: HashMap<IpAddr, Instant>,
+}
+
+impl Quarantine {
+    /// Rejects queries from the given address for the given duration (extending any existing quarantine).
+    pub fn add(&mut self, address: IpAddr, duration: Duration) {
+        let now = Instant::now();
+        self.until.retain(|_, until| *until > now); // Forgets expired quarantines so that they don't accumulate
+
+        let until = now + duration;
+        let entry = self.until.entry(address).or_insert(until);
+        *entry = (*entry).max(until);
+    }
+
+    /// Returns `true` if queries from the given address are currently rejected.
+    pub fn contains(&self, address: &IpAddr) -> bool {
+        match self.until.get(address) {
+            Some(until) => *until > Instant::now(),
+            None => false,
+        }
+    }
+}
+
+/// The error reported to clients whose queries are blocked, before any placeholders are filled in.
+#[derive(Clone)]
+pub struct ErrorTemplate {
+    /// The severity reported to Postgres clients (`ERROR`, `FATAL` or `PANIC`); the latter two end the client's session
+    pub severity: String,
+    pub sql_state: [u8; 5],
+    /// The error number reported to MySQL clients
+    pub code: u16,
+    pub message: String,
+    /// Omitted from the error if empty
+    pub detail: String,
+    /// Omitted from the error if empty
+    pub hint: String,
+}
+
+impl Default for ErrorTemplate {
+    fn default() -> Self {
+        ErrorTemplate {
+            severity: "ERROR".to_string(),
+            sql_state: *b"42000",
+            code: 1064, // ER_PARSE_ERROR
+            message: "Malformed input blocked by SQLFortify".to_string(),
+            // Left out by default, so that clients (and attackers probing the proxy) aren't told which rule fired or where
+            detail: String::new(),
+            hint: String::new(),
+        }
+    }
+}
+
+impl ErrorTemplate {
+    /// Returns `true` if the error includes a correlation ID, in which case one should be generated for each blocked query.
+    pub fn uses_correlation_id(&self) -> bool {
+        [&self.message, &self.detail, &self.hint]
+            .iter()
+            .any(|s| s.contains(CORRELATION_ID_PLACEHOLDER))
+    }
+
+    /// Fills in the error reported for a query blocked for the given reason.
+    pub fn render(&self, reason: &str, correlation_id: Option<&str>) -> ErrorFields {
+        let fill = |s: &str| {
+            s.replace(REASON_PLACEHOLDER, reason)
+                .replace(CORRELATION_ID_PLACEHOLDER, correlation_id.unwrap_or(""))
+        };
+        let optional = |s: &str| match fill(s) {
+            f if f.is_empty() => None,
+            f => Some(f),
+        };
+
+        ErrorFields {
+            severity: self.severity.clone(),
+            sql_state: self.sql_state,
+            code: self.code,
+            message: fill(&self.message),
+            detail: optional(&self.detail),
+            hint: optional(&self.hint),
+        }
+    }
+}
+
+/// Generates an ID for a blocked query that support staff can search for in the audit log, made up of the
+/// current time in milliseconds followed by a sequence number (e.g. `018b2c1e4f3a-0007`).
+pub fn correlation_id() -> String {
+    let millis = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_millis(),
+        Err(_) => 0,
+    };
+    let sequence = CORRELATION_SEQUENCE.fetch_add(1, Ordering::Relaxed) & 0xFFFF;
+    format!("{:012x}-{:04x}", millis, sequence)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_error_does_not_reveal_reason() {
+        let fields = ErrorTemplate::default().render("UNION query at bytes 30..35", None);
+        assert_eq!(fields.detail, None);
+        assert!(!fields.message.contains("UNION"));
+    }
+
+    #[test]
+    fn reason_is_filled_in_when_requested() {
+        let template = ErrorTemplate {
+            detail: REASON_PLACEHOLDER.to_string(),
+            ..ErrorTemplate::default()
+        };
+        let fields = template.render("tautology at bytes 3..5", None);
+        assert_eq!(fields.detail.as_deref(), Some("tautology at bytes 3..5"));
+    }
+
+    #[test]
+    fn actions_fall_back_to_the_default() {
+        let policy = Policy {
+            default_action: Action::Tarpit,
+            actions: HashMap::from([(sql::Rule::UnionQuery, Action::Disconnect)]),
+            ..Policy::default()
+        };
+        assert_eq!(policy.action_for(sql::Rule::UnionQuery), Action::Disconnect);
+        assert_eq!(policy.action_for(sql::Rule::TimeDelay), Action::Tarpit);
+    }
+
+    #[test]
+    fn quarantine_expires() {
+        let (address, other) = ("192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap());
+        let mut quarantine = Quarantine::default();
+        quarantine.add(address, Duration::from_millis(50));
+        assert!(quarantine.contains(&address));
+        assert!(!quarantine.contains(&other));
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(!quarantine.contains(&address));
+    }
+
+    #[test]
+    fn quarantine_is_only_ever_extended() {
+        let address = "2001:db8::1".parse().unwrap();
+        let mut quarantine = Quarantine::default();
+        quarantine.add(address, Duration::from_secs(60));
+        quarantine.add(address, Duration::ZERO);
+        assert!(quarantine.contains(&address));
+    }
+
+    #[test]
+    fn expired_quarantines_are_forgotten() {
+        let mut quarantine = Quarantine::default();
+        quarantine.add("192.0.2.1".parse().unwrap(), Duration::ZERO);
+        quarantine.add("192.0.2.2".parse().unwrap(), Duration::from_secs(60));
+        assert_eq!(quarantine.until.len(), 1);
+    }
+}
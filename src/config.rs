@@ -0,0 +1,1155 @@
+use serde::de::{MapAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+use socket2::SockAddr;
+use std::collections::HashMap;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::{error, fmt, fs, path};
+use toml::Spanned;
+
+use crate::audit;
+use crate::blocking;
+use crate::control;
+use crate::dialect;
+use crate::event_handler;
+use crate::metrics;
+use crate::snapshot;
+use crate::sql;
+use crate::tls;
+use crate::unix_socket;
+use crate::validator;
+
+/// The number of times a proxy instance is restarted after failing if `max_restarts` isn't set.
+pub const DEFAULT_MAX_RESTARTS: u32 = 3;
+
+#[derive(Debug)]
+pub struct ConfigError {
+    reason: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", &self.reason)
+    }
+}
+
+impl error::Error for ConfigError {}
+
+/// The fully-resolved contents of a configuration file.
+pub struct Config {
+    pub proxies: Vec<ProxyConfig>,
+    /// The address that the admin listener (which serves `/metrics`) is bound to, if enabled
+    pub admin_address: Option<SocketAddr>,
+}
+
+/// The fully-resolved configuration of a single proxy instance, with any settings not
+/// specified in its own section inherited from `[proxy]`.
+pub struct ProxyConfig {
+    pub name: String,
+    /// The SQL dialect (and wire protocol) spoken by the database, selected with `db_type`
+    pub dialect: &'static dialect::Dialect,
+    pub listen_address: SockAddr,
+    pub db_address: SockAddr,
+    pub handler_params: event_handler::Parameters,
+    /// The number of times the proxy instance may be restarted after failing before the process is terminated
+    pub max_restarts: u32,
+    /// How long the proxy instance's connections are given to finish what they're doing when the process is stopped
+    pub shutdown_timeout: Duration,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConfig {
+    proxy: RawProxySection,
+    admin: Option<Spanned<RawAdmin>>,
+}
+
+/// The contents of `[admin]`, which enables the admin listener.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawAdmin {
+    listen: String,
+    port: u16,
+}
+
+/// The contents of `[proxy]`: default settings, followed by any number of `[proxy.<name>]` instances.
+struct RawProxySection {
+    defaults: RawInstance,
+    instances: Vec<(String, Spanned<RawInstance>)>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawInstance {
+    db_type: Option<Spanned<String>>,
+    listen: Option<Spanned<String>>,
+    port: Option<Spanned<u16>>,
+    unix_socket_mode: Option<Spanned<String>>,
+    unix_socket_owner: Option<Spanned<String>>,
+    unix_socket_group: Option<Spanned<String>>,
+    db_address: Option<Spanned<String>>,
+    db_port: Option<Spanned<u16>>,
+    max_restarts: Option<u32>,
+    shutdown_timeout_seconds: Option<u64>,
+    snapshot_path: Option<Spanned<String>>,
+    snapshot_interval: Option<u64>,
+    mode: Option<Spanned<validator::Mode>>,
+    learning_window_seconds: Option<u64>,
+    learning_window_queries: Option<u64>,
+    audit_log: Option<Spanned<String>>,
+    audit_log_max_size: Option<u64>,
+    audit_log_max_files: Option<u32>,
+    tls_cert: Option<Spanned<String>>,
+    tls_key: Option<Spanned<String>>,
+    client_tls: Option<Spanned<tls::Mode>>,
+    db_tls: Option<Spanned<tls::Mode>>,
+    db_tls_ca: Option<Spanned<String>>,
+    db_tls_server_name: Option<Spanned<String>>,
+    #[serde(default)]
+    detector: RawDetectorProfiles,
+    metadata_relations: Option<Vec<String>>,
+    #[serde(default)]
+    blocked_error: RawBlockedError,
+    block_action: Option<blocking::Action>,
+    #[serde(default)]
+    block_actions: HashMap<sql::Rule, blocking::Action>,
+    tarpit_seconds: Option<u64>,
+    quarantine_seconds: Option<u64>,
+}
+
+/// The contents of `[proxy.<name>.blocked_error]`, which customizes the error reported for blocked queries.
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawBlockedError {
+    severity: Option<Spanned<String>>,
+    sqlstate: Option<Spanned<String>>,
+    code: Option<u16>,
+    message: Option<String>,
+    detail: Option<String>,
+    hint: Option<String>,
+}
+
+/// Detection settings for each of the three ways a query can match previously-learned patterns.
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawDetectorProfiles {
+    #[serde(default)]
+    nopattern: RawDetectorParams,
+    #[serde(default)]
+    prefix: RawDetectorParams,
+    #[serde(default)]
+    prefix_suffix: RawDetectorParams,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawDetectorParams {
+    disallow_block_comments: Option<bool>,
+    disallow_line_comments: Option<bool>,
+    disallow_executable_comments: Option<bool>,
+    disallow_time_delays: Option<bool>,
+    disallow_heavy_queries: Option<bool>,
+    disallow_file_access: Option<bool>,
+    disallow_command_execution: Option<bool>,
+    disallow_network_access: Option<bool>,
+    disallow_metadata_access: Option<bool>,
+    multi_queries: Option<sql::MultipleQueries>,
+    tautologies: Option<sql::Tautologies>,
+    unions: Option<sql::Unions>,
+}
+
+impl<'de> Deserialize<'de> for RawProxySection {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_map(RawProxySectionVisitor)
+    }
+}
+
+struct RawProxySectionVisitor;
+
+impl<'de> Visitor<'de> for RawProxySectionVisitor {
+    type Value = RawProxySection;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a table of default settings and named proxy instances")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut defaults = RawInstance::default();
+        let mut instances = Vec::new();
+
+        // Keys that may be set directly within `[proxy]` are inherited by every proxy instance;
+        // any other table found within `[proxy]` configures a named proxy instance.
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "db_type" => defaults.db_type = Some(map.next_value()?),
+                "listen" => defaults.listen = Some(map.next_value()?),
+                "port" => defaults.port = Some(map.next_value()?),
+                "unix_socket_mode" => defaults.unix_socket_mode = Some(map.next_value()?),
+                "unix_socket_owner" => defaults.unix_socket_owner = Some(map.next_value()?),
+                "unix_socket_group" => defaults.unix_socket_group = Some(map.next_value()?),
+                "db_address" => defaults.db_address = Some(map.next_value()?),
+                "db_port" => defaults.db_port = Some(map.next_value()?),
+                "max_restarts" => defaults.max_restarts = Some(map.next_value()?),
+                "shutdown_timeout_seconds" => {
+                    defaults.shutdown_timeout_seconds = Some(map.next_value()?)
+                }
+                "snapshot_interval" => defaults.snapshot_interval = Some(map.next_value()?),
+                "mode" => defaults.mode = Some(map.next_value()?),
+                "learning_window_seconds" => {
+                    defaults.learning_window_seconds = Some(map.next_value()?)
+                }
+                "learning_window_queries" => {
+                    defaults.learning_window_queries = Some(map.next_value()?)
+                }
+                "audit_log" => defaults.audit_log = Some(map.next_value()?),
+                "audit_log_max_size" => defaults.audit_log_max_size = Some(map.next_value()?),
+                "audit_log_max_files" => defaults.audit_log_max_files = Some(map.next_value()?),
+                "tls_cert" => defaults.tls_cert = Some(map.next_value()?),
+                "tls_key" => defaults.tls_key = Some(map.next_value()?),
+                "client_tls" => defaults.client_tls = Some(map.next_value()?),
+                "db_tls" => defaults.db_tls = Some(map.next_value()?),
+                "db_tls_ca" => defaults.db_tls_ca = Some(map.next_value()?),
+                "db_tls_server_name" => defaults.db_tls_server_name = Some(map.next_value()?),
+                // Proxy instances sharing a snapshot would overwrite each other's learned patterns
+                "snapshot_path" => return Err(serde::de::Error::custom(
+                    "snapshot_path must be set separately for each proxy instance, not in [proxy]",
+                )),
+                "detector" => defaults.detector = map.next_value()?,
+                "metadata_relations" => defaults.metadata_relations = Some(map.next_value()?),
+                "blocked_error" => defaults.blocked_error = map.next_value()?,
+                "block_action" => defaults.block_action = Some(map.next_value()?),
+                "block_actions" => defaults.block_actions = map.next_value()?,
+                "tarpit_seconds" => defaults.tarpit_seconds = Some(map.next_value()?),
+                "quarantine_seconds" => defaults.quarantine_seconds = Some(map.next_value()?),
+                _ => instances.push((key, map.next_value()?)),
+            }
+        }
+
+        Ok(RawProxySection {
+            defaults,
+            instances,
+        })
+    }
+}
+
+/// Reads and validates the configuration file at the given path.
+pub fn load(config_path: &path::Path) -> Result<Config, ConfigError> {
+    let contents = match fs::read_to_string(config_path) {
+        Ok(c) => c,
+        Err(e) => {
+            return Err(ConfigError {
+                reason: format!(
+                    "couldn't read configuration file {}: {}",
+                    config_path.display(),
+                    e
+                ),
+            })
+        }
+    };
+
+    parse(contents.as_str()).map_err(|e| ConfigError {
+        reason: format!("{}: {}", config_path.display(), e.reason),
+    })
+}
+
+/// Parses and validates the given configuration file contents.
+pub fn parse(contents: &str) -> Result<Config, ConfigError> {
+    let raw: RawConfig = match toml::from_str(contents) {
+        Ok(r) => r,
+        Err(e) => {
+            let location = match e.span() {
+                Some(span) => {
+                    let line = line_of(contents, span.start);
+                    format!(
+                        " at line {} (`{}`)",
+                        line,
+                        contents.lines().nth(line - 1).unwrap_or("").trim()
+                    )
+                }
+                None => String::new(),
+            };
+            return Err(ConfigError {
+                reason: format!("invalid configuration{}: {}", location, e.message()),
+            });
+        }
+    };
+
+    if raw.proxy.instances.is_empty() {
+        return Err(ConfigError {
+            reason: "no proxy instances configured (add a [proxy.<name>] section)".to_string(),
+        });
+    }
+
+    let defaults = &raw.proxy.defaults;
+    let mut configs: Vec<ProxyConfig> = Vec::new();
+    // Proxy instances configured with the same audit log path write to the same file
+    let mut audit_logs: Vec<audit::SharedAuditLog> = Vec::new();
+
+    for (name, instance) in raw.proxy.instances.iter() {
+        let section_line = line_of(contents, instance.span().start);
+        let instance = instance.get_ref();
+
+        let missing = |key: &str| ConfigError {
+            reason: format!(
+                "proxy.{} (line {}): missing required key '{}' (set it in [proxy.{}] or inherit it from [proxy])",
+                name, section_line, key, name
+            ),
+        };
+
+        let db_type = match instance.db_type.as_ref().or(defaults.db_type.as_ref()) {
+            Some(t) => t,
+            None => return Err(missing("db_type")),
+        };
+        let dialect = match dialect::lookup(db_type.get_ref()) {
+            Ok(d) => d,
+            Err(e) => {
+                return Err(ConfigError {
+                    reason: format!(
+                        "proxy.{}.db_type (line {}): {}",
+                        name,
+                        line_of(contents, db_type.span().start),
+                        e
+                    ),
+                })
+            }
+        };
+        let listen = match instance.listen.as_ref().or(defaults.listen.as_ref()) {
+            Some(l) => l,
+            None => return Err(missing("listen")),
+        };
+        let port = instance.port.as_ref().or(defaults.port.as_ref());
+        let db_address = match instance.db_address.as_ref().or(defaults.db_address.as_ref()) {
+            Some(a) => a,
+            None => return Err(missing("db_address")),
+        };
+        let db_port = instance.db_port.as_ref().or(defaults.db_port.as_ref());
+
+        // Ports are only needed for addresses that aren't Unix domain sockets
+        let listen_address = match (unix_socket::parse_address(listen.get_ref()), port) {
+            (Some(path), _) => SockAddr::unix(path).map_err(|e| e.to_string()),
+            (None, Some(p)) => resolve_address(listen.get_ref(), *p.get_ref()).map(SockAddr::from),
+            (None, None) => return Err(missing("port")),
+        };
+        let listen_address = match listen_address {
+            Ok(a) => a,
+            Err(e) => {
+                return Err(ConfigError {
+                    reason: format!(
+                        "proxy.{}.listen (line {}): {}",
+                        name,
+                        line_of(contents, listen.span().start),
+                        e
+                    ),
+                })
+            }
+        };
+
+        let unix_socket_params = resolve_unix_socket(
+            name,
+            instance,
+            defaults,
+            unix_socket::parse_address(listen.get_ref()),
+            contents,
+        )?;
+
+        let tls_params = resolve_tls(
+            name,
+            instance,
+            defaults,
+            dialect,
+            db_address.get_ref(),
+            contents,
+        )?;
+
+        let db_socket_address = match (unix_socket::parse_address(db_address.get_ref()), db_port) {
+            (Some(path), _) => SockAddr::unix(path).map_err(|e| e.to_string()),
+            (None, Some(p)) => {
+                resolve_address(db_address.get_ref(), *p.get_ref()).map(SockAddr::from)
+            }
+            (None, None) => return Err(missing("db_port")),
+        };
+        let db_address = match db_socket_address {
+            Ok(a) => a,
+            Err(e) => {
+                return Err(ConfigError {
+                    reason: format!(
+                        "proxy.{}.db_address (line {}): {}",
+                        name,
+                        line_of(contents, db_address.span().start),
+                        e
+                    ),
+                })
+            }
+        };
+
+        let snapshot_params = match instance.snapshot_path.as_ref() {
+            Some(snapshot_path) => {
+                let snapshot_file = path::PathBuf::from(snapshot_path.get_ref());
+                let shared_with = configs.iter().find(|c| {
+                    c.handler_params.snapshot_params.as_ref().map(|p| &p.path)
+                        == Some(&snapshot_file)
+                });
+                if let Some(other) = shared_with {
+                    return Err(ConfigError {
+                        reason: format!(
+                            "proxy.{}.snapshot_path (line {}): '{}' is already used by proxy.{}",
+                            name,
+                            line_of(contents, snapshot_path.span().start),
+                            snapshot_path.get_ref(),
+                            other.name
+                        ),
+                    });
+                }
+
+                Some(snapshot::Parameters {
+                    path: snapshot_file,
+                    interval: match instance.snapshot_interval.or(defaults.snapshot_interval) {
+                        Some(0) => None,
+                        Some(secs) => Some(Duration::from_secs(secs)),
+                        None => Some(snapshot::DEFAULT_INTERVAL),
+                    },
+                    dialect: dialect.name,
+                })
+            }
+            None => None,
+        };
+
+        let auditor = match instance.audit_log.as_ref().or(defaults.audit_log.as_ref()) {
+            Some(audit_path) => {
+                let audit_file = path::PathBuf::from(audit_path.get_ref());
+                let max_size = instance
+                    .audit_log_max_size
+                    .or(defaults.audit_log_max_size)
+                    .unwrap_or(audit::DEFAULT_MAX_SIZE);
+                let max_files = instance
+                    .audit_log_max_files
+                    .or(defaults.audit_log_max_files)
+                    .unwrap_or(audit::DEFAULT_MAX_FILES);
+
+                let existing = audit_logs.iter().find(|l| match l.lock() {
+                    Ok(l) => *l.get_path() == audit_file,
+                    Err(_) => false,
+                });
+                let audit_log = match existing {
+                    Some(l) => {
+                        let matches = match l.lock() {
+                            Ok(l) => l.get_max_size() == max_size && l.get_max_files() == max_files,
+                            Err(_) => false,
+                        };
+                        if !matches {
+                            return Err(ConfigError {
+                                reason: format!(
+                                    "proxy.{}.audit_log (line {}): '{}' is shared with another proxy that has a different audit_log_max_size or audit_log_max_files",
+                                    name,
+                                    line_of(contents, audit_path.span().start),
+                                    audit_path.get_ref()
+                                ),
+                            });
+                        }
+                        l.clone()
+                    }
+                    None => {
+                        let l = Arc::new(Mutex::new(audit::AuditLog::new(
+                            audit_file, max_size, max_files,
+                        )));
+                        audit_logs.push(l.clone());
+                        l
+                    }
+                };
+
+                Some(audit::Auditor {
+                    instance: name.clone(),
+                    log: audit_log,
+                })
+            }
+            None => None,
+        };
+
+        let mode = instance.mode.as_ref().or(defaults.mode.as_ref());
+        let learning_window = match (
+            instance
+                .learning_window_seconds
+                .or(defaults.learning_window_seconds),
+            instance
+                .learning_window_queries
+                .or(defaults.learning_window_queries),
+        ) {
+            (None, None) => None,
+            (seconds, queries) => Some(validator::LearningWindow {
+                duration: seconds.map(Duration::from_secs),
+                queries,
+            }),
+        };
+
+        // A learning window monitors queries before switching to enforcement, so it makes no sense when only monitoring
+        if let (Some(m), Some(_)) = (mode, learning_window) {
+            if *m.get_ref() == validator::Mode::Monitor {
+                return Err(ConfigError {
+                    reason: format!(
+                        "proxy.{}.mode (line {}): a learning window can't be used in monitor mode",
+                        name,
+                        line_of(contents, m.span().start)
+                    ),
+                });
+            }
+        }
+
+        // Shared by every detector profile; if unset, each dialect's detector uses its own list
+        let metadata_relations = instance
+            .metadata_relations
+            .as_ref()
+            .or(defaults.metadata_relations.as_ref());
+
+        let validator_params = validator::Parameters {
+            detector_nopattern: resolve_detector_params(
+                &instance.detector.nopattern,
+                &defaults.detector.nopattern,
+                sql::Parameters::default_nopattern(),
+                metadata_relations,
+            ),
+            detector_prefix: resolve_detector_params(
+                &instance.detector.prefix,
+                &defaults.detector.prefix,
+                sql::Parameters::default_prefix(),
+                metadata_relations,
+            ),
+            detector_prefix_suffix: resolve_detector_params(
+                &instance.detector.prefix_suffix,
+                &defaults.detector.prefix_suffix,
+                sql::Parameters::default_prefix_suffix(),
+                metadata_relations,
+            ),
+            mode: match mode {
+                Some(m) => *m.get_ref(),
+                None => validator::Mode::Enforce,
+            },
+            learning_window,
+        };
+
+        configs.push(ProxyConfig {
+            name: name.clone(),
+            dialect,
+            listen_address,
+            db_address,
+            handler_params: event_handler::Parameters {
+                validator_params,
+                snapshot_params,
+                auditor,
+                metrics: Arc::new(metrics::InstanceMetrics::default()),
+                tls: tls_params,
+                blocking: resolve_blocking(name, instance, defaults, contents)?,
+                unix_socket: unix_socket_params,
+                control: Arc::new(control::Control::default()),
+            },
+            max_restarts: instance
+                .max_restarts
+                .or(defaults.max_restarts)
+                .unwrap_or(DEFAULT_MAX_RESTARTS),
+            shutdown_timeout: match instance
+                .shutdown_timeout_seconds
+                .or(defaults.shutdown_timeout_seconds)
+            {
+                Some(secs) => Duration::from_secs(secs),
+                None => control::DEFAULT_SHUTDOWN_TIMEOUT,
+            },
+        });
+    }
+
+    let admin_address = match raw.admin.as_ref() {
+        Some(admin) => match resolve_address(&admin.get_ref().listen, admin.get_ref().port) {
+            Ok(a) => Some(a),
+            Err(e) => {
+                return Err(ConfigError {
+                    reason: format!(
+                        "admin.listen (line {}): {}",
+                        line_of(contents, admin.span().start),
+                        e
+                    ),
+                })
+            }
+        },
+        None => None,
+    };
+
+    if let Some(admin_address) = admin_address {
+        if let Some(other) = configs
+            .iter()
+            .find(|c| c.listen_address.as_socket() == Some(admin_address))
+        {
+            return Err(ConfigError {
+                reason: format!(
+                    "admin: address {} is already used by proxy.{}",
+                    admin_address, other.name
+                ),
+            });
+        }
+    }
+
+    Ok(Config {
+        proxies: configs,
+        admin_address,
+    })
+}
+
+/// Fills in each detection setting from the proxy instance, then `[proxy]`, then the built-in defaults (in that order of precedence).
+fn resolve_detector_params(
+    instance: &RawDetectorParams,
+    defaults: &RawDetectorParams,
+    builtin: sql::Parameters,
+    metadata_relations: Option<&Vec<String>>,
+) -> sql::Parameters {
+    sql::Parameters {
+        disallow_block_comments: instance
+            .disallow_block_comments
+            .or(defaults.disallow_block_comments)
+            .unwrap_or(builtin.disallow_block_comments),
+        disallow_line_comments: instance
+            .disallow_line_comments
+            .or(defaults.disallow_line_comments)
+            .unwrap_or(builtin.disallow_line_comments),
+        disallow_executable_comments: instance
+            .disallow_executable_comments
+            .or(defaults.disallow_executable_comments)
+            .unwrap_or(builtin.disallow_executable_comments),
+        disallow_time_delays: instance
+            .disallow_time_delays
+            .or(defaults.disallow_time_delays)
+            .unwrap_or(builtin.disallow_time_delays),
+        disallow_heavy_queries: instance
+            .disallow_heavy_queries
+            .or(defaults.disallow_heavy_queries)
+            .unwrap_or(builtin.disallow_heavy_queries),
+        disallow_file_access: instance
+            .disallow_file_access
+            .or(defaults.disallow_file_access)
+            .unwrap_or(builtin.disallow_file_access),
+        disallow_command_execution: instance
+            .disallow_command_execution
+            .or(defaults.disallow_command_execution)
+            .unwrap_or(builtin.disallow_command_execution),
+        disallow_network_access: instance
+            .disallow_network_access
+            .or(defaults.disallow_network_access)
+            .unwrap_or(builtin.disallow_network_access),
+        disallow_metadata_access: instance
+            .disallow_metadata_access
+            .or(defaults.disallow_metadata_access)
+            .unwrap_or(builtin.disallow_metadata_access),
+        metadata_relations: metadata_relations.cloned().or(builtin.metadata_relations),
+        multi_queries: instance
+            .multi_queries
+            .or(defaults.multi_queries)
+            .unwrap_or(builtin.multi_queries),
+        tautologies: instance
+            .tautologies
+            .or(defaults.tautologies)
+            .unwrap_or(builtin.tautologies),
+        unions: instance
+            .unions
+            .or(defaults.unions)
+            .unwrap_or(builtin.unions),
+    }
+}
+
+/// Fills in how a proxy instance responds to blocked queries. Actions for individual rules set in `[proxy.block_actions]`
+/// are kept unless the proxy instance sets its own action for the same rule.
+fn resolve_blocking(
+    name: &str,
+    instance: &RawInstance,
+    defaults: &RawInstance,
+    contents: &str,
+) -> Result<blocking::Policy, ConfigError> {
+    let builtin = blocking::Policy::default();
+
+    let mut actions = defaults.block_actions.clone();
+    actions.extend(instance.block_actions.iter());
+
+    Ok(blocking::Policy {
+        error: resolve_blocked_error(
+            name,
+            &instance.blocked_error,
+            &defaults.blocked_error,
+            contents,
+        )?,
+        default_action: instance
+            .block_action
+            .or(defaults.block_action)
+            .unwrap_or(builtin.default_action),
+        actions,
+        tarpit_delay: match instance.tarpit_seconds.or(defaults.tarpit_seconds) {
+            Some(secs) => Duration::from_secs(secs),
+            None => builtin.tarpit_delay,
+        },
+        quarantine_duration: match instance.quarantine_seconds.or(defaults.quarantine_seconds) {
+            Some(secs) => Duration::from_secs(secs),
+            None => builtin.quarantine_duration,
+        },
+    })
+}
+
+/// Fills in each field of the blocked error from the proxy instance, then `[proxy]`, then the built-in defaults
+/// (in that order of precedence), checking that the severity and SQLSTATE are ones that clients will accept.
+fn resolve_blocked_error(
+    name: &str,
+    instance: &RawBlockedError,
+    defaults: &RawBlockedError,
+    contents: &str,
+) -> Result<blocking::ErrorTemplate, ConfigError> {
+    let invalid = |key: &str, span: std::ops::Range<usize>, reason: &str| ConfigError {
+        reason: format!(
+            "proxy.{}.blocked_error.{} (line {}): {}",
+            name,
+            key,
+            line_of(contents, span.start),
+            reason
+        ),
+    };
+    let builtin = blocking::ErrorTemplate::default();
+
+    let severity = match instance.severity.as_ref().or(defaults.severity.as_ref()) {
+        Some(s) => match s.get_ref().as_str() {
+            "ERROR" | "FATAL" | "PANIC" => s.get_ref().clone(),
+            _ => {
+                return Err(invalid(
+                    "severity",
+                    s.span(),
+                    "must be \"ERROR\", \"FATAL\" or \"PANIC\"",
+                ))
+            }
+        },
+        None => builtin.severity,
+    };
+
+    let is_sqlstate_char = |c: &u8| c.is_ascii_digit() || c.is_ascii_uppercase();
+    let sql_state = match instance.sqlstate.as_ref().or(defaults.sqlstate.as_ref()) {
+        Some(s) => match <[u8; 5]>::try_from(s.get_ref().as_bytes()) {
+            Ok(code) if code.iter().all(is_sqlstate_char) => code,
+            _ => {
+                return Err(invalid(
+                    "sqlstate",
+                    s.span(),
+                    "must be 5 digits or uppercase letters (e.g. \"42601\")",
+                ))
+            }
+        },
+        None => builtin.sql_state,
+    };
+
+    Ok(blocking::ErrorTemplate {
+        severity,
+        sql_state,
+        code: instance.code.or(defaults.code).unwrap_or(builtin.code),
+        message: instance
+            .message
+            .clone()
+            .or_else(|| defaults.message.clone())
+            .unwrap_or(builtin.message),
+        detail: instance
+            .detail
+            .clone()
+            .or_else(|| defaults.detail.clone())
+            .unwrap_or(builtin.detail),
+        hint: instance
+            .hint
+            .clone()
+            .or_else(|| defaults.hint.clone())
+            .unwrap_or(builtin.hint),
+    })
+}
+
+/// Collects the permissions and ownership given to the socket file of a proxy instance listening on a Unix
+/// domain socket, which can't be set for instances listening on a host.
+fn resolve_unix_socket(
+    name: &str,
+    instance: &RawInstance,
+    defaults: &RawInstance,
+    listen_path: Option<&path::Path>,
+    contents: &str,
+) -> Result<Option<unix_socket::Parameters>, ConfigError> {
+    let invalid = |key: &str, span: std::ops::Range<usize>, reason: String| ConfigError {
+        reason: format!(
+            "proxy.{}.{} (line {}): {}",
+            name,
+            key,
+            line_of(contents, span.start),
+            reason
+        ),
+    };
+
+    let mode = instance
+        .unix_socket_mode
+        .as_ref()
+        .or(defaults.unix_socket_mode.as_ref());
+    let owner = instance
+        .unix_socket_owner
+        .as_ref()
+        .or(defaults.unix_socket_owner.as_ref());
+    let group = instance
+        .unix_socket_group
+        .as_ref()
+        .or(defaults.unix_socket_group.as_ref());
+
+    let path = match listen_path {
+        Some(p) => p,
+        None => {
+            let keys = [
+                ("unix_socket_mode", mode),
+                ("unix_socket_owner", owner),
+                ("unix_socket_group", group),
+            ];
+            return match keys.iter().find_map(|(key, value)| value.map(|v| (key, v))) {
+                Some((key, value)) => Err(invalid(
+                    key,
+                    value.span(),
+                    format!(
+                        "can only be set when listen is a Unix domain socket ('{}<path>')",
+                        unix_socket::ADDRESS_PREFIX
+                    ),
+                )),
+                None => Ok(None),
+            };
+        }
+    };
+
+    let mode = match mode {
+        Some(m) => match u32::from_str_radix(m.get_ref(), 8) {
+            Ok(bits) if bits <= 0o777 => Some(bits),
+            _ => {
+                return Err(invalid(
+                    "unix_socket_mode",
+                    m.span(),
+                    "must be an octal permission mode (e.g. \"0660\")".to_string(),
+                ))
+            }
+        },
+        None => None,
+    };
+    let owner = match owner {
+        Some(o) => match unix_socket::lookup_user(o.get_ref()) {
+            Ok(uid) => Some(uid),
+            Err(e) => return Err(invalid("unix_socket_owner", o.span(), e)),
+        },
+        None => None,
+    };
+    let group = match group {
+        Some(g) => match unix_socket::lookup_group(g.get_ref()) {
+            Ok(gid) => Some(gid),
+            Err(e) => return Err(invalid("unix_socket_group", g.span(), e)),
+        },
+        None => None,
+    };
+
+    Ok(Some(unix_socket::Parameters {
+        path: path.to_path_buf(),
+        mode,
+        owner,
+        group,
+    }))
+}
+
+/// Loads the certificates and keys used to encrypt a proxy instance's connections, checking that each
+/// TLS setting (inherited from `[proxy]` if unset) is consistent with the others.
+fn resolve_tls(
+    name: &str,
+    instance: &RawInstance,
+    defaults: &RawInstance,
+    dialect: &dialect::Dialect,
+    db_host: &str,
+    contents: &str,
+) -> Result<tls::Parameters, ConfigError> {
+    let invalid = |key: &str, span: std::ops::Range<usize>, reason: String| ConfigError {
+        reason: format!(
+            "proxy.{}.{} (line {}): {}",
+            name,
+            key,
+            line_of(contents, span.start),
+            reason
+        ),
+    };
+
+    let tls_cert = instance.tls_cert.as_ref().or(defaults.tls_cert.as_ref());
+    let tls_key = instance.tls_key.as_ref().or(defaults.tls_key.as_ref());
+    let client_tls = instance
+        .client_tls
+        .as_ref()
+        .or(defaults.client_tls.as_ref());
+    let db_tls = instance.db_tls.as_ref().or(defaults.db_tls.as_ref());
+    let db_tls_ca = instance.db_tls_ca.as_ref().or(defaults.db_tls_ca.as_ref());
+    let db_tls_server_name = instance
+        .db_tls_server_name
+        .as_ref()
+        .or(defaults.db_tls_server_name.as_ref());
+
+    // Clients are offered TLS by default whenever a certificate is available
+    let client_mode = match (client_tls, tls_cert) {
+        (Some(m), _) => *m.get_ref(),
+        (None, Some(_)) => tls::Mode::Prefer,
+        (None, None) => tls::Mode::Disable,
+    };
+    let db_mode = match db_tls {
+        Some(m) => *m.get_ref(),
+        None => tls::Mode::Disable,
+    };
+
+    if !dialect.supports_tls && (client_mode != tls::Mode::Disable || db_mode != tls::Mode::Disable)
+    {
+        return Err(ConfigError {
+            reason: format!(
+                "proxy.{}: TLS isn't supported for db_type '{}' (set client_tls and db_tls to \"disable\")",
+                name, dialect.name
+            ),
+        });
+    }
+
+    let frontend = match (client_mode, tls_cert, tls_key) {
+        (tls::Mode::Disable, _, _) => None,
+        (_, Some(cert), Some(key)) => {
+            let config = match tls::server_config(
+                path::Path::new(cert.get_ref()),
+                path::Path::new(key.get_ref()),
+            ) {
+                Ok(c) => c,
+                Err(e) => return Err(invalid("tls_cert", cert.span(), e.to_string())),
+            };
+            Some(tls::FrontendParameters {
+                required: client_mode == tls::Mode::Require,
+                config,
+            })
+        }
+        (_, Some(cert), None) => {
+            return Err(invalid(
+                "tls_cert",
+                cert.span(),
+                "tls_key must also be set".to_string(),
+            ))
+        }
+        (_, None, _) => {
+            return Err(invalid(
+                "client_tls",
+                client_tls.map_or(0..0, |m| m.span()),
+                "tls_cert and tls_key must be set to accept TLS connections".to_string(),
+            ));
+        }
+    };
+
+    let backend = match (db_mode, db_tls_ca) {
+        (tls::Mode::Disable, _) => None,
+        (_, Some(ca)) => {
+            let config = match tls::client_config(path::Path::new(ca.get_ref())) {
+                Ok(c) => c,
+                Err(e) => return Err(invalid("db_tls_ca", ca.span(), e.to_string())),
+            };
+            // The certificate is checked against the database's address as written unless another name is given
+            let server_name = match db_tls_server_name {
+                Some(n) => match rustls::ServerName::try_from(n.get_ref().as_str()) {
+                    Ok(sn) => sn,
+                    Err(_) => {
+                        return Err(invalid(
+                            "db_tls_server_name",
+                            n.span(),
+                            format!("'{}' is not a valid host name or IP address", n.get_ref()),
+                        ))
+                    }
+                },
+                None => match rustls::ServerName::try_from(db_host) {
+                    Ok(sn) => sn,
+                    Err(_) => {
+                        return Err(ConfigError {
+                            reason: format!(
+                                "proxy.{}: db_tls_server_name must be set, as db_address '{}' can't be used to verify the database's certificate",
+                                name, db_host
+                            ),
+                        })
+                    }
+                },
+            };
+            Some(tls::BackendParameters {
+                required: db_mode == tls::Mode::Require,
+                config,
+                server_name,
+            })
+        }
+        (_, None) => {
+            return Err(invalid(
+                "db_tls",
+                db_tls.map_or(0..0, |m| m.span()),
+                "db_tls_ca must be set to verify the database's certificate".to_string(),
+            ));
+        }
+    };
+
+    Ok(tls::Parameters { frontend, backend })
+}
+
+/// Resolves a host name or IP address into a socket address (using the first address found).
+fn resolve_address(host: &str, port: u16) -> Result<SocketAddr, String> {
+    match (host, port).to_socket_addrs() {
+        Ok(mut addrs) => match addrs.next() {
+            Some(addr) => Ok(addr),
+            None => Err(format!("'{}' did not resolve to any address", host)),
+        },
+        Err(e) => Err(format!("couldn't resolve address '{}': {}", host, e)),
+    }
+}
+
+/// Returns the (1-indexed) line number that the given byte offset falls on.
+fn line_of(contents: &str, offset: usize) -> usize {
+    contents
+        .get(..offset)
+        .unwrap_or(contents)
+        .matches('\n')
+        .count()
+        + 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INSTANCE: &str = r#"
+[proxy.pg]
+db_type = "postgresql"
+listen = "127.0.0.1"
+port = 6432
+db_address = "127.0.0.1"
+db_port = 5432
+"#;
+
+    fn error(contents: &str) -> String {
+        match parse(contents) {
+            Ok(_) => panic!("configuration was accepted:\n{}", contents),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn accepts_a_complete_instance() {
+        let config = parse(INSTANCE).unwrap();
+        assert_eq!(config.proxies.len(), 1);
+        assert_eq!(config.proxies[0].name, "pg");
+        assert_eq!(config.proxies[0].dialect.name, "postgresql");
+        assert!(config.admin_address.is_none());
+    }
+
+    #[test]
+    fn rejects_invalid_toml_with_its_location() {
+        let reason = error("[proxy.pg]\ndb_type = postgresql\n");
+        assert!(
+            reason.starts_with("invalid configuration at line 2 (`db_type = postgresql`)"),
+            "{}",
+            reason
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        let reason = error(&format!("{}db_porr = 5433\n", INSTANCE));
+        assert!(
+            reason.starts_with("invalid configuration at line 8"),
+            "{}",
+            reason
+        );
+    }
+
+    #[test]
+    fn rejects_missing_instances() {
+        assert_eq!(
+            error("[proxy]\ndb_type = \"postgresql\"\n"),
+            "no proxy instances configured (add a [proxy.<name>] section)"
+        );
+    }
+
+    #[test]
+    fn rejects_missing_required_keys() {
+        for key in ["db_type", "listen", "port", "db_address", "db_port"] {
+            let contents: String = INSTANCE
+                .lines()
+                .filter(|line| !line.starts_with(&format!("{} ", key)))
+                .map(|line| format!("{}\n", line))
+                .collect();
+            assert_eq!(
+                error(&contents),
+                format!(
+                    "proxy.pg (line 2): missing required key '{}' (set it in [proxy.pg] or inherit it from [proxy])",
+                    key
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn inherits_required_keys_from_defaults() {
+        let contents = "[proxy]\ndb_type = \"cockroach\"\nlisten = \"127.0.0.1\"\ndb_address = \"127.0.0.1\"\ndb_port = 26257\n\n[proxy.crdb]\nport = 6432\n";
+        let config = parse(contents).unwrap();
+        assert_eq!(config.proxies[0].dialect.name, "cockroach");
+    }
+
+    #[test]
+    fn rejects_unknown_database_types() {
+        let reason = error(&INSTANCE.replace("\"postgresql\"", "\"oracle\""));
+        assert!(
+            reason.starts_with("proxy.pg.db_type (line 3): unknown database type 'oracle'"),
+            "{}",
+            reason
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_blocked_errors() {
+        let reason = error(&format!(
+            "{}\n[proxy.pg.blocked_error]\nseverity = \"WARNING\"\n",
+            INSTANCE
+        ));
+        assert_eq!(
+            reason,
+            "proxy.pg.blocked_error.severity (line 10): must be \"ERROR\", \"FATAL\" or \"PANIC\""
+        );
+    }
+
+    #[test]
+    fn rejects_tls_for_dialects_without_it() {
+        let contents = INSTANCE.replace("\"postgresql\"", "\"mysql\"") + "db_tls = \"require\"\n";
+        assert_eq!(
+            error(&contents),
+            "proxy.pg: TLS isn't supported for db_type 'mysql' (set client_tls and db_tls to \"disable\")"
+        );
+    }
+
+    #[test]
+    fn rejects_learning_windows_in_monitor_mode() {
+        let contents = format!(
+            "{}mode = \"monitor\"\nlearning_window_queries = 100\n",
+            INSTANCE
+        );
+        assert_eq!(
+            error(&contents),
+            "proxy.pg.mode (line 8): a learning window can't be used in monitor mode"
+        );
+    }
+
+    #[test]
+    fn rejects_shared_snapshot_paths() {
+        let snapshot_path = "snapshot_path = \"/tmp/patterns.json\"\n";
+        let other = INSTANCE
+            .replace("proxy.pg", "proxy.pg2")
+            .replace("6432", "6433");
+        let contents = format!("{}{}{}{}", INSTANCE, snapshot_path, other, snapshot_path);
+        assert_eq!(
+            error(&contents),
+            "proxy.pg2.snapshot_path (line 16): '/tmp/patterns.json' is already used by proxy.pg"
+        );
+    }
+
+    #[test]
+    fn rejects_admin_addresses_used_by_a_proxy() {
+        let contents = format!(
+            "{}\n[admin]\nlisten = \"127.0.0.1\"\nport = 6432\n",
+            INSTANCE
+        );
+        assert_eq!(
+            error(&contents),
+            "admin: address 127.0.0.1:6432 is already used by proxy.pg"
+        );
+    }
+}
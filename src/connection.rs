@@ -4,9 +4,16 @@ use crate::sql_wire;
 use crate::sql_wire::{ClientPacket, ServerPacket};
 use socket2::{SockAddr, Socket};
 use std::collections::VecDeque;
+use std::io::Write;
+use std::sync::Arc;
+use std::time::Instant;
 use std::{io, net, ops};
 
-use super::validator::SqlValidator;
+use super::validator::{MatchPath, SqlValidator};
+use crate::audit;
+use crate::blocking;
+use crate::metrics;
+use crate::tls;
 
 /// The maximum number of requests or responses to buffer in each direction
 const REQUEST_QUEUE_SOFT_LIMIT: usize = 10;
@@ -16,17 +23,28 @@ const CODE_EISCONNECTED: i32 = 106;
 const CODE_EINPROGRESS: i32 = 115;
 
 struct RequestMetadata {
-    is_malicious: bool,
-    query: Option<String>,
+    /// Set if the request was blocked, as the error reported to the frontend in place of its result
+    blocked_error: Option<sql_wire::ErrorFields>,
+    /// Indicates that the request was still forwarded to the backend despite being malicious (such as the `Sync`
+    /// terminating a blocked extended query), so its result must be replaced with an error rather than spoofed outright.
+    is_forwarded: bool,
+    /// The SQL queries whose outcome is determined by the result of this request, along with the path each took when checked
+    queries: Vec<(String, MatchPath)>,
 }
 
 /// The possible connection states of a `Proxy`.
 #[derive(PartialEq, Eq)]
 enum ConnectionState {
-    // ClientTLSHandshake, // unimplemented
+    /// The frontend has been told that TLS is supported and is performing a TLS handshake
+    ClientTLSHandshake,
     /// The backend is performing a TCP handshake
     DatabaseTCPHandshake, // called connect() to database, awaiting completed connection
-    // DatabaseTLSHandshake, // unimplemented
+    /// The backend is being asked whether it supports TLS
+    DatabaseTLSRequest,
+    /// The backend's answer to whether it supports TLS is awaited
+    DatabaseTLSResponse,
+    /// The backend is performing a TLS handshake
+    DatabaseTLSHandshake,
     /// The frontend and backend are fully connected and transferring data
     Connected,
 }
@@ -101,20 +119,41 @@ impl ops::BitOrAssign for ProxyResult {
 /// Handles and proxies a single SQL connection between an incoming client connection
 /// and the backend database.
 ///
-pub struct Connection<D: sql::Detector, P: sql_wire::Proxy<Socket, Socket>> {
+pub struct Connection<D: sql::Detector, P: sql_wire::Proxy<tls::Stream, tls::Stream>> {
     backend_address: SockAddr,
     backend_key: usize,
     backend_read_closed: bool,
     backend_write_closed: bool,
+    /// The error reported for the request being discarded (see `discarding_request`)
+    blocked_error: Option<sql_wire::ErrorFields>,
+    /// Set when a query in a multi-message request has been blocked, causing the remaining messages of that request to be discarded
+    discarding_request: bool,
+    /// Set when a request has been blocked with an error that ends the session, after which no further requests are read
+    ending_session: bool,
     frontend_address: String,
+    /// The client's IP address, which is quarantined if the client's queries call for it
+    frontend_ip: Option<net::IpAddr>,
     frontend_key: usize,
     frontend_read_closed: bool,
     incoming_data: VecDeque<P::RequestType>,
+    metrics: Arc<metrics::InstanceMetrics>,
     outgoing_data: VecDeque<P::ResponseType>,
+    /// Queries carried by messages that will be answered by the next request (e.g. Postgres `Parse` messages preceding a `Sync`)
+    pending_queries: Vec<(String, MatchPath)>,
     request_queue: VecDeque<RequestMetadata>,
+    /// Set when messages belonging to a request have been forwarded before the request itself was received
+    request_partially_forwarded: bool,
     sql_session: P,
     /// The current connectivity state of the proxy
     state: ConnectionState,
+    /// If set, nothing is sent to the frontend until this time (delaying the error for a tarpitted query)
+    tarpit_until: Option<Instant>,
+    /// How TLS is used with the frontend and backend
+    tls: tls::Parameters,
+    /// The user that the client most recently authenticated as (if known)
+    username: Option<String>,
+    /// The database that the client most recently selected (if known)
+    database: Option<String>,
     _sqli_detector_type: std::marker::PhantomData<D>,
 }
 
@@ -127,7 +166,8 @@ pub struct Connection<D: sql::Detector, P: sql_wire::Proxy<Socket, Socket>> {
 // 4. Once the packet to remove is reached, use the `advance_read()` function in the Buffer to completely remove the packet
 // 5. Go back to write_raw() after done for efficiency
 
-impl<D: sql::Detector, P: sql_wire::Proxy<Socket, Socket>> Connection<D, P> {
+impl<D: sql::Detector, P: sql_wire::Proxy<tls::Stream, tls::Stream>> Connection<D, P> {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         backend_address: SockAddr,
         backend_key: usize,
@@ -135,24 +175,48 @@ impl<D: sql::Detector, P: sql_wire::Proxy<Socket, Socket>> Connection<D, P> {
         frontend_address: String,
         frontend_key: usize,
         frontend_socket: Socket,
+        metrics: Arc<metrics::InstanceMetrics>,
+        tls: tls::Parameters,
     ) -> Self {
         Connection {
             backend_address,
             backend_key,
             backend_read_closed: false,
             backend_write_closed: false,
+            blocked_error: None,
+            discarding_request: false,
+            ending_session: false,
+            frontend_ip: match frontend_address.parse::<net::SocketAddr>() {
+                Ok(addr) => Some(addr.ip()),
+                Err(_) => None,
+            },
             frontend_address: frontend_address,
             frontend_key,
             frontend_read_closed: false,
             incoming_data: VecDeque::new(),
+            metrics,
             outgoing_data: VecDeque::new(),
+            pending_queries: Vec::new(),
             request_queue: VecDeque::new(),
-            sql_session: P::new(backend_socket, frontend_socket),
+            request_partially_forwarded: false,
+            sql_session: P::new(
+                tls::Stream::new(backend_socket),
+                tls::Stream::new(frontend_socket),
+            ),
             state: ConnectionState::DatabaseTCPHandshake,
+            tarpit_until: None,
+            tls,
+            username: None,
+            database: None,
             _sqli_detector_type: std::marker::PhantomData {},
         }
     }
 
+    /// Returns the time until which nothing is sent to the frontend, if its client has been tarpitted.
+    pub fn get_tarpit_deadline(&self) -> Option<Instant> {
+        self.tarpit_until
+    }
+
     /// Returns the polling key associated with the database socket of the given proxy.
     pub fn get_backend_key(&self) -> usize {
         self.backend_key
@@ -160,7 +224,7 @@ impl<D: sql::Detector, P: sql_wire::Proxy<Socket, Socket>> Connection<D, P> {
 
     /// Returns the client socket of the given proxy connection.
     pub fn get_backend_socket(&self) -> &Socket {
-        self.sql_session.get_backend_io_ref()
+        self.sql_session.get_backend_io_ref().get_socket()
     }
 
     pub fn get_frontend_address<'a>(&'a self) -> &'a str {
@@ -174,7 +238,29 @@ impl<D: sql::Detector, P: sql_wire::Proxy<Socket, Socket>> Connection<D, P> {
 
     /// Returns the database socket of the given proxy.
     pub fn get_frontend_socket(&self) -> &Socket {
-        self.sql_session.get_frontend_io_ref()
+        self.sql_session.get_frontend_io_ref().get_socket()
+    }
+
+    /// Shuts down the given half of the backend socket, ending its TLS session first (if any).
+    fn shutdown_backend(&mut self, how: net::Shutdown) -> io::Result<()> {
+        self.sql_session.get_backend_io_mut().shutdown(how)
+    }
+
+    /// Shuts down the given half of the frontend socket, ending its TLS session first (if any).
+    fn shutdown_frontend(&mut self, how: net::Shutdown) -> io::Result<()> {
+        self.sql_session.get_frontend_io_mut().shutdown(how)
+    }
+
+    /// Returns `true` if the connection is established and nothing is in flight in either direction, so that it can be
+    /// closed without cutting off a request or its response.
+    pub fn is_idle(&self) -> bool {
+        self.state == ConnectionState::Connected
+            && self.incoming_data.is_empty()
+            && self.outgoing_data.is_empty()
+            && self.request_queue.is_empty()
+            && self.pending_queries.is_empty()
+            && !self.request_partially_forwarded
+            && self.tarpit_until.is_none()
     }
 
     /// Returns `true` if no more packets remain to be sent to the backend and the frontend client has closed its write end
@@ -186,33 +272,38 @@ impl<D: sql::Detector, P: sql_wire::Proxy<Socket, Socket>> Connection<D, P> {
     /// in order:
     ///
     /// 1. Connecting to the database via TCP (if not connected)
-    /// 2. Processing any data received from the client and validating any SQL query contained within it using `validator` (if connected)
-    /// 3. Forwarding data received from the client to the backend database (if connected)
+    /// 2. Performing a TLS handshake with the database or client (if either is in progress)
+    /// 3. Processing any data received from the client and validating any SQL query contained within it using `validator` (if connected),
+    ///    recording the outcome with `auditor` (if set) and responding to blocked queries according to `policy`
+    ///    (quarantining the client in `quarantine` if called for)
+    /// 4. Forwarding data received from the client to the backend database (if connected)
     ///
     /// Returns a tuple indicating the read/write events needed on the proxy's client socket and database socket (in that order).
     ///
-    pub fn process_incoming(&mut self, validator: &mut SqlValidator<D>) -> io::Result<ProxyResult> {
+    pub fn process_incoming(
+        &mut self,
+        validator: &mut SqlValidator<D>,
+        auditor: Option<&audit::Auditor>,
+        policy: &blocking::Policy,
+        quarantine: &mut blocking::Quarantine,
+    ) -> io::Result<ProxyResult> {
         let mut res = ProxyResult::none();
 
-        /*
-        if self.state == State::ClientTLSHandshake {
-            client_ev |= self.connect_client_tls()?;
-        }
-        */
-
         if self.state == ConnectionState::DatabaseTCPHandshake {
             res |= self.connect_backend()?;
         }
 
-        /*
-        if self.state == State::DatabaseTLSHandshake {
-            db_ev |= self.connect_db_tls()?;
+        match self.state {
+            ConnectionState::DatabaseTLSRequest
+            | ConnectionState::DatabaseTLSResponse
+            | ConnectionState::DatabaseTLSHandshake => res |= self.connect_backend_tls()?,
+            ConnectionState::ClientTLSHandshake => res |= self.connect_client_tls()?,
+            _ => (),
         }
-        */
 
         if self.state == ConnectionState::Connected {
             // Only go through one round of each phase to ensure fairness at the EventHandler layer
-            res |= match self.process_frontend_data(validator) {
+            res |= match self.process_frontend_data(validator, auditor, policy, quarantine) {
                 Ok(needs) => needs,
                 Err(e) => match e.kind() {
                     io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => ProxyResult {
@@ -243,7 +334,7 @@ impl<D: sql::Detector, P: sql_wire::Proxy<Socket, Socket>> Connection<D, P> {
     /// Progresses the state of the given proxy connection by attempting each of the following operations once,
     /// in order:
     ///
-    /// 1. Connecting to the database via TCP (if not connected)
+    /// 1. Performing a TLS handshake with the database, or writing out that of the client (if either is in progress)
     /// 2. Processing any data received from the backend database and updating `validator` based on SQL query response (if connected)
     /// 3. Forwarding data received from the database to the client (if connected)
     ///
@@ -252,23 +343,15 @@ impl<D: sql::Detector, P: sql_wire::Proxy<Socket, Socket>> Connection<D, P> {
     pub fn process_outgoing(&mut self, validator: &mut SqlValidator<D>) -> io::Result<ProxyResult> {
         let mut res = ProxyResult::none();
 
-        /*
-        if self.state == State::ClientTLSHandshake {
-            client_ev |= self.connect_client_tls()?;
-        }
-        */
-
-        /*
-        if self.state == ConnectionState::DatabaseTCPHandshake {
-            backend_ev |= self.connect_backend()?;
-        }
-        */
-
-        /*
-        if self.state == State::DatabaseTLSHandshake {
-            db_ev |= self.connect_db_tls()?;
+        match self.state {
+            ConnectionState::DatabaseTLSRequest
+            | ConnectionState::DatabaseTLSResponse
+            | ConnectionState::DatabaseTLSHandshake => res |= self.connect_backend_tls()?,
+            // Only the handshake's writes are made here; reading the frontend's replies is left to `process_incoming()`
+            // so that whatever the frontend sends once the handshake completes is read straight away
+            ConnectionState::ClientTLSHandshake => res |= self.flush_client_tls()?,
+            _ => (),
         }
-        */
 
         if self.state == ConnectionState::Connected {
             // Only go through one round of each phase to ensure fairness at the EventHandler layer
@@ -309,7 +392,7 @@ impl<D: sql::Detector, P: sql_wire::Proxy<Socket, Socket>> Connection<D, P> {
                     "Connection to database completed for socket with key {}",
                     self.get_backend_key()
                 );
-                self.state = ConnectionState::Connected;
+                self.state = self.tcp_connected_state();
                 Ok(ProxyResult {
                     frontend: IONeed::Read,
                     backend: IONeed::Read,
@@ -321,7 +404,7 @@ impl<D: sql::Detector, P: sql_wire::Proxy<Socket, Socket>> Connection<D, P> {
                     "EISCONNECTED error on connect() for socket with key {}",
                     self.get_backend_key()
                 );
-                self.state = ConnectionState::Connected;
+                self.state = self.tcp_connected_state();
                 Ok(ProxyResult {
                     frontend: IONeed::Read,
                     backend: IONeed::Read,
@@ -339,6 +422,201 @@ impl<D: sql::Detector, P: sql_wire::Proxy<Socket, Socket>> Connection<D, P> {
                     should_retry: false,
                 })
             }
+            Err(e) => {
+                self.metrics.backend_connect_failed();
+                Err(e)
+            }
+        }
+    }
+
+    /// Returns the state that follows a completed TCP connection to the database.
+    fn tcp_connected_state(&self) -> ConnectionState {
+        match self.tls.backend {
+            Some(_) => ConnectionState::DatabaseTLSRequest,
+            None => ConnectionState::Connected,
+        }
+    }
+
+    /// Asks the backend database to encrypt the connection, then performs a TLS handshake with it if it agrees.
+    fn connect_backend_tls(&mut self) -> io::Result<ProxyResult> {
+        let backend_tls = match self.tls.backend.as_ref() {
+            Some(t) => t,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "TLS handshake started with database without TLS being configured",
+                ))
+            }
+        };
+
+        if self.state == ConnectionState::DatabaseTLSRequest {
+            let request = self.sql_session.backend_request_ssl()?;
+            let result = self.sql_session.backend_send_request(&request);
+            self.sql_session.recycle_request(request);
+            match result {
+                Ok(()) => self.state = ConnectionState::DatabaseTLSResponse,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(ProxyResult {
+                        frontend: IONeed::None,
+                        backend: IONeed::Write,
+                        should_retry: false,
+                    })
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        if self.state == ConnectionState::DatabaseTLSResponse {
+            match self.sql_session.backend_receive_ssl_response() {
+                Ok(true) => {
+                    let session = match rustls::ClientConnection::new(
+                        backend_tls.config.clone(),
+                        backend_tls.server_name.clone(),
+                    ) {
+                        Ok(s) => s,
+                        Err(e) => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidInput,
+                                format!("couldn't start TLS session with database: {}", e),
+                            ))
+                        }
+                    };
+                    self.sql_session
+                        .get_backend_io_mut()
+                        .start_tls(rustls::Connection::Client(session));
+                    self.state = ConnectionState::DatabaseTLSHandshake;
+                }
+                Ok(false) if backend_tls.required => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::ConnectionRefused,
+                        "database doesn't support TLS, which db_tls requires",
+                    ))
+                }
+                Ok(false) => {
+                    log::warn!(
+                        "Database doesn't support TLS--connection to it left unencrypted for {}",
+                        self.frontend_address.as_str()
+                    );
+                    self.state = ConnectionState::Connected;
+                    return Ok(ProxyResult {
+                        frontend: IONeed::Read,
+                        backend: IONeed::Read,
+                        should_retry: false,
+                    });
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(ProxyResult {
+                        frontend: IONeed::None,
+                        backend: IONeed::Read,
+                        should_retry: false,
+                    })
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        match self.sql_session.get_backend_io_mut().handshake() {
+            Ok(()) => {
+                log::debug!(
+                    "TLS handshake with database completed for {}",
+                    self.frontend_address.as_str()
+                );
+                self.state = ConnectionState::Connected;
+                Ok(ProxyResult {
+                    frontend: IONeed::Read,
+                    backend: IONeed::Read,
+                    should_retry: false,
+                })
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(ProxyResult {
+                frontend: IONeed::None,
+                backend: handshake_need(self.sql_session.get_backend_io_ref()),
+                should_retry: false,
+            }),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Performs a TLS handshake with the frontend once it has been told (in plaintext) that TLS is supported.
+    fn connect_client_tls(&mut self) -> io::Result<ProxyResult> {
+        match self.flush_client_tls()? {
+            res if res.frontend == IONeed::Write => return Ok(res),
+            _ => (),
+        }
+
+        if !self.sql_session.get_frontend_io_ref().is_tls() {
+            let config = match self.tls.frontend.as_ref() {
+                Some(t) => t.config.clone(),
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "TLS handshake started with client without TLS being configured",
+                    ))
+                }
+            };
+            let session = match rustls::ServerConnection::new(config) {
+                Ok(s) => s,
+                Err(e) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("couldn't start TLS session with client: {}", e),
+                    ))
+                }
+            };
+            self.sql_session
+                .get_frontend_io_mut()
+                .start_tls(rustls::Connection::Server(session));
+        }
+
+        match self.sql_session.get_frontend_io_mut().handshake() {
+            Ok(()) => {
+                log::info!(
+                    "TLS session established with client {}",
+                    self.frontend_address.as_str()
+                );
+                self.state = ConnectionState::Connected;
+                Ok(ProxyResult {
+                    frontend: IONeed::Read,
+                    backend: IONeed::None,
+                    should_retry: false,
+                })
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(ProxyResult {
+                frontend: handshake_need(self.sql_session.get_frontend_io_ref()),
+                backend: IONeed::None,
+                should_retry: false,
+            }),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Writes out the response telling the frontend to begin its TLS handshake, followed by any part of the handshake
+    /// waiting to be sent.
+    fn flush_client_tls(&mut self) -> io::Result<ProxyResult> {
+        let need_write = ProxyResult {
+            frontend: IONeed::Write,
+            backend: IONeed::None,
+            should_retry: false,
+        };
+
+        while let Some(response) = self.outgoing_data.pop_front() {
+            match self.sql_session.frontend_send_response(&response) {
+                Ok(()) => self.sql_session.recycle_response(response),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.outgoing_data.push_front(response);
+                    return Ok(need_write);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        match self.sql_session.get_frontend_io_mut().flush() {
+            Ok(()) => Ok(ProxyResult {
+                frontend: IONeed::Read,
+                backend: IONeed::None,
+                should_retry: false,
+            }),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(need_write),
             Err(e) => Err(e),
         }
     }
@@ -346,19 +624,28 @@ impl<D: sql::Detector, P: sql_wire::Proxy<Socket, Socket>> Connection<D, P> {
     fn process_frontend_data(
         &mut self,
         validator: &mut SqlValidator<D>,
+        auditor: Option<&audit::Auditor>,
+        policy: &blocking::Policy,
+        quarantine: &mut blocking::Quarantine,
     ) -> io::Result<ProxyResult> {
         if self.frontend_read_closed {
             log::debug!("Not reading any new requests as frontend read end is closed");
             if !self.backend_write_closed && self.incoming_data.len() == 0 {
                 log::info!("No more packets to forward to backend and frontend read closed--closing backend write for {}", self.frontend_address.as_str());
-                self.get_backend_socket().shutdown(net::Shutdown::Write)?; // No more data to process, so close other half of the incoming stream
+                self.shutdown_backend(net::Shutdown::Write)?; // No more data to process, so close other half of the incoming stream
                 self.backend_write_closed = true;
             }
             return Ok(ProxyResult::none());
         }
 
+        if self.ending_session && !self.discarding_request {
+            log::debug!("Not reading any new requests as the session ends with the error for a blocked query");
+            return Ok(ProxyResult::none());
+        }
+
         if self.incoming_data.len() >= REQUEST_QUEUE_SOFT_LIMIT {
             log::debug!("Deferring reading additional packets as incoming stream has filled its buffer allowance");
+            self.metrics.request_deferred();
             return Ok(ProxyResult::none()); // Defer receiving requests until the buffer has had time to be drained
         }
 
@@ -377,6 +664,13 @@ impl<D: sql::Detector, P: sql_wire::Proxy<Socket, Socket>> Connection<D, P> {
 
         log::debug!("Successfully received request from frontend");
 
+        if let Some(username) = request.get_basic_info().username.as_ref() {
+            self.username = Some(username.clone());
+        }
+        if let Some(database) = request.get_basic_info().database.as_ref() {
+            self.database = Some(database.clone());
+        }
+
         let mut io_needs = ProxyResult {
             frontend: IONeed::None,
             backend: IONeed::None,
@@ -395,7 +689,17 @@ impl<D: sql::Detector, P: sql_wire::Proxy<Socket, Socket>> Connection<D, P> {
             }
         }
 
+        let frontend_is_tls = self.sql_session.get_frontend_io_ref().is_tls();
         if request.get_basic_info().ssl_requested {
+            if self.tls.frontend.is_some() && !frontend_is_tls {
+                log::debug!("Frontend requested SSL--starting TLS handshake");
+                let resp = self.sql_session.frontend_accept_ssl(&mut request)?;
+                self.sql_session.recycle_request(request);
+                self.outgoing_data.push_back(resp);
+                self.state = ConnectionState::ClientTLSHandshake;
+                return Ok(io_needs);
+            }
+
             log::warn!("Frontend attempted to encrypt stream with SSL (not supported)--session encryption downgraded");
             if let Some(resp) = self.sql_session.frontend_downgrade_ssl(&mut request) {
                 self.outgoing_data.push_back(resp);
@@ -406,6 +710,15 @@ impl<D: sql::Detector, P: sql_wire::Proxy<Socket, Socket>> Connection<D, P> {
                 // if is_valid set to `false` then the request is not to be forwarded
                 return Ok(io_needs);
             }
+        } else if !frontend_is_tls && self.tls.frontend.as_ref().is_some_and(|t| t.required) {
+            log::warn!(
+                "Client {} didn't request TLS, which client_tls requires--closing connection",
+                self.frontend_address.as_str()
+            );
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                "client didn't request TLS",
+            ));
         }
 
         if let Some(query) = request.get_basic_info().query.as_ref() {
@@ -413,51 +726,208 @@ impl<D: sql::Detector, P: sql_wire::Proxy<Socket, Socket>> Connection<D, P> {
                 "SQL query received from frontend for {}--checking for SQL injection attempts...",
                 self.frontend_address.as_str()
             );
-            match validator.check_query(query.as_str()) {
-                Err(e) => {
-                    log::warn!("SQL injection detected in query: {}", e);
-                    self.request_queue.push_back(RequestMetadata {
-                        query: Some(query.clone()),
-                        is_malicious: true,
+            if self.discarding_request {
+                log::debug!("Not checking SQL query as it belongs to a request that has already been blocked");
+            } else {
+                let check_started = Instant::now();
+                let verdict = validator.check_query(query.as_str());
+                let check_duration = check_started.elapsed();
+                let quarantined = validator.is_enforcing()
+                    && self.frontend_ip.is_some_and(|ip| quarantine.contains(&ip));
+                let mut correlation_id = None;
+                let mut disconnect = false;
+                let action = match verdict.detection.as_ref() {
+                    Some(finding) if validator.is_enforcing() => {
+                        log::warn!("SQL injection detected in query: {}", finding);
+                        match policy.action_for(finding.rule) {
+                            blocking::Action::Error => (),
+                            blocking::Action::Disconnect => disconnect = true,
+                            blocking::Action::Tarpit => {
+                                self.tarpit_until = Some(Instant::now() + policy.tarpit_delay)
+                            }
+                            blocking::Action::Quarantine => match self.frontend_ip {
+                                Some(ip) => {
+                                    log::warn!(
+                                        "Quarantining client address {} for {} seconds",
+                                        ip,
+                                        policy.quarantine_duration.as_secs()
+                                    );
+                                    quarantine.add(ip, policy.quarantine_duration);
+                                }
+                                None => log::warn!(
+                                    "Client {} can't be quarantined as it has no IP address",
+                                    self.frontend_address.as_str()
+                                ),
+                            },
+                        }
+                        correlation_id = self.block_request(
+                            &policy.error,
+                            &finding.to_string(),
+                            (query.clone(), verdict.path),
+                        );
+                        audit::Action::Blocked
+                    }
+                    Some(finding) => {
+                        // The trie keeps learning while monitoring, so the query is learned from its outcome like any other
+                        log::warn!(
+                            "SQL injection detected in query (forwarded anyway in monitor mode): {} ({})",
+                            finding,
+                            query.as_str()
+                        );
+                        self.pending_queries.push((query.clone(), verdict.path));
+                        audit::Action::Flagged
+                    }
+                    None if quarantined => {
+                        log::warn!(
+                            "SQL query blocked as client {} is quarantined",
+                            self.frontend_address.as_str()
+                        );
+                        correlation_id = self.block_request(
+                            &policy.error,
+                            "client is quarantined",
+                            (query.clone(), verdict.path),
+                        );
+                        audit::Action::Blocked
+                    }
+                    None => {
+                        log::info!("SQL query was benign");
+                        self.pending_queries.push((query.clone(), verdict.path));
+                        audit::Action::Allowed
+                    }
+                };
+                self.metrics
+                    .query_checked(verdict.path, action, check_duration);
+
+                if let Some(auditor) = auditor {
+                    auditor.record(&audit::AuditRecord {
+                        timestamp: audit::timestamp_now(),
+                        instance: auditor.instance.as_str(),
+                        client: self.frontend_address.as_str(),
+                        user: self.username.as_deref(),
+                        database: self.database.as_deref(),
+                        query: query.as_str(),
+                        action,
+                        correlation_id: correlation_id.as_deref(),
+                        quarantined,
+                        verdict: &verdict,
                     });
-
-                    io_needs.frontend |= IONeed::Write; // To ensure that the frontend gets this packet written out
                 }
-                Ok(()) => {
-                    log::info!("SQL query was benign");
-                    self.request_queue.push_back(RequestMetadata {
-                        query: Some(query.clone()),
-                        is_malicious: false,
-                    });
-                    self.incoming_data.push_back(request);
+
+                if disconnect {
+                    log::warn!(
+                        "Disconnecting client {} after blocking its query",
+                        self.frontend_address.as_str()
+                    );
+                    return Err(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        "client disconnected after its query was blocked",
+                    ));
                 }
             }
         } else {
             log::debug!("Received request was not a SQL query");
-            if request.get_basic_info().is_request {
+        }
+
+        if request.get_basic_info().is_request {
+            let queries = std::mem::take(&mut self.pending_queries);
+            let partially_forwarded = self.request_partially_forwarded;
+            self.request_partially_forwarded = false;
+            if !self.discarding_request {
+                self.request_queue.push_back(RequestMetadata {
+                    blocked_error: None,
+                    is_forwarded: true,
+                    queries,
+                });
+                self.incoming_data.push_back(request);
+            } else if !partially_forwarded {
+                self.discarding_request = false;
+                self.sql_session.recycle_request(request);
+                if let Some(abort_request) = self.sql_session.transaction_abort_request() {
+                    // The backend is in a transaction block, which must fail just as the frontend will be told it has
+                    log::debug!("Failing backend transaction in place of blocked request");
+                    self.request_queue.push_back(RequestMetadata {
+                        blocked_error: self.blocked_error.take(),
+                        is_forwarded: true,
+                        queries,
+                    });
+                    self.incoming_data.push_back(abort_request);
+                } else {
+                    // Nothing from this request has reached the backend, so the request can be answered without involving it
+                    self.request_queue.push_back(RequestMetadata {
+                        blocked_error: self.blocked_error.take(),
+                        is_forwarded: false,
+                        queries,
+                    });
+                    io_needs.frontend |= IONeed::Write; // To ensure that the frontend gets this packet written out
+                }
+            } else {
+                // Earlier messages of this request were already sent, so the backend still needs the request to complete them.
+                // It's made to fail the request first, so that it undoes them (as the frontend will be told the request failed)
+                // rather than committing them
+                self.discarding_request = false;
+                if let Some(abort_request) = self.sql_session.batch_abort_request() {
+                    log::debug!("Failing the part of the blocked request already sent to the backend");
+                    self.incoming_data.push_back(abort_request);
+                }
                 self.request_queue.push_back(RequestMetadata {
-                    query: None,
-                    is_malicious: false,
+                    blocked_error: self.blocked_error.take(),
+                    is_forwarded: true,
+                    queries,
                 });
+                self.incoming_data.push_back(request);
             }
-
+        } else if request.get_basic_info().is_standalone {
+            self.incoming_data.push_back(request);
+        } else if self.discarding_request {
+            log::debug!("Discarding message belonging to a blocked request");
+            self.sql_session.recycle_request(request);
+        } else {
+            self.request_partially_forwarded = true;
             self.incoming_data.push_back(request);
         }
 
         Ok(io_needs)
     }
 
+    /// Discards the rest of the request that the given query belongs to, reporting the block to the frontend in place of
+    /// the request's result. Returns the correlation ID given to the blocked query (if the error includes one).
+    fn block_request(
+        &mut self,
+        error: &blocking::ErrorTemplate,
+        reason: &str,
+        query: (String, MatchPath),
+    ) -> Option<String> {
+        let correlation_id = if error.uses_correlation_id() {
+            Some(blocking::correlation_id())
+        } else {
+            None
+        };
+        let blocked_error = error.render(reason, correlation_id.as_deref());
+        self.discarding_request = true;
+        self.ending_session |= blocked_error.ends_session();
+        self.blocked_error = Some(blocked_error);
+        self.pending_queries.push(query);
+        correlation_id
+    }
+
     fn proxy_data_to_backend(&mut self) -> io::Result<ProxyResult> {
         if self.backend_write_closed {
             return Ok(ProxyResult::none());
         }
 
+        // Any data still buffered by an earlier (encrypted) write goes out first
+        self.sql_session.get_backend_io_mut().flush()?;
+
         // NOTE: chould change these while loops to `if` and return ProxyResult { frontend: IONeed::None, backend: IONeed::None, do_again: self.incoming_data.len() > 0 }
         // But only if we feel like this while loop could be enough for one client to starve others of resources (very unlikely if even at all possible)
+        let mut any_forwarded = false;
         while let Some(request) = self.incoming_data.pop_front() {
             log::debug!("Proxying next request in queue to backend");
             match self.sql_session.backend_send_request(&request) {
-                Ok(()) => self.sql_session.recycle_request(request),
+                Ok(()) => {
+                    any_forwarded = true;
+                    self.sql_session.recycle_request(request)
+                }
                 Err(e) if e.kind() == io::ErrorKind::ConnectionAborted => {
                     log::info!(
                         "Backend write end closed--closing frontend read end and backend for {}",
@@ -465,7 +935,7 @@ impl<D: sql::Detector, P: sql_wire::Proxy<Socket, Socket>> Connection<D, P> {
                     );
                     if !self.frontend_read_closed {
                         self.frontend_read_closed = true;
-                        match self.get_frontend_socket().shutdown(net::Shutdown::Read) {
+                        match self.shutdown_frontend(net::Shutdown::Read) {
                             Ok(_) => (),
                             Err(e) => {
                                 return Err(io::Error::new(
@@ -490,9 +960,11 @@ impl<D: sql::Detector, P: sql_wire::Proxy<Socket, Socket>> Connection<D, P> {
             }
         }
 
+        self.sql_session.get_backend_io_mut().flush()?;
+
         if self.frontend_read_closed {
             self.backend_write_closed = true;
-            match self.get_backend_socket().shutdown(net::Shutdown::Write) {
+            match self.shutdown_backend(net::Shutdown::Write) {
                 Ok(_) => (),
                 Err(e) => log::info!(
                     "error when closing {} backend socket write end: {}",
@@ -502,6 +974,15 @@ impl<D: sql::Detector, P: sql_wire::Proxy<Socket, Socket>> Connection<D, P> {
             }
         }
 
+        if any_forwarded {
+            // A blocked write may have replaced the backend's read interest in the poller, so it is restored for the responses to come
+            return Ok(ProxyResult {
+                frontend: IONeed::None,
+                backend: IONeed::Read,
+                should_retry: false,
+            });
+        }
+
         Ok(ProxyResult::none())
     }
 
@@ -510,7 +991,7 @@ impl<D: sql::Detector, P: sql_wire::Proxy<Socket, Socket>> Connection<D, P> {
         if self.no_more_incoming() && self.request_queue.is_empty() && self.outgoing_data.is_empty()
         {
             if !self.backend_write_closed {
-                match self.get_backend_socket().shutdown(net::Shutdown::Write) {
+                match self.shutdown_backend(net::Shutdown::Write) {
                     Ok(_) => (),
                     Err(e) => return Err(io::Error::new(io::ErrorKind::ConnectionAborted, format!("connection otherwise gracefully terminated, but error when attempting to close backend socket write end: {}", e.to_string()))),
                 }
@@ -528,18 +1009,25 @@ impl<D: sql::Detector, P: sql_wire::Proxy<Socket, Socket>> Connection<D, P> {
 
         // Remove any requests from the queue that are to be spoofed with error responses
         while let Some(request_info) = self.request_queue.pop_front() {
-            if request_info.is_malicious {
-                log::debug!("Error response packets injected into stream for malicious query");
-                self.outgoing_data
-                    .push_back(self.sql_session.error_response());
-            } else {
-                self.request_queue.push_front(request_info);
-                break;
+            match (
+                request_info.blocked_error.as_ref(),
+                request_info.is_forwarded,
+            ) {
+                (Some(error), false) => {
+                    log::debug!("Error response packets injected into stream for malicious query");
+                    let response = self.sql_session.error_response(error);
+                    self.outgoing_data.push_back(response);
+                }
+                _ => {
+                    self.request_queue.push_front(request_info);
+                    break;
+                }
             }
         }
 
         if self.outgoing_data.len() >= RESPONSE_QUEUE_SOFT_LIMIT {
             log::debug!("Deferring reading additional responses as outgoing stream has filled its buffer allowance");
+            self.metrics.response_deferred();
             return Ok(ProxyResult::none()); // Defer receiving responses until the buffer has had time to be drained
         }
 
@@ -552,7 +1040,7 @@ impl<D: sql::Detector, P: sql_wire::Proxy<Socket, Socket>> Connection<D, P> {
                 self.backend_read_closed = true;
                 if !self.frontend_read_closed {
                     self.frontend_read_closed = true; // If we can't get data from our db, no sense in accepting new data from our client
-                    match self.get_frontend_socket().shutdown(net::Shutdown::Read) {
+                    match self.shutdown_frontend(net::Shutdown::Read) {
                         Ok(_) => (),
                         Err(e) => {
                             return Err(io::Error::new(
@@ -568,7 +1056,7 @@ impl<D: sql::Detector, P: sql_wire::Proxy<Socket, Socket>> Connection<D, P> {
                 }
                 if !self.backend_write_closed {
                     self.backend_write_closed = true;
-                    match self.get_backend_socket().shutdown(net::Shutdown::Write) {
+                    match self.shutdown_backend(net::Shutdown::Write) {
                         Ok(_) => (),
                         Err(e) => log::warn!(
                             "error when attempting to close {} backend socket write end: {}",
@@ -583,20 +1071,53 @@ impl<D: sql::Detector, P: sql_wire::Proxy<Socket, Socket>> Connection<D, P> {
             Err(e) => return Err(e),
         };
 
+        if response.get_basic_info().is_error
+            && self
+                .request_queue
+                .front()
+                .is_some_and(|r| r.blocked_error.is_some())
+        {
+            // The error reported to the frontend for a blocked request is its own, not whatever the backend made of it
+            log::debug!("Suppressing backend error for malicious query");
+            self.sql_session.recycle_response(response);
+            return Ok(ProxyResult {
+                frontend: IONeed::None,
+                backend: IONeed::None,
+                should_retry: true,
+            });
+        }
+
         if let Some(was_successful) = response.get_basic_info().result {
             if let Some(request_info) = self.request_queue.pop_front() {
+                if let Some(error) = request_info.blocked_error.as_ref() {
+                    // The backend completed what remained of a blocked request--report the block to the frontend in place of its result
+                    log::debug!("Error response packets substituted into stream for malicious query");
+                    self.sql_session.recycle_response(response);
+                    let response = self.sql_session.error_response(error);
+                    self.outgoing_data.push_back(response);
+                    return Ok(ProxyResult {
+                        frontend: IONeed::None,
+                        backend: IONeed::None,
+                        should_retry: true,
+                    });
+                }
+
+                if request_info.queries.is_empty() {
+                    log::debug!("SQL response detected for some non-query request"); // some other non-query request (e.g. a FunctionCall)
+                }
+
                 // We presume that no SQL queries coming from an application will trigger errors by default.
                 // Thus, the presence of an error potentially indicates the introduction of additional command syntax (i.e. SQL Injection)
-                match (request_info.query, was_successful) {
-                    (Some(query), true) => {
+                for (query, path) in request_info.queries.iter() {
+                    if was_successful {
                         log::debug!("SQL success response detected from backend--updating query as good: ({})", query.as_str());
-                        validator.update_good_query(query.as_str())
-                    }
-                    (Some(query), false) => {
+                        validator.update_good_query(query.as_str());
+                        self.metrics.query_learned(*path);
+                    } else {
                         log::warn!("SQL error response detected from backend--updating query as malicious: ({})", query.as_str());
-                        validator.update_bad_query(query.as_str())
+                        validator.update_bad_query(query.as_str());
+                        self.metrics.bad_query();
                     }
-                    _ => log::debug!("SQL response detected for some non-query request"), // some other non-query request (e.g. a FunctionCall)
                 }
             } else {
                 // Somehow, we received one more response from the database than what we were expecting. This is BAD (as it could lead to erroneous data and errors being passed to the application, not to mention request smuggling...)
@@ -622,15 +1143,29 @@ impl<D: sql::Detector, P: sql_wire::Proxy<Socket, Socket>> Connection<D, P> {
     }
 
     fn proxy_data_to_frontend(&mut self) -> io::Result<ProxyResult> {
+        if let Some(until) = self.tarpit_until {
+            if Instant::now() < until {
+                log::debug!(
+                    "Not sending responses to tarpitted client {}",
+                    self.frontend_address.as_str()
+                );
+                return Ok(ProxyResult::none()); // The event handler resumes the connection once the delay is over
+            }
+            self.tarpit_until = None;
+        }
+
+        // Any data still buffered by an earlier (encrypted) write goes out first
+        self.sql_session.get_frontend_io_mut().flush()?;
+
         while let Some(response) = self.outgoing_data.pop_front() {
             match self.sql_session.frontend_send_response(&response) {
                 Ok(()) => log::debug!("Successfully forwarded response to frontend"),
                 Err(e) if e.kind() == io::ErrorKind::ConnectionAborted => {
                     log::info!("Frontend write end closed--closing all sockets for {} and freeing proxy resources", self.frontend_address.as_str());
                     // Close proxy according to TCP spec
-                    self.get_frontend_socket().shutdown(net::Shutdown::Write)?;
-                    self.get_frontend_socket().shutdown(net::Shutdown::Read)?;
-                    self.get_backend_socket().shutdown(net::Shutdown::Read)?;
+                    self.shutdown_frontend(net::Shutdown::Write)?;
+                    self.shutdown_frontend(net::Shutdown::Read)?;
+                    self.shutdown_backend(net::Shutdown::Read)?;
                     return Err(io::Error::new(io::ErrorKind::ConnectionAborted, format!("{} client unexpectedly closed connection despite data remaining to be returned", self.frontend_address.as_str())));
                 }
                 Err(e) => {
@@ -639,13 +1174,29 @@ impl<D: sql::Detector, P: sql_wire::Proxy<Socket, Socket>> Connection<D, P> {
                 }
             }
 
+            let ends_session = response.get_basic_info().ends_session;
             self.sql_session.recycle_response(response);
+            self.sql_session.get_frontend_io_mut().flush()?;
+            if ends_session {
+                log::info!(
+                    "Closing connection for {} as the error for its blocked query ends the session",
+                    self.frontend_address.as_str()
+                );
+                self.shutdown_frontend(net::Shutdown::Write)?;
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    format!(
+                        "{} session ended by the error for a blocked query",
+                        self.frontend_address.as_str()
+                    ),
+                ));
+            }
             return Ok(ProxyResult::none());
         }
 
         // Successfully wrote all remaining data from buffers, so connection can be closed
         if self.backend_read_closed {
-            match self.get_frontend_socket().shutdown(net::Shutdown::Write) {
+            match self.shutdown_frontend(net::Shutdown::Write) {
                 Ok(_) => (),
                 Err(e) => return Err(io::Error::new(io::ErrorKind::ConnectionAborted, format!("connection otherwise gracefully terminated, but error when closing {} frontend socket write end: {}", self.frontend_address.as_str(), e.to_string()))),
             }
@@ -661,3 +1212,471 @@ impl<D: sql::Detector, P: sql_wire::Proxy<Socket, Socket>> Connection<D, P> {
         Ok(ProxyResult::none())
     }
 }
+
+/// Returns the I/O needed to progress a TLS handshake interrupted by `WouldBlock`.
+fn handshake_need(stream: &tls::Stream) -> IONeed {
+    match stream.wants_write() {
+        true => IONeed::Write,
+        false => IONeed::Read,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql::postgres_detector::PostgresDetector;
+    use crate::sql_wire::postgres_session::PostgresProxySession;
+    use crate::validator;
+    use std::io::Read;
+    use std::time::Duration;
+
+    type PostgresConnection =
+        Connection<PostgresDetector, PostgresProxySession<tls::Stream, tls::Stream>>;
+
+    /// A proxy connection between a client and a database server, each played by the test through its end of a socket pair.
+    struct Harness {
+        connection: PostgresConnection,
+        validator: SqlValidator<PostgresDetector>,
+        policy: blocking::Policy,
+        quarantine: blocking::Quarantine,
+        client: Socket,
+        server: Socket,
+        /// The error that ended the connection (if it has ended)
+        ended: Option<io::Error>,
+    }
+
+    impl Harness {
+        /// Connects a client to a server through the proxy, completing the startup handshake.
+        fn new() -> Self {
+            let mut harness = Harness::connect(tls::Parameters::default());
+            let mut ready = message(b'R', &0_i32.to_be_bytes()); // AuthenticationOk
+            ready.extend(ready_for_query(b'I'));
+            harness.client_sends(&startup_message());
+            harness.server_receives();
+            harness.server_sends(&ready);
+            assert_eq!(harness.client_receives(), ready);
+            harness
+        }
+
+        /// Connects a client to a server through the proxy, leaving the client to start the session.
+        fn connect(tls: tls::Parameters) -> Self {
+            let (client, frontend_socket) = tcp_pair();
+            let (backend_socket, server) = tcp_pair();
+            let backend_address = server.local_addr().unwrap();
+            let frontend_address = client.local_addr().unwrap().as_socket().unwrap();
+            for socket in [&client, &frontend_socket, &backend_socket, &server] {
+                socket.set_nonblocking(true).unwrap();
+                socket.set_nodelay(true).unwrap(); // So that each message is readable as soon as it's written
+            }
+
+            Harness {
+                connection: Connection::new(
+                    backend_address,
+                    0,
+                    backend_socket,
+                    frontend_address.to_string(),
+                    1,
+                    frontend_socket,
+                    Arc::new(metrics::InstanceMetrics::default()),
+                    tls,
+                ),
+                validator: SqlValidator::new(validator::Parameters {
+                    detector_nopattern: sql::Parameters::default_prefix_suffix(),
+                    ..validator::Parameters::default()
+                }),
+                policy: blocking::Policy::default(),
+                quarantine: blocking::Quarantine::default(),
+                client,
+                server,
+                ended: None,
+            }
+        }
+
+        /// Lets the proxy process everything sent to it so far (unless the connection has ended).
+        fn pump(&mut self) {
+            for _ in 0..50 {
+                if self.ended.is_some() {
+                    return;
+                }
+
+                let result = self
+                    .connection
+                    .process_incoming(
+                        &mut self.validator,
+                        None,
+                        &self.policy,
+                        &mut self.quarantine,
+                    )
+                    .and_then(|_| self.connection.process_outgoing(&mut self.validator));
+                if let Err(e) = result {
+                    self.ended = Some(e);
+                }
+            }
+        }
+
+        fn client_sends(&mut self, data: &[u8]) {
+            self.client.write_all(data).unwrap();
+            self.pump();
+        }
+
+        fn server_sends(&mut self, data: &[u8]) {
+            self.server.write_all(data).unwrap();
+            self.pump();
+        }
+
+        fn client_receives(&self) -> Vec<u8> {
+            read_available(&self.client)
+        }
+
+        fn server_receives(&self) -> Vec<u8> {
+            read_available(&self.server)
+        }
+    }
+
+    /// Returns both ends of a TCP connection over the loopback interface.
+    fn tcp_pair() -> (Socket, Socket) {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let connecting = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+        (Socket::from(connecting), Socket::from(accepted))
+    }
+
+    fn read_available(mut socket: &Socket) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut buf = [0; 4096];
+        loop {
+            match socket.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => data.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => panic!("read failed: {}", e),
+            }
+        }
+        data
+    }
+
+    fn startup_message() -> Vec<u8> {
+        let mut startup = 196608_i32.to_be_bytes().to_vec(); // Protocol version 3.0
+        startup.extend_from_slice(b"user\x00test\x00\x00");
+        let mut data = ((startup.len() + 4) as i32).to_be_bytes().to_vec();
+        data.extend(startup);
+        data
+    }
+
+    /// Frames a message body with its type byte and length.
+    fn message(kind: u8, body: &[u8]) -> Vec<u8> {
+        let mut msg = vec![kind];
+        msg.extend_from_slice(&((body.len() + 4) as i32).to_be_bytes());
+        msg.extend_from_slice(body);
+        msg
+    }
+
+    fn ready_for_query(status: u8) -> Vec<u8> {
+        message(b'Z', &[status])
+    }
+
+    fn nul_terminated(text: &str) -> Vec<u8> {
+        let mut data = text.as_bytes().to_vec();
+        data.push(0);
+        data
+    }
+
+    fn parse(query: &str) -> Vec<u8> {
+        let mut body = b"\x00".to_vec(); // Unnamed statement
+        body.extend(nul_terminated(query));
+        body.extend_from_slice(b"\x00\x00"); // No parameter types
+        message(b'P', &body)
+    }
+
+    /// Binds and executes the unnamed statement.
+    fn bind_execute() -> Vec<u8> {
+        let mut data = message(b'B', b"\x00\x00\x00\x00\x00\x00\x00\x00");
+        data.extend(message(b'E', b"\x00\x00\x00\x00\x00"));
+        data
+    }
+
+    fn sync() -> Vec<u8> {
+        message(b'S', b"")
+    }
+
+    /// Splits a stream of standard messages into their types and bodies.
+    fn messages(mut data: &[u8]) -> Vec<(u8, Vec<u8>)> {
+        let mut messages = Vec::new();
+        while data.len() >= 5 {
+            let len = i32::from_be_bytes(data[1..5].try_into().unwrap()) as usize;
+            messages.push((data[0], data[5..1 + len].to_vec()));
+            data = &data[1 + len..];
+        }
+        messages
+    }
+
+    /// Answers an extended-query batch the way PostgreSQL does: once a message fails, the rest of the batch is
+    /// skipped up to its Sync, and the batch's transaction fails. Returns the responses, and whether the batch's work
+    /// was kept (committed if outside of a transaction block).
+    fn answer_batch(received: &[u8], in_transaction: bool) -> (Vec<u8>, bool) {
+        let mut responses = Vec::new();
+        let mut failed = false;
+        for (kind, body) in messages(received) {
+            match kind {
+                b'S' => {
+                    let status = match (in_transaction, failed) {
+                        (true, true) => b'E',
+                        (true, false) => b'T',
+                        (false, _) => b'I',
+                    };
+                    responses.extend(ready_for_query(status));
+                }
+                _ if failed => (),
+                b'P' if body.windows(10).any(|w| w == b"SQLFORTIFY") => {
+                    failed = true;
+                    responses.extend(message(b'E', b"SERROR\x00C42601\x00Msyntax error\x00\x00"));
+                }
+                b'P' => responses.extend(message(b'1', b"")),
+                b'B' => responses.extend(message(b'2', b"")),
+                b'E' => responses.extend(message(b'C', &nul_terminated("INSERT 0 1"))),
+                _ => panic!("unexpected message type {}", kind as char),
+            }
+        }
+        (responses, !failed)
+    }
+
+    /// Pipelines a statement and a blocked one in a single batch, returning the frontend's ReadyForQuery status and
+    /// whether the backend kept the first statement's work.
+    fn block_partially_forwarded_batch(harness: &mut Harness, in_transaction: bool) -> (u8, bool) {
+        let mut batch = parse("INSERT INTO t VALUES (1)");
+        batch.extend(bind_execute());
+        batch.extend(parse("SELECT pg_sleep(5)"));
+        batch.extend(bind_execute());
+        batch.extend(sync());
+        harness.client_sends(&batch);
+
+        let received = harness.server_receives();
+        let kinds: Vec<u8> = messages(&received).iter().map(|(kind, _)| *kind).collect();
+        assert_eq!(kinds, b"PBEPS"); // The blocked statement is replaced by a failing Parse
+
+        let (responses, kept) = answer_batch(&received, in_transaction);
+        harness.server_sends(&responses);
+
+        let frontend_messages = messages(&harness.client_receives());
+        let (kind, body) = frontend_messages.last().unwrap();
+        assert_eq!(*kind, b'Z');
+        assert!(frontend_messages
+            .iter()
+            .any(|(kind, body)| *kind == b'E' && body.windows(6).any(|w| w == b"C42000")));
+        assert!(!frontend_messages
+            .iter()
+            .any(|(kind, body)| *kind == b'E' && body.windows(6).any(|w| w == b"C42601")));
+        (body[0], kept)
+    }
+
+    #[test]
+    fn extended_query_is_learned_once_synced() {
+        let mut harness = Harness::new();
+        let query = "SELECT name FROM users WHERE id = 1";
+        let mut batch = parse(query);
+        batch.extend(sync());
+        harness.client_sends(&batch);
+        assert_eq!(harness.server_receives(), batch);
+
+        // Nothing is learned until the backend has reported the outcome of the batch
+        assert_ne!(
+            harness.validator.check_query(query).path,
+            validator::MatchPath::ExactMatch
+        );
+        let mut responses = message(b'1', b"");
+        responses.extend(ready_for_query(b'I'));
+        harness.server_sends(&responses);
+        assert_eq!(harness.client_receives(), responses);
+
+        assert_eq!(
+            harness.validator.check_query(query).path,
+            validator::MatchPath::ExactMatch
+        );
+    }
+
+    #[test]
+    fn partially_forwarded_batch_is_rolled_back_when_idle() {
+        let mut harness = Harness::new();
+        assert_eq!(
+            block_partially_forwarded_batch(&mut harness, false),
+            (b'I', false)
+        );
+    }
+
+    #[test]
+    fn partially_forwarded_batch_fails_transaction_block() {
+        let mut harness = Harness::new();
+        harness.client_sends(&message(b'Q', &nul_terminated("BEGIN")));
+        assert_eq!(messages(&harness.server_receives())[0].0, b'Q');
+        let mut begun = message(b'C', &nul_terminated("BEGIN"));
+        begun.extend(ready_for_query(b'T'));
+        harness.server_sends(&begun);
+        assert_eq!(harness.client_receives(), begun);
+
+        assert_eq!(
+            block_partially_forwarded_batch(&mut harness, true),
+            (b'E', false)
+        );
+    }
+
+    #[test]
+    fn fatal_blocked_error_ends_the_session() {
+        let mut harness = Harness::new();
+        harness.policy.error.severity = "FATAL".to_string();
+
+        let mut queries = message(b'Q', &nul_terminated("SELECT pg_sleep(5)"));
+        queries.extend(message(b'Q', &nul_terminated("SELECT 1")));
+        harness.client_sends(&queries);
+
+        // Neither the blocked query nor the one pipelined after it reaches the backend
+        assert!(harness.server_receives().is_empty());
+        let frontend_messages = messages(&harness.client_receives());
+        assert_eq!(frontend_messages.len(), 1);
+        let (kind, body) = &frontend_messages[0];
+        assert_eq!(*kind, b'E');
+        assert!(body.starts_with(b"SFATAL\x00"));
+        assert_eq!(
+            harness.ended.as_ref().map(|e| e.kind()),
+            Some(io::ErrorKind::ConnectionAborted)
+        );
+        assert_eq!((&harness.client).read(&mut [0]).unwrap(), 0);
+    }
+
+    #[test]
+    fn flagged_query_is_forwarded_and_learned_in_monitor_mode() {
+        let mut harness = Harness::new();
+        harness.validator.set_parameters(validator::Parameters {
+            detector_nopattern: sql::Parameters::default_prefix_suffix(),
+            mode: validator::Mode::Monitor,
+            ..validator::Parameters::default()
+        });
+
+        let query = message(b'Q', &nul_terminated("SELECT pg_sleep(5)"));
+        harness.client_sends(&query);
+        assert_eq!(harness.server_receives(), query);
+        let mut response = message(b'C', &nul_terminated("SELECT 1"));
+        response.extend(ready_for_query(b'I'));
+        harness.server_sends(&response);
+        assert_eq!(harness.client_receives(), response);
+
+        let verdict = harness.validator.check_query("SELECT pg_sleep(5)");
+        assert_eq!(verdict.path, validator::MatchPath::ExactMatch);
+    }
+
+    /// Returns the path of a file in `testdata/tls`, which holds a test CA and a certificate it issued for `localhost`.
+    fn tls_test_file(name: &str) -> std::path::PathBuf {
+        std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata/tls")
+            .join(name)
+    }
+
+    /// Returns TLS parameters that offer clients the test certificate, requiring TLS if `required` is set.
+    fn client_tls(required: bool) -> tls::Parameters {
+        let config =
+            tls::server_config(&tls_test_file("server.pem"), &tls_test_file("server.key")).unwrap();
+        tls::Parameters {
+            frontend: Some(tls::FrontendParameters { required, config }),
+            backend: None,
+        }
+    }
+
+    #[test]
+    fn client_tls_is_negotiated_before_startup() {
+        let mut harness = Harness::connect(client_tls(true));
+        let mut ssl_request = 8_i32.to_be_bytes().to_vec();
+        ssl_request.extend_from_slice(&80877103_i32.to_be_bytes());
+        harness.client_sends(&ssl_request);
+        assert_eq!(harness.client_receives(), b"S");
+
+        let config = tls::client_config(&tls_test_file("ca.pem")).unwrap();
+        let server_name = rustls::ServerName::try_from("localhost").unwrap();
+        let session = rustls::ClientConnection::new(config, server_name).unwrap();
+        let mut client = tls::Stream::new(harness.client.try_clone().unwrap());
+        client.start_tls(rustls::Connection::Client(session));
+        let mut handshake = client.handshake();
+        for _ in 0..20 {
+            if handshake.is_ok() {
+                break;
+            }
+            harness.pump();
+            handshake = client.handshake();
+        }
+        handshake.unwrap();
+
+        // The backend only ever sees the decrypted session
+        client.write_all(&startup_message()).unwrap();
+        client.flush().unwrap();
+        harness.pump();
+        assert_eq!(harness.server_receives(), startup_message());
+        let mut ready = message(b'R', &0_i32.to_be_bytes()); // AuthenticationOk
+        ready.extend(ready_for_query(b'I'));
+        harness.server_sends(&ready);
+        let mut received = vec![0; ready.len()];
+        client.read_exact(&mut received).unwrap();
+        assert_eq!(received, ready);
+        assert!(harness.ended.is_none());
+    }
+
+    #[test]
+    fn required_client_tls_refuses_plaintext_startup() {
+        let mut harness = Harness::connect(client_tls(true));
+        harness.client_sends(&startup_message());
+
+        assert!(harness.server_receives().is_empty());
+        assert_eq!(
+            harness.ended.as_ref().map(|e| e.kind()),
+            Some(io::ErrorKind::ConnectionRefused)
+        );
+    }
+
+    #[test]
+    fn optional_client_tls_allows_plaintext_startup() {
+        let mut harness = Harness::connect(client_tls(false));
+        harness.client_sends(&startup_message());
+
+        assert_eq!(harness.server_receives(), startup_message());
+        assert!(harness.ended.is_none());
+    }
+
+    #[test]
+    fn tarpit_delays_the_blocked_error() {
+        let mut harness = Harness::new();
+        harness.policy.default_action = blocking::Action::Tarpit;
+        harness.policy.tarpit_delay = Duration::from_millis(100);
+
+        harness.client_sends(&message(b'Q', &nul_terminated("SELECT pg_sleep(5)")));
+        assert!(harness.server_receives().is_empty());
+        assert!(harness.client_receives().is_empty());
+        let deadline = harness.connection.get_tarpit_deadline().unwrap();
+
+        std::thread::sleep(deadline.saturating_duration_since(Instant::now()));
+        harness.pump();
+        let kinds: Vec<u8> = messages(&harness.client_receives())
+            .iter()
+            .map(|(kind, _)| *kind)
+            .collect();
+        assert_eq!(kinds, b"EZ");
+        assert!(harness.connection.get_tarpit_deadline().is_none());
+    }
+
+    #[test]
+    fn quarantined_client_has_benign_queries_blocked() {
+        let mut harness = Harness::new();
+        harness.policy.default_action = blocking::Action::Quarantine;
+
+        harness.client_sends(&message(b'Q', &nul_terminated("SELECT pg_sleep(5)")));
+        assert!(harness.quarantine.contains(&"127.0.0.1".parse().unwrap()));
+        harness.client_receives();
+
+        let benign = message(b'Q', &nul_terminated("SELECT 1"));
+        harness.client_sends(&benign);
+        assert!(harness.server_receives().is_empty());
+        let frontend_messages = messages(&harness.client_receives());
+        assert_eq!(frontend_messages[0].0, b'E');
+
+        // Once the quarantine is over, the client's queries are checked as usual
+        harness.quarantine = blocking::Quarantine::default();
+        harness.client_sends(&benign);
+        assert_eq!(harness.server_receives(), benign);
+    }
+}
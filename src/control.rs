@@ -0,0 +1,68 @@
+use polling::Poller;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::validator;
+
+/// How long a proxy instance waits for its connections to finish what they're doing when stopping, if
+/// `shutdown_timeout_seconds` isn't set.
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Lets the supervisor ask a running proxy instance to stop, or to change how it detects SQL injection, from
+/// another thread. Shared by every run of the instance, so that requests aren't lost while it is being restarted.
+#[derive(Default)]
+pub struct Control {
+    /// Once set, the instance stops accepting connections and stops running once its connections are idle or the
+    /// deadline has passed
+    shutdown_deadline: Mutex<Option<Instant>>,
+    /// Detection settings that the instance has yet to switch to
+    pending_parameters: Mutex<Option<validator::Parameters>>,
+    /// The poller of the instance's current event loop, which may otherwise wait indefinitely for socket events
+    poller: Mutex<Option<Arc<Poller>>>,
+}
+
+impl Control {
+    /// Registers the poller of a newly-started event loop, so that it is woken up whenever a request is made.
+    pub fn attach(&self, poller: Arc<Poller>) {
+        *lock(&self.poller) = Some(poller);
+    }
+
+    /// Asks the instance to stop, giving its connections until `deadline` to finish what they're doing.
+    pub fn request_shutdown(&self, deadline: Instant) {
+        *lock(&self.shutdown_deadline) = Some(deadline);
+        self.wake();
+    }
+
+    /// Returns the time by which the instance must have stopped, or `None` if it hasn't been asked to stop.
+    pub fn shutdown_deadline(&self) -> Option<Instant> {
+        *lock(&self.shutdown_deadline)
+    }
+
+    /// Asks the instance to switch to the given detection settings (keeping the queries it has learned).
+    pub fn request_reload(&self, params: validator::Parameters) {
+        *lock(&self.pending_parameters) = Some(params);
+        self.wake();
+    }
+
+    /// Returns the detection settings most recently requested with `request_reload()`, if they haven't been taken yet.
+    pub fn take_parameters(&self) -> Option<validator::Parameters> {
+        lock(&self.pending_parameters).take()
+    }
+
+    fn wake(&self) {
+        if let Some(poller) = lock(&self.poller).as_ref() {
+            match poller.notify() {
+                Ok(()) => (),
+                Err(e) => log::warn!("Failed to wake up proxy instance's event loop: {}", e),
+            }
+        }
+    }
+}
+
+/// Locks the given mutex, even if a thread panicked while holding it (which terminates the process anyway).
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
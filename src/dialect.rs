@@ -0,0 +1,111 @@
+use socket2::SockAddr;
+use std::{error, fmt};
+
+use crate::event_handler::{self, EventHandler};
+use crate::sql;
+use crate::sql::cockroach_detector::CockroachDetector;
+use crate::sql::mysql_detector::MysqlDetector;
+use crate::sql::postgres_detector::PostgresDetector;
+use crate::sql_wire;
+use crate::sql_wire::mysql_session::MysqlProxySession;
+use crate::sql_wire::postgres_session::PostgresProxySession;
+use crate::tls;
+use crate::train;
+
+/// Runs the event loop of a proxy instance (listening on the first address and forwarding to the database at the
+/// second) until it fails, returning the reason it stopped.
+pub type Runner = fn(SockAddr, SockAddr, event_handler::Parameters) -> String;
+
+/// Learns the queries in a set of log files and saves them to a snapshot (see `sqlfortify train`).
+pub type Trainer = fn(&train::Options) -> Result<usize, train::TrainError>;
+
+/// A SQL dialect, paired with the detector and wire protocol used to proxy databases that speak it.
+pub struct Dialect {
+    /// The name that selects this dialect in the configuration file (`db_type = "<name>"`)
+    pub name: &'static str,
+    pub run: Runner,
+    pub train: Trainer,
+    /// Whether the proxy can encrypt connections made with this dialect's wire protocol
+    pub supports_tls: bool,
+}
+
+/// Every supported dialect. Adding a dialect only requires adding it here.
+static DIALECTS: &[Dialect] = &[
+    Dialect {
+        name: "cockroach",
+        run: run::<CockroachDetector, PostgresProxySession<tls::Stream, tls::Stream>>,
+        train: train::train::<CockroachDetector>,
+        supports_tls: true,
+    },
+    Dialect {
+        name: "mysql",
+        run: run::<MysqlDetector, MysqlProxySession<tls::Stream, tls::Stream>>,
+        train: train::train::<MysqlDetector>,
+        supports_tls: false,
+    },
+    Dialect {
+        name: "postgresql",
+        run: run::<PostgresDetector, PostgresProxySession<tls::Stream, tls::Stream>>,
+        train: train::train::<PostgresDetector>,
+        supports_tls: true,
+    },
+];
+
+#[derive(Debug)]
+pub struct UnknownDialectError {
+    reason: String,
+}
+
+impl fmt::Display for UnknownDialectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", &self.reason)
+    }
+}
+
+impl error::Error for UnknownDialectError {}
+
+/// Returns the dialect with the given name.
+pub fn lookup(name: &str) -> Result<&'static Dialect, UnknownDialectError> {
+    match DIALECTS.iter().find(|dialect| dialect.name == name) {
+        Some(dialect) => Ok(dialect),
+        None => Err(UnknownDialectError {
+            reason: format!(
+                "unknown database type '{}' (expected one of {})",
+                name,
+                DIALECTS
+                    .iter()
+                    .map(|dialect| format!("\"{}\"", dialect.name))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ),
+        }),
+    }
+}
+
+fn run<D, P>(
+    listen_address: SockAddr,
+    db_address: SockAddr,
+    params: event_handler::Parameters,
+) -> String
+// NOTE: could add C, S generics here that impl io::read and io::write to extend functionality beyond inet sockets
+where
+    D: sql::Detector,
+    P: sql_wire::Proxy<tls::Stream, tls::Stream>,
+{
+    match EventHandler::<D, P>::new(listen_address, db_address, params) {
+        Ok(mut handler) => {
+            let result = handler.handle_loop();
+            handler.save_snapshot(); // Keep whatever was learned before the proxy instance is restarted
+
+            match result {
+                // The event loop only returns successfully once the supervisor has asked it to stop
+                Ok(()) => "stopped as requested".to_string(),
+                Err(e) => format!("unrecoverable error caused event loop to crash ({})", e),
+            }
+        }
+        Err(e) => format!(
+            "unrecoverable error occurred while initializing event handler ({})",
+            e
+        ),
+    }
+}
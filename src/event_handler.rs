@@ -3,24 +3,55 @@ use nohash_hasher;
 use polling::{Event, Poller};
 use socket2::{Domain, SockAddr, Socket, Type};
 use std::collections::{HashMap, VecDeque};
-use std::time::Duration;
-use std::{error, fmt, io};
-
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::{error, fmt, fs, io};
+
+use crate::audit;
+use crate::blocking;
+use crate::control;
+use crate::metrics;
+use crate::snapshot;
 use crate::sql;
 use crate::sql_wire;
+use crate::tls;
+use crate::unix_socket;
 
 use super::connection::{Connection, IONeed};
 use super::key_pool::KeyPool;
 use super::validator;
 
+#[derive(Clone)]
 pub struct Parameters {
     pub validator_params: validator::Parameters,
+    /// Where (and how often) learned query patterns are saved; if `None`, they are lost when the proxy stops
+    pub snapshot_params: Option<snapshot::Parameters>,
+    /// Where the outcome of each checked query is recorded (if anywhere)
+    pub auditor: Option<audit::Auditor>,
+    /// Shared with the admin listener (if enabled), which reports them at `/metrics`
+    pub metrics: Arc<metrics::InstanceMetrics>,
+    /// How TLS is used with clients and the database
+    pub tls: tls::Parameters,
+    /// How clients whose queries are blocked are responded to
+    pub blocking: blocking::Policy,
+    /// The socket file listened on, if listening on a Unix domain socket
+    pub unix_socket: Option<unix_socket::Parameters>,
+    /// Shared with the supervisor, which uses it to stop the proxy instance or change its detection settings
+    pub control: Arc<control::Control>,
 }
 
 impl Parameters {
     pub fn default() -> Self {
         Parameters {
             validator_params: validator::Parameters::default(),
+            snapshot_params: None,
+            auditor: None,
+            metrics: Arc::new(metrics::InstanceMetrics::default()),
+            tls: tls::Parameters::default(),
+            blocking: blocking::Policy::default(),
+            unix_socket: None,
+            control: Arc::new(control::Control::default()),
         }
     }
 }
@@ -46,26 +77,41 @@ impl std::convert::From<std::io::Error> for HandlerError {
     }
 }
 
-pub struct EventHandler<D: sql::Detector, P: sql_wire::Proxy<Socket, Socket>> {
+pub struct EventHandler<D: sql::Detector, P: sql_wire::Proxy<tls::Stream, tls::Stream>> {
     db_addr: SockAddr,
     db_key_map: HashMap<usize, usize, nohash_hasher::BuildNoHashHasher<usize>>,
     key_pool: KeyPool,
     listener: Socket,
     listener_key: usize,
-    poller: Poller,
+    poller: Arc<Poller>,
     connections: HashMap<usize, Connection<D, P>, nohash_hasher::BuildNoHashHasher<usize>>,
     validator: validator::SqlValidator<D>,
+    snapshot_params: Option<snapshot::Parameters>,
+    last_snapshot: Instant,
+    auditor: Option<audit::Auditor>,
+    metrics: Arc<metrics::InstanceMetrics>,
+    tls: tls::Parameters,
+    blocking: blocking::Policy,
+    /// Client addresses whose queries are rejected (shared by all of their connections)
+    quarantine: blocking::Quarantine,
+    /// The connections (by frontend key) that send nothing to their clients until the given time
+    tarpits: HashMap<usize, Instant, nohash_hasher::BuildNoHashHasher<usize>>,
+    /// The socket file listened on (if any), which is removed once the proxy instance stops
+    unix_socket_path: Option<PathBuf>,
+    control: Arc<control::Control>,
+    /// Cleared once the proxy instance has been asked to stop, after which no new connections are accepted
+    accepting: bool,
 }
 
-impl<D: sql::Detector, P: sql_wire::Proxy<Socket, Socket>> EventHandler<D, P> {
+impl<D: sql::Detector, P: sql_wire::Proxy<tls::Stream, tls::Stream>> EventHandler<D, P> {
     pub fn new(
         listen_address: SockAddr,
         db_address: SockAddr,
         params: Parameters,
     ) -> Result<Self, HandlerError> {
-        let listener = create_listener(&listen_address)?;
+        let listener = create_listener(&listen_address, params.unix_socket.as_ref())?;
         let poller = match Poller::new() {
-            Ok(p) => p,
+            Ok(p) => Arc::new(p),
             Err(e) => {
                 return Err(HandlerError {
                     reason: format!("failed to initialize poller: {}", e.to_string()),
@@ -87,6 +133,34 @@ impl<D: sql::Detector, P: sql_wire::Proxy<Socket, Socket>> EventHandler<D, P> {
             }
         };
 
+        let mut validator = validator::SqlValidator::new(params.validator_params);
+        if let Some(snapshot_params) = params.snapshot_params.as_ref() {
+            match validator.load_patterns(snapshot_params) {
+                Ok(true) => log::info!(
+                    "Loaded learned query patterns from {}",
+                    snapshot_params.path.display()
+                ),
+                Ok(false) => log::info!(
+                    "No learned query patterns found at {}--starting with none",
+                    snapshot_params.path.display()
+                ),
+                Err(e) => {
+                    // Starting over here would overwrite the snapshot on the next save
+                    return Err(HandlerError {
+                        reason: format!("failed to load learned query patterns: {}", e),
+                    });
+                }
+            }
+        }
+
+        params.control.attach(poller.clone());
+
+        // Connections don't survive a restart of the proxy instance
+        params.metrics.set_active_connections(0);
+        params
+            .metrics
+            .set_pattern_nodes(validator.pattern_node_count());
+
         Ok(EventHandler::<D, P> {
             db_addr: db_address,
             key_pool: pool,
@@ -95,40 +169,130 @@ impl<D: sql::Detector, P: sql_wire::Proxy<Socket, Socket>> EventHandler<D, P> {
             poller: poller,
             connections: HashMap::with_hasher(nohash_hasher::BuildNoHashHasher::default()),
             db_key_map: HashMap::with_hasher(nohash_hasher::BuildNoHashHasher::default()),
-            validator: validator::SqlValidator::new(params.validator_params),
+            validator,
+            snapshot_params: params.snapshot_params,
+            last_snapshot: Instant::now(),
+            auditor: params.auditor,
+            metrics: params.metrics,
+            tls: params.tls,
+            blocking: params.blocking,
+            quarantine: blocking::Quarantine::default(),
+            tarpits: HashMap::with_hasher(nohash_hasher::BuildNoHashHasher::default()),
+            unix_socket_path: params.unix_socket.map(|u| u.path),
+            control: params.control,
+            accepting: true,
         })
     }
 
+    /// Saves learned query patterns to the configured snapshot (if any have been learned since the last save).
+    pub fn save_snapshot(&mut self) {
+        self.last_snapshot = Instant::now();
+        let snapshot_params = match self.snapshot_params.as_ref() {
+            Some(p) => p,
+            None => return,
+        };
+
+        match self.validator.save_patterns(snapshot_params) {
+            Ok(true) => log::info!(
+                "Saved learned query patterns to {}",
+                snapshot_params.path.display()
+            ),
+            Ok(false) => log::debug!("No new query patterns learned since last snapshot"),
+            Err(e) => log::error!("Failed to save learned query patterns: {}", e),
+        }
+    }
+
+    /// Returns how long until learned query patterns are next due to be saved, or `None` if they're never periodically saved.
+    fn time_until_snapshot(&self) -> Option<Duration> {
+        self.snapshot_params
+            .as_ref()
+            .and_then(|p| p.interval)
+            .map(|interval| interval.saturating_sub(self.last_snapshot.elapsed()))
+    }
+
+    /// Returns how long until the next tarpitted connection may resume sending to its client, or `None` if there are none.
+    fn time_until_tarpit_ends(&self) -> Option<Duration> {
+        let now = Instant::now();
+        self.tarpits
+            .values()
+            .map(|until| until.saturating_duration_since(now))
+            .min()
+    }
+
     pub fn handle_loop(&mut self) -> Result<(), HandlerError> {
         let mut new_events = Vec::new();
         let mut event_keys = HashMap::with_hasher(nohash_hasher::BuildNoHashHasher::default());
 
         loop {
+            if let Some(params) = self.control.take_parameters() {
+                self.validator.set_parameters(params);
+                log::info!("Switched to reloaded detection settings");
+            }
+
+            let shutdown_deadline = self.control.shutdown_deadline();
+            if let Some(deadline) = shutdown_deadline {
+                if self.accepting {
+                    self.stop_accepting()?;
+                }
+                self.close_idle_connections(&mut event_keys);
+
+                if self.connections.is_empty() {
+                    log::info!("All connections closed--stopping proxy instance");
+                    return Ok(());
+                }
+                if Instant::now() >= deadline {
+                    log::warn!(
+                        "Shutdown timeout reached--closing {} connections that are still in use",
+                        self.connections.len()
+                    );
+                    return Ok(());
+                }
+            }
+
             // If we still have connections to service in the queue, return additional connections immediately
             let timeout = if event_keys.len() > 0 {
                 log::debug!("Temporarily polling for new socket events");
                 Some(Duration::ZERO)
+            } else if let Some(until_tarpit) = self.time_until_tarpit_ends() {
+                log::debug!("Polling for new socket events until a tarpit ends...");
+                Some(match self.time_until_snapshot() {
+                    Some(until_snapshot) => until_snapshot.min(until_tarpit),
+                    None => until_tarpit,
+                })
+            } else if let Some(until_snapshot) = self.time_until_snapshot() {
+                log::debug!("Polling for new socket events until the next snapshot is due...");
+                Some(until_snapshot)
             } else {
                 log::debug!("Polling for new socket events indefinitely...");
                 None
             };
-
-            // We always want our listening socket to be polled
-            match self
-                .poller
-                .modify(&self.listener, Event::readable(self.listener_key))
-            {
-                Ok(_) => (),
-                Err(e) => {
-                    return Err(HandlerError {
-                        reason: format!(
-                            "failed to poll read events on listening socket: {}",
-                            e.to_string()
-                        ),
-                    })
+            // Busy connections are given until the shutdown deadline to become idle
+            let timeout = match shutdown_deadline {
+                Some(deadline) => {
+                    let until_deadline = deadline.saturating_duration_since(Instant::now());
+                    Some(timeout.map_or(until_deadline, |t| t.min(until_deadline)))
                 }
+                None => timeout,
             };
 
+            // We always want our listening socket to be polled (until the proxy instance is asked to stop)
+            if self.accepting {
+                match self
+                    .poller
+                    .modify(&self.listener, Event::readable(self.listener_key))
+                {
+                    Ok(_) => (),
+                    Err(e) => {
+                        return Err(HandlerError {
+                            reason: format!(
+                                "failed to poll read events on listening socket: {}",
+                                e.to_string()
+                            ),
+                        })
+                    }
+                };
+            }
+
             match self.poller.wait(&mut new_events, timeout) {
                 Ok(_) => (),
                 Err(e) => {
@@ -165,7 +329,23 @@ impl<D: sql::Detector, P: sql_wire::Proxy<Socket, Socket>> EventHandler<D, P> {
                 }
             }
 
+            // Connections whose tarpits have ended resume sending to their clients
+            let now = Instant::now();
+            self.tarpits.retain(|key, until| {
+                if *until > now {
+                    return true;
+                }
+                event_keys.entry(*key).or_insert((false, false)).1 = true;
+                false
+            });
+
             self.handle_queue(&mut event_keys)?;
+            self.metrics
+                .set_pattern_nodes(self.validator.pattern_node_count());
+
+            if self.time_until_snapshot() == Some(Duration::ZERO) {
+                self.save_snapshot();
+            }
         }
     }
 
@@ -180,6 +360,9 @@ impl<D: sql::Detector, P: sql_wire::Proxy<Socket, Socket>> EventHandler<D, P> {
             Ok((new_client, new_client_addr)) => {
                 let client_addr_name = match new_client_addr.as_socket() {
                     Some(socket_addr) => socket_addr.to_string(),
+                    None if new_client_addr.family() as i32 == libc::AF_UNIX => {
+                        unix_socket::CLIENT_ADDRESS.to_string()
+                    }
                     None => "<unknown_addr_type>".to_string(),
                 };
 
@@ -202,6 +385,7 @@ impl<D: sql::Detector, P: sql_wire::Proxy<Socket, Socket>> EventHandler<D, P> {
                         Ok(sock) => sock,
                         Err(e) => {
                             log::error!("{}", e.reason);
+                            self.metrics.backend_connect_failed();
                             return Ok(()); // Fail gracefully in this case--we don't want an influx of new connections causing a db error to crash existing connections, that would be DOS
                         }
                     };
@@ -245,8 +429,12 @@ impl<D: sql::Detector, P: sql_wire::Proxy<Socket, Socket>> EventHandler<D, P> {
                         client_addr_name,
                         client_key,
                         new_client,
+                        self.metrics.clone(),
+                        self.tls.clone(),
                     ),
                 );
+                self.metrics.connection_accepted();
+                self.metrics.set_active_connections(self.connections.len());
 
                 self.db_key_map.insert(backend_key, client_key);
                 event_keys.insert(client_key, (true, false)); // Necessary to call `connect` on db_socket
@@ -264,6 +452,41 @@ impl<D: sql::Detector, P: sql_wire::Proxy<Socket, Socket>> EventHandler<D, P> {
         Ok(())
     }
 
+    /// Stops accepting new connections, leaving existing ones to finish what they're doing.
+    fn stop_accepting(&mut self) -> Result<(), HandlerError> {
+        log::info!(
+            "Proxy instance asked to stop--no longer accepting connections, and waiting for {} to become idle",
+            self.connections.len()
+        );
+        self.accepting = false;
+
+        match self.poller.delete(&self.listener) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(HandlerError {
+                reason: format!("failed to remove listening socket from poller: {}", e),
+            }),
+        }
+    }
+
+    /// Closes every connection that has no request or response in flight (used while the proxy instance is stopping).
+    fn close_idle_connections(
+        &mut self,
+        event_keys: &mut HashMap<usize, (bool, bool), nohash_hasher::BuildNoHashHasher<usize>>,
+    ) {
+        let idle_keys: Vec<usize> = self
+            .connections
+            .iter()
+            .filter(|(_, connection)| connection.is_idle())
+            .map(|(key, _)| *key)
+            .collect();
+
+        for key in idle_keys {
+            log::debug!("Closing idle connection with key {} during shutdown", key);
+            event_keys.remove(&key);
+            self.connection_cleanup(key);
+        }
+    }
+
     fn connection_cleanup(&mut self, connection_key: usize) {
         let connection = match self.connections.remove(&connection_key) {
             Some(conn) => conn,
@@ -277,7 +500,9 @@ impl<D: sql::Detector, P: sql_wire::Proxy<Socket, Socket>> EventHandler<D, P> {
         self.key_pool.return_key(connection.get_backend_key());
 
         self.db_key_map.remove(&connection.get_backend_key());
+        self.tarpits.remove(&connection_key);
         self.connections.remove(&connection_key); // Allows `connection` to be freed up
+        self.metrics.set_active_connections(self.connections.len());
         match self.poller.delete(connection.get_frontend_socket()) {
             Ok(()) => (),
             Err(e) => log::warn!(
@@ -363,7 +588,12 @@ impl<D: sql::Detector, P: sql_wire::Proxy<Socket, Socket>> EventHandler<D, P> {
                     connection.get_frontend_address(),
                     frontend_key
                 );
-                match connection.process_incoming(&mut self.validator) {
+                match connection.process_incoming(
+                    &mut self.validator,
+                    self.auditor.as_ref(),
+                    &self.blocking,
+                    &mut self.quarantine,
+                ) {
                     Ok(res) => {
                         still_incoming = res.should_retry;
                         frontend_events |= res.frontend;
@@ -382,6 +612,10 @@ impl<D: sql::Detector, P: sql_wire::Proxy<Socket, Socket>> EventHandler<D, P> {
                 };
             }
 
+            if let Some(until) = connection.get_tarpit_deadline() {
+                self.tarpits.insert(frontend_key, until);
+            }
+
             if still_incoming || still_outgoing {
                 log::debug!("Connection was still_incoming or still_outgoing--put back into event_keys for next iteration");
                 match events.insert(frontend_key, (still_incoming, still_outgoing)) {
@@ -412,6 +646,17 @@ impl<D: sql::Detector, P: sql_wire::Proxy<Socket, Socket>> EventHandler<D, P> {
     }
 }
 
+impl<D: sql::Detector, P: sql_wire::Proxy<tls::Stream, tls::Stream>> Drop for EventHandler<D, P> {
+    fn drop(&mut self) {
+        if let Some(path) = self.unix_socket_path.as_ref() {
+            match fs::remove_file(path) {
+                Ok(()) => (),
+                Err(e) => log::warn!("Failed to remove socket file {}: {}", path.display(), e),
+            }
+        }
+    }
+}
+
 fn match_event(res: IONeed, key: usize) -> Event {
     match res {
         IONeed::Read => Event::readable(key),
@@ -421,10 +666,14 @@ fn match_event(res: IONeed, key: usize) -> Event {
     }
 }
 
-fn create_listener(listen_address: &SockAddr) -> Result<Socket, HandlerError> {
+fn create_listener(
+    listen_address: &SockAddr,
+    unix_socket: Option<&unix_socket::Parameters>,
+) -> Result<Socket, HandlerError> {
     let family = match listen_address.family() as i32 {
         libc::AF_INET => Domain::IPV4,
         libc::AF_INET6 => Domain::IPV6,
+        libc::AF_UNIX => Domain::UNIX,
         _ => {
             return Err(HandlerError {
                 reason: format!(
@@ -456,6 +705,34 @@ fn create_listener(listen_address: &SockAddr) -> Result<Socket, HandlerError> {
         }
     };
 
+    match unix_socket {
+        // A socket file left behind by an earlier run would otherwise prevent the path from being bound
+        Some(params) => match unix_socket::remove_stale(&params.path) {
+            Ok(()) => (),
+            Err(e) => {
+                return Err(HandlerError {
+                    reason: format!(
+                        "socket file {} could not be replaced: {}",
+                        params.path.display(),
+                        e
+                    ),
+                })
+            }
+        },
+        // Allows a restarted proxy instance to rebind its address while old connections are still in TIME_WAIT
+        None => match listener.set_reuse_address(true) {
+            Ok(_) => (),
+            Err(e) => {
+                return Err(HandlerError {
+                    reason: format!(
+                        "listening socket could not be set to reuse its address: {}",
+                        e.to_string()
+                    ),
+                })
+            }
+        },
+    };
+
     match listener.bind(&listen_address) {
         Ok(_) => (),
         Err(e) => {
@@ -467,6 +744,21 @@ fn create_listener(listen_address: &SockAddr) -> Result<Socket, HandlerError> {
             })
         }
     };
+    if let Some(params) = unix_socket {
+        match unix_socket::set_permissions(params) {
+            Ok(()) => (),
+            Err(e) => {
+                return Err(HandlerError {
+                    reason: format!(
+                        "permissions of socket file {} could not be set: {}",
+                        params.path.display(),
+                        e
+                    ),
+                })
+            }
+        }
+    }
+
     match listener.listen(4096) {
         // Maximum number of backlogged connections
         Ok(_) => (),
@@ -509,3 +801,198 @@ fn create_backend_socket(family: Domain) -> Result<Socket, HandlerError> {
 
     Ok(db_socket)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql::postgres_detector::PostgresDetector;
+    use crate::sql_wire::postgres_session::PostgresProxySession;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::sync::mpsc;
+    use std::thread;
+
+    type PostgresHandler =
+        EventHandler<PostgresDetector, PostgresProxySession<tls::Stream, tls::Stream>>;
+
+    const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// A proxy instance running on its own thread in front of a database server played by the test.
+    struct Proxy {
+        address: SocketAddr,
+        backend: TcpListener,
+        control: Arc<control::Control>,
+        /// Reports when the instance's event loop stopped
+        stopped: mpsc::Receiver<Instant>,
+    }
+
+    impl Proxy {
+        /// Starts a proxy instance that blocks queries unless they're exact matches.
+        fn start() -> Self {
+            let backend = TcpListener::bind("127.0.0.1:0").unwrap();
+            let db_address = SockAddr::from(backend.local_addr().unwrap());
+            let control = Arc::new(control::Control::default());
+            let mut params = Parameters::default();
+            params.validator_params = strict_parameters(validator::Mode::Enforce);
+            params.control = control.clone();
+
+            let (address_sender, address_receiver) = mpsc::channel();
+            let (stopped_sender, stopped) = mpsc::channel();
+            thread::spawn(move || {
+                let listen_address = SockAddr::from("127.0.0.1:0".parse::<SocketAddr>().unwrap());
+                let mut handler = PostgresHandler::new(listen_address, db_address, params).unwrap();
+                let address = handler.listener.local_addr().unwrap().as_socket().unwrap();
+                address_sender.send(address).unwrap();
+                handler.handle_loop().unwrap();
+                stopped_sender.send(Instant::now()).unwrap();
+            });
+
+            Proxy {
+                address: address_receiver.recv().unwrap(),
+                backend,
+                control,
+                stopped,
+            }
+        }
+
+        /// Connects a client through the proxy, completing the startup handshake. Returns the client's connection
+        /// and the database's end of the proxy's connection to it.
+        fn start_session(&self) -> (TcpStream, TcpStream) {
+            let mut client = TcpStream::connect(self.address).unwrap();
+            client.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+            let mut startup = 196608_i32.to_be_bytes().to_vec(); // Protocol version 3.0
+            startup.extend_from_slice(b"user\x00test\x00\x00");
+            let mut startup_message = ((startup.len() + 4) as i32).to_be_bytes().to_vec();
+            startup_message.extend(startup);
+            client.write_all(&startup_message).unwrap();
+
+            let (mut server, _) = self.backend.accept().unwrap();
+            server.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+            let mut received = vec![0; startup_message.len()];
+            server.read_exact(&mut received).unwrap();
+            let mut ready = message(b'R', &0_i32.to_be_bytes()); // AuthenticationOk
+            ready.extend(message(b'Z', b"I"));
+            server.write_all(&ready).unwrap();
+            assert_eq!(read_until_ready(&mut client), ready);
+            (client, server)
+        }
+
+        /// Waits for the instance's event loop to stop, returning when it stopped.
+        fn stopped(&self) -> Instant {
+            self.stopped.recv_timeout(READ_TIMEOUT).unwrap()
+        }
+    }
+
+    /// Returns detection settings that block every query detected as SQL injection, even if it matches no pattern.
+    fn strict_parameters(mode: validator::Mode) -> validator::Parameters {
+        validator::Parameters {
+            detector_nopattern: sql::Parameters::default_prefix_suffix(),
+            mode,
+            ..validator::Parameters::default()
+        }
+    }
+
+    fn message(kind: u8, body: &[u8]) -> Vec<u8> {
+        let mut msg = vec![kind];
+        msg.extend_from_slice(&((body.len() + 4) as i32).to_be_bytes());
+        msg.extend_from_slice(body);
+        msg
+    }
+
+    fn query(text: &str) -> Vec<u8> {
+        let mut body = text.as_bytes().to_vec();
+        body.push(0);
+        message(b'Q', &body)
+    }
+
+    /// Reads messages up to and including the next ReadyForQuery.
+    fn read_until_ready(stream: &mut TcpStream) -> Vec<u8> {
+        let mut data = Vec::new();
+        loop {
+            let mut header = [0; 5];
+            stream.read_exact(&mut header).unwrap();
+            let len = i32::from_be_bytes(header[1..].try_into().unwrap()) as usize;
+            let mut body = vec![0; len - 4];
+            stream.read_exact(&mut body).unwrap();
+            data.extend_from_slice(&header);
+            data.extend(body);
+            if header[0] == b'Z' {
+                return data;
+            }
+        }
+    }
+
+    /// Asserts that nothing more is received on the stream within a short while.
+    fn assert_nothing_received(stream: &mut TcpStream) {
+        stream
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let result = stream.read(&mut [0; 64]);
+        assert!(
+            matches!(&result, Err(e) if e.kind() == io::ErrorKind::WouldBlock),
+            "unexpectedly received {:?}",
+            result
+        );
+        stream.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+    }
+
+    #[test]
+    fn shutdown_closes_idle_connections_straight_away() {
+        let proxy = Proxy::start();
+        let (mut client, _server) = proxy.start_session();
+
+        let requested = Instant::now();
+        proxy
+            .control
+            .request_shutdown(requested + Duration::from_secs(60));
+
+        assert!(proxy.stopped() - requested < Duration::from_secs(5));
+        assert_eq!(client.read(&mut [0; 16]).unwrap(), 0);
+    }
+
+    #[test]
+    fn shutdown_waits_for_busy_connections_until_the_deadline() {
+        let proxy = Proxy::start();
+        let (mut client, mut server) = proxy.start_session();
+        let (mut idle_client, _idle_server) = proxy.start_session();
+
+        // The query is never answered, so its connection stays busy
+        let busy_query = query("SELECT 1");
+        client.write_all(&busy_query).unwrap();
+        let mut received = vec![0; busy_query.len()];
+        server.read_exact(&mut received).unwrap();
+
+        let deadline = Instant::now() + Duration::from_millis(300);
+        proxy.control.request_shutdown(deadline);
+
+        assert_eq!(idle_client.read(&mut [0; 16]).unwrap(), 0);
+        let stopped = proxy.stopped();
+        assert!(stopped >= deadline);
+        assert!(stopped - deadline < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn reloaded_parameters_apply_to_open_connections() {
+        let proxy = Proxy::start();
+        let (mut client, mut server) = proxy.start_session();
+
+        let detected = query("SELECT pg_sleep(5)");
+        client.write_all(&detected).unwrap();
+        assert_eq!(read_until_ready(&mut client)[0], b'E');
+        assert_nothing_received(&mut server);
+
+        proxy
+            .control
+            .request_reload(strict_parameters(validator::Mode::Monitor));
+        // The reload is applied before the event loop handles any further events, including this session's startup
+        let (_other_client, _other_server) = proxy.start_session();
+
+        client.write_all(&detected).unwrap();
+        let mut received = vec![0; detected.len()];
+        server.read_exact(&mut received).unwrap();
+        assert_eq!(received, detected);
+
+        proxy.control.request_shutdown(Instant::now());
+        proxy.stopped();
+    }
+}
@@ -1,32 +1,60 @@
+mod audit;
+mod blocking;
+mod config;
 mod connection;
+mod control;
+mod dialect;
 mod event_handler;
 mod key_pool;
 mod matcher;
+mod metrics;
+mod snapshot;
+mod train;
+mod unix_socket;
 mod validator;
 
 mod sql;
 mod sql_wire;
+mod tls;
 
-use socket2::{SockAddr, Socket};
-use sql::cockroach_detector::CockroachDetector;
-use std::{env, fs, io::Read, net::SocketAddr, path};
-//use toml::{self, Deserializer};
-
-use sql_wire::postgres_session::PostgresProxySession;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use socket2::SockAddr;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+use std::{env, io, net::SocketAddr, panic, path, process, thread};
 
 #[macro_use]
 extern crate enum_display_derive;
 
+/// How long the supervisor waits before restarting a failed proxy instance
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// Sent to the supervisor (the main thread) whenever a proxy instance's thread stops running.
+struct InstanceExit {
+    /// The index of the proxy instance's configuration
+    index: usize,
+    reason: String,
+}
+
+/// Everything that the supervisor responds to.
+enum SupervisorEvent {
+    InstanceExit(InstanceExit),
+    /// SIGTERM or SIGINT was received: every proxy instance is stopped once its connections are idle
+    Shutdown,
+    /// SIGHUP was received: the configuration file is read again and detection settings are updated
+    Reload,
+}
+
 fn main() {
     env_logger::init(); // Logging to stderr by default
 
-    /*
     // TODO: Someday we'll have per-thread handling of crashes... but not today.
     // If any thread panics, we kill the entire process so that the system's
     // service handler (systemd or sc, for instance) can handle the shutdown
     // and potentially reload the service.
     panic::set_hook(Box::new(|info| {
-        trace!("Thread panicked--entered custom panic handler");
+        log::trace!("Thread panicked--entered custom panic handler");
         let thread = thread::current();
         let thread_name = thread.name().unwrap_or("<unnamed thread>");
 
@@ -38,150 +66,399 @@ fn main() {
             },
         };
 
-        error!(
+        log::error!(
             "An unexpected crash occurred in thread '{}': {}",
-            thread_name, msg
+            thread_name,
+            msg
         );
 
         if let Some(location) = info.location() {
-            info!(
+            log::info!(
                 "Panic occurred in file '{}' at line {}",
                 location.file(),
                 location.line()
             );
         } else {
-            info!("Panic occurred at an unspecified location.");
+            log::info!("Panic occurred at an unspecified location.");
         }
 
-        error!("Forcing process termination with exit code 1");
+        log::error!("Forcing process termination with exit code 1");
         process::exit(1); // Important part--kills all threads if any one dies
     }));
-    */
-
-    /*
-    let config_path = path::Path::new("/home/nathaniel/Code/sqlfortify/default_config.toml");
-    let mut file = match fs::File::open(&config_path) {
-        Ok(file) => file,
-        Err(e) => panic!(
-            "Couldn't open configuration file {}: {}",
-            config_path.display(),
-            e
-        ),
-    };
 
-    let mut config = String::new();
-    match file.read_to_string(&mut config) {
-        Ok(_) => (),
-        Err(e) => panic!(
-            "Configuration file located at {} had malformed data: {}",
-            config_path.display(),
-            e
-        ),
+    let args: Vec<String> = env::args().collect();
+    if args.len() > 1 && args[1] == "train" {
+        run_training(&args[2..]);
     }
 
-    let mut des = Deserializer::new(config.as_str());
-    */
+    let config_path = match args.len() {
+        3 if args[1] == "--config" => Some(path::Path::new(args[2].as_str())),
+        _ => None,
+    };
 
-    /*
+    let config = match args.len() {
+        3 if args[1] == "--config" => match config::load(path::Path::new(args[2].as_str())) {
+            Ok(c) => c,
+            Err(e) => {
+                log::error!("Failed to load configuration: {}", e);
+                process::exit(1);
+            }
+        },
+        5 => match legacy_config(&args[1], &args[2], &args[3], &args[4]) {
+            Ok(c) => config::Config {
+                proxies: vec![c],
+                admin_address: None,
+            },
+            Err(e) => {
+                log::error!("Invalid arguments: {}", e);
+                process::exit(1);
+            }
+        },
+        _ => {
+            println!("Usage: sqlfortify --config <config_file>");
+            println!("       sqlfortify <listening_addr> <listening_port> <db_addr> <db_port>");
+            println!("       {}", train::USAGE);
+            process::exit(1);
+        }
+    };
 
-    // Required arguments:
-    // - Configuration file location
-    let mut args:Vec<String> = env::args().collect();
-    if args.len() < 1 || args.len() > 2 {
-        error!("Invalid arguments supplied to command. Exiting...");
-        process::exit(1);
+    let mut configs = config.proxies;
+
+    if let Some(admin_address) = config.admin_address {
+        let instances = configs
+            .iter()
+            .map(|c| (c.name.clone(), c.handler_params.metrics.clone()))
+            .collect();
+        match metrics::serve(admin_address, instances) {
+            Ok(()) => log::info!("Serving metrics at http://{}/metrics", admin_address),
+            Err(e) => {
+                log::error!("Failed to start admin listener on {}: {}", admin_address, e);
+                process::exit(1);
+            }
+        }
     }
 
+    let (event_sender, event_receiver) = mpsc::channel();
 
-    let config_path = String::from("config.toml"); // Default configuration path
-    if let Some(s) = args.drain(1..).next() {
-        let config_path = s;
+    if let Err(e) = forward_signals(event_sender.clone()) {
+        log::error!("Failed to set up signal handling ({})", e);
+        process::exit(1);
     }
-    */
 
-    // TODO: parse configuration here
-
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 5 {
-        println!("Usage: sqlfortify <listening_addr> <listening_port> <db_addr> <db_port>");
+    for (index, proxy_config) in configs.iter().enumerate() {
+        log::info!("Starting proxy instance '{}'", proxy_config.name);
+        if let Err(e) = spawn_instance(index, proxy_config, event_sender.clone()) {
+            log::error!(
+                "Failed to start thread for proxy instance '{}' ({})",
+                proxy_config.name,
+                e
+            );
+            process::exit(1);
+        }
     }
 
-    let (laddr, lport, daddr, dport) = (args[1].as_str(), args[2].as_str(), args[3].as_str(), args[4].as_str());
+    // Each instance is restarted a limited number of times over the lifetime of the process.
+    // Once an instance exhausts its restarts, the whole process is terminated so that the
+    // system's service handler can deal with it (just as with panics).
+    let mut restarts = vec![0; configs.len()];
+    let mut running = configs.len();
+    let mut stopping = false;
 
-    let listen: SocketAddr = format!("{}:{}", laddr, lport).parse().unwrap(); // TODO: remove unwrap
-    let db: SocketAddr = format!("{}:{}", daddr, dport).parse().unwrap(); // TODO: remove unwrap
+    loop {
+        let exit = match event_receiver.recv() {
+            Ok(SupervisorEvent::InstanceExit(exit)) => exit,
+            Ok(SupervisorEvent::Shutdown) if stopping => {
+                log::warn!("Asked to stop again--forcing process termination with exit code 1");
+                process::exit(1);
+            }
+            Ok(SupervisorEvent::Shutdown) => {
+                log::info!("Stopping every proxy instance once its connections are idle (signal again to stop immediately)");
+                stopping = true;
+                for proxy_config in configs.iter() {
+                    let deadline = Instant::now() + proxy_config.shutdown_timeout;
+                    proxy_config
+                        .handler_params
+                        .control
+                        .request_shutdown(deadline);
+                }
+                continue;
+            }
+            Ok(SupervisorEvent::Reload) => {
+                reload_config(config_path, &mut configs);
+                continue;
+            }
+            Err(_) => {
+                // Invariant: should never happen (this thread holds a sender for the channel)
+                log::error!("Supervisor channel unexpectedly closed--forcing process termination");
+                process::exit(1);
+            }
+        };
 
-    let listen = SockAddr::from(listen);
-    let db = SockAddr::from(db);
+        running -= 1;
+        let proxy_config = &configs[exit.index];
+        if stopping {
+            log::info!(
+                "Proxy instance '{}' stopped: {}",
+                proxy_config.name,
+                exit.reason
+            );
+            if running == 0 {
+                log::info!("Every proxy instance has stopped--exiting");
+                process::exit(0);
+            }
+            continue;
+        }
 
-    // This will get populated by the config file
-    let params = event_handler::Parameters::default();
+        log::error!(
+            "Proxy instance '{}' stopped: {}",
+            proxy_config.name,
+            exit.reason
+        );
 
-    // Both 'parse' and 'from' support IPv4 and IPv6, but not Unix domain sockets. Do this:
-    // #[cfg(target_family="unix")]
-    // SockAddr::unix(String::from("Path"));
+        if restarts[exit.index] >= proxy_config.max_restarts {
+            log::error!(
+                "Proxy instance '{}' exceeded its maximum of {} restarts--forcing process termination with exit code 1",
+                proxy_config.name,
+                proxy_config.max_restarts
+            );
+            process::exit(1);
+        }
 
-    /*
-    create_thread::<CockroachDetector, PostgresProxySession<Socket, Socket>>(listen, db, params);
+        restarts[exit.index] += 1;
+        log::warn!(
+            "Restarting proxy instance '{}' (restart {} of {})",
+            proxy_config.name,
+            restarts[exit.index],
+            proxy_config.max_restarts
+        );
+        thread::sleep(RESTART_DELAY);
 
-    loop {
-        thread::park(); // Not guaranteed to block forever, so we loop
+        if let Err(e) = spawn_instance(exit.index, proxy_config, event_sender.clone()) {
+            log::error!(
+                "Failed to restart thread for proxy instance '{}' ({})--forcing process termination with exit code 1",
+                proxy_config.name,
+                e
+            );
+            process::exit(1);
+        }
+        running += 1;
     }
-    */
+}
 
-    let mut handler: event_handler::EventHandler<
-        CockroachDetector,
-        PostgresProxySession<Socket, Socket>,
-    >;
+/// Forwards the signals that stop the process (SIGTERM and SIGINT) or reload its configuration (SIGHUP)
+/// to the supervisor, from a thread of their own.
+fn forward_signals(event_sender: mpsc::Sender<SupervisorEvent>) -> io::Result<()> {
+    let mut signals = Signals::new([SIGTERM, SIGINT, SIGHUP])?;
 
-    match event_handler::EventHandler::new(listen, db, params) {
-        Ok(h) => handler = h,
+    thread::Builder::new()
+        .name("signals".to_string())
+        .spawn(move || {
+            for signal in signals.forever() {
+                let event = match signal {
+                    SIGHUP => SupervisorEvent::Reload,
+                    _ => SupervisorEvent::Shutdown,
+                };
+                if event_sender.send(event).is_err() {
+                    return;
+                }
+            }
+        })?;
+
+    Ok(())
+}
+
+/// Reads the configuration file again, passing the detection settings (`detector`, `mode` and the learning window)
+/// of each proxy instance on to it without interrupting its connections. Any other changes only take effect once the
+/// process is restarted. If the file is no longer valid, the current configuration is kept.
+fn reload_config(config_path: Option<&path::Path>, configs: &mut [config::ProxyConfig]) {
+    let config_path = match config_path {
+        Some(p) => p,
+        None => {
+            log::warn!("Ignoring SIGHUP, as there is no configuration file to reload");
+            return;
+        }
+    };
+
+    let reloaded = match config::load(config_path) {
+        Ok(c) => c,
         Err(e) => {
             log::error!(
-                "Unrecoverable error occurred while initializing event handler ({})",
+                "Failed to reload configuration (keeping the current one): {}",
                 e
             );
             return;
         }
     };
 
-    match handler.handle_loop() {
-        Ok(()) => log::error!("An unknown error occurred that caused the event loop to return"), // Invariant: should never happen (event loop is infinite loop)
-        Err(e) => log::error!("Unrecoverable error caused event loop to crash ({})", e),
+    for new_config in reloaded.proxies.iter() {
+        match configs.iter_mut().find(|c| c.name == new_config.name) {
+            Some(proxy_config) => {
+                let validator_params = new_config.handler_params.validator_params.clone();
+                // Kept so that the new settings survive the proxy instance being restarted
+                proxy_config.handler_params.validator_params = validator_params.clone();
+                proxy_config
+                    .handler_params
+                    .control
+                    .request_reload(validator_params);
+                log::info!(
+                    "Reloaded detection settings of proxy instance '{}'",
+                    proxy_config.name
+                );
+            }
+            None => log::warn!(
+                "Proxy instance '{}' was added to the configuration, but won't be started until the process is restarted",
+                new_config.name
+            ),
+        }
+    }
+
+    for proxy_config in configs.iter() {
+        if !reloaded.proxies.iter().any(|c| c.name == proxy_config.name) {
+            log::warn!(
+                "Proxy instance '{}' was removed from the configuration, but won't be stopped until the process is restarted",
+                proxy_config.name
+            );
+        }
     }
 }
 
-/*
-fn create_thread<D, P>(
-    listen_address: SockAddr,
-    db_address: SockAddr,
-    params: event_handler::Parameters,
-)
-// NOTE: could add C, S generics here that impl io::read and io::write to extend functionality beyond inet sockets
-where
-    D: sqli_detector::Detector,
-    P: ProxySession<Socket, Socket>,
-{
-    let _ = std::thread::Builder::new()
-        .name("CockroachDB".to_string())
+/// Runs the `train` subcommand with the given arguments, then exits.
+fn run_training(args: &[String]) -> ! {
+    let options = match train::parse_args(args) {
+        Ok(o) => o,
+        Err(e) => {
+            log::error!("Invalid arguments: {}", e);
+            println!("Usage: {}", train::USAGE);
+            process::exit(1);
+        }
+    };
+
+    match (options.dialect.train)(&options) {
+        Ok(count) => {
+            println!(
+                "Learned {} queries; patterns saved to {}",
+                count,
+                options.output.display()
+            );
+            process::exit(0);
+        }
+        Err(e) => {
+            log::error!("Training failed: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Starts the given proxy instance on a new thread using the detector and wire protocol of its dialect.
+fn spawn_instance(
+    index: usize,
+    proxy_config: &config::ProxyConfig,
+    event_sender: mpsc::Sender<SupervisorEvent>,
+) -> io::Result<()> {
+    let run = proxy_config.dialect.run;
+    let listen_address = proxy_config.listen_address.clone();
+    let db_address = proxy_config.db_address.clone();
+    let params = proxy_config.handler_params.clone();
+
+    thread::Builder::new()
+        .name(proxy_config.name.clone())
         .spawn(move || {
-            let mut handler: event_handler::EventHandler<D, P>;
-
-            match event_handler::EventHandler::new(listen_address, db_address, params) {
-                Ok(h) => handler = h,
-                Err(e) => {
-                    log::error!(
-                        "Unrecoverable error occurred while initializing event handler: {}",
-                        e
-                    );
-                    return;
-                }
-            };
+            let reason = run(listen_address, db_address, params);
+            let exit = InstanceExit { index, reason };
 
-            match handler.handle_loop() {
-                Ok(()) => log::error!("An unknown error occurred that caused the event loop to return"), // Invariant: should never happen (event loop is infinite loop)
-                Err(e) => log::error!("Unrecoverable error caused event loop to crash: {}", e),
+            match event_sender.send(SupervisorEvent::InstanceExit(exit)) {
+                Ok(()) => (),
+                Err(_) => log::error!("Supervisor could not be notified that the proxy instance stopped"),
             }
-        });
+        })?;
+
+    Ok(())
+}
+
+/// Builds the configuration of a single proxy instance from the positional command-line arguments.
+///
+/// These have always run the CockroachDB dialect, so it is kept for them rather than the stock PostgreSQL one.
+fn legacy_config(
+    listen_addr: &str,
+    listen_port: &str,
+    db_addr: &str,
+    db_port: &str,
+) -> Result<config::ProxyConfig, String> {
+    let listen: SocketAddr = match format!("{}:{}", listen_addr, listen_port).parse() {
+        Ok(a) => a,
+        Err(e) => return Err(format!("invalid listening address: {}", e)),
+    };
+
+    let db: SocketAddr = match format!("{}:{}", db_addr, db_port).parse() {
+        Ok(a) => a,
+        Err(e) => return Err(format!("invalid database address: {}", e)),
+    };
+
+    Ok(config::ProxyConfig {
+        name: format!("cockroach{}", listen.port()),
+        dialect: match dialect::lookup("cockroach") {
+            Ok(d) => d,
+            Err(e) => return Err(e.to_string()),
+        },
+        listen_address: SockAddr::from(listen),
+        db_address: SockAddr::from(db),
+        handler_params: event_handler::Parameters::default(),
+        max_restarts: config::DEFAULT_MAX_RESTARTS,
+        shutdown_timeout: control::DEFAULT_SHUTDOWN_TIMEOUT,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    const INSTANCE: &str = r#"
+[proxy.pg]
+db_type = "postgresql"
+listen = "127.0.0.1"
+port = 6432
+db_address = "127.0.0.1"
+db_port = 5432
+"#;
+
+    #[test]
+    fn reload_passes_detection_settings_on_to_running_instances() {
+        let dir = std::env::temp_dir().join(format!("sqlfortify-reload-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let config_path = dir.join("sqlfortify.toml");
+        fs::write(&config_path, INSTANCE).unwrap();
+        let mut configs = config::load(&config_path).unwrap().proxies;
+        let control = configs[0].handler_params.control.clone();
+
+        // Proxies added by the new configuration are ignored until restarted
+        let reloaded = format!(
+            "{}mode = \"monitor\"\n{}",
+            INSTANCE,
+            INSTANCE.replace("proxy.pg", "proxy.other")
+        );
+        fs::write(&config_path, reloaded).unwrap();
+        reload_config(Some(&config_path), &mut configs);
+        assert_eq!(configs.len(), 1);
+        assert_eq!(
+            configs[0].handler_params.validator_params.mode,
+            validator::Mode::Monitor
+        );
+        assert_eq!(
+            control.take_parameters().map(|p| p.mode),
+            Some(validator::Mode::Monitor)
+        );
+
+        // An invalid configuration leaves the current settings in place
+        fs::write(&config_path, "[proxy.pg]\n").unwrap();
+        reload_config(Some(&config_path), &mut configs);
+        assert!(control.take_parameters().is_none());
+        assert_eq!(
+            configs[0].handler_params.validator_params.mode,
+            validator::Mode::Monitor
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
-*/
@@ -1,6 +1,7 @@
 use crate::sql;
 use crate::sql::Token as SqlToken;
 use fnv;
+use serde::{Deserialize, Serialize};
 use std::collections::{hash_map::Entry, HashMap};
 
 #[derive(Clone, Copy, PartialEq, Eq, Hash)]
@@ -92,6 +93,118 @@ impl<T: sql::Token> Node<T> {
     }
 }
 
+/// A single node of a trie, flattened so that a trie of any depth can be (de)serialized without recursion.
+#[derive(Serialize, Deserialize)]
+struct NodeRecord<T> {
+    /// The index of the node's parent within the list of records (the root is its own parent)
+    parent: usize,
+    /// The token leading from the parent to this node (`None` only for the root)
+    token: Option<T>,
+    id: usize,
+    next_param_id: Option<usize>,
+    is_valid_pattern: bool,
+    is_vuln_prefix: bool,
+    is_constant: bool,
+}
+
+/// The serializable contents of a `BiTrie`. Each tree is stored in pre-order, so every node comes after its parent.
+#[derive(Serialize, Deserialize)]
+pub struct TrieSnapshot<T> {
+    id_counter: usize,
+    forward: Vec<NodeRecord<T>>,
+    reverse: Vec<NodeRecord<T>>,
+}
+
+impl<T: sql::Token> Node<T> {
+    /// Flattens the tree rooted at this node into a list of records.
+    fn to_records(&self) -> Vec<NodeRecord<T>> {
+        let mut records = Vec::new();
+        let mut stack: Vec<(&Node<T>, usize, Option<&T>)> = vec![(self, 0, None)];
+
+        while let Some((node, parent, token)) = stack.pop() {
+            let index = records.len();
+            records.push(NodeRecord {
+                parent,
+                token: token.cloned(),
+                id: node.id.value,
+                next_param_id: node.next_param_id.map(|id| id.value),
+                is_valid_pattern: node.is_valid_pattern,
+                is_vuln_prefix: node.is_vuln_prefix,
+                is_constant: node.is_constant,
+            });
+
+            for (next_token, next_node) in node.next.iter() {
+                stack.push((next_node, index, Some(next_token)));
+            }
+        }
+
+        records
+    }
+
+    /// Rebuilds a tree from a list of records, checking that the records actually form a tree.
+    fn from_records(records: Vec<NodeRecord<T>>, id_counter: usize) -> Result<Self, String> {
+        if records.is_empty() {
+            return Err("tree has no root node".to_string());
+        }
+
+        let mut parents = Vec::with_capacity(records.len());
+        let mut nodes = Vec::with_capacity(records.len());
+
+        for (index, record) in records.into_iter().enumerate() {
+            if record.id == 0 || record.id > id_counter {
+                return Err(format!("node {} has an out-of-range ID", index));
+            }
+
+            match (index, &record.token) {
+                (0, None) => (),
+                (0, Some(_)) => return Err("root node has a token".to_string()),
+                (_, None) => return Err(format!("node {} has no token", index)),
+                (_, Some(_)) if record.parent >= index => {
+                    return Err(format!("node {} comes before its parent", index))
+                }
+                (_, Some(_)) => (),
+            }
+
+            let mut node = Node::new(NodeID { value: record.id });
+            node.next_param_id = record.next_param_id.map(|value| NodeID { value });
+            node.is_valid_pattern = record.is_valid_pattern;
+            node.is_vuln_prefix = record.is_vuln_prefix;
+            node.is_constant = record.is_constant;
+
+            parents.push(record.parent);
+            nodes.push(Some((record.token, node)));
+        }
+
+        // Children always come after their parents, so attaching nodes from last to first
+        // guarantees that each node already has all of its own children by the time it's attached.
+        for index in (1..nodes.len()).rev() {
+            let (token, node) = match nodes[index].take() {
+                Some((Some(token), node)) => (token, node),
+                _ => return Err(format!("node {} is malformed", index)), // Invariant: should never happen (checked above)
+            };
+
+            let parent = match nodes[parents[index]].as_mut() {
+                Some((_, parent)) => parent,
+                None => return Err(format!("node {} has an invalid parent", index)),
+            };
+
+            match parent.next.entry(token) {
+                Entry::Vacant(entry) => {
+                    entry.insert(node);
+                }
+                Entry::Occupied(_) => {
+                    return Err(format!("node {} duplicates one of its siblings", index))
+                }
+            }
+        }
+
+        match nodes[0].take() {
+            Some((_, root)) => Ok(root),
+            None => Err("tree has no root node".to_string()), // Invariant: should never happen (checked above)
+        }
+    }
+}
+
 pub struct NodeInfo<'a, T: sql::Token> {
     node: &'a Node<T>,
     pub absolute_index: usize,
@@ -111,6 +224,8 @@ pub struct BiTrie<D: sql::Detector> {
     fwd_root: Node<D::Token>, // Empty node to make traversal more easy
     rev_root: Node<D::Token>, // ditto
     id_counter: IDCounter,
+    /// Whether the trie has changed since it was last saved to (or loaded from) a snapshot
+    modified: bool,
     // Settings for check strictness can be stored in here (and passed in on new())
 }
 
@@ -121,9 +236,54 @@ impl<D: sql::Detector> BiTrie<D> {
             fwd_root: Node::new(counter.next()),
             rev_root: Node::new(counter.next()),
             id_counter: counter,
+            modified: false,
         }
     }
 
+    /// Captures the entire contents of the trie so that they can be saved.
+    pub fn to_snapshot(&self) -> TrieSnapshot<D::Token> {
+        TrieSnapshot {
+            id_counter: self.id_counter.counter,
+            forward: self.fwd_root.to_records(),
+            reverse: self.rev_root.to_records(),
+        }
+    }
+
+    /// Restores a trie from previously-saved contents, failing if they are inconsistent.
+    pub fn from_snapshot(snapshot: TrieSnapshot<D::Token>) -> Result<Self, String> {
+        let fwd_root = match Node::from_records(snapshot.forward, snapshot.id_counter) {
+            Ok(root) => root,
+            Err(e) => return Err(format!("forward tree is invalid: {}", e)),
+        };
+        let rev_root = match Node::from_records(snapshot.reverse, snapshot.id_counter) {
+            Ok(root) => root,
+            Err(e) => return Err(format!("reverse tree is invalid: {}", e)),
+        };
+
+        Ok(Self {
+            fwd_root,
+            rev_root,
+            id_counter: IDCounter {
+                counter: snapshot.id_counter,
+            },
+            modified: false,
+        })
+    }
+
+    pub fn is_modified(&self) -> bool {
+        self.modified
+    }
+
+    /// Records that the current contents of the trie have been saved.
+    pub fn mark_saved(&mut self) {
+        self.modified = false;
+    }
+
+    /// Returns the number of nodes in both directions of the trie (nodes are never removed, so every ID handed out is in use).
+    pub fn node_count(&self) -> usize {
+        self.id_counter.counter
+    }
+
     pub fn match_prefix<'a>(
         &'a self,
         forward_tokens: &Vec<(D::Token, usize)>,
@@ -216,6 +376,7 @@ impl<D: sql::Detector> BiTrie<D> {
         sql_query: &Vec<(D::Token, usize)>,
         vuln_prefix_id: Option<NodeID>,
     ) {
+        self.modified = true;
         let mut node = &mut self.fwd_root;
         match vuln_prefix_id {
             Some(vuln_id) => {
@@ -257,6 +418,7 @@ impl<D: sql::Detector> BiTrie<D> {
     // If pattern already exists, just updates is_constant values.
     // Could also name insert()
     pub fn insert(&mut self, sql_query: Vec<(D::Token, usize)>) {
+        self.modified = true;
         let token_id_pairs = self.update_fwd_tree(sql_query);
         self.update_rev_tree(token_id_pairs);
     }
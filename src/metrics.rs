@@ -0,0 +1,482 @@
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::audit::Action;
+use crate::validator::MatchPath;
+
+/// The upper bounds (in seconds) of the histogram buckets that `check_query()` latencies are counted in
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.00001, 0.000025, 0.00005, 0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.1,
+];
+
+const MATCH_PATHS: [MatchPath; 5] = [
+    MatchPath::ExactMatch,
+    MatchPath::VulnerablePrefix,
+    MatchPath::PrefixSuffix,
+    MatchPath::Prefix,
+    MatchPath::NoPattern,
+];
+
+const ACTIONS: [Action; 3] = [Action::Allowed, Action::Blocked, Action::Flagged];
+
+/// How long the admin listener waits on a scraper before giving up on its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The longest request (line and headers) the admin listener will read
+const MAX_REQUEST_LEN: u64 = 8 * 1024;
+
+/// Counts how many observed durations fall into each of the `LATENCY_BUCKETS`.
+#[derive(Default)]
+struct Histogram {
+    /// The number of observations in each bucket (not cumulative--an observation is only counted in the first bucket it fits)
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    /// Observations that exceeded the largest bucket
+    overflow: AtomicU64,
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        match LATENCY_BUCKETS.iter().position(|bound| secs <= *bound) {
+            Some(index) => self.buckets[index].fetch_add(1, Ordering::Relaxed),
+            None => self.overflow.fetch_add(1, Ordering::Relaxed),
+        };
+        self.sum_nanos
+            .fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+/// The counters and gauges of a single proxy instance. These are updated by the instance's thread and read by
+/// the admin listener, and outlive any restarts of the instance.
+#[derive(Default)]
+pub struct InstanceMetrics {
+    connections_accepted: AtomicU64,
+    connections_active: AtomicU64,
+    backend_connect_failures: AtomicU64,
+    /// Indexed by match path, then by the action taken
+    queries_checked: [[AtomicU64; ACTIONS.len()]; MATCH_PATHS.len()],
+    /// Indexed by the match path the query took when it was checked
+    queries_learned: [AtomicU64; MATCH_PATHS.len()],
+    /// The number of queries marked as vulnerable after the backend returned an error for them
+    bad_queries: AtomicU64,
+    request_deferrals: AtomicU64,
+    response_deferrals: AtomicU64,
+    pattern_nodes: AtomicU64,
+    check_latency: Histogram,
+}
+
+impl InstanceMetrics {
+    pub fn connection_accepted(&self) {
+        self.connections_accepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_active_connections(&self, count: usize) {
+        self.connections_active
+            .store(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn backend_connect_failed(&self) {
+        self.backend_connect_failures
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records the outcome of `check_query()` for a query, along with how long the check took.
+    pub fn query_checked(&self, path: MatchPath, action: Action, duration: Duration) {
+        self.queries_checked[path_index(path)][action_index(action)]
+            .fetch_add(1, Ordering::Relaxed);
+        self.check_latency.observe(duration);
+    }
+
+    pub fn query_learned(&self, path: MatchPath) {
+        self.queries_learned[path_index(path)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn bad_query(&self) {
+        self.bad_queries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records that reading requests from a client was put off because `REQUEST_QUEUE_SOFT_LIMIT` was reached.
+    pub fn request_deferred(&self) {
+        self.request_deferrals.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records that reading responses from the backend was put off because `RESPONSE_QUEUE_SOFT_LIMIT` was reached.
+    pub fn response_deferred(&self) {
+        self.response_deferrals.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_pattern_nodes(&self, count: usize) {
+        self.pattern_nodes.store(count as u64, Ordering::Relaxed);
+    }
+}
+
+/// Binds the admin listener to the given address, then serves `/metrics` for the given proxy instances on a new thread.
+pub fn serve(
+    address: SocketAddr,
+    instances: Vec<(String, Arc<InstanceMetrics>)>,
+) -> io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+
+    thread::Builder::new()
+        .name("admin".to_string())
+        .spawn(move || {
+            // Scrapes are infrequent and quick to answer, so requests are simply handled one at a time
+            for stream in listener.incoming() {
+                let result = stream.and_then(|s| handle_request(s, &instances));
+                match result {
+                    Ok(()) => (),
+                    Err(e) => log::debug!("Failed to answer admin request: {}", e),
+                }
+            }
+        })?;
+
+    Ok(())
+}
+
+fn handle_request(
+    mut stream: TcpStream,
+    instances: &[(String, Arc<InstanceMetrics>)],
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+
+    let mut reader = BufReader::new((&stream).take(MAX_REQUEST_LEN));
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+
+    // The headers aren't needed, but are read so that the client doesn't see the connection reset
+    let mut header = String::new();
+    loop {
+        header.clear();
+        if reader.read_line(&mut header)? == 0 || header.trim_end().is_empty() {
+            break;
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render(instances)),
+        (Some("GET"), Some(_)) => ("404 Not Found", "Not found\n".to_string()),
+        _ => (
+            "405 Method Not Allowed",
+            "Only GET is supported\n".to_string(),
+        ),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+/// Formats the metrics of every proxy instance in the Prometheus text exposition format.
+fn render(instances: &[(String, Arc<InstanceMetrics>)]) -> String {
+    let mut out = String::new();
+    let load = |value: &AtomicU64| value.load(Ordering::Relaxed);
+
+    let mut simple = |name: &str, kind: &str, help: &str, value: fn(&InstanceMetrics) -> u64| {
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+        for (instance, metrics) in instances.iter() {
+            let _ = writeln!(
+                out,
+                "{}{{instance=\"{}\"}} {}",
+                name,
+                escape_label(instance),
+                value(metrics)
+            );
+        }
+    };
+
+    simple(
+        "sqlfortify_connections_accepted_total",
+        "counter",
+        "Client connections accepted.",
+        |m| m.connections_accepted.load(Ordering::Relaxed),
+    );
+    simple(
+        "sqlfortify_connections_active",
+        "gauge",
+        "Client connections currently being proxied.",
+        |m| m.connections_active.load(Ordering::Relaxed),
+    );
+    simple(
+        "sqlfortify_backend_connect_failures_total",
+        "counter",
+        "Failed attempts to connect to the database.",
+        |m| m.backend_connect_failures.load(Ordering::Relaxed),
+    );
+    simple(
+        "sqlfortify_bad_queries_total",
+        "counter",
+        "Queries marked as vulnerable after the database returned an error for them.",
+        |m| m.bad_queries.load(Ordering::Relaxed),
+    );
+    simple(
+        "sqlfortify_request_deferrals_total",
+        "counter",
+        "Times reading client requests was put off because the request queue was full.",
+        |m| m.request_deferrals.load(Ordering::Relaxed),
+    );
+    simple(
+        "sqlfortify_response_deferrals_total",
+        "counter",
+        "Times reading database responses was put off because the response queue was full.",
+        |m| m.response_deferrals.load(Ordering::Relaxed),
+    );
+    simple(
+        "sqlfortify_pattern_nodes",
+        "gauge",
+        "Nodes in the trie of learned query patterns.",
+        |m| m.pattern_nodes.load(Ordering::Relaxed),
+    );
+
+    let _ = writeln!(
+        out,
+        "# HELP sqlfortify_queries_checked_total Queries checked for SQL injection, by match path and action taken.\n# TYPE sqlfortify_queries_checked_total counter"
+    );
+    for (instance, metrics) in instances.iter() {
+        for (path_idx, path) in MATCH_PATHS.iter().enumerate() {
+            for (action_idx, action) in ACTIONS.iter().enumerate() {
+                let _ = writeln!(
+                    out,
+                    "sqlfortify_queries_checked_total{{instance=\"{}\",path=\"{}\",action=\"{}\"}} {}",
+                    escape_label(instance),
+                    path_label(*path),
+                    action_label(*action),
+                    load(&metrics.queries_checked[path_idx][action_idx])
+                );
+            }
+        }
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP sqlfortify_queries_learned_total Queries learned as benign after succeeding, by the match path they took when checked.\n# TYPE sqlfortify_queries_learned_total counter"
+    );
+    for (instance, metrics) in instances.iter() {
+        for (path_idx, path) in MATCH_PATHS.iter().enumerate() {
+            let _ = writeln!(
+                out,
+                "sqlfortify_queries_learned_total{{instance=\"{}\",path=\"{}\"}} {}",
+                escape_label(instance),
+                path_label(*path),
+                load(&metrics.queries_learned[path_idx])
+            );
+        }
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP sqlfortify_check_duration_seconds Time taken to check each query for SQL injection.\n# TYPE sqlfortify_check_duration_seconds histogram"
+    );
+    for (instance, metrics) in instances.iter() {
+        let instance = escape_label(instance);
+        let histogram = &metrics.check_latency;
+        let mut cumulative = 0;
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(histogram.buckets.iter()) {
+            cumulative += load(bucket);
+            let _ = writeln!(
+                out,
+                "sqlfortify_check_duration_seconds_bucket{{instance=\"{}\",le=\"{}\"}} {}",
+                instance, bound, cumulative
+            );
+        }
+        cumulative += load(&histogram.overflow);
+        let _ = writeln!(
+            out,
+            "sqlfortify_check_duration_seconds_bucket{{instance=\"{}\",le=\"+Inf\"}} {}",
+            instance, cumulative
+        );
+        let _ = writeln!(
+            out,
+            "sqlfortify_check_duration_seconds_sum{{instance=\"{}\"}} {}",
+            instance,
+            load(&histogram.sum_nanos) as f64 / 1e9
+        );
+        let _ = writeln!(
+            out,
+            "sqlfortify_check_duration_seconds_count{{instance=\"{}\"}} {}",
+            instance, cumulative
+        );
+    }
+
+    out
+}
+
+fn path_index(path: MatchPath) -> usize {
+    match path {
+        MatchPath::ExactMatch => 0,
+        MatchPath::VulnerablePrefix => 1,
+        MatchPath::PrefixSuffix => 2,
+        MatchPath::Prefix => 3,
+        MatchPath::NoPattern => 4,
+    }
+}
+
+fn path_label(path: MatchPath) -> &'static str {
+    match path {
+        MatchPath::ExactMatch => "exact_match",
+        MatchPath::VulnerablePrefix => "vulnerable_prefix",
+        MatchPath::PrefixSuffix => "prefix_suffix",
+        MatchPath::Prefix => "prefix",
+        MatchPath::NoPattern => "no_pattern",
+    }
+}
+
+fn action_index(action: Action) -> usize {
+    match action {
+        Action::Allowed => 0,
+        Action::Blocked => 1,
+        Action::Flagged => 2,
+    }
+}
+
+fn action_label(action: Action) -> &'static str {
+    match action {
+        Action::Allowed => "allowed",
+        Action::Blocked => "blocked",
+        Action::Flagged => "flagged",
+    }
+}
+
+/// Escapes a value for use within a quoted label.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn has_line(out: &str, line: &str) -> bool {
+        out.lines().any(|l| l == line)
+    }
+
+    /// Sends a request line to the admin listener's request handler, returning the response.
+    fn request(request_line: &str, instances: &[(String, Arc<InstanceMetrics>)]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        write!(client, "{}\r\nHost: localhost\r\n\r\n", request_line).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        handle_request(server, instances).unwrap();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn renders_each_instance() {
+        let primary = Arc::new(InstanceMetrics::default());
+        primary.connection_accepted();
+        primary.connection_accepted();
+        primary.set_active_connections(1);
+        primary.bad_query();
+        primary.set_pattern_nodes(42);
+        primary.query_checked(MatchPath::Prefix, Action::Blocked, Duration::ZERO);
+        primary.query_learned(MatchPath::NoPattern);
+        let replica = Arc::new(InstanceMetrics::default());
+        replica.backend_connect_failed();
+        replica.request_deferred();
+        replica.response_deferred();
+        replica.query_checked(MatchPath::ExactMatch, Action::Flagged, Duration::ZERO);
+
+        let out = render(&[
+            ("primary".to_string(), primary),
+            ("replica \"b\"".to_string(), replica),
+        ]);
+
+        for line in [
+            "# TYPE sqlfortify_connections_accepted_total counter",
+            "sqlfortify_connections_accepted_total{instance=\"primary\"} 2",
+            "sqlfortify_connections_accepted_total{instance=\"replica \\\"b\\\"\"} 0",
+            "# TYPE sqlfortify_connections_active gauge",
+            "sqlfortify_connections_active{instance=\"primary\"} 1",
+            "sqlfortify_backend_connect_failures_total{instance=\"replica \\\"b\\\"\"} 1",
+            "sqlfortify_bad_queries_total{instance=\"primary\"} 1",
+            "sqlfortify_request_deferrals_total{instance=\"replica \\\"b\\\"\"} 1",
+            "sqlfortify_response_deferrals_total{instance=\"replica \\\"b\\\"\"} 1",
+            "sqlfortify_pattern_nodes{instance=\"primary\"} 42",
+            "sqlfortify_queries_checked_total{instance=\"primary\",path=\"prefix\",action=\"blocked\"} 1",
+            "sqlfortify_queries_checked_total{instance=\"primary\",path=\"prefix\",action=\"allowed\"} 0",
+            "sqlfortify_queries_checked_total{instance=\"replica \\\"b\\\"\",path=\"exact_match\",action=\"flagged\"} 1",
+            "sqlfortify_queries_learned_total{instance=\"primary\",path=\"no_pattern\"} 1",
+        ] {
+            assert!(has_line(&out, line), "missing {}", line);
+        }
+
+        // Every series of each metric is listed once per instance
+        let checked = out
+            .lines()
+            .filter(|l| l.starts_with("sqlfortify_queries_checked_total{"))
+            .count();
+        assert_eq!(checked, 2 * MATCH_PATHS.len() * ACTIONS.len());
+    }
+
+    #[test]
+    fn renders_cumulative_latency_buckets() {
+        let metrics = Arc::new(InstanceMetrics::default());
+        for micros in [5, 20, 20, 2_000, 1_000_000] {
+            metrics.query_checked(
+                MatchPath::NoPattern,
+                Action::Allowed,
+                Duration::from_micros(micros),
+            );
+        }
+
+        let out = render(&[("primary".to_string(), metrics)]);
+        for line in [
+            "# TYPE sqlfortify_check_duration_seconds histogram",
+            "sqlfortify_check_duration_seconds_bucket{instance=\"primary\",le=\"0.00001\"} 1",
+            "sqlfortify_check_duration_seconds_bucket{instance=\"primary\",le=\"0.000025\"} 3",
+            "sqlfortify_check_duration_seconds_bucket{instance=\"primary\",le=\"0.001\"} 3",
+            "sqlfortify_check_duration_seconds_bucket{instance=\"primary\",le=\"0.0025\"} 4",
+            "sqlfortify_check_duration_seconds_bucket{instance=\"primary\",le=\"0.1\"} 4",
+            "sqlfortify_check_duration_seconds_bucket{instance=\"primary\",le=\"+Inf\"} 5",
+            "sqlfortify_check_duration_seconds_sum{instance=\"primary\"} 1.002045",
+            "sqlfortify_check_duration_seconds_count{instance=\"primary\"} 5",
+        ] {
+            assert!(has_line(&out, line), "missing {}", line);
+        }
+    }
+
+    #[test]
+    fn escapes_labels() {
+        assert_eq!(escape_label("a\\b\"c\nd"), "a\\\\b\\\"c\\nd");
+    }
+
+    #[test]
+    fn serves_metrics_over_http() {
+        let metrics = Arc::new(InstanceMetrics::default());
+        metrics.connection_accepted();
+        let instances = [("primary".to_string(), metrics)];
+
+        let response = request("GET /metrics HTTP/1.1", &instances);
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head.contains(&format!("\r\nContent-Length: {}\r\n", body.len())));
+        assert!(has_line(
+            body,
+            "sqlfortify_connections_accepted_total{instance=\"primary\"} 1"
+        ));
+
+        assert!(
+            request("GET /other HTTP/1.1", &instances).starts_with("HTTP/1.1 404 Not Found\r\n")
+        );
+        assert!(request("POST /metrics HTTP/1.1", &instances)
+            .starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+    }
+}
@@ -0,0 +1,372 @@
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{any, error, fmt, fs, io};
+
+use crate::matcher::{BiTrie, TrieSnapshot};
+use crate::sql;
+
+// A snapshot is a text file made up of two lines of JSON: a header that identifies what the
+// snapshot contains, followed by the contents of the trie. The header is read first so that
+// snapshots from other dialects or versions are rejected before their contents are parsed.
+
+/// Identifies a file as a snapshot of learned query patterns
+const FORMAT_NAME: &str = "sqlfortify-patterns";
+
+/// Must be incremented whenever the layout of the snapshot changes in a way that older versions can't read
+const FORMAT_VERSION: u32 = 1;
+
+/// How often learned query patterns are saved if `snapshot_interval` isn't set.
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(300);
+
+#[derive(Clone)]
+pub struct Parameters {
+    /// The file that learned query patterns are loaded from and saved to
+    pub path: PathBuf,
+    /// How often learned query patterns are saved while the proxy runs (if `None`, they are only saved when it stops)
+    pub interval: Option<Duration>,
+    /// The name of the dialect being learned (snapshots of other dialects are rejected)
+    pub dialect: &'static str,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    format: String,
+    version: u32,
+    dialect: String,
+    /// The Rust type of the tokens stored in the trie
+    token_type: String,
+}
+
+#[derive(Debug)]
+pub struct SnapshotError {
+    reason: String,
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", &self.reason)
+    }
+}
+
+impl error::Error for SnapshotError {}
+
+/// Atomically replaces the snapshot at the configured path with the contents of the given trie.
+pub fn save<D: sql::Detector>(trie: &BiTrie<D>, params: &Parameters) -> Result<(), SnapshotError> {
+    let temp_path = sibling_path(&params.path, "tmp");
+
+    match write_snapshot(trie, params, &temp_path) {
+        Ok(()) => (),
+        Err(e) => {
+            let _ = fs::remove_file(&temp_path);
+            return Err(SnapshotError {
+                reason: format!("couldn't write {}: {}", temp_path.display(), e),
+            });
+        }
+    }
+
+    // Renaming is atomic, so the snapshot is either entirely old or entirely new if the process dies partway through
+    match fs::rename(&temp_path, &params.path) {
+        Ok(()) => (),
+        Err(e) => {
+            let _ = fs::remove_file(&temp_path);
+            return Err(SnapshotError {
+                reason: format!(
+                    "couldn't replace {} with {}: {}",
+                    params.path.display(),
+                    temp_path.display(),
+                    e
+                ),
+            });
+        }
+    }
+
+    // Makes the rename itself durable; failing to do so only risks losing the newest snapshot on power loss
+    if let Some(dir) = params.path.parent() {
+        let dir = if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            dir
+        };
+        match fs::File::open(dir).and_then(|d| d.sync_all()) {
+            Ok(()) => (),
+            Err(e) => log::debug!("Couldn't sync directory {}: {}", dir.display(), e),
+        }
+    }
+
+    Ok(())
+}
+
+fn write_snapshot<D: sql::Detector>(
+    trie: &BiTrie<D>,
+    params: &Parameters,
+    temp_path: &Path,
+) -> io::Result<()> {
+    let header = Header {
+        format: FORMAT_NAME.to_string(),
+        version: FORMAT_VERSION,
+        dialect: params.dialect.to_string(),
+        token_type: any::type_name::<D::Token>().to_string(),
+    };
+
+    // Only readable by the proxy's user, as learned queries may include sensitive literals
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(temp_path)?;
+    let mut writer = BufWriter::new(file);
+    serde_json::to_writer(&mut writer, &header)?;
+    writer.write_all(b"\n")?;
+    serde_json::to_writer(&mut writer, &trie.to_snapshot())?;
+    writer.write_all(b"\n")?;
+
+    let file = match writer.into_inner() {
+        Ok(f) => f,
+        Err(e) => return Err(e.into_error()),
+    };
+    file.sync_all()
+}
+
+/// Loads the trie saved at the configured path. If no snapshot has been saved yet, `None` is returned.
+///
+/// Snapshots that are corrupt or were saved for a different dialect or format version are moved
+/// aside (so that they aren't overwritten by the next save) and `None` is returned. An error is
+/// only returned if the snapshot couldn't be read at all.
+pub fn load<D: sql::Detector>(params: &Parameters) -> Result<Option<BiTrie<D>>, SnapshotError> {
+    let file = match fs::File::open(&params.path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            return Err(SnapshotError {
+                reason: format!("couldn't open {}: {}", params.path.display(), e),
+            })
+        }
+    };
+
+    let invalid_reason = match read_snapshot::<D>(BufReader::new(file), params) {
+        Ok(Ok(trie)) => return Ok(Some(trie)),
+        Ok(Err(reason)) => reason,
+        Err(e) => {
+            return Err(SnapshotError {
+                reason: format!("couldn't read {}: {}", params.path.display(), e),
+            })
+        }
+    };
+
+    let secs = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs(),
+        Err(_) => 0,
+    };
+    let rejected_path = sibling_path(&params.path, &format!("rejected-{}", secs));
+
+    match fs::rename(&params.path, &rejected_path) {
+        Ok(()) => {
+            log::warn!(
+                "Ignoring snapshot {} ({})--it was moved to {} and learning will start over",
+                params.path.display(),
+                invalid_reason,
+                rejected_path.display()
+            );
+            Ok(None)
+        }
+        Err(e) => Err(SnapshotError {
+            reason: format!(
+                "snapshot {} is unusable ({}) and couldn't be moved aside: {}",
+                params.path.display(),
+                invalid_reason,
+                e
+            ),
+        }),
+    }
+}
+
+/// Reads a snapshot, returning `Ok(Err(reason))` if its contents are corrupt or incompatible.
+fn read_snapshot<D: sql::Detector>(
+    mut reader: BufReader<fs::File>,
+    params: &Parameters,
+) -> io::Result<Result<BiTrie<D>, String>> {
+    let mut header_line = String::new();
+    match reader.read_line(&mut header_line) {
+        Ok(_) => (),
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+            return Ok(Err("header is not valid UTF-8".to_string()))
+        }
+        Err(e) => return Err(e),
+    }
+
+    let header: Header = match serde_json::from_str(&header_line) {
+        Ok(h) => h,
+        Err(e) => return Ok(Err(format!("header is corrupt: {}", e))),
+    };
+
+    if header.format != FORMAT_NAME {
+        return Ok(Err(format!(
+            "not a snapshot of learned query patterns (format '{}')",
+            header.format
+        )));
+    }
+
+    if header.version != FORMAT_VERSION {
+        return Ok(Err(format!(
+            "saved with snapshot format version {}, but version {} is required",
+            header.version, FORMAT_VERSION
+        )));
+    }
+
+    if header.dialect != params.dialect {
+        return Ok(Err(format!(
+            "saved for db_type '{}', but this proxy uses '{}'",
+            header.dialect, params.dialect
+        )));
+    }
+
+    if header.token_type != any::type_name::<D::Token>() {
+        return Ok(Err(format!(
+            "saved with token type '{}', but this proxy uses '{}'",
+            header.token_type,
+            any::type_name::<D::Token>()
+        )));
+    }
+
+    let contents: TrieSnapshot<D::Token> = match serde_json::from_reader(reader) {
+        Ok(c) => c,
+        Err(e) if e.is_io() => return Err(e.into()),
+        Err(e) => return Ok(Err(format!("contents are corrupt: {}", e))),
+    };
+
+    match BiTrie::from_snapshot(contents) {
+        Ok(trie) => Ok(Ok(trie)),
+        Err(e) => Ok(Err(format!("contents are inconsistent: {}", e))),
+    }
+}
+
+/// Returns the path of a file in the same directory as `path`, named after it with the given extension appended.
+fn sibling_path(path: &Path, extension: &str) -> PathBuf {
+    let mut name = match path.file_name() {
+        Some(n) => n.to_os_string(),
+        None => "patterns".into(),
+    };
+    name.push(".");
+    name.push(extension);
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql::postgres_detector::PostgresDetector;
+    use crate::sql::Token;
+    use std::os::unix::fs::PermissionsExt;
+
+    type PostgresToken = <PostgresDetector as sql::Detector>::Token;
+
+    /// Returns the parameters of a snapshot in a fresh directory of its own.
+    fn test_params(name: &str) -> Parameters {
+        let dir = std::env::temp_dir().join(format!("sqlfortify-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Parameters {
+            path: dir.join("patterns"),
+            interval: None,
+            dialect: "postgresql",
+        }
+    }
+
+    fn learned_trie() -> BiTrie<PostgresDetector> {
+        let mut trie = BiTrie::new();
+        for query in [
+            "SELECT * FROM users WHERE id = 1",
+            "SELECT * FROM users WHERE name = 'a' ORDER BY id",
+            "UPDATE users SET name = 'b' WHERE id = 2",
+        ] {
+            trie.insert(PostgresToken::scan_forward(query));
+        }
+        trie
+    }
+
+    fn contents(trie: &BiTrie<PostgresDetector>) -> String {
+        serde_json::to_string(&trie.to_snapshot()).unwrap()
+    }
+
+    /// Loads the snapshot, asserting that it's rejected and moved aside.
+    fn assert_rejected(params: &Parameters) {
+        assert!(load::<PostgresDetector>(params).unwrap().is_none());
+        assert!(!params.path.exists());
+
+        let dir = params.path.parent().unwrap();
+        let rejected = fs::read_dir(dir)
+            .unwrap()
+            .filter_map(|entry| entry.ok())
+            .any(|entry| {
+                entry
+                    .file_name()
+                    .to_string_lossy()
+                    .starts_with("patterns.rejected-")
+            });
+        assert!(rejected);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn round_trip() {
+        let params = test_params("round-trip");
+        let trie = learned_trie();
+        save(&trie, &params).unwrap();
+
+        let loaded = load::<PostgresDetector>(&params).unwrap().unwrap();
+        assert_eq!(contents(&loaded), contents(&trie));
+        assert_eq!(loaded.node_count(), trie.node_count());
+        assert!(!loaded.is_modified());
+
+        let mode = fs::metadata(&params.path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        fs::remove_dir_all(params.path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn missing_snapshot_is_not_an_error() {
+        let params = test_params("missing");
+        assert!(load::<PostgresDetector>(&params).unwrap().is_none());
+        fs::remove_dir_all(params.path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        let params = test_params("garbage");
+        fs::write(&params.path, b"\xff\xfe not a snapshot\n").unwrap();
+        assert_rejected(&params);
+    }
+
+    #[test]
+    fn rejects_truncated_contents() {
+        let params = test_params("truncated");
+        save(&learned_trie(), &params).unwrap();
+        let saved = fs::read(&params.path).unwrap();
+        fs::write(&params.path, &saved[..saved.len() - 20]).unwrap();
+        assert_rejected(&params);
+    }
+
+    #[test]
+    fn rejects_other_dialects_and_versions() {
+        let params = test_params("other-dialect");
+        let cockroach = Parameters {
+            dialect: "cockroach",
+            ..params.clone()
+        };
+        save(&learned_trie(), &cockroach).unwrap();
+        assert_rejected(&params);
+
+        let params = test_params("other-version");
+        save(&learned_trie(), &params).unwrap();
+        let saved = fs::read_to_string(&params.path).unwrap();
+        let current = format!("\"version\":{}", FORMAT_VERSION);
+        assert!(saved.contains(&current));
+        fs::write(&params.path, saved.replace(&current, "\"version\":0")).unwrap();
+        assert_rejected(&params);
+    }
+}
@@ -1,13 +1,20 @@
 pub mod cockroach_detector;
+pub mod mysql_detector;
+pub mod postgres_detector;
 
 mod cockroach_token;
-//mod postgres_token;
+mod mysql_token;
+mod postgres_token;
 
-use std::fmt::{Debug, Display};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Debug, Display};
 use std::hash::Hash;
+use std::ops::Range;
 
 // Note: this trait is meant to work especially well with Enums
-pub trait Token: Eq + Hash + Clone + Debug + Display {
+// Tokens are serialized (by variant name) when the learned query patterns are saved to a snapshot
+pub trait Token: Eq + Hash + Clone + Debug + Display + Serialize + DeserializeOwned {
     // Trait Eq should evaluate true if two tokens are the same type.
     // Make sure Hash is implemented so that k1 == k2 -> hash(k1) == hash(k2)
     // deep_eq should evaluate true if two tokens have the same contents.
@@ -43,46 +50,482 @@ pub trait Token: Eq + Hash + Clone + Debug + Display {
     // is specific to a given token within the token's file (or else
     // referenced directly by the token's file).
     fn scan_forward(query: &str) -> Vec<(Self, usize)>;
-    // TODO: you NEED to consolidate anything found in comments into a single token.
-    // The reason for this is that comments could appear to be malicious SQL when they are in fact benign.
-    // After all, an attacker would have no reason or benefit to inserting SQL comments containing malicious commands...
+    // Anything found in comments should be consolidated into a single token, so that SQL that
+    // has merely been commented out isn't mistaken for malicious input. The exception is any
+    // comment that the database executes as code (such as MySQL's `/*! ... */`)--attackers
+    // routinely hide payloads in these, so their contents must be scanned into tokens like any other SQL.
 
     fn scan_reverse(query: &str) -> Vec<(Self, usize)>;
+
+    /// Returns the byte range of the query spanned by each token, indexed by the token indices that
+    /// `scan_forward()` and `scan_reverse()` return.
+    fn token_offsets(query: &str) -> Vec<Range<usize>>;
 }
 
 pub trait Detector {
     type Token: Token;
 
-    fn is_malicious_query<'a, I: std::iter::DoubleEndedIterator<Item = &'a Self::Token> + Clone>(
+    /// Checks the tokens of a query (each paired with its index from `Token::scan_forward()`) against the
+    /// detection rules enabled in `params`, returning the first rule matched.
+    fn detect_injection<
+        'a,
+        I: std::iter::DoubleEndedIterator<Item = &'a (Self::Token, usize)> + Clone,
+    >(
         query_iter: I,
         params: &Parameters,
-    ) -> bool
+    ) -> Option<Detection>
     where
         Self::Token: 'a;
 }
 
+/// A rule that identifies a query as SQL injection.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Rule {
+    LineComment,
+    BlockComment,
+    ExecutableComment,
+    TimeDelay,
+    FileAccess,
+    /// Running programs or loading native code on the database server (e.g. `COPY ... TO PROGRAM` for PostgreSQL)
+    CommandExecution,
+    /// Connecting from the database server to other servers (e.g. `dblink()` for PostgreSQL)
+    NetworkAccess,
+    MetadataAccess,
+    /// More than one statement in a single query
+    StackedQuery,
+    Tautology,
+    /// A UNION appended to the query to return the results of the attacker's own SELECT
+    UnionQuery,
+    /// `ORDER BY <n>`, used to find out how many columns a query returns before appending a UNION to it
+    ColumnEnumeration,
+    /// The query extends a learned pattern whose parameter was previously found to be vulnerable (this is
+    /// found by the validator rather than a detector)
+    VulnerablePrefix,
+}
+
+impl Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            Rule::LineComment => "line comment",
+            Rule::BlockComment => "block comment",
+            Rule::ExecutableComment => "executable comment",
+            Rule::TimeDelay => "time delay function",
+            Rule::FileAccess => "file access",
+            Rule::CommandExecution => "command execution",
+            Rule::NetworkAccess => "network access",
+            Rule::MetadataAccess => "metadata access",
+            Rule::StackedQuery => "stacked query",
+            Rule::Tautology => "tautology",
+            Rule::UnionQuery => "UNION query",
+            Rule::ColumnEnumeration => "column enumeration",
+            Rule::VulnerablePrefix => "vulnerable parameter",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+/// A detection rule matched by a query.
+#[derive(Clone, Copy, Debug)]
+pub struct Detection {
+    pub rule: Rule,
+    /// The index of the token that triggered the rule
+    pub token_index: usize,
+}
+
+/// Strips the indices from an iterator over indexed tokens, for checks that only need the tokens themselves.
+fn without_indices<'a, T: 'a, I>(
+    iter: I,
+) -> impl std::iter::DoubleEndedIterator<Item = &'a T> + Clone
+where
+    I: std::iter::DoubleEndedIterator<Item = &'a (T, usize)> + Clone,
+{
+    iter.map(|(token, _)| token)
+}
+
+/// Whether a name, split into the parts that qualify it (e.g. `["PG_CATALOG", "PG_USER"]`), refers to any of the
+/// given schemas or relations. Each of these may be qualified with a `.` (e.g. `mysql.user`), in which case the name
+/// must contain every part of it in sequence. Names are compared case-insensitively.
+fn references_relation<S: AsRef<str>>(relations: &[S], name: &[String]) -> bool {
+    relations.iter().any(|relation| {
+        let relation: Vec<&str> = relation.as_ref().split('.').collect();
+        name.windows(relation.len()).any(|window| {
+            window
+                .iter()
+                .zip(relation.iter())
+                .all(|(part, expected)| part.eq_ignore_ascii_case(expected))
+        })
+    })
+}
+
+/// Whether the name of a called function (split into the parts that qualify it, as for `references_relation()`) is one
+/// of the given functions, whichever schema it is qualified with. Names are compared case-insensitively.
+fn is_function_named(name: &[String], functions: &[&str]) -> bool {
+    match name.last() {
+        Some(function) => functions
+            .iter()
+            .any(|expected| function.eq_ignore_ascii_case(expected)),
+        None => false,
+    }
+}
+
+/// The keywords that the checks shared between dialects look for. Each dialect's tokens map their own keywords onto
+/// these (see `DialectToken::class()`), and any other keyword onto `Other`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum CommonKeyword {
+    All,
+    And,
+    By,
+    Distinct,
+    Except,
+    False,
+    For,
+    From,
+    Group,
+    Having,
+    Intersect,
+    Limit,
+    Not,
+    Null,
+    Offset,
+    Or,
+    Order,
+    Returning,
+    Select,
+    True,
+    Union,
+    Where,
+    Window,
+    Other,
+}
+
+/// What a token is, as far as the checks shared between dialects are concerned.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum TokenClass<'t> {
+    /// Whitespace, comments and the delimiters of comments whose contents aren't executed
+    Insignificant,
+    Keyword(CommonKeyword),
+    Identifier,
+    /// A number, or the contents of a quoted string (or of a quoted name, for dialects that scan them that way)
+    Constant(&'t str),
+    /// A quote that opens a string, which is closed by the next token equal to it
+    StringQuote,
+    /// A prefix that opens a literal other than a plain string (e.g. `X'..'`)
+    LiteralPrefix,
+    /// A quote around a name, which isn't part of the name itself
+    NameQuote,
+    /// A single-character operator or punctuation
+    Symbol(char),
+    /// An operator made up of more than one character (for dialects that scan them as one token)
+    Operator(&'t str),
+    Other,
+}
+
+/// A dialect's tokens, as classified for the checks shared between detectors.
+trait DialectToken: Token {
+    fn class(&self) -> TokenClass<'_>;
+
+    /// Whether the token makes a predicate that otherwise only involves constants vary from one query to the next, such
+    /// as reading the current time (as in `AND now() > '2020-01-01'`) or testing a subquery (`AND EXISTS (SELECT 1)`).
+    fn is_variable(&self) -> bool;
+
+    /// Returns `true` for tokens other than whitespace and comments.
+    fn is_significant(&self) -> bool {
+        self.class() != TokenClass::Insignificant
+    }
+}
+
+/// Returns the next token that isn't whitespace or a comment.
+fn next_significant_token<'a, T: DialectToken + 'a, I: Iterator<Item = &'a T>>(
+    mut iter: I,
+) -> Option<&'a T> {
+    iter.find(|token| token.is_significant())
+}
+
+/// Returns the name beginning with the token that was just consumed from `iter`, split into the parts that qualify
+/// it (e.g. `pg_catalog.pg_user`). Quoted parts are included as written.
+fn qualified_name<'a, T: DialectToken + 'a, I: Iterator<Item = &'a T>>(
+    first: &'a T,
+    iter: I,
+) -> Vec<String> {
+    let mut iter = iter.filter(|token| token.is_significant());
+    let mut name = match name_part(first, &mut iter) {
+        Some(part) => vec![part],
+        None => return vec![],
+    };
+    while let Some(TokenClass::Symbol('.')) = next_after_name(&mut iter).map(DialectToken::class) {
+        match iter.next().and_then(|token| name_part(token, &mut iter)) {
+            Some(part) => name.push(part),
+            None => break,
+        }
+    }
+    name
+}
+
+/// Returns the text of an identifier or keyword that forms (part of) a name. If `token` opens a quoted name, the rest
+/// of it is consumed from `iter`.
+fn name_part<'a, T: DialectToken + 'a, I: Iterator<Item = &'a T>>(
+    token: &'a T,
+    iter: &mut I,
+) -> Option<String> {
+    match token.class() {
+        TokenClass::Identifier | TokenClass::Keyword(_) => Some(token.to_string()),
+        TokenClass::NameQuote => {
+            let (name, close) = (iter.next()?, iter.next()?);
+            match (name.class(), close.class()) {
+                (
+                    TokenClass::Identifier | TokenClass::Keyword(_) | TokenClass::Constant(_),
+                    TokenClass::NameQuote,
+                ) => Some(name.to_string()),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Returns the token that follows a name part, past the quote that closes it if `iter` began within a quoted name.
+fn next_after_name<'a, T: DialectToken + 'a, I: Iterator<Item = &'a T>>(
+    iter: &mut I,
+) -> Option<&'a T> {
+    iter.find(|token| token.class() != TokenClass::NameQuote)
+}
+
+/// Returns the name of the function called by the tokens beginning with the one that was just consumed from `iter`,
+/// split into the parts that qualify it (e.g. `pg_catalog."pg_sleep"(5)`), or `None` if they don't call a function.
+fn called_function<'a, T: DialectToken + 'a, I: Iterator<Item = &'a T>>(
+    first: &'a T,
+    iter: I,
+) -> Option<Vec<String>> {
+    let mut iter = iter.filter(|token| token.is_significant());
+    let mut name = vec![name_part(first, &mut iter)?];
+    loop {
+        match next_after_name(&mut iter)?.class() {
+            TokenClass::Symbol('.') => {
+                let token = iter.next()?;
+                name.push(name_part(token, &mut iter)?)
+            }
+            TokenClass::Symbol('(') => return Some(name),
+            _ => return None,
+        }
+    }
+}
+
+/// Whether the `UNION` that was just consumed from `iter` is followed by a SELECT that returns nothing but constants
+/// (such as `UNION ALL SELECT NULL,NULL,NULL`), as used to find out how many columns the original query returns.
+fn is_union_probe<'a, T: DialectToken + 'a, I: Iterator<Item = &'a T>>(iter: I) -> bool {
+    let mut iter = iter
+        .map(DialectToken::class)
+        .filter(|class| *class != TokenClass::Insignificant)
+        .peekable();
+    if let Some(TokenClass::Keyword(CommonKeyword::All | CommonKeyword::Distinct)) = iter.peek() {
+        iter.next();
+    }
+    if iter.next() != Some(TokenClass::Keyword(CommonKeyword::Select)) {
+        return false;
+    }
+
+    let mut has_constant = false;
+    for class in iter {
+        match class {
+            TokenClass::Keyword(
+                CommonKeyword::From
+                | CommonKeyword::Where
+                | CommonKeyword::Order
+                | CommonKeyword::Limit
+                | CommonKeyword::Union
+                | CommonKeyword::Intersect
+                | CommonKeyword::Except,
+            )
+            | TokenClass::Symbol(';') => break,
+            TokenClass::Constant(_)
+            | TokenClass::Keyword(CommonKeyword::Null)
+            | TokenClass::StringQuote
+            | TokenClass::LiteralPrefix => has_constant = true,
+            TokenClass::Symbol(',' | '(' | ')' | '-' | '+') => (),
+            _ => return false, // Selects a column or calls a function, so it's after more than the column count
+        }
+    }
+    has_constant
+}
+
+/// Whether the `ORDER` that was just consumed from `iter` sorts by a column's position (e.g. `ORDER BY 3`), which
+/// attackers increment until the query fails in order to find out how many columns it returns.
+fn is_order_by_position<'a, T: DialectToken + 'a, I: Iterator<Item = &'a T>>(iter: I) -> bool {
+    let mut iter = iter
+        .map(DialectToken::class)
+        .filter(|class| *class != TokenClass::Insignificant);
+    match (iter.next(), iter.next(), iter.next()) {
+        (
+            Some(TokenClass::Keyword(CommonKeyword::By)),
+            Some(TokenClass::Constant(position)),
+            next,
+        ) if position.bytes().all(|b| b.is_ascii_digit()) => {
+            // Anything else following the number would make it part of an expression
+            matches!(
+                next,
+                None | Some(
+                    TokenClass::Symbol(',' | ';' | ')')
+                        | TokenClass::Keyword(_)
+                        | TokenClass::Identifier
+                )
+            )
+        }
+        _ => false,
+    }
+}
+
+/// Whether the `WHERE`, `OR` or `AND` that was just consumed from `iter` is followed by one of the constant predicates
+/// that SQL injection tools commonly use (e.g. `OR 1=1`, `AND 'a'='b'` or `OR NOT false`), which are always true or
+/// always false.
+fn is_common_tautology<'a, T: DialectToken + 'a, I: Iterator<Item = &'a T>>(iter: I) -> bool {
+    let mut iter = iter
+        .filter(|token| token.is_significant())
+        .skip_while(|token| {
+            matches!(
+                token.class(),
+                TokenClass::Symbol('(' | '!') | TokenClass::Keyword(CommonKeyword::Not)
+            )
+        });
+    if !consume_literal(&mut iter) {
+        return false;
+    }
+
+    match iter.next() {
+        // A literal on its own, such as `OR true` or `AND 0`
+        token if ends_predicate(token) => true,
+        token => match token.map(DialectToken::class) {
+            // Operators such as `!=`, `<>` and `<=>` are scanned as one symbol per character by some dialects
+            Some(TokenClass::Symbol('=' | '<' | '>' | '!')) => {
+                let mut iter =
+                    iter.skip_while(|token| matches!(token.class(), TokenClass::Symbol('=' | '>')));
+                consume_literal(&mut iter)
+            }
+            Some(TokenClass::Operator("!=" | "<>" | "<=" | ">=")) => consume_literal(&mut iter),
+            _ => false,
+        },
+    }
+}
+
+/// Whether the `WHERE` that was just consumed from `iter` is followed by nothing but `true` or `1=1`, which query
+/// builders often begin with so that every condition can be appended with `AND`.
+fn is_where_true<'a, T: DialectToken + 'a, I: Iterator<Item = &'a T>>(iter: I) -> bool {
+    let mut iter = iter.filter(|token| token.is_significant());
+    let is_true = match iter.next().map(DialectToken::class) {
+        Some(TokenClass::Keyword(CommonKeyword::True)) => true,
+        Some(TokenClass::Constant("1")) => {
+            iter.next().map(DialectToken::class) == Some(TokenClass::Symbol('='))
+                && iter.next().map(DialectToken::class) == Some(TokenClass::Constant("1"))
+        }
+        _ => false,
+    };
+    is_true && ends_predicate(iter.next())
+}
+
+/// Whether the token (or the end of the query) ends a predicate, so that a literal followed by it stands on its own (as
+/// in `OR 1 ORDER BY 2`). Other keywords continue it (as in `AND 'a' LIKE col`).
+fn ends_predicate<T: DialectToken>(token: Option<&T>) -> bool {
+    matches!(
+        token.map(DialectToken::class),
+        None | Some(
+            TokenClass::Symbol(')' | ';')
+                | TokenClass::Keyword(
+                    CommonKeyword::And
+                        | CommonKeyword::Or
+                        | CommonKeyword::Order
+                        | CommonKeyword::Group
+                        | CommonKeyword::Having
+                        | CommonKeyword::Limit
+                        | CommonKeyword::Offset
+                        | CommonKeyword::Union
+                        | CommonKeyword::Intersect
+                        | CommonKeyword::Except
+                        | CommonKeyword::Window
+                        | CommonKeyword::For
+                        | CommonKeyword::Returning
+                )
+        )
+    )
+}
+
+/// Consumes a literal (a number, string or boolean) from `iter`, returning whether there was one.
+fn consume_literal<'a, T: DialectToken + 'a, I: Iterator<Item = &'a T>>(iter: &mut I) -> bool {
+    match iter.next() {
+        Some(token) => match token.class() {
+            TokenClass::Constant(_)
+            | TokenClass::Keyword(CommonKeyword::True | CommonKeyword::False) => true,
+            TokenClass::StringQuote => iter.any(|other| other == token),
+            _ => false,
+        },
+        None => false,
+    }
+}
+
+/// The number of rows that a set-returning function given only constants (e.g. `generate_series(1, 10000000)`) must
+/// produce to be counted by `disallow_heavy_queries`. Attackers use such queries to delay responses when the dialect's
+/// delay functions are blocked.
+const HEAVY_QUERY_ROWS: f64 = 1_000_000.0;
+
+#[derive(Clone)]
 pub struct Parameters {
     /// Any detected block comments (commonly `/*` followed by `*/`)
     pub disallow_block_comments: bool,
     /// Any detected line comments (commonly `--`)
     pub disallow_line_comments: bool,
-    /// Any detected commands that intentionally pause the query (e.g. pg_sleep(time) for PostgreSQL)
+    /// Any detected comments whose contents are executed by the database (e.g. `/*! ... */` or `/*!50000 ... */` for MySQL)
+    pub disallow_executable_comments: bool,
+    /// Any detected commands that intentionally pause the query (e.g. pg_sleep(time) for PostgreSQL), according to the
+    /// detector's list of delay functions for its dialect, along with `WAITFOR DELAY` (which only SQL Server supports)
     pub disallow_time_delays: bool,
+    /// Any detected subqueries that take a long time despite only being given constants (e.g. `generate_series(1, 1e8)`),
+    /// which are detected as time delays
+    pub disallow_heavy_queries: bool,
+    /// Any detected commands that read or write files on the database server (e.g. LOAD_FILE() or SELECT ... INTO OUTFILE for MySQL)
+    pub disallow_file_access: bool,
+    /// Any detected commands that run programs or load native code on the database server (e.g. COPY ... TO PROGRAM or
+    /// CREATE EXTENSION for PostgreSQL)
+    pub disallow_command_execution: bool,
+    /// Any detected commands that connect from the database server to other servers (e.g. dblink() for PostgreSQL)
+    pub disallow_network_access: bool,
+    /// Any detected access to tables that describe the database's structure (e.g. information_schema)
+    pub disallow_metadata_access: bool,
+    /// The schemas and relations counted as metadata by `disallow_metadata_access`, each optionally qualified
+    /// (e.g. `information_schema` or `mysql.user`); if `None`, the detector's own list for its dialect is used
+    pub metadata_relations: Option<Vec<String>>,
     /// Queries containing more than one SQL statement, i.e. those broken up by one or more semicolons
     pub multi_queries: MultipleQueries,
-    /// Queries containing statements that always evaluate to true
+    /// Queries containing statements that always evaluate to true (or always to false)
     pub tautologies: Tautologies,
+    /// Queries that combine their results with those of another SELECT (or that probe for how to do so)
+    pub unions: Unions,
 }
 
 impl Parameters {
+    /// Whether the given name (split into parts, as for `references_relation()`) refers to metadata, according to
+    /// `metadata_relations` if set, or otherwise the relations given for the detector's dialect.
+    fn is_metadata_relation(&self, name: &[String], dialect_relations: &[&str]) -> bool {
+        match self.metadata_relations.as_ref() {
+            Some(relations) => references_relation(relations, name),
+            None => references_relation(dialect_relations, name),
+        }
+    }
+
     /// Default SQLI detection parameters for queries that matched prefix & suffix
     pub fn default_prefix_suffix() -> Self {
         Parameters {
             disallow_line_comments: true,
             disallow_block_comments: true,
+            disallow_executable_comments: true,
             disallow_time_delays: true,
+            disallow_heavy_queries: false,
+            disallow_file_access: true,
+            disallow_command_execution: true,
+            disallow_network_access: true,
+            disallow_metadata_access: true,
+            metadata_relations: None,
             multi_queries: MultipleQueries::DisallowAll, // TODO: should we set this to DisallowOnOtherIndications?
             tautologies: Tautologies::DisallowAll,
+            unions: Unions::DisallowAll,
         }
     }
 
@@ -91,9 +534,17 @@ impl Parameters {
         Parameters {
             disallow_line_comments: false, // The threat model here is that the attacker is using a null byte injection, which replaces the use of a line comment...
             disallow_block_comments: true,
+            disallow_executable_comments: true,
             disallow_time_delays: true,
+            disallow_heavy_queries: false,
+            disallow_file_access: true,
+            disallow_command_execution: true,
+            disallow_network_access: true,
+            disallow_metadata_access: true,
+            metadata_relations: None,
             multi_queries: MultipleQueries::DisallowCommit,
             tautologies: Tautologies::DisallowCommon,
+            unions: Unions::DisallowProbing,
         }
     }
 
@@ -102,14 +553,24 @@ impl Parameters {
         Parameters {
             disallow_line_comments: false,
             disallow_block_comments: false,
+            disallow_executable_comments: false,
             disallow_time_delays: false,
+            disallow_heavy_queries: false,
+            disallow_file_access: false,
+            disallow_command_execution: false,
+            disallow_network_access: false,
+            disallow_metadata_access: false,
+            metadata_relations: None,
             multi_queries: MultipleQueries::AllowAll,
             tautologies: Tautologies::AllowAll,
+            unions: Unions::AllowAll,
         }
     }
 }
 
 #[allow(dead_code)]
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum MultipleQueries {
     /// Any instance of multiple SQL queries in one request (i.e. semicolons) are considered malicious
     DisallowAll,
@@ -124,16 +585,242 @@ pub enum MultipleQueries {
     AllowAll,
 }
 
-// TODO: update code to check for cases in addition to just following `OR`
 #[allow(dead_code)]
-#[derive(PartialEq, Eq)]
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Tautologies {
-    /// Any detected instance of 'OR' followed by a tautology (a statement that always evaluates to `true`) is considered malicious
+    /// Any detected predicate that only depends on constants is considered malicious, whether it follows `WHERE`, `OR`
+    /// or `AND`: tautologies (statements that always evaluate to `true`, like `OR 1=1`) as well as contradictions
+    /// (statements that always evaluate to `false`, like `AND 1=2`, which boolean-blind injection compares against)
     DisallowAll,
-    /// All tautologies are considered except for instances of `... WHERE true...`
+    /// All tautologies are considered except for `... WHERE true ...` and `... WHERE 1=1 ...` written just so, which query
+    /// builders often begin with so that every condition can be appended with `AND`
     AllowWhereTrue,
-    /// Searches for commonly-used tautologies, such as `OR true`, `OR '1'='1'`, etc. Most of these come from well-used tools like SQLMap
+    /// Searches for commonly-used tautologies and contradictions following `OR` or `AND`, such as `OR true`, `OR '1'='1'`,
+    /// `AND 1=2`, etc. Most of these come from well-used tools like SQLMap
     DisallowCommon,
     /// Doesn't consider tautologies to be malicious
     AllowAll,
 }
+
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Unions {
+    /// Any UNION (including UNION ALL) is considered malicious, as is any `ORDER BY <n>` (which attackers increment to
+    /// find out how many columns a query returns)
+    DisallowAll,
+    /// Only UNIONs whose SELECT returns nothing but constants are considered malicious (e.g. `UNION SELECT NULL,NULL`,
+    /// which SQLMap uses to find out how many columns a query returns). `ORDER BY <n>` is allowed, as applications
+    /// commonly sort by position
+    DisallowProbing,
+    /// Doesn't consider UNIONs to be malicious
+    AllowAll,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::cockroach_detector::CockroachDetector;
+    use super::mysql_detector::MysqlDetector;
+    use super::postgres_detector::PostgresDetector;
+    use super::*;
+
+    fn detect<D: Detector>(query: &str, params: &Parameters) -> Option<Rule> {
+        let tokens = D::Token::scan_forward(query);
+        D::detect_injection(tokens.iter(), params).map(|detection| detection.rule)
+    }
+
+    /// Asserts that the detector for each dialect finds `expected` in the query.
+    fn assert_detects(query: &str, params: &Parameters, expected: Option<Rule>) {
+        assert_eq!(
+            detect::<PostgresDetector>(query, params),
+            expected,
+            "PostgreSQL: {query}"
+        );
+        assert_eq!(
+            detect::<MysqlDetector>(query, params),
+            expected,
+            "MySQL: {query}"
+        );
+        assert_eq!(
+            detect::<CockroachDetector>(query, params),
+            expected,
+            "CockroachDB: {query}"
+        );
+    }
+
+    #[test]
+    fn order_by_position_is_only_disallowed_with_all_unions() {
+        let query = "SELECT a, b FROM t ORDER BY 1, 2";
+        assert_detects(query, &Parameters::default_prefix(), None);
+        assert_detects(
+            query,
+            &Parameters::default_prefix_suffix(),
+            Some(Rule::ColumnEnumeration),
+        );
+        assert_detects(
+            "SELECT a FROM t ORDER BY 3",
+            &Parameters::default_prefix_suffix(),
+            Some(Rule::ColumnEnumeration),
+        );
+        assert_detects(
+            "SELECT a FROM t ORDER BY a",
+            &Parameters::default_prefix_suffix(),
+            None,
+        );
+    }
+
+    #[test]
+    fn spaced_metadata_names() {
+        let params = Parameters::default_prefix_suffix();
+        for query in [
+            "SELECT * FROM information_schema.tables",
+            "SELECT * FROM information_schema . tables",
+            "SELECT * FROM INFORMATION_SCHEMA.COLUMNS",
+        ] {
+            assert_detects(query, &params, Some(Rule::MetadataAccess));
+        }
+        for query in [
+            "SELECT * FROM users WHERE name = 'information_schema.tables'",
+            "SELECT * FROM information_schemas",
+        ] {
+            assert_detects(query, &params, None);
+        }
+    }
+
+    #[test]
+    fn waitfor_delay() {
+        let params = Parameters::default_prefix();
+        assert_detects(
+            "SELECT 1 WAITFOR DELAY '0:0:5'",
+            &params,
+            Some(Rule::TimeDelay),
+        );
+        assert_detects("SELECT waitfor, delay FROM t", &params, None);
+    }
+
+    #[test]
+    fn constant_predicates_end_at_predicate_boundaries() {
+        for tautologies in [
+            Tautologies::DisallowAll,
+            Tautologies::AllowWhereTrue,
+            Tautologies::DisallowCommon,
+        ] {
+            let params = Parameters {
+                tautologies,
+                ..Parameters::default_nopattern()
+            };
+            for query in [
+                "SELECT * FROM t WHERE x = 1 OR 1=1",
+                "SELECT * FROM t WHERE x = 1 OR 'a'='a' -- ",
+                "SELECT * FROM t WHERE x = 1 AND 1=2 ORDER BY x",
+                "SELECT * FROM t WHERE x = 1 OR true LIMIT 1",
+                "SELECT * FROM t WHERE (x = 1 OR 1) AND y = 2",
+                "SELECT * FROM t WHERE x = 1 OR NOT false UNION SELECT a FROM u",
+                "SELECT * FROM t WHERE x = 1 OR 1<>2",
+            ] {
+                assert_detects(query, &params, Some(Rule::Tautology));
+            }
+            for query in [
+                "SELECT * FROM t WHERE x = 1 AND 'a' LIKE col",
+                "SELECT * FROM t WHERE x = 1 AND 1 IN (SELECT y FROM u)",
+                "SELECT * FROM t WHERE x = 1 AND now() > '2020-01-01'",
+                "SELECT * FROM t WHERE x = 1 AND CURRENT_DATE < '2030-01-01'",
+                "SELECT * FROM t WHERE x = 1 AND EXISTS (SELECT 1)",
+                "SELECT * FROM t WHERE x BETWEEN 1 AND 5",
+                "SELECT * FROM t WHERE x = 1 AND 5 < col",
+            ] {
+                assert_detects(query, &params, None);
+            }
+        }
+    }
+
+    #[test]
+    fn constant_predicates_per_mode() {
+        let tautology = Some(Rule::Tautology);
+        // Detected under `DisallowAll`, `AllowWhereTrue`, `DisallowCommon` and `AllowAll` respectively
+        for (query, expected) in [
+            ("SELECT * FROM t WHERE true", [tautology, None, None, None]),
+            (
+                "SELECT * FROM t WHERE 1=1 AND x = 1",
+                [tautology, None, None, None],
+            ),
+            (
+                "SELECT * FROM t WHERE 'a'='a'",
+                [tautology, tautology, None, None],
+            ),
+            (
+                "SELECT * FROM t WHERE 2>1",
+                [tautology, tautology, None, None],
+            ),
+            (
+                "SELECT * FROM t WHERE 1=1 AND 1=2",
+                [tautology, tautology, tautology, None],
+            ),
+            (
+                "SELECT * FROM t WHERE x = 1 AND SUBSTRING(version(),1,1)='P'",
+                [tautology, tautology, None, None],
+            ),
+            ("SELECT * FROM t WHERE x = 1", [None, None, None, None]),
+        ] {
+            for (tautologies, expected) in [
+                Tautologies::DisallowAll,
+                Tautologies::AllowWhereTrue,
+                Tautologies::DisallowCommon,
+                Tautologies::AllowAll,
+            ]
+            .into_iter()
+            .zip(expected)
+            {
+                let params = Parameters {
+                    tautologies,
+                    ..Parameters::default_nopattern()
+                };
+                assert_detects(query, &params, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn stacked_queries_and_comments() {
+        let params = Parameters::default_prefix_suffix();
+        for (query, rule) in [
+            (
+                "SELECT * FROM t WHERE id = 1; DROP TABLE t",
+                Rule::StackedQuery,
+            ),
+            ("SELECT * FROM t WHERE id = 1 -- ", Rule::LineComment),
+            ("SELECT * FROM t WHERE id = 1 /* */", Rule::BlockComment),
+        ] {
+            assert_detects(query, &params, Some(rule));
+        }
+        for query in [
+            "SELECT * FROM t WHERE id = 1",
+            "SELECT '-- a', '/* b */', 'c; d' FROM t",
+        ] {
+            assert_detects(query, &params, None);
+        }
+    }
+
+    #[test]
+    fn unions_are_disallowed_or_only_probes() {
+        assert_detects(
+            "SELECT a FROM t UNION SELECT b FROM u",
+            &Parameters::default_prefix_suffix(),
+            Some(Rule::UnionQuery),
+        );
+
+        let params = Parameters::default_prefix();
+        for query in [
+            "SELECT a FROM t WHERE id = 1 UNION ALL SELECT NULL, NULL",
+            "SELECT a FROM t WHERE id = 1 UNION SELECT 1, 'a', -2",
+        ] {
+            assert_detects(query, &params, Some(Rule::UnionQuery));
+        }
+        for query in [
+            "SELECT a FROM t UNION SELECT b FROM u",
+            "SELECT a FROM t UNION SELECT 1, b FROM u",
+        ] {
+            assert_detects(query, &params, None);
+        }
+    }
+}
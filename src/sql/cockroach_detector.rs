@@ -1,64 +1,300 @@
 use super::cockroach_token::*;
 use crate::sql::*;
 
+/// The schemas and relations that describe the cluster's structure, users or internal state, which
+/// `disallow_metadata_access` detects references to (unless `metadata_relations` is configured).
+pub const METADATA_RELATIONS: &[&str] = &[
+    "information_schema",
+    "pg_catalog",
+    "crdb_internal",
+    "system.users",
+    "system.role_members",
+    "system.namespace",
+    "system.descriptor",
+    "pg_shadow",
+    "pg_user",
+    "pg_roles",
+    "pg_tables",
+    "pg_class",
+    "pg_namespace",
+    "pg_attribute",
+    "pg_database",
+];
+
+/// The functions that pause a query, which `disallow_time_delays` detects calls to (whichever schema they're
+/// qualified with).
+pub const TIME_DELAY_FUNCTIONS: &[&str] = &["pg_sleep"];
+
 pub struct CockroachDetector {}
 
 impl Detector for CockroachDetector {
     type Token = CockroachToken;
 
-    fn is_malicious_query<
+    fn detect_injection<
         'a,
-        I: std::iter::DoubleEndedIterator<Item = &'a CockroachToken> + Clone,
+        I: std::iter::DoubleEndedIterator<Item = &'a (CockroachToken, usize)> + Clone,
     >(
         mut query_iter: I,
         params: &Parameters,
-    ) -> bool
+    ) -> Option<Detection>
     where
         Self: 'a,
     {
-        let test_iter: Vec<&CockroachToken> = query_iter.clone().collect();
-        log::info!(
-            "tokens being checked for is_malicious_query: {:?}",
-            test_iter
-        );
+        let test_iter: Vec<&CockroachToken> = without_indices(query_iter.clone()).collect();
+        log::info!("tokens being checked for detect_injection: {:?}", test_iter);
+
+        let mut in_between = false;
+        while let Some((token, idx)) = query_iter.next() {
+            let detected = |rule| {
+                Some(Detection {
+                    rule,
+                    token_index: *idx,
+                })
+            };
 
-        while let Some(token) = query_iter.next() {
             match token {
                 CockroachToken::Symbol(';') => match params.multi_queries {
-                    MultipleQueries::DisallowAll => return true,
-                    MultipleQueries::DisallowCommit => return true,
-                    MultipleQueries::DisallowOnOtherIndications => return true,
+                    MultipleQueries::DisallowAll => return detected(Rule::StackedQuery),
+                    MultipleQueries::DisallowCommit => return detected(Rule::StackedQuery),
+                    MultipleQueries::DisallowOnOtherIndications => {
+                        return detected(Rule::StackedQuery)
+                    }
                     MultipleQueries::AllowAll => (),
                 },
-                CockroachToken::LineComment if params.disallow_line_comments => return true,
-                CockroachToken::BlockCommentOpen if params.disallow_block_comments => return true,
-                CockroachToken::Identifier(i) => {
-                    if i == "PG_SLEEP" && params.disallow_time_delays {
-                        return true;
-                    }
-                    // Block metadata tables here
-                    // Block file/socket/exec functions here?
+                CockroachToken::LineComment if params.disallow_line_comments => {
+                    return detected(Rule::LineComment)
+                }
+                CockroachToken::BlockCommentOpen if params.disallow_block_comments => {
+                    return detected(Rule::BlockComment)
                 }
-                CockroachToken::Keyword(Keyword::Or)
-                    if params.tautologies != Tautologies::AllowAll =>
+                // Checked before metadata access, so that calls qualified with their schema (e.g. `pg_catalog.pg_sleep(5)`)
+                // are reported as what they do
+                CockroachToken::Identifier(_)
+                | CockroachToken::Keyword(_)
+                | CockroachToken::DoubleQuote
+                    if params.disallow_time_delays
+                        && matches!(
+                            called_function(token, without_indices(query_iter.clone())),
+                            Some(name) if is_function_named(&name, TIME_DELAY_FUNCTIONS)
+                        ) =>
                 {
-                    if is_tautology(query_iter.clone()) {
-                        match params.tautologies {
-                            Tautologies::DisallowAll => return true,
-                            Tautologies::AllowWhereTrue => return true,
-                            Tautologies::DisallowCommon => return true, // TODO: need to fix
-                            Tautologies::AllowAll => (),
+                    return detected(Rule::TimeDelay)
+                }
+                // `WAITFOR DELAY '0:0:5'` only pauses SQL Server queries, but injection tools send it to whatever database
+                // they're probing
+                CockroachToken::Identifier(i)
+                    if params.disallow_time_delays
+                        && i.eq_ignore_ascii_case("WAITFOR")
+                        && is_waitfor_delay(without_indices(query_iter.clone())) =>
+                {
+                    return detected(Rule::TimeDelay)
+                }
+                CockroachToken::Identifier(i)
+                    if params.disallow_heavy_queries
+                        && i.eq_ignore_ascii_case("GENERATE_SERIES")
+                        && is_heavy_series(without_indices(query_iter.clone())) =>
+                {
+                    return detected(Rule::TimeDelay)
+                }
+                // Internal functions can crash nodes, rewrite descriptors and change the cluster's state (also checked
+                // before metadata access, as the schema they belong to is metadata too)
+                CockroachToken::Identifier(_) | CockroachToken::DoubleQuote
+                    if params.disallow_command_execution
+                        && matches!(
+                            called_function(token, without_indices(query_iter.clone())),
+                            Some(name) if name.len() > 1 && name[0].eq_ignore_ascii_case("CRDB_INTERNAL")
+                        ) =>
+                {
+                    return detected(Rule::CommandExecution)
+                }
+                CockroachToken::Identifier(_)
+                | CockroachToken::Keyword(_)
+                | CockroachToken::DoubleQuote
+                    if params.disallow_metadata_access
+                        && params.is_metadata_relation(
+                            &qualified_name(token, without_indices(query_iter.clone())),
+                            METADATA_RELATIONS,
+                        ) =>
+                {
+                    return detected(Rule::MetadataAccess)
+                }
+                // `CREATE EXTENSION` enables optional features of the cluster
+                CockroachToken::Keyword(Keyword::Create)
+                    if params.disallow_command_execution
+                        && matches!(
+                            next_significant_token(without_indices(query_iter.clone())),
+                            Some(CockroachToken::Keyword(Keyword::Extension))
+                        ) =>
+                {
+                    return detected(Rule::CommandExecution)
+                }
+                // The AND in `BETWEEN x AND y` separates the range's bounds rather than predicates
+                CockroachToken::Keyword(Keyword::Between) => in_between = true,
+                CockroachToken::Keyword(Keyword::And) if in_between => in_between = false,
+                CockroachToken::Keyword(kw @ (Keyword::Or | Keyword::And | Keyword::Where)) => {
+                    let predicate = without_indices(query_iter.clone());
+                    let is_constant = match (params.tautologies, kw) {
+                        (Tautologies::AllowAll, _) => false,
+                        (Tautologies::DisallowCommon, Keyword::Where) => false,
+                        (Tautologies::AllowWhereTrue, Keyword::Where)
+                            if is_where_true(predicate.clone()) =>
+                        {
+                            false
+                        }
+                        (Tautologies::DisallowCommon, _) => is_common_tautology(predicate),
+                        (Tautologies::DisallowAll | Tautologies::AllowWhereTrue, _) => {
+                            is_common_tautology(predicate.clone())
+                                || (is_tautology(predicate.clone())
+                                    && !predicate.clone().any(DialectToken::is_variable))
                         }
+                    };
+                    if is_constant {
+                        return detected(Rule::Tautology);
                     }
                 }
-                // TODO: Add Keyword::And here to checks for (negative) tautology immediately following 'AND'?
-                CockroachToken::Keyword(Keyword::Union) => {}
-                // TODO: add logic to see whether a UNION JOIN is malicious
+                CockroachToken::Keyword(Keyword::Union) => match params.unions {
+                    Unions::DisallowAll => return detected(Rule::UnionQuery),
+                    Unions::DisallowProbing
+                        if is_union_probe(without_indices(query_iter.clone())) =>
+                    {
+                        return detected(Rule::UnionQuery)
+                    }
+                    _ => (),
+                },
+                CockroachToken::Keyword(Keyword::Order)
+                    if params.unions == Unions::DisallowAll
+                        && is_order_by_position(without_indices(query_iter.clone())) =>
+                {
+                    return detected(Rule::ColumnEnumeration)
+                }
                 _ => (),
             }
         }
 
-        false
+        None
+    }
+}
+
+impl DialectToken for CockroachToken {
+    fn class(&self) -> TokenClass<'_> {
+        match self {
+            CockroachToken::Whitespace(_)
+            | CockroachToken::Comment(_)
+            | CockroachToken::LineComment
+            | CockroachToken::BlockCommentOpen
+            | CockroachToken::BlockCommentClose => TokenClass::Insignificant,
+            CockroachToken::Keyword(kw) => TokenClass::Keyword(match kw {
+                Keyword::All => CommonKeyword::All,
+                Keyword::And => CommonKeyword::And,
+                Keyword::By => CommonKeyword::By,
+                Keyword::Distinct => CommonKeyword::Distinct,
+                Keyword::Except => CommonKeyword::Except,
+                Keyword::False => CommonKeyword::False,
+                Keyword::For => CommonKeyword::For,
+                Keyword::From => CommonKeyword::From,
+                Keyword::Group => CommonKeyword::Group,
+                Keyword::Having => CommonKeyword::Having,
+                Keyword::Intersect => CommonKeyword::Intersect,
+                Keyword::Limit => CommonKeyword::Limit,
+                Keyword::Not => CommonKeyword::Not,
+                Keyword::Null => CommonKeyword::Null,
+                Keyword::Offset => CommonKeyword::Offset,
+                Keyword::Or => CommonKeyword::Or,
+                Keyword::Order => CommonKeyword::Order,
+                Keyword::Returning => CommonKeyword::Returning,
+                Keyword::Select => CommonKeyword::Select,
+                Keyword::True => CommonKeyword::True,
+                Keyword::Union => CommonKeyword::Union,
+                Keyword::Where => CommonKeyword::Where,
+                Keyword::Window => CommonKeyword::Window,
+                _ => CommonKeyword::Other,
+            }),
+            CockroachToken::Identifier(_) => TokenClass::Identifier,
+            CockroachToken::Const(c) => TokenClass::Constant(c),
+            CockroachToken::SingleQuote => TokenClass::StringQuote,
+            // Quoted names are scanned as a `Const` between two `DoubleQuote`s
+            CockroachToken::DoubleQuote => TokenClass::NameQuote,
+            CockroachToken::Symbol(c) => TokenClass::Symbol(*c),
+            _ => TokenClass::Other,
+        }
+    }
+
+    fn is_variable(&self) -> bool {
+        match self {
+            CockroachToken::Identifier(name) => matches!(
+                name.to_ascii_uppercase().as_str(),
+                "NOW" | "CLOCK_TIMESTAMP" | "STATEMENT_TIMESTAMP" | "TRANSACTION_TIMESTAMP"
+            ),
+            CockroachToken::Keyword(
+                Keyword::Exists
+                | Keyword::CurrentDate
+                | Keyword::CurrentTime
+                | Keyword::CurrentTimestamp
+                | Keyword::Localtime
+                | Keyword::Localtimestamp,
+            ) => true,
+            // A quoted name, which `is_tautology()` would otherwise take for a constant
+            CockroachToken::DoubleQuote => true,
+            _ => false,
+        }
+    }
+}
+
+/// Whether the `WAITFOR` that was just consumed from `iter` is followed by `DELAY` or `TIME` (as in SQL Server's
+/// `WAITFOR DELAY '0:0:5'`).
+fn is_waitfor_delay<'a, I: Iterator<Item = &'a CockroachToken>>(iter: I) -> bool {
+    match next_significant_token(iter) {
+        Some(CockroachToken::Identifier(word)) => {
+            word.eq_ignore_ascii_case("DELAY") || word.eq_ignore_ascii_case("TIME")
+        }
+        Some(CockroachToken::Keyword(Keyword::Time)) => true,
+        _ => false,
+    }
+}
+
+/// Whether the `generate_series` that was just consumed from `iter` is given constant bounds that produce at least
+/// `HEAVY_QUERY_ROWS` rows (e.g. `generate_series(1, 100000000)`).
+fn is_heavy_series<'a, I: Iterator<Item = &'a CockroachToken>>(iter: I) -> bool {
+    let mut iter =
+        iter.filter(|token| token.is_significant() && **token != CockroachToken::DoubleQuote);
+    if !matches!(iter.next(), Some(CockroachToken::Symbol('('))) {
+        return false;
+    }
+
+    let start = match constant_number(&mut iter) {
+        Some(n) => n,
+        None => return false,
+    };
+    if !matches!(iter.next(), Some(CockroachToken::Symbol(','))) {
+        return false;
+    }
+    let stop = match constant_number(&mut iter) {
+        Some(n) => n,
+        None => return false,
+    };
+    let step = match iter.next() {
+        Some(CockroachToken::Symbol(',')) => match constant_number(&mut iter) {
+            Some(n) => n,
+            None => return false,
+        },
+        _ => 1.0,
+    };
+
+    (stop - start) / step >= HEAVY_QUERY_ROWS
+}
+
+/// Consumes a numeric constant (which may be negated) from `iter`, returning its value.
+fn constant_number<'a, I: Iterator<Item = &'a CockroachToken>>(iter: &mut I) -> Option<f64> {
+    let (negative, token) = match iter.next()? {
+        CockroachToken::Symbol('-') => (true, iter.next()?),
+        token => (false, token),
+    };
+    match token {
+        CockroachToken::Const(n) => {
+            let value: f64 = n.replace('_', "").parse().ok()?;
+            Some(if negative { -value } else { value })
+        }
+        _ => None,
     }
 }
 
@@ -138,7 +374,7 @@ where
     };
 
     // If the entire rest of the query has no more Identifiers, it would
-    // mean that the OR expression is based on values that are constant at the
+    // mean that the OR (or AND) expression is based on values that are constant at the
     // time they are passed in (meaning that it would evaluate to always true/always false,
     // rather than conditionally true based on values in a particular column/table).
     // This can catch either a tautology + Null byte injection attack, or else a
@@ -176,3 +412,92 @@ where
 // TODO: we could also check tautology when we pattern match--if the pattern doesn't match,
 // retrieve a vec of Tokens that the prefix/suffix stopped at. IF there is and OR and IF there
 // are no Identifiers after that OR
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detect(query: &str, params: &Parameters) -> Option<Rule> {
+        let tokens = CockroachToken::scan_forward(query);
+        CockroachDetector::detect_injection(tokens.iter(), params).map(|detection| detection.rule)
+    }
+
+    #[test]
+    fn quoted_metadata_names() {
+        let params = Parameters::default_prefix_suffix();
+        for query in [
+            "SELECT * FROM \"information_schema\".\"tables\"",
+            "SELECT * FROM system.\"users\"",
+            "SELECT * FROM \"crdb_internal\".ranges",
+        ] {
+            assert_eq!(
+                detect(query, &params),
+                Some(Rule::MetadataAccess),
+                "{query}"
+            );
+        }
+        for query in [
+            "SELECT * FROM \"users\" WHERE name = 'tables'",
+            "SELECT * FROM system_users",
+            "SELECT \"a\".\"b\" FROM \"a\"",
+        ] {
+            assert_eq!(detect(query, &params), None, "{query}");
+        }
+    }
+
+    #[test]
+    fn quoted_qualified_and_heavy_time_delays() {
+        let params = Parameters {
+            disallow_heavy_queries: true,
+            ..Parameters::default_prefix()
+        };
+        for query in [
+            "SELECT \"pg_sleep\"(5)",
+            "SELECT pg_catalog.\"pg_sleep\"(5)",
+            "SELECT \"pg_catalog\".\"pg_sleep\" (5)",
+            "SELECT PG_SLEEP(5)",
+            "SELECT * FROM generate_series(1, 100000000)",
+            "SELECT * FROM generate_series(1, 1e8)",
+        ] {
+            assert_eq!(detect(query, &params), Some(Rule::TimeDelay), "{query}");
+        }
+        for query in [
+            "SELECT pg_sleep FROM t",
+            "SELECT \"pg_sleep\" FROM t",
+            "SELECT * FROM generate_series(1, 10)",
+        ] {
+            assert_eq!(detect(query, &params), None, "{query}");
+        }
+    }
+
+    #[test]
+    fn quoted_names_are_not_constants() {
+        let params = Parameters {
+            tautologies: Tautologies::DisallowAll,
+            ..Parameters::default_nopattern()
+        };
+        assert_eq!(detect("SELECT * FROM t WHERE \"a\" = 'b'", &params), None);
+        assert_eq!(
+            detect("SELECT * FROM t WHERE \"a\" = 'b' OR 'a' = 'a'", &params),
+            Some(Rule::Tautology)
+        );
+    }
+
+    #[test]
+    fn internal_functions_and_extensions() {
+        let params = Parameters::default_prefix_suffix();
+        for query in [
+            "SELECT crdb_internal.force_panic('a')",
+            "CREATE EXTENSION fuzzystrmatch",
+        ] {
+            assert_eq!(
+                detect(query, &params),
+                Some(Rule::CommandExecution),
+                "{query}"
+            );
+        }
+        for query in ["SELECT force_panic('a')", "CREATE TABLE t (a INT)"] {
+            assert_eq!(detect(query, &params), None, "{query}");
+        }
+    }
+}
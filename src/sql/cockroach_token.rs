@@ -1,7 +1,10 @@
 use crate::sql::*;
 use phf::phf_map;
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
 use std::fmt::Display;
 use std::hash::{Hash, Hasher};
+use std::ops::Range;
 
 // Derived from https://www.cockroachlabs.com/docs/stable/sql-grammar.html
 // And more importantly,https://github.com/cockroachdb/cockroach/blob/release-22.1.0/docs/generated/sql/bnf/stmt_block.bnf
@@ -9,7 +12,7 @@ use std::hash::{Hash, Hasher};
 
 // Full scanner described here: https://github.com/cockroachdb/cockroach/blob/master/pkg/sql/parser/scanner_test.go
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum CockroachToken {
     UnknownToken(char),
     Identifier(String), // must: begin with underscore?; subsequent alphanumeric, underscores, or dollar signs. Double-quotes bypass these rules and preserves case sensitivity. Examples include asdf and "asdf", as well as $asdf
@@ -27,14 +30,8 @@ pub enum CockroachToken {
     Whitespace(char),
 }
 
-#[derive(PartialEq, Eq)]
-enum ScanDirection {
-    Forward,
-    Reverse,
-}
-
 // These are all accepted keywords
-#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug, Display)]
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug, Display, Serialize, Deserialize)]
 pub enum Keyword {
     Analyze,
     Analyse,
@@ -1395,11 +1392,23 @@ impl Display for CockroachToken {
 
 impl Token for CockroachToken {
     fn scan_forward(query: &str) -> Vec<(Self, usize)> {
-        Self::scan_with_parameters(query, ScanDirection::Forward)
+        Self::scan_with_parameters(query)
     }
 
     fn scan_reverse(query: &str) -> Vec<(Self, usize)> {
-        Self::scan_with_parameters(query, ScanDirection::Reverse)
+        // Whether a quote or comment delimiter opens anything depends on what precedes it (such as a quote within a line
+        // comment, or an unterminated string), so the tokens are found by a forward scan and then reversed. This keeps
+        // both directions in agreement, which suffix matching relies on.
+        let mut tokens = Self::scan_with_parameters(query);
+        tokens.reverse();
+        tokens
+    }
+
+    fn token_offsets(query: &str) -> Vec<Range<usize>> {
+        CockroachToken::scan_without_parameters(query)
+            .into_iter()
+            .map(|(_, start, end)| start..end)
+            .collect()
     }
 
     fn deep_eq(&self, other: &Self) -> bool {
@@ -1428,12 +1437,21 @@ impl Token for CockroachToken {
 }
 
 impl CockroachToken {
-    fn scan_without_parameters(query: &str) -> Vec<Self> {
-        let mut iter = query.chars().peekable();
+    /// Scans the query into tokens without accounting for quoted or commented-out portions.
+    ///
+    /// Each token is returned along with the byte range it spans in the query.
+    fn scan_without_parameters(query: &str) -> Vec<(Self, usize, usize)> {
+        // The matching functions below only see characters, so the bytes they take are counted as they go
+        let consumed = Cell::new(0);
+        let mut iter = query
+            .chars()
+            .inspect(|c| consumed.set(consumed.get() + c.len_utf8()))
+            .peekable();
         let mut tokens = vec![];
+        let mut start = 0;
 
         while let Some(c) = iter.next() {
-            tokens.push(match (c, iter.peek()) {
+            let token = match (c, iter.peek()) {
                 ('-', Some('-')) => {
                     iter.next(); // consume '-'
                     CockroachToken::LineComment
@@ -1468,7 +1486,12 @@ impl CockroachToken {
                 (c, _) if c.is_alphabetic() => match_kw_id(&mut iter, vec![c.to_ascii_uppercase()]),
                 // Not alphanumeric, and not any of the special chars we listed above: must be a Weasley
                 (c, _) => CockroachToken::UnknownToken(c),
-            });
+            };
+
+            // A character that was peeked at (but not taken) has already been counted
+            let end = consumed.get() - iter.peek().map_or(0, |p| p.len_utf8());
+            tokens.push((token, start, end));
+            start = end;
         }
 
         tokens
@@ -1478,9 +1501,12 @@ impl CockroachToken {
     ///
     /// This function also condenses block comments and line comments.
     /// It takes into account all nesting rules related to comments and parameters in PostgreSQL.
-    fn scan_with_parameters(query: &str, direction: ScanDirection) -> Vec<(Self, usize)> {
+    fn scan_with_parameters(query: &str) -> Vec<(Self, usize)> {
         // First, scan tokens without accounting for any quoted or commented-out portions
-        let mut tokens = CockroachToken::scan_without_parameters(query);
+        let tokens: Vec<Self> = CockroachToken::scan_without_parameters(query)
+            .into_iter()
+            .map(|(token, _, _)| token)
+            .collect();
         if tokens.len() == 0 {
             return Vec::new();
         }
@@ -1488,19 +1514,13 @@ impl CockroachToken {
         let mut norm_tokens = Vec::new();
         let mut layers = Vec::new();
         let mut contents: Option<String> = None;
-        let (indices, last_idx) = if direction == ScanDirection::Forward {
-            (0..=(tokens.len() - 1), tokens.len() - 1)
-        } else {
-            tokens.reverse();
-            ((tokens.len() - 1)..=0, 0)
-        };
+        let last_idx = tokens.len() - 1;
 
-        let mut iter = indices.zip(tokens.into_iter()).peekable();
+        let mut iter = (0..=last_idx).zip(tokens.into_iter()).peekable();
         while let Some((idx, next_token)) = iter.next() {
             if let Some(top_layer) = layers.last() {
                 match (top_layer, &next_token) {
                     (CockroachToken::BlockCommentOpen, CockroachToken::BlockCommentClose)
-                    | (CockroachToken::BlockCommentClose, CockroachToken::BlockCommentOpen)
                     | (CockroachToken::SingleQuote, CockroachToken::SingleQuote) => {
                         layers.pop();
                     }
@@ -1522,8 +1542,7 @@ impl CockroachToken {
                     (CockroachToken::DollarQuote(_), CockroachToken::DollarQuote(_)) => {
                         layers.push(next_token.clone())
                     } // Dollar quoting can be nested
-                    (CockroachToken::BlockCommentOpen, CockroachToken::BlockCommentOpen)
-                    | (CockroachToken::BlockCommentClose, CockroachToken::BlockCommentClose) => {
+                    (CockroachToken::BlockCommentOpen, CockroachToken::BlockCommentOpen) => {
                         layers.push(next_token.clone())
                     } // Block comments can also be nested
                     _ => (),
@@ -1538,10 +1557,7 @@ impl CockroachToken {
                             }
                             _ => CockroachToken::Const(contents.unwrap_or(String::new())),
                         },
-                        match direction {
-                            ScanDirection::Forward => idx - 1,
-                            ScanDirection::Reverse => idx + 1,
-                        },
+                        idx - 1,
                     ));
                     norm_tokens.push((next_token, idx));
                     contents = None;
@@ -1556,13 +1572,8 @@ impl CockroachToken {
                     CockroachToken::SingleQuote
                     | CockroachToken::DollarQuote(_)
                     | CockroachToken::DoubleQuote
-                    | CockroachToken::LineComment => layers.push(next_token.clone()),
-                    CockroachToken::BlockCommentOpen if direction == ScanDirection::Forward => {
-                        layers.push(next_token.clone())
-                    }
-                    CockroachToken::BlockCommentClose if direction == ScanDirection::Reverse => {
-                        layers.push(next_token.clone())
-                    }
+                    | CockroachToken::LineComment
+                    | CockroachToken::BlockCommentOpen => layers.push(next_token.clone()),
                     _ => (),
                 }
                 norm_tokens.push((next_token, idx));
@@ -1592,8 +1603,8 @@ impl CockroachToken {
     }
 }
 
-fn match_kw_id(
-    iter: &mut std::iter::Peekable<std::str::Chars>,
+fn match_kw_id<I: Iterator<Item = char>>(
+    iter: &mut std::iter::Peekable<I>,
     mut chars: Vec<char>,
 ) -> CockroachToken {
     while let Some(p) = iter.peek() {
@@ -1611,7 +1622,7 @@ fn match_kw_id(
     }
 }
 
-fn match_iconst_0x(iter: &mut std::iter::Peekable<std::str::Chars>) -> CockroachToken {
+fn match_iconst_0x<I: Iterator<Item = char>>(iter: &mut std::iter::Peekable<I>) -> CockroachToken {
     let mut chars = vec!['0', 'x'];
 
     while let Some(n @ ('0'..='9' | 'a'..='f' | 'A'..='F')) = iter.peek() {
@@ -1622,8 +1633,8 @@ fn match_iconst_0x(iter: &mut std::iter::Peekable<std::str::Chars>) -> Cockroach
     CockroachToken::Const(chars.into_iter().collect::<String>())
 }
 
-fn match_fconst_period(
-    iter: &mut std::iter::Peekable<std::str::Chars>,
+fn match_fconst_period<I: Iterator<Item = char>>(
+    iter: &mut std::iter::Peekable<I>,
     mut chars: Vec<char>,
 ) -> CockroachToken {
     while let Some(p) = iter.peek().copied() {
@@ -1632,7 +1643,7 @@ fn match_fconst_period(
                 chars.push(p);
                 iter.next();
             }
-            'e' => {
+            'e' | 'E' => {
                 chars.push(p);
                 iter.next();
                 match iter.peek() {
@@ -1652,8 +1663,8 @@ fn match_fconst_period(
     CockroachToken::Const(chars.into_iter().collect::<String>())
 }
 
-fn match_fconst_e(
-    iter: &mut std::iter::Peekable<std::str::Chars>,
+fn match_fconst_e<I: Iterator<Item = char>>(
+    iter: &mut std::iter::Peekable<I>,
     mut chars: Vec<char>,
 ) -> CockroachToken {
     while let Some(p @ '0'..='9') = iter.peek() {
@@ -1664,7 +1675,9 @@ fn match_fconst_e(
     CockroachToken::Const(chars.into_iter().collect::<String>())
 }
 
-fn match_placeholder(iter: &mut std::iter::Peekable<std::str::Chars>) -> CockroachToken {
+fn match_placeholder<I: Iterator<Item = char>>(
+    iter: &mut std::iter::Peekable<I>,
+) -> CockroachToken {
     let mut chars: Vec<char> = vec![];
 
     while let Some(p @ ('0'..='9')) = iter.peek() {
@@ -1675,7 +1688,9 @@ fn match_placeholder(iter: &mut std::iter::Peekable<std::str::Chars>) -> Cockroa
     CockroachToken::Placeholder(chars.into_iter().collect::<String>())
 }
 
-fn match_dollar_opening(iter: &mut std::iter::Peekable<std::str::Chars>) -> CockroachToken {
+fn match_dollar_opening<I: Iterator<Item = char>>(
+    iter: &mut std::iter::Peekable<I>,
+) -> CockroachToken {
     let mut ident = vec![];
     while let Some(p @ ('a'..='z' | 'A'..='Z' | '0'..='9' | '_')) = iter.peek() {
         ident.push(p.clone());
@@ -1692,8 +1707,8 @@ fn match_dollar_opening(iter: &mut std::iter::Peekable<std::str::Chars>) -> Cock
     }
 }
 
-fn match_const_digit(
-    iter: &mut std::iter::Peekable<std::str::Chars>,
+fn match_const_digit<I: Iterator<Item = char>>(
+    iter: &mut std::iter::Peekable<I>,
     mut chars: Vec<char>,
 ) -> CockroachToken {
     while let Some(p @ '0'..='9') = iter.peek() {
@@ -1706,6 +1721,40 @@ fn match_const_digit(
             chars.push('.');
             match_fconst_period(iter, chars)
         }
+        // An exponent (e.g. `1e8`), which is scanned the same way as one following a decimal point
+        Some('e' | 'E') => match_fconst_period(iter, chars),
         Some(_) | None => CockroachToken::Const(chars.into_iter().collect::<String>()),
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Asserts that a reverse scan finds the same tokens (and indices) as a forward scan, in reverse order.
+    fn assert_scans_agree(query: &str) {
+        let forward = CockroachToken::scan_forward(query);
+        let mut reverse = CockroachToken::scan_reverse(query);
+        reverse.reverse();
+        assert_eq!(forward.len(), reverse.len(), "{query:?}");
+        for ((f, f_idx), (r, r_idx)) in forward.iter().zip(&reverse) {
+            assert!(f.deep_eq(r) && f_idx == r_idx, "{query:?}: {f:?} != {r:?}");
+        }
+    }
+
+    #[test]
+    fn reverse_scan_agrees_with_forward_scan() {
+        for query in [
+            "SELECT * FROM t WHERE a = 'b' AND c = 2",
+            "SELECT 1 -- it's\nFROM t WHERE a = 'b'",
+            "SELECT 1 -- 'x",
+            "SELECT /* a /* b */ c */ 1",
+            "SELECT 'abc",
+            "SELECT 1 /* a",
+            "SELECT 'a' /* 'b",
+            "SELECT $x$ it's $$ $x$ FROM t",
+        ] {
+            assert_scans_agree(query);
+        }
+    }
+}
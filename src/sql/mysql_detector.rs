@@ -0,0 +1,436 @@
+use super::mysql_token::*;
+use crate::sql::*;
+
+/// The schemas and relations that describe the database's structure or its accounts, which
+/// `disallow_metadata_access` detects references to (unless `metadata_relations` is configured).
+pub const METADATA_RELATIONS: &[&str] = &[
+    "information_schema",
+    "performance_schema",
+    "mysql.user",
+    "mysql.global_priv",
+    "mysql.db",
+    "mysql.tables_priv",
+    "mysql.columns_priv",
+    "mysql.proc",
+];
+
+/// The functions that pause a query (or keep the server busy for long enough to), which `disallow_time_delays` detects
+/// calls to (whichever database they're qualified with).
+pub const TIME_DELAY_FUNCTIONS: &[&str] = &["sleep", "benchmark"];
+
+pub struct MysqlDetector {}
+
+impl Detector for MysqlDetector {
+    type Token = MysqlToken;
+
+    fn detect_injection<
+        'a,
+        I: std::iter::DoubleEndedIterator<Item = &'a (MysqlToken, usize)> + Clone,
+    >(
+        mut query_iter: I,
+        params: &Parameters,
+    ) -> Option<Detection>
+    where
+        Self: 'a,
+    {
+        let test_iter: Vec<&MysqlToken> = without_indices(query_iter.clone()).collect();
+        log::info!("tokens being checked for detect_injection: {:?}", test_iter);
+
+        let mut in_between = false;
+        while let Some((token, idx)) = query_iter.next() {
+            let detected = |rule| {
+                Some(Detection {
+                    rule,
+                    token_index: *idx,
+                })
+            };
+
+            match token {
+                MysqlToken::Symbol(';') => match params.multi_queries {
+                    MultipleQueries::DisallowAll => return detected(Rule::StackedQuery),
+                    MultipleQueries::DisallowCommit => return detected(Rule::StackedQuery),
+                    MultipleQueries::DisallowOnOtherIndications => {
+                        return detected(Rule::StackedQuery)
+                    }
+                    MultipleQueries::AllowAll => (),
+                },
+                MysqlToken::LineComment if params.disallow_line_comments => {
+                    return detected(Rule::LineComment)
+                }
+                MysqlToken::BlockCommentOpen if params.disallow_block_comments => {
+                    return detected(Rule::BlockComment)
+                }
+                MysqlToken::ExecutableCommentOpen(_) if params.disallow_executable_comments => {
+                    return detected(Rule::ExecutableComment)
+                }
+                MysqlToken::Identifier(_) | MysqlToken::Keyword(_)
+                    if params.disallow_time_delays
+                        && matches!(
+                            called_function(token, without_indices(query_iter.clone())),
+                            Some(name) if is_function_named(&name, TIME_DELAY_FUNCTIONS)
+                        ) =>
+                {
+                    return detected(Rule::TimeDelay)
+                }
+                // `WAITFOR DELAY '0:0:5'` only pauses SQL Server queries, but injection tools send it to whatever database
+                // they're probing
+                MysqlToken::Identifier(i)
+                    if params.disallow_time_delays
+                        && i.eq_ignore_ascii_case("WAITFOR")
+                        && is_waitfor_delay(without_indices(query_iter.clone())) =>
+                {
+                    return detected(Rule::TimeDelay)
+                }
+                MysqlToken::Identifier(_) | MysqlToken::Keyword(_)
+                    if params.disallow_metadata_access
+                        && params.is_metadata_relation(
+                            &qualified_name(token, without_indices(query_iter.clone())),
+                            METADATA_RELATIONS,
+                        ) =>
+                {
+                    return detected(Rule::MetadataAccess)
+                }
+                MysqlToken::Identifier(i) => match i.as_str() {
+                    "LOAD_FILE"
+                        if params.disallow_file_access
+                            && is_function_call(without_indices(query_iter.clone())) =>
+                    {
+                        return detected(Rule::FileAccess)
+                    }
+                    // Commonly-installed UDFs (from lib_mysqludf_sys) that run shell commands
+                    "SYS_EXEC" | "SYS_EVAL"
+                        if params.disallow_command_execution
+                            && is_function_call(without_indices(query_iter.clone())) =>
+                    {
+                        return detected(Rule::CommandExecution)
+                    }
+                    _ => (),
+                },
+                MysqlToken::Keyword(Keyword::Create)
+                    if params.disallow_command_execution
+                        && is_udf_creation(without_indices(query_iter.clone())) =>
+                {
+                    return detected(Rule::CommandExecution)
+                }
+                // `SELECT ... INTO OUTFILE` and `SELECT ... INTO DUMPFILE` write query results to the server's filesystem
+                MysqlToken::Keyword(Keyword::Into) if params.disallow_file_access => {
+                    match next_significant_token(without_indices(query_iter.clone())) {
+                        Some(MysqlToken::Keyword(Keyword::Outfile)) => {
+                            return detected(Rule::FileAccess)
+                        }
+                        Some(MysqlToken::Identifier(i)) if i == "DUMPFILE" => {
+                            return detected(Rule::FileAccess)
+                        }
+                        _ => (),
+                    }
+                }
+                // The AND in `BETWEEN x AND y` separates the range's bounds rather than predicates
+                MysqlToken::Keyword(Keyword::Between) => in_between = true,
+                MysqlToken::Keyword(Keyword::And) if in_between => in_between = false,
+                MysqlToken::Keyword(Keyword::Or | Keyword::And | Keyword::Where)
+                | MysqlToken::Symbol('|' | '&')
+                    if params.tautologies != Tautologies::AllowAll =>
+                {
+                    // `||` is a synonym for OR unless the PIPES_AS_CONCAT SQL mode is enabled, and `&&` is a synonym for AND
+                    if let MysqlToken::Symbol(symbol) = token {
+                        match query_iter.clone().next() {
+                            Some((MysqlToken::Symbol(next), _)) if next == symbol => {
+                                query_iter.next();
+                            }
+                            _ => continue,
+                        }
+                    }
+
+                    let predicate = without_indices(query_iter.clone());
+                    let is_constant = match (params.tautologies, token) {
+                        (Tautologies::AllowAll, _) => false,
+                        (Tautologies::DisallowCommon, MysqlToken::Keyword(Keyword::Where)) => false,
+                        (Tautologies::AllowWhereTrue, MysqlToken::Keyword(Keyword::Where))
+                            if is_where_true(predicate.clone()) =>
+                        {
+                            false
+                        }
+                        (Tautologies::DisallowCommon, _) => is_common_tautology(predicate),
+                        (Tautologies::DisallowAll | Tautologies::AllowWhereTrue, _) => {
+                            is_common_tautology(predicate.clone())
+                                || (is_tautology(predicate.clone())
+                                    && !predicate.clone().any(DialectToken::is_variable))
+                        }
+                    };
+                    if is_constant {
+                        return detected(Rule::Tautology);
+                    }
+                }
+                MysqlToken::Keyword(Keyword::Union) => match params.unions {
+                    Unions::DisallowAll => return detected(Rule::UnionQuery),
+                    Unions::DisallowProbing
+                        if is_union_probe(without_indices(query_iter.clone())) =>
+                    {
+                        return detected(Rule::UnionQuery)
+                    }
+                    _ => (),
+                },
+                MysqlToken::Keyword(Keyword::Order)
+                    if params.unions == Unions::DisallowAll
+                        && is_order_by_position(without_indices(query_iter.clone())) =>
+                {
+                    return detected(Rule::ColumnEnumeration)
+                }
+                _ => (),
+            }
+        }
+
+        None
+    }
+}
+
+impl DialectToken for MysqlToken {
+    /// The delimiters of executable comments are insignificant as well, since their contents are what the server runs.
+    fn class(&self) -> TokenClass<'_> {
+        match self {
+            MysqlToken::Whitespace(_)
+            | MysqlToken::Comment(_)
+            | MysqlToken::LineComment
+            | MysqlToken::BlockCommentOpen
+            | MysqlToken::ExecutableCommentOpen(_)
+            | MysqlToken::BlockCommentClose => TokenClass::Insignificant,
+            // MySQL's keyword list only contains reserved words, so it has no `OFFSET` or `RETURNING`
+            MysqlToken::Keyword(kw) => TokenClass::Keyword(match kw {
+                Keyword::All => CommonKeyword::All,
+                Keyword::And => CommonKeyword::And,
+                Keyword::By => CommonKeyword::By,
+                Keyword::Distinct => CommonKeyword::Distinct,
+                Keyword::Except => CommonKeyword::Except,
+                Keyword::False => CommonKeyword::False,
+                Keyword::For => CommonKeyword::For,
+                Keyword::From => CommonKeyword::From,
+                Keyword::Group => CommonKeyword::Group,
+                Keyword::Having => CommonKeyword::Having,
+                Keyword::Intersect => CommonKeyword::Intersect,
+                Keyword::Limit => CommonKeyword::Limit,
+                Keyword::Not => CommonKeyword::Not,
+                Keyword::Null => CommonKeyword::Null,
+                Keyword::Or => CommonKeyword::Or,
+                Keyword::Order => CommonKeyword::Order,
+                Keyword::Select => CommonKeyword::Select,
+                Keyword::True => CommonKeyword::True,
+                Keyword::Union => CommonKeyword::Union,
+                Keyword::Where => CommonKeyword::Where,
+                Keyword::Window => CommonKeyword::Window,
+                _ => CommonKeyword::Other,
+            }),
+            MysqlToken::Identifier(_) => TokenClass::Identifier,
+            MysqlToken::Const(c) => TokenClass::Constant(c),
+            MysqlToken::SingleQuote | MysqlToken::DoubleQuote => TokenClass::StringQuote,
+            MysqlToken::LiteralPrefix(_) => TokenClass::LiteralPrefix,
+            MysqlToken::Backtick => TokenClass::NameQuote,
+            MysqlToken::Symbol(c) => TokenClass::Symbol(*c),
+            _ => TokenClass::Other,
+        }
+    }
+
+    fn is_variable(&self) -> bool {
+        match self {
+            MysqlToken::Identifier(name) => matches!(
+                name.to_ascii_uppercase().as_str(),
+                "NOW" | "SYSDATE" | "CURDATE" | "CURTIME" | "UNIX_TIMESTAMP"
+            ),
+            MysqlToken::Keyword(
+                Keyword::Exists
+                | Keyword::CurrentDate
+                | Keyword::CurrentTime
+                | Keyword::CurrentTimestamp
+                | Keyword::Localtime
+                | Keyword::Localtimestamp
+                | Keyword::UtcDate
+                | Keyword::UtcTime
+                | Keyword::UtcTimestamp,
+            ) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Whether the `WAITFOR` that was just consumed from `iter` is followed by `DELAY` or `TIME` (as in SQL Server's
+/// `WAITFOR DELAY '0:0:5'`).
+fn is_waitfor_delay<'a, I: Iterator<Item = &'a MysqlToken>>(iter: I) -> bool {
+    match next_significant_token(iter) {
+        Some(MysqlToken::Identifier(word)) => {
+            word.eq_ignore_ascii_case("DELAY") || word.eq_ignore_ascii_case("TIME")
+        }
+        _ => false,
+    }
+}
+
+/// Whether the identifier that was just consumed from `iter` is the name of a function being called.
+///
+/// MySQL permits whitespace (and comments) between the name of most built-in functions and their opening parenthesis.
+fn is_function_call<'a, I: Iterator<Item = &'a MysqlToken>>(iter: I) -> bool {
+    matches!(next_significant_token(iter), Some(MysqlToken::Symbol('(')))
+}
+
+/// Whether the `CREATE` that was just consumed from `iter` creates a function from a shared library on the database
+/// server (`CREATE [AGGREGATE] FUNCTION ... SONAME '...'`), which runs native code with the server's privileges.
+fn is_udf_creation<'a, I: Iterator<Item = &'a MysqlToken>>(iter: I) -> bool {
+    let mut iter = iter.filter(|token| token.is_significant()).peekable();
+    if let Some(MysqlToken::Identifier(i)) = iter.peek() {
+        if i == "AGGREGATE" {
+            iter.next();
+        }
+    }
+    if !matches!(iter.next(), Some(MysqlToken::Keyword(Keyword::Function))) {
+        return false;
+    }
+
+    iter.take_while(|token| **token != MysqlToken::Symbol(';'))
+        .any(|token| matches!(token, MysqlToken::Identifier(i) if i == "SONAME"))
+}
+
+fn is_tautology<'a, I: std::iter::DoubleEndedIterator<Item = &'a MysqlToken>>(iter: I) -> bool {
+    let mut iter = iter
+        .skip_while(|token| -> bool { token.is_whitespace() })
+        .peekable(); // Ignore whitespace
+
+    // Catches some of the trivial cases that are commonly used
+    match iter.next() {
+        Some(MysqlToken::Keyword(Keyword::True)) => return true,
+        Some(c1 @ MysqlToken::Const(_)) => {
+            match (iter.next(), iter.next(), iter.next()) {
+                (Some(MysqlToken::Symbol('=')), Some(c2), _) if c1.deep_eq(c2) => return true, // Covers `OR 1=1`
+                (Some(MysqlToken::Symbol('!')), Some(MysqlToken::Symbol('=')), Some(c2))
+                | (Some(MysqlToken::Symbol('<')), Some(MysqlToken::Symbol('>')), Some(c2))
+                    if c1 == c2 && !c1.deep_eq(c2) =>
+                {
+                    return true; // `OR 1!=2`
+                }
+                (Some(MysqlToken::Identifier(_)), _, _)
+                | (_, Some(MysqlToken::Identifier(_)), _)
+                | (_, _, Some(MysqlToken::Identifier(_))) => {
+                    match iter.peek() {
+                        Some(MysqlToken::Symbol('(')) => (), // Found a function (still not a legitimate variable)
+                        _ => return false,
+                    }
+                }
+                _ => (),
+            };
+        }
+        Some(MysqlToken::Identifier(_)) => {
+            match iter.peek() {
+                Some(MysqlToken::Symbol('(')) => (), // Found a function (still not a legitimate variable)
+                _ => return false,
+            }
+        }
+        _ => (),
+    };
+
+    // If the entire rest of the query has no more Identifiers, it would
+    // mean that the OR (or AND) expression is based on values that are constant at the
+    // time they are passed in (meaning that it would evaluate to always true/always false,
+    // rather than conditionally true based on values in a particular column/table).
+    while let Some(token) = iter.next() {
+        if let MysqlToken::Identifier(_) = token {
+            match iter.peek() {
+                Some(MysqlToken::Symbol('(')) => (), // Found a function (still not a legitimate variable)
+                _ => return false,
+            }
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detect(query: &str, params: &Parameters) -> Option<Rule> {
+        let tokens = MysqlToken::scan_forward(query);
+        MysqlDetector::detect_injection(tokens.iter(), params).map(|detection| detection.rule)
+    }
+
+    #[test]
+    fn quoted_metadata_names() {
+        let params = Parameters::default_prefix_suffix();
+        for query in [
+            "SELECT * FROM mysql . user",
+            "SELECT * FROM `mysql`.`user`",
+            "SELECT * FROM `information_schema` . `columns`",
+        ] {
+            assert_eq!(
+                detect(query, &params),
+                Some(Rule::MetadataAccess),
+                "{query}"
+            );
+        }
+        for query in [
+            "SELECT * FROM `users` WHERE `name` = 'mysql.user'",
+            "SELECT user FROM accounts",
+            "SELECT `a`.`b` FROM `a`",
+        ] {
+            assert_eq!(detect(query, &params), None, "{query}");
+        }
+    }
+
+    #[test]
+    fn quoted_and_spaced_time_delays() {
+        let params = Parameters::default_prefix();
+        for query in [
+            "SELECT SLEEP(5)",
+            "SELECT `sleep`(5)",
+            "SELECT sleep /* x */ (5)",
+            "SELECT BENCHMARK(10000000, MD5('a'))",
+        ] {
+            assert_eq!(detect(query, &params), Some(Rule::TimeDelay), "{query}");
+        }
+        for query in [
+            "SELECT sleep FROM t",
+            "SELECT `sleep` FROM t",
+            "SELECT * FROM benchmarks WHERE id = 1",
+        ] {
+            assert_eq!(detect(query, &params), None, "{query}");
+        }
+    }
+
+    #[test]
+    fn hash_line_comments() {
+        let params = Parameters::default_prefix_suffix();
+        assert_eq!(
+            detect("SELECT * FROM t WHERE id = 1 # ", &params),
+            Some(Rule::LineComment)
+        );
+        assert_eq!(detect("SELECT '# a', \"# b\" FROM t", &params), None);
+    }
+
+    #[test]
+    fn executable_comments() {
+        let params = Parameters::default_prefix_suffix();
+        assert_eq!(
+            detect("SELECT * FROM t WHERE id = 1 /*!50000 AND 1 */", &params),
+            Some(Rule::ExecutableComment)
+        );
+        assert_eq!(detect("SELECT '/*!50000 a */' FROM t", &params), None);
+    }
+
+    #[test]
+    fn file_access_and_command_execution() {
+        let params = Parameters::default_prefix_suffix();
+        for (query, rule) in [
+            ("SELECT load_file('/etc/passwd')", Rule::FileAccess),
+            ("SELECT a FROM t INTO OUTFILE '/tmp/a'", Rule::FileAccess),
+            ("SELECT a FROM t INTO DUMPFILE '/tmp/a'", Rule::FileAccess),
+            ("SELECT sys_exec('id')", Rule::CommandExecution),
+            (
+                "CREATE FUNCTION sys_exec RETURNS INTEGER SONAME 'lib_mysqludf_sys.so'",
+                Rule::CommandExecution,
+            ),
+        ] {
+            assert_eq!(detect(query, &params), Some(rule), "{query}");
+        }
+        for query in [
+            "SELECT load_file, sys_exec FROM t",
+            "SELECT a FROM t INTO @a",
+            "CREATE TABLE t (a INT)",
+        ] {
+            assert_eq!(detect(query, &params), None, "{query}");
+        }
+    }
+}
@@ -0,0 +1,1038 @@
+use crate::sql::*;
+use phf::phf_map;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::hash::{Hash, Hasher};
+use std::iter::Peekable;
+use std::ops::Range;
+use std::str::CharIndices;
+
+// Derived from https://dev.mysql.com/doc/refman/8.0/en/language-structure.html
+// Keywords are only the reserved words listed in https://dev.mysql.com/doc/refman/8.0/en/keywords.html;
+// MySQL accepts any non-reserved keyword as an identifier, so those are scanned as Identifiers.
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum MysqlToken {
+    UnknownToken(char),
+    Identifier(String), // Unquoted identifiers consist of alphanumerics, underscores and dollar signs and are case-insensitive. Backtick-quoted identifiers preserve their contents.
+    Variable(String),   // User-defined (`@name`) or system (`@@name`) variables
+    SingleQuote,
+    DoubleQuote, // Double quotes delimit strings (rather than identifiers) unless the ANSI_QUOTES SQL mode is enabled
+    Backtick,
+    LiteralPrefix(String), // Hexadecimal (X'..'), bit-value (B'..') and national (N'..') literals, as well as character set introducers (_utf8mb4'..')
+    Const(String),
+    Placeholder, // `?` in prepared statements
+    Keyword(Keyword),
+    Symbol(char), // Characters that form unary/binary operators
+    Escape,       // A backslash, which escapes the next character within a string
+    LineComment,  // Either `#` or `--` followed by whitespace
+    BlockCommentOpen,
+    ExecutableCommentOpen(String), // `/*!` (or MariaDB's `/*M!`) followed by an optional minimum server version, such as `/*!50000`. Its contents are executed as SQL.
+    BlockCommentClose,
+    Comment(String),
+    Whitespace(char),
+}
+
+// These are all reserved keywords
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug, Display, Serialize, Deserialize)]
+pub enum Keyword {
+    Accessible,
+    Add,
+    All,
+    Alter,
+    Analyze,
+    And,
+    As,
+    Asc,
+    Asensitive,
+    Before,
+    Between,
+    Bigint,
+    Binary,
+    Blob,
+    Both,
+    By,
+    Call,
+    Cascade,
+    Case,
+    Change,
+    Char,
+    Character,
+    Check,
+    Collate,
+    Column,
+    Condition,
+    Constraint,
+    Continue,
+    Convert,
+    Create,
+    Cross,
+    Cube,
+    CumeDist,
+    CurrentDate,
+    CurrentTime,
+    CurrentTimestamp,
+    CurrentUser,
+    Cursor,
+    Database,
+    Databases,
+    DayHour,
+    DayMicrosecond,
+    DayMinute,
+    DaySecond,
+    Dec,
+    Decimal,
+    Declare,
+    Default,
+    Delayed,
+    Delete,
+    DenseRank,
+    Desc,
+    Describe,
+    Deterministic,
+    Distinct,
+    Distinctrow,
+    Div,
+    Double,
+    Drop,
+    Dual,
+    Each,
+    Else,
+    Elseif,
+    Empty,
+    Enclosed,
+    Escaped,
+    Except,
+    Exists,
+    Exit,
+    Explain,
+    False,
+    Fetch,
+    FirstValue,
+    Float,
+    Float4,
+    Float8,
+    For,
+    Force,
+    Foreign,
+    From,
+    Fulltext,
+    Function,
+    Generated,
+    Get,
+    Grant,
+    Group,
+    Grouping,
+    Groups,
+    Having,
+    HighPriority,
+    HourMicrosecond,
+    HourMinute,
+    HourSecond,
+    If,
+    Ignore,
+    In,
+    Index,
+    Infile,
+    Inner,
+    Inout,
+    Insensitive,
+    Insert,
+    Int,
+    Int1,
+    Int2,
+    Int3,
+    Int4,
+    Int8,
+    Integer,
+    Intersect,
+    Interval,
+    Into,
+    IoAfterGtids,
+    IoBeforeGtids,
+    Is,
+    Iterate,
+    Join,
+    JsonTable,
+    Key,
+    Keys,
+    Kill,
+    Lag,
+    LastValue,
+    Lateral,
+    Lead,
+    Leading,
+    Leave,
+    Left,
+    Like,
+    Limit,
+    Linear,
+    Lines,
+    Load,
+    Localtime,
+    Localtimestamp,
+    Lock,
+    Long,
+    Longblob,
+    Longtext,
+    Loop,
+    LowPriority,
+    Match,
+    Maxvalue,
+    Mediumblob,
+    Mediumint,
+    Mediumtext,
+    Middleint,
+    MinuteMicrosecond,
+    MinuteSecond,
+    Mod,
+    Modifies,
+    Natural,
+    Not,
+    NoWriteToBinlog,
+    NthValue,
+    Ntile,
+    Null,
+    Numeric,
+    Of,
+    On,
+    Optimize,
+    OptimizerCosts,
+    Option,
+    Optionally,
+    Or,
+    Order,
+    Out,
+    Outer,
+    Outfile,
+    Over,
+    Partition,
+    PercentRank,
+    Precision,
+    Primary,
+    Procedure,
+    Purge,
+    Range,
+    Rank,
+    Read,
+    Reads,
+    ReadWrite,
+    Real,
+    Recursive,
+    References,
+    Regexp,
+    Release,
+    Rename,
+    Repeat,
+    Replace,
+    Require,
+    Resignal,
+    Restrict,
+    Return,
+    Revoke,
+    Right,
+    Rlike,
+    Row,
+    Rows,
+    RowNumber,
+    Schema,
+    Schemas,
+    SecondMicrosecond,
+    Select,
+    Sensitive,
+    Separator,
+    Set,
+    Show,
+    Signal,
+    Smallint,
+    Spatial,
+    Specific,
+    Sql,
+    Sqlexception,
+    Sqlstate,
+    Sqlwarning,
+    SqlBigResult,
+    SqlCalcFoundRows,
+    SqlSmallResult,
+    Ssl,
+    Starting,
+    Stored,
+    StraightJoin,
+    System,
+    Table,
+    Terminated,
+    Then,
+    Tinyblob,
+    Tinyint,
+    Tinytext,
+    To,
+    Trailing,
+    Trigger,
+    True,
+    Undo,
+    Union,
+    Unique,
+    Unlock,
+    Unsigned,
+    Update,
+    Usage,
+    Use,
+    Using,
+    UtcDate,
+    UtcTime,
+    UtcTimestamp,
+    Values,
+    Varbinary,
+    Varchar,
+    Varcharacter,
+    Varying,
+    Virtual,
+    When,
+    Where,
+    While,
+    Window,
+    With,
+    Write,
+    Xor,
+    YearMonth,
+    Zerofill,
+}
+
+static KEYWORDS: phf::Map<&'static str, MysqlToken> = phf_map! {
+    "ACCESSIBLE" => MysqlToken::Keyword(Keyword::Accessible),
+    "ADD" => MysqlToken::Keyword(Keyword::Add),
+    "ALL" => MysqlToken::Keyword(Keyword::All),
+    "ALTER" => MysqlToken::Keyword(Keyword::Alter),
+    "ANALYZE" => MysqlToken::Keyword(Keyword::Analyze),
+    "AND" => MysqlToken::Keyword(Keyword::And),
+    "AS" => MysqlToken::Keyword(Keyword::As),
+    "ASC" => MysqlToken::Keyword(Keyword::Asc),
+    "ASENSITIVE" => MysqlToken::Keyword(Keyword::Asensitive),
+    "BEFORE" => MysqlToken::Keyword(Keyword::Before),
+    "BETWEEN" => MysqlToken::Keyword(Keyword::Between),
+    "BIGINT" => MysqlToken::Keyword(Keyword::Bigint),
+    "BINARY" => MysqlToken::Keyword(Keyword::Binary),
+    "BLOB" => MysqlToken::Keyword(Keyword::Blob),
+    "BOTH" => MysqlToken::Keyword(Keyword::Both),
+    "BY" => MysqlToken::Keyword(Keyword::By),
+    "CALL" => MysqlToken::Keyword(Keyword::Call),
+    "CASCADE" => MysqlToken::Keyword(Keyword::Cascade),
+    "CASE" => MysqlToken::Keyword(Keyword::Case),
+    "CHANGE" => MysqlToken::Keyword(Keyword::Change),
+    "CHAR" => MysqlToken::Keyword(Keyword::Char),
+    "CHARACTER" => MysqlToken::Keyword(Keyword::Character),
+    "CHECK" => MysqlToken::Keyword(Keyword::Check),
+    "COLLATE" => MysqlToken::Keyword(Keyword::Collate),
+    "COLUMN" => MysqlToken::Keyword(Keyword::Column),
+    "CONDITION" => MysqlToken::Keyword(Keyword::Condition),
+    "CONSTRAINT" => MysqlToken::Keyword(Keyword::Constraint),
+    "CONTINUE" => MysqlToken::Keyword(Keyword::Continue),
+    "CONVERT" => MysqlToken::Keyword(Keyword::Convert),
+    "CREATE" => MysqlToken::Keyword(Keyword::Create),
+    "CROSS" => MysqlToken::Keyword(Keyword::Cross),
+    "CUBE" => MysqlToken::Keyword(Keyword::Cube),
+    "CUME_DIST" => MysqlToken::Keyword(Keyword::CumeDist),
+    "CURRENT_DATE" => MysqlToken::Keyword(Keyword::CurrentDate),
+    "CURRENT_TIME" => MysqlToken::Keyword(Keyword::CurrentTime),
+    "CURRENT_TIMESTAMP" => MysqlToken::Keyword(Keyword::CurrentTimestamp),
+    "CURRENT_USER" => MysqlToken::Keyword(Keyword::CurrentUser),
+    "CURSOR" => MysqlToken::Keyword(Keyword::Cursor),
+    "DATABASE" => MysqlToken::Keyword(Keyword::Database),
+    "DATABASES" => MysqlToken::Keyword(Keyword::Databases),
+    "DAY_HOUR" => MysqlToken::Keyword(Keyword::DayHour),
+    "DAY_MICROSECOND" => MysqlToken::Keyword(Keyword::DayMicrosecond),
+    "DAY_MINUTE" => MysqlToken::Keyword(Keyword::DayMinute),
+    "DAY_SECOND" => MysqlToken::Keyword(Keyword::DaySecond),
+    "DEC" => MysqlToken::Keyword(Keyword::Dec),
+    "DECIMAL" => MysqlToken::Keyword(Keyword::Decimal),
+    "DECLARE" => MysqlToken::Keyword(Keyword::Declare),
+    "DEFAULT" => MysqlToken::Keyword(Keyword::Default),
+    "DELAYED" => MysqlToken::Keyword(Keyword::Delayed),
+    "DELETE" => MysqlToken::Keyword(Keyword::Delete),
+    "DENSE_RANK" => MysqlToken::Keyword(Keyword::DenseRank),
+    "DESC" => MysqlToken::Keyword(Keyword::Desc),
+    "DESCRIBE" => MysqlToken::Keyword(Keyword::Describe),
+    "DETERMINISTIC" => MysqlToken::Keyword(Keyword::Deterministic),
+    "DISTINCT" => MysqlToken::Keyword(Keyword::Distinct),
+    "DISTINCTROW" => MysqlToken::Keyword(Keyword::Distinctrow),
+    "DIV" => MysqlToken::Keyword(Keyword::Div),
+    "DOUBLE" => MysqlToken::Keyword(Keyword::Double),
+    "DROP" => MysqlToken::Keyword(Keyword::Drop),
+    "DUAL" => MysqlToken::Keyword(Keyword::Dual),
+    "EACH" => MysqlToken::Keyword(Keyword::Each),
+    "ELSE" => MysqlToken::Keyword(Keyword::Else),
+    "ELSEIF" => MysqlToken::Keyword(Keyword::Elseif),
+    "EMPTY" => MysqlToken::Keyword(Keyword::Empty),
+    "ENCLOSED" => MysqlToken::Keyword(Keyword::Enclosed),
+    "ESCAPED" => MysqlToken::Keyword(Keyword::Escaped),
+    "EXCEPT" => MysqlToken::Keyword(Keyword::Except),
+    "EXISTS" => MysqlToken::Keyword(Keyword::Exists),
+    "EXIT" => MysqlToken::Keyword(Keyword::Exit),
+    "EXPLAIN" => MysqlToken::Keyword(Keyword::Explain),
+    "FALSE" => MysqlToken::Keyword(Keyword::False),
+    "FETCH" => MysqlToken::Keyword(Keyword::Fetch),
+    "FIRST_VALUE" => MysqlToken::Keyword(Keyword::FirstValue),
+    "FLOAT" => MysqlToken::Keyword(Keyword::Float),
+    "FLOAT4" => MysqlToken::Keyword(Keyword::Float4),
+    "FLOAT8" => MysqlToken::Keyword(Keyword::Float8),
+    "FOR" => MysqlToken::Keyword(Keyword::For),
+    "FORCE" => MysqlToken::Keyword(Keyword::Force),
+    "FOREIGN" => MysqlToken::Keyword(Keyword::Foreign),
+    "FROM" => MysqlToken::Keyword(Keyword::From),
+    "FULLTEXT" => MysqlToken::Keyword(Keyword::Fulltext),
+    "FUNCTION" => MysqlToken::Keyword(Keyword::Function),
+    "GENERATED" => MysqlToken::Keyword(Keyword::Generated),
+    "GET" => MysqlToken::Keyword(Keyword::Get),
+    "GRANT" => MysqlToken::Keyword(Keyword::Grant),
+    "GROUP" => MysqlToken::Keyword(Keyword::Group),
+    "GROUPING" => MysqlToken::Keyword(Keyword::Grouping),
+    "GROUPS" => MysqlToken::Keyword(Keyword::Groups),
+    "HAVING" => MysqlToken::Keyword(Keyword::Having),
+    "HIGH_PRIORITY" => MysqlToken::Keyword(Keyword::HighPriority),
+    "HOUR_MICROSECOND" => MysqlToken::Keyword(Keyword::HourMicrosecond),
+    "HOUR_MINUTE" => MysqlToken::Keyword(Keyword::HourMinute),
+    "HOUR_SECOND" => MysqlToken::Keyword(Keyword::HourSecond),
+    "IF" => MysqlToken::Keyword(Keyword::If),
+    "IGNORE" => MysqlToken::Keyword(Keyword::Ignore),
+    "IN" => MysqlToken::Keyword(Keyword::In),
+    "INDEX" => MysqlToken::Keyword(Keyword::Index),
+    "INFILE" => MysqlToken::Keyword(Keyword::Infile),
+    "INNER" => MysqlToken::Keyword(Keyword::Inner),
+    "INOUT" => MysqlToken::Keyword(Keyword::Inout),
+    "INSENSITIVE" => MysqlToken::Keyword(Keyword::Insensitive),
+    "INSERT" => MysqlToken::Keyword(Keyword::Insert),
+    "INT" => MysqlToken::Keyword(Keyword::Int),
+    "INT1" => MysqlToken::Keyword(Keyword::Int1),
+    "INT2" => MysqlToken::Keyword(Keyword::Int2),
+    "INT3" => MysqlToken::Keyword(Keyword::Int3),
+    "INT4" => MysqlToken::Keyword(Keyword::Int4),
+    "INT8" => MysqlToken::Keyword(Keyword::Int8),
+    "INTEGER" => MysqlToken::Keyword(Keyword::Integer),
+    "INTERSECT" => MysqlToken::Keyword(Keyword::Intersect),
+    "INTERVAL" => MysqlToken::Keyword(Keyword::Interval),
+    "INTO" => MysqlToken::Keyword(Keyword::Into),
+    "IO_AFTER_GTIDS" => MysqlToken::Keyword(Keyword::IoAfterGtids),
+    "IO_BEFORE_GTIDS" => MysqlToken::Keyword(Keyword::IoBeforeGtids),
+    "IS" => MysqlToken::Keyword(Keyword::Is),
+    "ITERATE" => MysqlToken::Keyword(Keyword::Iterate),
+    "JOIN" => MysqlToken::Keyword(Keyword::Join),
+    "JSON_TABLE" => MysqlToken::Keyword(Keyword::JsonTable),
+    "KEY" => MysqlToken::Keyword(Keyword::Key),
+    "KEYS" => MysqlToken::Keyword(Keyword::Keys),
+    "KILL" => MysqlToken::Keyword(Keyword::Kill),
+    "LAG" => MysqlToken::Keyword(Keyword::Lag),
+    "LAST_VALUE" => MysqlToken::Keyword(Keyword::LastValue),
+    "LATERAL" => MysqlToken::Keyword(Keyword::Lateral),
+    "LEAD" => MysqlToken::Keyword(Keyword::Lead),
+    "LEADING" => MysqlToken::Keyword(Keyword::Leading),
+    "LEAVE" => MysqlToken::Keyword(Keyword::Leave),
+    "LEFT" => MysqlToken::Keyword(Keyword::Left),
+    "LIKE" => MysqlToken::Keyword(Keyword::Like),
+    "LIMIT" => MysqlToken::Keyword(Keyword::Limit),
+    "LINEAR" => MysqlToken::Keyword(Keyword::Linear),
+    "LINES" => MysqlToken::Keyword(Keyword::Lines),
+    "LOAD" => MysqlToken::Keyword(Keyword::Load),
+    "LOCALTIME" => MysqlToken::Keyword(Keyword::Localtime),
+    "LOCALTIMESTAMP" => MysqlToken::Keyword(Keyword::Localtimestamp),
+    "LOCK" => MysqlToken::Keyword(Keyword::Lock),
+    "LONG" => MysqlToken::Keyword(Keyword::Long),
+    "LONGBLOB" => MysqlToken::Keyword(Keyword::Longblob),
+    "LONGTEXT" => MysqlToken::Keyword(Keyword::Longtext),
+    "LOOP" => MysqlToken::Keyword(Keyword::Loop),
+    "LOW_PRIORITY" => MysqlToken::Keyword(Keyword::LowPriority),
+    "MATCH" => MysqlToken::Keyword(Keyword::Match),
+    "MAXVALUE" => MysqlToken::Keyword(Keyword::Maxvalue),
+    "MEDIUMBLOB" => MysqlToken::Keyword(Keyword::Mediumblob),
+    "MEDIUMINT" => MysqlToken::Keyword(Keyword::Mediumint),
+    "MEDIUMTEXT" => MysqlToken::Keyword(Keyword::Mediumtext),
+    "MIDDLEINT" => MysqlToken::Keyword(Keyword::Middleint),
+    "MINUTE_MICROSECOND" => MysqlToken::Keyword(Keyword::MinuteMicrosecond),
+    "MINUTE_SECOND" => MysqlToken::Keyword(Keyword::MinuteSecond),
+    "MOD" => MysqlToken::Keyword(Keyword::Mod),
+    "MODIFIES" => MysqlToken::Keyword(Keyword::Modifies),
+    "NATURAL" => MysqlToken::Keyword(Keyword::Natural),
+    "NOT" => MysqlToken::Keyword(Keyword::Not),
+    "NO_WRITE_TO_BINLOG" => MysqlToken::Keyword(Keyword::NoWriteToBinlog),
+    "NTH_VALUE" => MysqlToken::Keyword(Keyword::NthValue),
+    "NTILE" => MysqlToken::Keyword(Keyword::Ntile),
+    "NULL" => MysqlToken::Keyword(Keyword::Null),
+    "NUMERIC" => MysqlToken::Keyword(Keyword::Numeric),
+    "OF" => MysqlToken::Keyword(Keyword::Of),
+    "ON" => MysqlToken::Keyword(Keyword::On),
+    "OPTIMIZE" => MysqlToken::Keyword(Keyword::Optimize),
+    "OPTIMIZER_COSTS" => MysqlToken::Keyword(Keyword::OptimizerCosts),
+    "OPTION" => MysqlToken::Keyword(Keyword::Option),
+    "OPTIONALLY" => MysqlToken::Keyword(Keyword::Optionally),
+    "OR" => MysqlToken::Keyword(Keyword::Or),
+    "ORDER" => MysqlToken::Keyword(Keyword::Order),
+    "OUT" => MysqlToken::Keyword(Keyword::Out),
+    "OUTER" => MysqlToken::Keyword(Keyword::Outer),
+    "OUTFILE" => MysqlToken::Keyword(Keyword::Outfile),
+    "OVER" => MysqlToken::Keyword(Keyword::Over),
+    "PARTITION" => MysqlToken::Keyword(Keyword::Partition),
+    "PERCENT_RANK" => MysqlToken::Keyword(Keyword::PercentRank),
+    "PRECISION" => MysqlToken::Keyword(Keyword::Precision),
+    "PRIMARY" => MysqlToken::Keyword(Keyword::Primary),
+    "PROCEDURE" => MysqlToken::Keyword(Keyword::Procedure),
+    "PURGE" => MysqlToken::Keyword(Keyword::Purge),
+    "RANGE" => MysqlToken::Keyword(Keyword::Range),
+    "RANK" => MysqlToken::Keyword(Keyword::Rank),
+    "READ" => MysqlToken::Keyword(Keyword::Read),
+    "READS" => MysqlToken::Keyword(Keyword::Reads),
+    "READ_WRITE" => MysqlToken::Keyword(Keyword::ReadWrite),
+    "REAL" => MysqlToken::Keyword(Keyword::Real),
+    "RECURSIVE" => MysqlToken::Keyword(Keyword::Recursive),
+    "REFERENCES" => MysqlToken::Keyword(Keyword::References),
+    "REGEXP" => MysqlToken::Keyword(Keyword::Regexp),
+    "RELEASE" => MysqlToken::Keyword(Keyword::Release),
+    "RENAME" => MysqlToken::Keyword(Keyword::Rename),
+    "REPEAT" => MysqlToken::Keyword(Keyword::Repeat),
+    "REPLACE" => MysqlToken::Keyword(Keyword::Replace),
+    "REQUIRE" => MysqlToken::Keyword(Keyword::Require),
+    "RESIGNAL" => MysqlToken::Keyword(Keyword::Resignal),
+    "RESTRICT" => MysqlToken::Keyword(Keyword::Restrict),
+    "RETURN" => MysqlToken::Keyword(Keyword::Return),
+    "REVOKE" => MysqlToken::Keyword(Keyword::Revoke),
+    "RIGHT" => MysqlToken::Keyword(Keyword::Right),
+    "RLIKE" => MysqlToken::Keyword(Keyword::Rlike),
+    "ROW" => MysqlToken::Keyword(Keyword::Row),
+    "ROWS" => MysqlToken::Keyword(Keyword::Rows),
+    "ROW_NUMBER" => MysqlToken::Keyword(Keyword::RowNumber),
+    "SCHEMA" => MysqlToken::Keyword(Keyword::Schema),
+    "SCHEMAS" => MysqlToken::Keyword(Keyword::Schemas),
+    "SECOND_MICROSECOND" => MysqlToken::Keyword(Keyword::SecondMicrosecond),
+    "SELECT" => MysqlToken::Keyword(Keyword::Select),
+    "SENSITIVE" => MysqlToken::Keyword(Keyword::Sensitive),
+    "SEPARATOR" => MysqlToken::Keyword(Keyword::Separator),
+    "SET" => MysqlToken::Keyword(Keyword::Set),
+    "SHOW" => MysqlToken::Keyword(Keyword::Show),
+    "SIGNAL" => MysqlToken::Keyword(Keyword::Signal),
+    "SMALLINT" => MysqlToken::Keyword(Keyword::Smallint),
+    "SPATIAL" => MysqlToken::Keyword(Keyword::Spatial),
+    "SPECIFIC" => MysqlToken::Keyword(Keyword::Specific),
+    "SQL" => MysqlToken::Keyword(Keyword::Sql),
+    "SQLEXCEPTION" => MysqlToken::Keyword(Keyword::Sqlexception),
+    "SQLSTATE" => MysqlToken::Keyword(Keyword::Sqlstate),
+    "SQLWARNING" => MysqlToken::Keyword(Keyword::Sqlwarning),
+    "SQL_BIG_RESULT" => MysqlToken::Keyword(Keyword::SqlBigResult),
+    "SQL_CALC_FOUND_ROWS" => MysqlToken::Keyword(Keyword::SqlCalcFoundRows),
+    "SQL_SMALL_RESULT" => MysqlToken::Keyword(Keyword::SqlSmallResult),
+    "SSL" => MysqlToken::Keyword(Keyword::Ssl),
+    "STARTING" => MysqlToken::Keyword(Keyword::Starting),
+    "STORED" => MysqlToken::Keyword(Keyword::Stored),
+    "STRAIGHT_JOIN" => MysqlToken::Keyword(Keyword::StraightJoin),
+    "SYSTEM" => MysqlToken::Keyword(Keyword::System),
+    "TABLE" => MysqlToken::Keyword(Keyword::Table),
+    "TERMINATED" => MysqlToken::Keyword(Keyword::Terminated),
+    "THEN" => MysqlToken::Keyword(Keyword::Then),
+    "TINYBLOB" => MysqlToken::Keyword(Keyword::Tinyblob),
+    "TINYINT" => MysqlToken::Keyword(Keyword::Tinyint),
+    "TINYTEXT" => MysqlToken::Keyword(Keyword::Tinytext),
+    "TO" => MysqlToken::Keyword(Keyword::To),
+    "TRAILING" => MysqlToken::Keyword(Keyword::Trailing),
+    "TRIGGER" => MysqlToken::Keyword(Keyword::Trigger),
+    "TRUE" => MysqlToken::Keyword(Keyword::True),
+    "UNDO" => MysqlToken::Keyword(Keyword::Undo),
+    "UNION" => MysqlToken::Keyword(Keyword::Union),
+    "UNIQUE" => MysqlToken::Keyword(Keyword::Unique),
+    "UNLOCK" => MysqlToken::Keyword(Keyword::Unlock),
+    "UNSIGNED" => MysqlToken::Keyword(Keyword::Unsigned),
+    "UPDATE" => MysqlToken::Keyword(Keyword::Update),
+    "USAGE" => MysqlToken::Keyword(Keyword::Usage),
+    "USE" => MysqlToken::Keyword(Keyword::Use),
+    "USING" => MysqlToken::Keyword(Keyword::Using),
+    "UTC_DATE" => MysqlToken::Keyword(Keyword::UtcDate),
+    "UTC_TIME" => MysqlToken::Keyword(Keyword::UtcTime),
+    "UTC_TIMESTAMP" => MysqlToken::Keyword(Keyword::UtcTimestamp),
+    "VALUES" => MysqlToken::Keyword(Keyword::Values),
+    "VARBINARY" => MysqlToken::Keyword(Keyword::Varbinary),
+    "VARCHAR" => MysqlToken::Keyword(Keyword::Varchar),
+    "VARCHARACTER" => MysqlToken::Keyword(Keyword::Varcharacter),
+    "VARYING" => MysqlToken::Keyword(Keyword::Varying),
+    "VIRTUAL" => MysqlToken::Keyword(Keyword::Virtual),
+    "WHEN" => MysqlToken::Keyword(Keyword::When),
+    "WHERE" => MysqlToken::Keyword(Keyword::Where),
+    "WHILE" => MysqlToken::Keyword(Keyword::While),
+    "WINDOW" => MysqlToken::Keyword(Keyword::Window),
+    "WITH" => MysqlToken::Keyword(Keyword::With),
+    "WRITE" => MysqlToken::Keyword(Keyword::Write),
+    "XOR" => MysqlToken::Keyword(Keyword::Xor),
+    "YEAR_MONTH" => MysqlToken::Keyword(Keyword::YearMonth),
+    "ZEROFILL" => MysqlToken::Keyword(Keyword::Zerofill)
+};
+
+impl PartialEq for MysqlToken {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (MysqlToken::UnknownToken(c1), MysqlToken::UnknownToken(c2))
+            | (MysqlToken::Symbol(c1), MysqlToken::Symbol(c2))
+            | (MysqlToken::Whitespace(c1), MysqlToken::Whitespace(c2)) => c1 == c2,
+            (MysqlToken::Identifier(s1), MysqlToken::Identifier(s2))
+            | (MysqlToken::Variable(s1), MysqlToken::Variable(s2))
+            | (MysqlToken::LiteralPrefix(s1), MysqlToken::LiteralPrefix(s2))
+            | (MysqlToken::ExecutableCommentOpen(s1), MysqlToken::ExecutableCommentOpen(s2))
+            | (MysqlToken::Comment(s1), MysqlToken::Comment(s2)) => s1 == s2,
+            (MysqlToken::Keyword(k1), MysqlToken::Keyword(k2)) => k1 == k2,
+            (MysqlToken::Const(_), MysqlToken::Const(_)) // Contents are compared in deep_eq() instead
+            | (MysqlToken::SingleQuote, MysqlToken::SingleQuote)
+            | (MysqlToken::DoubleQuote, MysqlToken::DoubleQuote)
+            | (MysqlToken::Backtick, MysqlToken::Backtick)
+            | (MysqlToken::Placeholder, MysqlToken::Placeholder)
+            | (MysqlToken::Escape, MysqlToken::Escape)
+            | (MysqlToken::LineComment, MysqlToken::LineComment)
+            | (MysqlToken::BlockCommentOpen, MysqlToken::BlockCommentOpen)
+            | (MysqlToken::BlockCommentClose, MysqlToken::BlockCommentClose) => true,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for MysqlToken {}
+
+impl Hash for MysqlToken {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            MysqlToken::UnknownToken(c) | MysqlToken::Symbol(c) | MysqlToken::Whitespace(c) => {
+                c.hash(state)
+            }
+            MysqlToken::Identifier(s)
+            | MysqlToken::Variable(s)
+            | MysqlToken::LiteralPrefix(s)
+            | MysqlToken::ExecutableCommentOpen(s)
+            | MysqlToken::Comment(s) => s.hash(state),
+            MysqlToken::Keyword(k) => k.hash(state),
+            MysqlToken::Const(_) => (), // Don't want to hash this--use deep_eq instead
+            _ => (),
+        }
+    }
+}
+
+impl Display for MysqlToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MysqlToken::UnknownToken(c) | MysqlToken::Whitespace(c) | MysqlToken::Symbol(c) => {
+                write!(f, "{}", c)
+            }
+            MysqlToken::Identifier(s)
+            | MysqlToken::Variable(s)
+            | MysqlToken::LiteralPrefix(s)
+            | MysqlToken::Const(s)
+            | MysqlToken::Comment(s) => write!(f, "{}", s),
+            MysqlToken::SingleQuote => write!(f, "'"),
+            MysqlToken::DoubleQuote => write!(f, "\""),
+            MysqlToken::Backtick => write!(f, "`"),
+            MysqlToken::Placeholder => write!(f, "?"),
+            MysqlToken::Escape => write!(f, "\\"),
+            MysqlToken::LineComment => write!(f, "#"),
+            MysqlToken::BlockCommentOpen => write!(f, "/*"),
+            MysqlToken::ExecutableCommentOpen(marker) => write!(f, "/*{}", marker),
+            MysqlToken::BlockCommentClose => write!(f, "*/"),
+            MysqlToken::Keyword(k) => write!(f, "{}", k),
+        }
+    }
+}
+
+impl Token for MysqlToken {
+    fn scan_forward(query: &str) -> Vec<(Self, usize)> {
+        Self::scan_with_parameters(query)
+    }
+
+    fn scan_reverse(query: &str) -> Vec<(Self, usize)> {
+        // Whether a quote or comment delimiter opens anything depends on what precedes it (such as a quote within a line
+        // comment, or an unterminated string), so the tokens are found by a forward scan and then reversed. This keeps
+        // both directions in agreement, which suffix matching relies on.
+        let mut tokens = Self::scan_with_parameters(query);
+        tokens.reverse();
+        tokens
+    }
+
+    fn token_offsets(query: &str) -> Vec<Range<usize>> {
+        MysqlToken::scan_without_parameters(query)
+            .into_iter()
+            .map(|(_, start, end)| start..end)
+            .collect()
+    }
+
+    fn deep_eq(&self, other: &Self) -> bool {
+        (self == other)
+            && match (self, other) {
+                (MysqlToken::Const(s1), MysqlToken::Const(s2)) => s1 == s2,
+                _ => true,
+            }
+    }
+
+    fn is_param_token(&self) -> bool {
+        matches!(self, MysqlToken::Const(_))
+    }
+
+    fn is_whitespace(&self) -> bool {
+        matches!(self, MysqlToken::Whitespace(_))
+    }
+}
+
+impl MysqlToken {
+    /// Scans the query into tokens without accounting for quoted or commented-out portions.
+    ///
+    /// Each token is returned along with the byte range it spans in the query.
+    fn scan_without_parameters(query: &str) -> Vec<(Self, usize, usize)> {
+        let mut iter = query.char_indices().peekable();
+        let mut tokens = vec![];
+
+        while let Some((start, c)) = iter.next() {
+            let token = match (c, iter.peek().map(|(_, p)| *p)) {
+                ('-', Some('-')) => {
+                    // `--` only begins a comment when followed by whitespace, a control character or the end of the query
+                    let mut lookahead = iter.clone();
+                    lookahead.next();
+                    match lookahead.peek() {
+                        Some((_, p)) if !p.is_whitespace() && !p.is_control() => {
+                            MysqlToken::Symbol(c)
+                        }
+                        _ => {
+                            iter.next(); // consume '-'
+                            MysqlToken::LineComment
+                        }
+                    }
+                }
+                ('#', _) => MysqlToken::LineComment,
+                ('/', Some('*')) => {
+                    iter.next(); // consume '*'
+                    match_block_comment_open(&mut iter)
+                }
+                ('*', Some('/')) => {
+                    iter.next(); // consume '/'
+                    MysqlToken::BlockCommentClose
+                }
+                ('\'', _) => MysqlToken::SingleQuote,
+                ('"', _) => MysqlToken::DoubleQuote,
+                ('`', _) => MysqlToken::Backtick,
+                ('\\', _) => MysqlToken::Escape,
+                ('?', _) => MysqlToken::Placeholder,
+                ('@', _) => match_variable(&mut iter),
+                ('.', Some('0'..='9')) => match_fconst_period(&mut iter, vec!['.']),
+                ('0', Some('x')) => {
+                    iter.next();
+                    match_const_radix(&mut iter, vec!['0', 'x'], 16)
+                }
+                ('0', Some('b')) => {
+                    iter.next();
+                    match_const_radix(&mut iter, vec!['0', 'b'], 2)
+                }
+                ('0'..='9', _) => match_const_digit(&mut iter, vec![c]),
+                ('x' | 'X' | 'b' | 'B' | 'n' | 'N', Some('\'')) => {
+                    MysqlToken::LiteralPrefix(c.to_ascii_uppercase().to_string())
+                }
+                (c, _) if c == '_' || c.is_alphabetic() => {
+                    match match_kw_id(&mut iter, vec![c.to_ascii_uppercase()]) {
+                        MysqlToken::Identifier(charset)
+                            if charset.starts_with('_')
+                                && matches!(iter.peek(), Some((_, '\'' | '"'))) =>
+                        {
+                            MysqlToken::LiteralPrefix(charset)
+                        }
+                        token => token,
+                    }
+                }
+                (
+                    '/' | '-' | '^' | ';' | '(' | ')' | ',' | '=' | '*' | '+' | '~' | '%' | '&'
+                    | '|' | '!' | '<' | '>' | '[' | ']' | '{' | '}' | ':' | '.',
+                    _,
+                ) => MysqlToken::Symbol(c),
+                (' ' | '\t' | '\r' | '\n' | '\x0b' | '\x0c', _) => MysqlToken::Whitespace(c),
+                (c, _) => MysqlToken::UnknownToken(c),
+            };
+
+            let end = match iter.peek() {
+                Some((idx, _)) => *idx,
+                None => query.len(),
+            };
+            tokens.push((token, start, end));
+        }
+
+        tokens
+    }
+
+    /// Takes any instances of quoted parameters (such as 'param' or "param") and condenses their contents into a single
+    /// `Const` token. Backtick-quoted identifiers are likewise condensed into a single `Identifier` token.
+    ///
+    /// This function also condenses block comments and line comments. Unlike PostgreSQL, MySQL doesn't nest comments or
+    /// quotes, so only one layer is ever open at a time. Backslash escapes and doubled quotes within strings are honored.
+    ///
+    /// Executable comments (`/*! ... */`) are not condensed, as the server runs their contents like any other SQL.
+    /// Their contents are scanned into tokens between the `ExecutableCommentOpen` and `BlockCommentClose` tokens.
+    fn scan_with_parameters(query: &str) -> Vec<(Self, usize)> {
+        let tokens = MysqlToken::scan_without_parameters(query);
+        let mut norm_tokens: Vec<(Self, usize)> = Vec::new();
+        let mut layer: Option<usize> = None; // The index of the token that opened the current quote or comment
+
+        let mut idx = 0;
+        while idx < tokens.len() {
+            let next_token = &tokens[idx].0;
+            let token_idx = idx;
+            idx += 1;
+
+            let open_idx = match layer {
+                Some(open_idx) => open_idx,
+                None => {
+                    if let MysqlToken::SingleQuote
+                    | MysqlToken::DoubleQuote
+                    | MysqlToken::Backtick
+                    | MysqlToken::BlockCommentOpen
+                    | MysqlToken::LineComment = next_token
+                    {
+                        layer = Some(token_idx);
+                    }
+                    norm_tokens.push((next_token.clone(), token_idx));
+                    continue;
+                }
+            };
+
+            let is_closed = match (&tokens[open_idx].0, next_token) {
+                (MysqlToken::SingleQuote, MysqlToken::Escape)
+                | (MysqlToken::DoubleQuote, MysqlToken::Escape) => {
+                    idx += 1; // The escaped token is part of the string's contents
+                    false
+                }
+                (MysqlToken::SingleQuote, MysqlToken::SingleQuote)
+                | (MysqlToken::DoubleQuote, MysqlToken::DoubleQuote)
+                | (MysqlToken::Backtick, MysqlToken::Backtick) => {
+                    if idx < tokens.len() && tokens[idx].0 == *next_token {
+                        idx += 1; // A doubled quote is an escaped quote
+                        false
+                    } else {
+                        true
+                    }
+                }
+                (MysqlToken::BlockCommentOpen, MysqlToken::BlockCommentClose)
+                | (MysqlToken::LineComment, MysqlToken::Whitespace('\n')) => true,
+                _ => false,
+            };
+
+            if is_closed {
+                let contents = query[tokens[open_idx].2..tokens[token_idx].1].to_string();
+                norm_tokens.push((Self::condense(&tokens[open_idx].0, contents), open_idx + 1));
+                norm_tokens.push((next_token.clone(), token_idx));
+                layer = None;
+            }
+        }
+
+        // If there was no closing quote or comment, then just output the Const/Identifier/Comment anyway
+        if let Some(open_idx) = layer {
+            let contents = query[tokens[open_idx].2..].to_string();
+            norm_tokens.push((Self::condense(&tokens[open_idx].0, contents), open_idx + 1));
+        }
+
+        norm_tokens
+    }
+
+    /// Returns the token that the contents of a quote or comment opened by `opening_token` are condensed into.
+    fn condense(opening_token: &MysqlToken, contents: String) -> MysqlToken {
+        match opening_token {
+            MysqlToken::SingleQuote | MysqlToken::DoubleQuote => MysqlToken::Const(contents),
+            MysqlToken::Backtick => MysqlToken::Identifier(contents),
+            _ => MysqlToken::Comment(contents),
+        }
+    }
+}
+
+fn match_kw_id(iter: &mut Peekable<CharIndices>, mut chars: Vec<char>) -> MysqlToken {
+    while let Some((_, p)) = iter.peek() {
+        if !p.is_alphabetic() && !p.is_ascii_digit() && *p != '_' && *p != '$' {
+            break;
+        }
+        chars.push(p.to_ascii_uppercase());
+        iter.next();
+    }
+
+    let identifier = chars.into_iter().collect::<String>();
+    match KEYWORDS.get(&identifier) {
+        Some(token) => token.clone(),
+        None => MysqlToken::Identifier(identifier),
+    }
+}
+
+fn match_variable(iter: &mut Peekable<CharIndices>) -> MysqlToken {
+    let mut chars = vec!['@'];
+    if let Some((_, '@')) = iter.peek() {
+        chars.push('@');
+        iter.next();
+    }
+
+    // System variables may be qualified with their scope, such as `@@session.sql_mode`
+    while let Some((_, p)) = iter.peek() {
+        if !p.is_alphabetic() && !p.is_ascii_digit() && !matches!(p, '_' | '$' | '.') {
+            break;
+        }
+        chars.push(p.to_ascii_uppercase());
+        iter.next();
+    }
+
+    match chars.len() {
+        1 => MysqlToken::Symbol('@'), // Quoted variable names (e.g. @'name') are scanned as a separate quote
+        _ => MysqlToken::Variable(chars.into_iter().collect::<String>()),
+    }
+}
+
+/// Matches the remainder of a `/*`, which may open an executable comment (`/*!`, `/*!50000` or MariaDB's `/*M!100301`).
+///
+/// The version gating an executable comment is ignored; its contents are treated as code regardless of the server's version.
+fn match_block_comment_open(iter: &mut Peekable<CharIndices>) -> MysqlToken {
+    let mut marker = String::new();
+    let mut lookahead = iter.clone();
+    if let Some((_, 'M')) = lookahead.peek() {
+        marker.push('M');
+        lookahead.next();
+    }
+
+    match lookahead.peek() {
+        Some((_, '!')) => {
+            marker.push('!');
+            lookahead.next();
+            *iter = lookahead;
+        }
+        _ => return MysqlToken::BlockCommentOpen,
+    }
+
+    // The version is written as 5 digits (Mmmrr), or 6 digits (MMmmrr) in newer servers
+    let mut digits = 0;
+    while let Some((_, p @ '0'..='9')) = iter.peek() {
+        if digits == 6 {
+            break;
+        }
+        marker.push(*p);
+        digits += 1;
+        iter.next();
+    }
+
+    MysqlToken::ExecutableCommentOpen(marker)
+}
+
+fn match_const_radix(
+    iter: &mut Peekable<CharIndices>,
+    mut chars: Vec<char>,
+    radix: u32,
+) -> MysqlToken {
+    while let Some((_, p)) = iter.peek() {
+        if !p.is_digit(radix) {
+            break;
+        }
+        chars.push(*p);
+        iter.next();
+    }
+
+    MysqlToken::Const(chars.into_iter().collect::<String>())
+}
+
+fn match_fconst_period(iter: &mut Peekable<CharIndices>, mut chars: Vec<char>) -> MysqlToken {
+    while let Some((_, p @ '0'..='9')) = iter.peek() {
+        chars.push(*p);
+        iter.next();
+    }
+
+    match_fconst_e(iter, chars)
+}
+
+fn match_fconst_e(iter: &mut Peekable<CharIndices>, mut chars: Vec<char>) -> MysqlToken {
+    if let Some((_, e @ ('e' | 'E'))) = iter.peek().copied() {
+        let mut lookahead = iter.clone();
+        lookahead.next();
+        let sign = match lookahead.peek() {
+            Some((_, s @ ('+' | '-'))) => {
+                let s = *s;
+                lookahead.next();
+                Some(s)
+            }
+            _ => None,
+        };
+
+        // Only an exponent if digits follow (otherwise the 'e' is the start of something else)
+        if let Some((_, '0'..='9')) = lookahead.peek() {
+            chars.push(e);
+            iter.next();
+            if let Some(s) = sign {
+                chars.push(s);
+                iter.next();
+            }
+            while let Some((_, p @ '0'..='9')) = iter.peek() {
+                chars.push(*p);
+                iter.next();
+            }
+        }
+    }
+
+    MysqlToken::Const(chars.into_iter().collect::<String>())
+}
+
+fn match_const_digit(iter: &mut Peekable<CharIndices>, mut chars: Vec<char>) -> MysqlToken {
+    while let Some((_, p @ '0'..='9')) = iter.peek() {
+        chars.push(*p);
+        iter.next();
+    }
+
+    match iter.peek() {
+        Some((_, '.')) => {
+            chars.push('.');
+            iter.next();
+            match_fconst_period(iter, chars)
+        }
+        Some(_) | None => match_fconst_e(iter, chars),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Asserts that a reverse scan finds the same tokens (and indices) as a forward scan, in reverse order.
+    fn assert_scans_agree(query: &str) {
+        let forward = MysqlToken::scan_forward(query);
+        let mut reverse = MysqlToken::scan_reverse(query);
+        reverse.reverse();
+        assert_eq!(forward.len(), reverse.len(), "{query:?}");
+        for ((f, f_idx), (r, r_idx)) in forward.iter().zip(&reverse) {
+            assert!(f.deep_eq(r) && f_idx == r_idx, "{query:?}: {f:?} != {r:?}");
+        }
+    }
+
+    #[test]
+    fn quotes_in_line_comments_do_not_open_strings() {
+        assert_scans_agree("SELECT 1 # don't\nFROM t");
+        assert_scans_agree("SELECT 1 -- it's\nFROM t WHERE a = 'b'");
+        assert_scans_agree("SELECT 1 -- 'x");
+
+        let tokens = MysqlToken::scan_forward("SELECT 1 # don't\nFROM t");
+        assert!(tokens[5]
+            .0
+            .deep_eq(&MysqlToken::Comment(" don't".to_string())));
+        assert_eq!(tokens[7], (MysqlToken::Keyword(Keyword::From), 10));
+    }
+
+    #[test]
+    fn comments_do_not_nest() {
+        let query = "SELECT /* a /* b */ c */ 1";
+        assert_scans_agree(query);
+        let tokens = MysqlToken::scan_forward(query);
+        assert!(tokens[3]
+            .0
+            .deep_eq(&MysqlToken::Comment(" a /* b ".to_string())));
+        assert_eq!(tokens[6], (MysqlToken::Identifier("C".to_string()), 12));
+    }
+
+    #[test]
+    fn unterminated_quotes_and_comments() {
+        assert_scans_agree("SELECT 'abc");
+        assert_scans_agree("SELECT \"abc");
+        assert_scans_agree("SELECT `abc");
+        assert_scans_agree("SELECT 1 /* abc");
+        assert_scans_agree("SELECT 'a' /* 'b");
+    }
+
+    #[test]
+    fn escapes_and_executable_comments() {
+        assert_scans_agree("SELECT 'it\\'s' FROM t");
+        assert_scans_agree("SELECT 'it''s', \"a\"\"b\" FROM t");
+        assert_scans_agree("SELECT 'C:\\\\' FROM t -- '");
+        assert_scans_agree("SELECT /*!50000 1, 'a*/' */ FROM t");
+    }
+}
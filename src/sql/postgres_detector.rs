@@ -0,0 +1,537 @@
+use super::postgres_token::*;
+use crate::sql::*;
+
+/// The schemas and relations that describe the database's structure, roles or activity, which
+/// `disallow_metadata_access` detects references to (unless `metadata_relations` is configured).
+pub const METADATA_RELATIONS: &[&str] = &[
+    "information_schema",
+    "pg_catalog",
+    "pg_shadow",
+    "pg_authid",
+    "pg_user",
+    "pg_roles",
+    "pg_group",
+    "pg_auth_members",
+    "pg_tables",
+    "pg_views",
+    "pg_class",
+    "pg_namespace",
+    "pg_attribute",
+    "pg_database",
+    "pg_proc",
+    "pg_settings",
+    "pg_stat_activity",
+];
+
+/// The functions that pause a query, which `disallow_time_delays` detects calls to (whichever schema they're
+/// qualified with).
+pub const TIME_DELAY_FUNCTIONS: &[&str] = &["pg_sleep", "pg_sleep_for", "pg_sleep_until"];
+
+pub struct PostgresDetector {}
+
+impl Detector for PostgresDetector {
+    type Token = PostgresToken;
+
+    fn detect_injection<
+        'a,
+        I: std::iter::DoubleEndedIterator<Item = &'a (PostgresToken, usize)> + Clone,
+    >(
+        mut query_iter: I,
+        params: &Parameters,
+    ) -> Option<Detection>
+    where
+        Self: 'a,
+    {
+        let test_iter: Vec<&PostgresToken> = without_indices(query_iter.clone()).collect();
+        log::info!("tokens being checked for detect_injection: {:?}", test_iter);
+
+        let mut in_between = false;
+        while let Some((token, idx)) = query_iter.next() {
+            let detected = |rule| {
+                Some(Detection {
+                    rule,
+                    token_index: *idx,
+                })
+            };
+
+            match token {
+                PostgresToken::Symbol(';') => match params.multi_queries {
+                    MultipleQueries::DisallowAll => return detected(Rule::StackedQuery),
+                    MultipleQueries::DisallowCommit => return detected(Rule::StackedQuery),
+                    MultipleQueries::DisallowOnOtherIndications => {
+                        return detected(Rule::StackedQuery)
+                    }
+                    MultipleQueries::AllowAll => (),
+                },
+                PostgresToken::LineComment if params.disallow_line_comments => {
+                    return detected(Rule::LineComment)
+                }
+                PostgresToken::BlockCommentOpen if params.disallow_block_comments => {
+                    return detected(Rule::BlockComment)
+                }
+                // Checked before metadata access, so that calls qualified with their schema (e.g. `pg_catalog.pg_sleep(5)`)
+                // are reported as what they do
+                PostgresToken::Identifier(_) | PostgresToken::Keyword(_)
+                    if params.disallow_time_delays
+                        && matches!(
+                            called_function(token, without_indices(query_iter.clone())),
+                            Some(name) if is_function_named(&name, TIME_DELAY_FUNCTIONS)
+                        ) =>
+                {
+                    return detected(Rule::TimeDelay)
+                }
+                // `WAITFOR DELAY '0:0:5'` only pauses SQL Server queries, but injection tools send it to whatever database
+                // they're probing
+                PostgresToken::Identifier(i)
+                    if params.disallow_time_delays
+                        && i.eq_ignore_ascii_case("WAITFOR")
+                        && is_waitfor_delay(without_indices(query_iter.clone())) =>
+                {
+                    return detected(Rule::TimeDelay)
+                }
+                PostgresToken::Identifier(i)
+                    if params.disallow_heavy_queries
+                        && i.eq_ignore_ascii_case("GENERATE_SERIES")
+                        && is_heavy_series(without_indices(query_iter.clone())) =>
+                {
+                    return detected(Rule::TimeDelay)
+                }
+                PostgresToken::Identifier(_) | PostgresToken::Keyword(_)
+                    if params.disallow_metadata_access
+                        && params.is_metadata_relation(
+                            &qualified_name(token, without_indices(query_iter.clone())),
+                            METADATA_RELATIONS,
+                        ) =>
+                {
+                    return detected(Rule::MetadataAccess)
+                }
+                // Quoted identifiers keep their case, but PostgreSQL folds unquoted names to lowercase anyway
+                PostgresToken::Identifier(i) => match i.to_ascii_uppercase().as_str() {
+                    "PG_READ_FILE"
+                    | "PG_READ_BINARY_FILE"
+                    | "PG_LS_DIR"
+                    | "PG_STAT_FILE"
+                    | "PG_FILE_WRITE"
+                    | "LO_IMPORT"
+                    | "LO_EXPORT"
+                        if params.disallow_file_access
+                            && is_function_call(without_indices(query_iter.clone())) =>
+                    {
+                        return detected(Rule::FileAccess)
+                    }
+                    "DBLINK" | "DBLINK_EXEC" | "DBLINK_CONNECT" | "DBLINK_CONNECT_U"
+                    | "DBLINK_SEND_QUERY" | "DBLINK_OPEN"
+                        if params.disallow_network_access
+                            && is_function_call(without_indices(query_iter.clone())) =>
+                    {
+                        return detected(Rule::NetworkAccess)
+                    }
+                    _ => (),
+                },
+                PostgresToken::Keyword(Keyword::Copy) => {
+                    match copy_access(without_indices(query_iter.clone())) {
+                        Some(Rule::FileAccess) if params.disallow_file_access => {
+                            return detected(Rule::FileAccess)
+                        }
+                        Some(Rule::CommandExecution) if params.disallow_command_execution => {
+                            return detected(Rule::CommandExecution)
+                        }
+                        _ => (),
+                    }
+                }
+                // `CREATE EXTENSION` can load native code into the server, and `ALTER SYSTEM` can set options (such as
+                // `archive_command`) that run shell commands
+                PostgresToken::Keyword(Keyword::Create)
+                    if params.disallow_command_execution
+                        && matches!(
+                            next_significant_token(without_indices(query_iter.clone())),
+                            Some(PostgresToken::Keyword(Keyword::Extension))
+                        ) =>
+                {
+                    return detected(Rule::CommandExecution)
+                }
+                PostgresToken::Keyword(Keyword::Alter)
+                    if params.disallow_command_execution
+                        && matches!(
+                            next_significant_token(without_indices(query_iter.clone())),
+                            Some(PostgresToken::Keyword(Keyword::System))
+                        ) =>
+                {
+                    return detected(Rule::CommandExecution)
+                }
+                // The AND in `BETWEEN x AND y` separates the range's bounds rather than predicates
+                PostgresToken::Keyword(Keyword::Between) => in_between = true,
+                PostgresToken::Keyword(Keyword::And) if in_between => in_between = false,
+                PostgresToken::Keyword(kw @ (Keyword::Or | Keyword::And | Keyword::Where)) => {
+                    let predicate = without_indices(query_iter.clone());
+                    let is_constant = match (params.tautologies, kw) {
+                        (Tautologies::AllowAll, _) => false,
+                        (Tautologies::DisallowCommon, Keyword::Where) => false,
+                        (Tautologies::AllowWhereTrue, Keyword::Where)
+                            if is_where_true(predicate.clone()) =>
+                        {
+                            false
+                        }
+                        (Tautologies::DisallowCommon, _) => is_common_tautology(predicate),
+                        (Tautologies::DisallowAll | Tautologies::AllowWhereTrue, _) => {
+                            is_common_tautology(predicate.clone())
+                                || (is_tautology(predicate.clone())
+                                    && !predicate.clone().any(DialectToken::is_variable))
+                        }
+                    };
+                    if is_constant {
+                        return detected(Rule::Tautology);
+                    }
+                }
+                PostgresToken::Keyword(Keyword::Union) => match params.unions {
+                    Unions::DisallowAll => return detected(Rule::UnionQuery),
+                    Unions::DisallowProbing
+                        if is_union_probe(without_indices(query_iter.clone())) =>
+                    {
+                        return detected(Rule::UnionQuery)
+                    }
+                    _ => (),
+                },
+                PostgresToken::Keyword(Keyword::Order)
+                    if params.unions == Unions::DisallowAll
+                        && is_order_by_position(without_indices(query_iter.clone())) =>
+                {
+                    return detected(Rule::ColumnEnumeration)
+                }
+                _ => (),
+            }
+        }
+
+        None
+    }
+}
+
+impl DialectToken for PostgresToken {
+    fn class(&self) -> TokenClass<'_> {
+        match self {
+            PostgresToken::Whitespace(_)
+            | PostgresToken::Comment(_)
+            | PostgresToken::LineComment
+            | PostgresToken::BlockCommentOpen
+            | PostgresToken::BlockCommentClose => TokenClass::Insignificant,
+            PostgresToken::Keyword(kw) => TokenClass::Keyword(match kw {
+                Keyword::All => CommonKeyword::All,
+                Keyword::And => CommonKeyword::And,
+                Keyword::By => CommonKeyword::By,
+                Keyword::Distinct => CommonKeyword::Distinct,
+                Keyword::Except => CommonKeyword::Except,
+                Keyword::False => CommonKeyword::False,
+                Keyword::For => CommonKeyword::For,
+                Keyword::From => CommonKeyword::From,
+                Keyword::Group => CommonKeyword::Group,
+                Keyword::Having => CommonKeyword::Having,
+                Keyword::Intersect => CommonKeyword::Intersect,
+                Keyword::Limit => CommonKeyword::Limit,
+                Keyword::Not => CommonKeyword::Not,
+                Keyword::Null => CommonKeyword::Null,
+                Keyword::Offset => CommonKeyword::Offset,
+                Keyword::Or => CommonKeyword::Or,
+                Keyword::Order => CommonKeyword::Order,
+                Keyword::Returning => CommonKeyword::Returning,
+                Keyword::Select => CommonKeyword::Select,
+                Keyword::True => CommonKeyword::True,
+                Keyword::Union => CommonKeyword::Union,
+                Keyword::Where => CommonKeyword::Where,
+                Keyword::Window => CommonKeyword::Window,
+                _ => CommonKeyword::Other,
+            }),
+            PostgresToken::Identifier(_) => TokenClass::Identifier,
+            PostgresToken::Const(c) => TokenClass::Constant(c),
+            PostgresToken::SingleQuote => TokenClass::StringQuote,
+            PostgresToken::StringPrefix(_) => TokenClass::LiteralPrefix,
+            PostgresToken::DoubleQuote => TokenClass::NameQuote,
+            PostgresToken::Symbol(c) => TokenClass::Symbol(*c),
+            PostgresToken::Operator(op) => TokenClass::Operator(op),
+            _ => TokenClass::Other,
+        }
+    }
+
+    fn is_variable(&self) -> bool {
+        match self {
+            PostgresToken::Identifier(name) => matches!(
+                name.to_ascii_uppercase().as_str(),
+                "NOW"
+                    | "CLOCK_TIMESTAMP"
+                    | "STATEMENT_TIMESTAMP"
+                    | "TRANSACTION_TIMESTAMP"
+                    | "TIMEOFDAY"
+            ),
+            PostgresToken::Keyword(
+                Keyword::Exists
+                | Keyword::CurrentDate
+                | Keyword::CurrentTime
+                | Keyword::CurrentTimestamp
+                | Keyword::Localtime
+                | Keyword::Localtimestamp,
+            ) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Whether the `WAITFOR` that was just consumed from `iter` is followed by `DELAY` or `TIME` (as in SQL Server's
+/// `WAITFOR DELAY '0:0:5'`).
+fn is_waitfor_delay<'a, I: Iterator<Item = &'a PostgresToken>>(iter: I) -> bool {
+    match next_significant_token(iter) {
+        Some(PostgresToken::Identifier(word)) => {
+            word.eq_ignore_ascii_case("DELAY") || word.eq_ignore_ascii_case("TIME")
+        }
+        Some(PostgresToken::Keyword(Keyword::Time)) => true,
+        _ => false,
+    }
+}
+
+/// Whether the identifier that was just consumed from `iter` is the name of a function being called.
+fn is_function_call<'a, I: Iterator<Item = &'a PostgresToken>>(iter: I) -> bool {
+    matches!(
+        next_significant_token(iter),
+        Some(PostgresToken::Symbol('('))
+    )
+}
+
+/// Whether the `generate_series` that was just consumed from `iter` is given constant bounds that produce at least
+/// `HEAVY_QUERY_ROWS` rows (e.g. `generate_series(1, 100000000)`).
+fn is_heavy_series<'a, I: Iterator<Item = &'a PostgresToken>>(iter: I) -> bool {
+    let mut iter =
+        iter.filter(|token| token.is_significant() && **token != PostgresToken::DoubleQuote);
+    if !matches!(iter.next(), Some(PostgresToken::Symbol('('))) {
+        return false;
+    }
+
+    let start = match constant_number(&mut iter) {
+        Some(n) => n,
+        None => return false,
+    };
+    if !matches!(iter.next(), Some(PostgresToken::Symbol(','))) {
+        return false;
+    }
+    let stop = match constant_number(&mut iter) {
+        Some(n) => n,
+        None => return false,
+    };
+    let step = match iter.next() {
+        Some(PostgresToken::Symbol(',')) => match constant_number(&mut iter) {
+            Some(n) => n,
+            None => return false,
+        },
+        _ => 1.0,
+    };
+
+    (stop - start) / step >= HEAVY_QUERY_ROWS
+}
+
+/// Consumes a numeric constant (which may be negated) from `iter`, returning its value.
+fn constant_number<'a, I: Iterator<Item = &'a PostgresToken>>(iter: &mut I) -> Option<f64> {
+    let (negative, token) = match iter.next()? {
+        PostgresToken::Symbol('-') => (true, iter.next()?),
+        token => (false, token),
+    };
+    match token {
+        PostgresToken::Const(n) => {
+            let value: f64 = n.replace('_', "").parse().ok()?;
+            Some(if negative { -value } else { value })
+        }
+        _ => None,
+    }
+}
+
+/// Returns how the `COPY` statement that was just consumed from `iter` reaches outside the database: `FileAccess` if it
+/// reads or writes a file on the database server, `CommandExecution` if it runs a program there, or `None` if it only
+/// exchanges data with the client through STDIN/STDOUT.
+fn copy_access<'a, I: Iterator<Item = &'a PostgresToken> + Clone>(mut iter: I) -> Option<Rule> {
+    while let Some(token) = iter.next() {
+        match token {
+            PostgresToken::Symbol(';') => return None,
+            PostgresToken::Keyword(Keyword::From | Keyword::To) => {
+                match next_significant_token(iter.clone()) {
+                    Some(PostgresToken::SingleQuote | PostgresToken::StringPrefix(_)) => {
+                        return Some(Rule::FileAccess)
+                    }
+                    Some(PostgresToken::Identifier(i)) if i == "PROGRAM" => {
+                        return Some(Rule::CommandExecution)
+                    }
+                    _ => (),
+                }
+            }
+            _ => (),
+        }
+    }
+    None
+}
+
+fn is_tautology<'a, I: std::iter::DoubleEndedIterator<Item = &'a PostgresToken>>(iter: I) -> bool {
+    let mut iter = iter
+        .skip_while(|token| -> bool { token.is_whitespace() })
+        .peekable(); // Ignore whitespace
+
+    // Catches some of the trivial cases that are commonly used
+    match iter.next() {
+        Some(PostgresToken::Keyword(Keyword::True)) => return true,
+        Some(c1 @ PostgresToken::Const(_)) => {
+            match (iter.next(), iter.next()) {
+                (Some(PostgresToken::Symbol('=')), Some(c2)) if c1.deep_eq(c2) => return true, // Covers `OR 1=1`
+                (Some(PostgresToken::Operator(op)), Some(c2))
+                    if (op == "!=" || op == "<>") && c1 == c2 && !c1.deep_eq(c2) =>
+                {
+                    return true; // `OR 1!=2`
+                }
+                (Some(PostgresToken::Keyword(kw)), _) | (_, Some(PostgresToken::Keyword(kw))) => {
+                    match iter.peek() {
+                        Some(PostgresToken::Symbol('(')) => (),
+                        _ => {
+                            if !kw.is_reserved() {
+                                return false;
+                            }
+                        }
+                    };
+                }
+                (Some(PostgresToken::Identifier(_)), _)
+                | (_, Some(PostgresToken::Identifier(_))) => {
+                    match iter.peek() {
+                        Some(PostgresToken::Symbol('(')) => (), // Found a function (still not a legitimate variable)
+                        _ => return false,
+                    }
+                }
+                _ => (),
+            };
+        }
+        Some(PostgresToken::Keyword(kw)) => {
+            match iter.peek() {
+                Some(PostgresToken::Symbol('(')) => (),
+                _ => {
+                    if !kw.is_reserved() {
+                        return false;
+                    }
+                }
+            };
+        }
+        Some(PostgresToken::Identifier(_)) => {
+            match iter.peek() {
+                Some(PostgresToken::Symbol('(')) => (), // Found a function (still not a legitimate variable)
+                _ => return false,
+            }
+        }
+        _ => (),
+    };
+
+    // If the entire rest of the query has no more Identifiers, it would
+    // mean that the OR (or AND) expression is based on values that are constant at the
+    // time they are passed in (meaning that it would evaluate to always true/always false,
+    // rather than conditionally true based on values in a particular column/table).
+    while let Some(token) = iter.next() {
+        match token {
+            PostgresToken::Identifier(_) => {
+                match iter.peek() {
+                    Some(PostgresToken::Symbol('(')) => (), // Found a function (still not a legitimate variable)
+                    _ => return false,
+                }
+            }
+            PostgresToken::Keyword(kw) => {
+                match iter.peek() {
+                    Some(PostgresToken::Symbol('(')) => (), // Found a function (still not a legitimate variable)
+                    _ => {
+                        if !kw.is_reserved() {
+                            return false;
+                        }
+                    }
+                };
+            }
+            // We can't know whether or not a non-reserved keyword is acutally an identifier, so we treat it as such
+            _ => (),
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detect(query: &str, params: &Parameters) -> Option<Rule> {
+        let tokens = PostgresToken::scan_forward(query);
+        PostgresDetector::detect_injection(tokens.iter(), params).map(|detection| detection.rule)
+    }
+
+    #[test]
+    fn quoted_metadata_names() {
+        let params = Parameters::default_prefix_suffix();
+        for query in [
+            "SELECT * FROM \"pg_catalog\".\"pg_class\"",
+            "SELECT * FROM information_schema.\"tables\"",
+            "SELECT usename FROM pg_shadow",
+        ] {
+            assert_eq!(
+                detect(query, &params),
+                Some(Rule::MetadataAccess),
+                "{query}"
+            );
+        }
+        for query in [
+            "SELECT * FROM \"users\" WHERE \"name\" = 'pg_class'",
+            "SELECT * FROM public.pg_classes",
+            "SELECT \"a\".\"b\" FROM \"a\"",
+        ] {
+            assert_eq!(detect(query, &params), None, "{query}");
+        }
+    }
+
+    #[test]
+    fn quoted_qualified_and_heavy_time_delays() {
+        let params = Parameters {
+            disallow_heavy_queries: true,
+            ..Parameters::default_prefix()
+        };
+        for query in [
+            "SELECT \"pg_sleep\"(5)",
+            "SELECT pg_catalog.\"pg_sleep\"(5)",
+            "SELECT \"pg_catalog\".\"pg_sleep_for\" ('5 seconds')",
+            "SELECT PG_SLEEP_UNTIL(now() + '5 seconds')",
+            "SELECT * FROM generate_series(1, 100000000)",
+            "SELECT * FROM generate_series(1, 1e8)",
+        ] {
+            assert_eq!(detect(query, &params), Some(Rule::TimeDelay), "{query}");
+        }
+        for query in [
+            "SELECT pg_sleep FROM t",
+            "SELECT \"pg_sleep\" FROM t",
+            "SELECT * FROM generate_series(1, 10)",
+        ] {
+            assert_eq!(detect(query, &params), None, "{query}");
+        }
+    }
+
+    #[test]
+    fn file_access_command_execution_and_network_access() {
+        let params = Parameters::default_prefix_suffix();
+        for (query, rule) in [
+            ("SELECT pg_read_file('/etc/passwd')", Rule::FileAccess),
+            ("COPY t FROM '/etc/passwd'", Rule::FileAccess),
+            ("COPY t TO PROGRAM 'id'", Rule::CommandExecution),
+            ("CREATE EXTENSION plpython3u", Rule::CommandExecution),
+            (
+                "ALTER SYSTEM SET archive_command = 'id'",
+                Rule::CommandExecution,
+            ),
+            (
+                "SELECT dblink_connect('host=example.com')",
+                Rule::NetworkAccess,
+            ),
+        ] {
+            assert_eq!(detect(query, &params), Some(rule), "{query}");
+        }
+        for query in [
+            "SELECT pg_read_file, dblink FROM t",
+            "COPY t FROM STDIN",
+            "COPY t TO STDOUT",
+            "CREATE TABLE t (a int)",
+            "ALTER TABLE t ADD COLUMN b int",
+        ] {
+            assert_eq!(detect(query, &params), None, "{query}");
+        }
+    }
+}
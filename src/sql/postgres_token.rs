@@ -1,27 +1,48 @@
-use crate::sql;
+use crate::sql::*;
 use phf::phf_map;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::hash::{Hash, Hasher};
+use std::iter::Peekable;
+use std::ops::Range;
+use std::str::CharIndices;
 
-// Currently just a clone of CockroachToken
+// Lexical structure described here: https://www.postgresql.org/docs/current/sql-syntax-lexical.html
+// And the scanner itself: https://github.com/postgres/postgres/blob/master/src/backend/parser/scan.l
 
-#[derive(Hash, Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum PostgresToken {
     UnknownToken(char),
-    Identifier(String),
-    Sconst(String),
-    Bconst(String),
-    Bitconst(String),
-    Iconst(String),
-    Fconst(String),
-    Placeholder(String),
+    Identifier(String), // Unquoted identifiers begin with a letter or underscore, followed by alphanumerics, underscores or dollar signs. Double-quoted identifiers preserve their contents.
+    SingleQuote,
+    DoubleQuote,
+    DollarQuote(String),
+    StringPrefix(String), // Escape (E'..'), Unicode escape (U&'..' or U&".."), bit-string (B'..' or X'..') and national (N'..') prefixes
+    Const(String),
+    Placeholder(String), // looks like $1
     Keyword(Keyword),
-    Symbol(char),
-    LineComment(String),
-    BlockComment(String),
+    Symbol(char),     // Single-character operators and punctuation
+    Operator(String), // Operators made up of more than one character, such as `@@`, `~*` or `->>`
+    Escape,           // A backslash, which escapes the next character within escape (E'..') strings
+    LineComment,
+    BlockCommentOpen,
+    BlockCommentClose,
+    Comment(String),
     Whitespace(char),
 }
 
+/// A quoted string, quoted identifier or comment that is being condensed into a single token.
+struct Layer {
+    /// The index of the token that opened the layer
+    open_idx: usize,
+    /// How many block comments are open (block comments can be nested)
+    depth: usize,
+    /// Whether backslashes escape the character that follows them
+    backslash_escapes: bool,
+}
+
 // These are all accepted keywords
-#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug, Display, Serialize, Deserialize)]
 pub enum Keyword {
     Analyze,
     Analyse,
@@ -584,6 +605,93 @@ pub enum Keyword {
     GeneratedByDefault,
 }
 
+impl Keyword {
+    pub fn is_reserved(&self) -> bool {
+        matches!(
+            self,
+            Keyword::All
+                | Keyword::Analyze
+                | Keyword::Analyse
+                | Keyword::And
+                | Keyword::Any
+                | Keyword::Array
+                | Keyword::As
+                | Keyword::Asc
+                | Keyword::Asymmetric
+                | Keyword::Both
+                | Keyword::Case
+                | Keyword::Cast
+                | Keyword::Check
+                | Keyword::Collate
+                | Keyword::Column
+                | Keyword::Concurrently
+                | Keyword::Constraint
+                | Keyword::Create
+                | Keyword::CurrentCatalog
+                | Keyword::CurrentDate
+                | Keyword::CurrentRole
+                | Keyword::CurrentSchema
+                | Keyword::CurrentTime
+                | Keyword::CurrentTimestamp
+                | Keyword::CurrentUser
+                | Keyword::Default
+                | Keyword::Deferrable
+                | Keyword::Desc
+                | Keyword::Distinct
+                | Keyword::Do
+                | Keyword::Else
+                | Keyword::End
+                | Keyword::Except
+                | Keyword::False
+                | Keyword::Fetch
+                | Keyword::For
+                | Keyword::Foreign
+                | Keyword::From
+                | Keyword::Grant
+                | Keyword::Group
+                | Keyword::Having
+                | Keyword::In
+                | Keyword::Initially
+                | Keyword::Intersect
+                | Keyword::Into
+                | Keyword::Lateral
+                | Keyword::Leading
+                | Keyword::Limit
+                | Keyword::Localtime
+                | Keyword::Localtimestamp
+                | Keyword::Not
+                | Keyword::Null
+                | Keyword::Offset
+                | Keyword::On
+                | Keyword::Only
+                | Keyword::Or
+                | Keyword::Order
+                | Keyword::Placing
+                | Keyword::Primary
+                | Keyword::References
+                | Keyword::Returning
+                | Keyword::Select
+                | Keyword::SessionUser
+                | Keyword::Some
+                | Keyword::Symmetric
+                | Keyword::Table
+                | Keyword::Then
+                | Keyword::To
+                | Keyword::Trailing
+                | Keyword::True
+                | Keyword::Union
+                | Keyword::Unique
+                | Keyword::User
+                | Keyword::Using
+                | Keyword::Variadic
+                | Keyword::When
+                | Keyword::Where
+                | Keyword::Window
+                | Keyword::With
+        )
+    }
+}
+
 static KEYWORDS: phf::Map<&'static str, PostgresToken> = phf_map! {
     "ANALYZE" => PostgresToken::Keyword(Keyword::Analyze),
     "ANALYSE" => PostgresToken::Keyword(Keyword::Analyse),
@@ -1146,422 +1254,591 @@ static KEYWORDS: phf::Map<&'static str, PostgresToken> = phf_map! {
     "GENERATED_BY_DEFAULT" => PostgresToken::Keyword(Keyword::GeneratedByDefault)
 };
 
-// And today's award for strangest code goes to...
 impl PartialEq for PostgresToken {
     fn eq(&self, other: &Self) -> bool {
-        match self {
-            PostgresToken::UnknownToken(u1) => match other {
-                PostgresToken::UnknownToken(u2) => u1 == u2,
-                _ => false,
-            },
-            PostgresToken::Identifier(i1) => match other {
-                PostgresToken::Identifier(i2) => i1 == i2,
-                _ => false,
-            },
-            PostgresToken::Sconst(_) => match other {
-                PostgresToken::Sconst(_) => true,
-                _ => false,
-            },
-            PostgresToken::Bconst(_) => match other {
-                PostgresToken::Bconst(_) => true,
-                _ => false,
-            },
-            PostgresToken::Bitconst(_) => match other {
-                PostgresToken::Bitconst(_) => true,
-                _ => false,
-            },
-            PostgresToken::Iconst(_) => match other {
-                PostgresToken::Iconst(_) => true,
-                _ => false,
-            },
-            PostgresToken::Fconst(_) => match other {
-                PostgresToken::Fconst(_) => true,
-                _ => false,
-            },
-            PostgresToken::Placeholder(p1) => match other {
-                PostgresToken::Placeholder(p2) => p1 == p2,
-                _ => false,
-            },
-            PostgresToken::Keyword(k1) => match other {
-                PostgresToken::Keyword(k2) => k1 == k2,
-                _ => false,
-            },
-            PostgresToken::LineComment(l1) => match other {
-                PostgresToken::LineComment(l2) => l1 == l2,
-                _ => false,
-            },
-            PostgresToken::BlockComment(b1) => match other {
-                PostgresToken::BlockComment(b2) => b1 == b2,
-                _ => false,
-            },
-            PostgresToken::Symbol(c1) => match other {
-                PostgresToken::Symbol(c2) => c1 == c2,
-                _ => false,
-            },
-            PostgresToken::Whitespace(c1) => match other {
-                PostgresToken::Whitespace(c2) => c1 == c2,
-                _ => false,
-            },
+        match (self, other) {
+            (PostgresToken::UnknownToken(c1), PostgresToken::UnknownToken(c2))
+            | (PostgresToken::Symbol(c1), PostgresToken::Symbol(c2))
+            | (PostgresToken::Whitespace(c1), PostgresToken::Whitespace(c2)) => c1 == c2,
+            (PostgresToken::Identifier(s1), PostgresToken::Identifier(s2))
+            | (PostgresToken::DollarQuote(s1), PostgresToken::DollarQuote(s2))
+            | (PostgresToken::StringPrefix(s1), PostgresToken::StringPrefix(s2))
+            | (PostgresToken::Placeholder(s1), PostgresToken::Placeholder(s2))
+            | (PostgresToken::Operator(s1), PostgresToken::Operator(s2))
+            | (PostgresToken::Comment(s1), PostgresToken::Comment(s2)) => s1 == s2,
+            (PostgresToken::Keyword(k1), PostgresToken::Keyword(k2)) => k1 == k2,
+            (PostgresToken::Const(_), PostgresToken::Const(_)) // Contents are compared in deep_eq() instead
+            | (PostgresToken::SingleQuote, PostgresToken::SingleQuote)
+            | (PostgresToken::DoubleQuote, PostgresToken::DoubleQuote)
+            | (PostgresToken::Escape, PostgresToken::Escape)
+            | (PostgresToken::LineComment, PostgresToken::LineComment)
+            | (PostgresToken::BlockCommentOpen, PostgresToken::BlockCommentOpen)
+            | (PostgresToken::BlockCommentClose, PostgresToken::BlockCommentClose) => true,
+            _ => false,
         }
     }
 }
 
 impl Eq for PostgresToken {}
 
-impl sql::Token for PostgresToken {
+impl Hash for PostgresToken {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            PostgresToken::UnknownToken(c)
+            | PostgresToken::Symbol(c)
+            | PostgresToken::Whitespace(c) => c.hash(state),
+            PostgresToken::Identifier(s)
+            | PostgresToken::DollarQuote(s)
+            | PostgresToken::StringPrefix(s)
+            | PostgresToken::Placeholder(s)
+            | PostgresToken::Operator(s)
+            | PostgresToken::Comment(s) => s.hash(state),
+            PostgresToken::Keyword(k) => k.hash(state),
+            PostgresToken::Const(_) => (), // Don't want to hash this--use deep_eq instead
+            _ => (),
+        }
+    }
+}
+
+impl Display for PostgresToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PostgresToken::UnknownToken(c)
+            | PostgresToken::Whitespace(c)
+            | PostgresToken::Symbol(c) => write!(f, "{}", c),
+            PostgresToken::Identifier(s)
+            | PostgresToken::StringPrefix(s)
+            | PostgresToken::Const(s)
+            | PostgresToken::Operator(s)
+            | PostgresToken::Comment(s) => write!(f, "{}", s),
+            PostgresToken::SingleQuote => write!(f, "'"),
+            PostgresToken::DoubleQuote => write!(f, "\""),
+            PostgresToken::DollarQuote(tag) => write!(f, "${}$", tag),
+            PostgresToken::Placeholder(s) => write!(f, "${}", s),
+            PostgresToken::Escape => write!(f, "\\"),
+            PostgresToken::LineComment => write!(f, "--"),
+            PostgresToken::BlockCommentOpen => write!(f, "/*"),
+            PostgresToken::BlockCommentClose => write!(f, "*/"),
+            PostgresToken::Keyword(k) => write!(f, "{}", k),
+        }
+    }
+}
+
+impl Token for PostgresToken {
+    fn scan_forward(query: &str) -> Vec<(Self, usize)> {
+        Self::scan_with_parameters(query)
+    }
+
+    fn scan_reverse(query: &str) -> Vec<(Self, usize)> {
+        // Whether a quote or comment delimiter opens anything depends on what precedes it (such as a quote within a line
+        // comment, an `E` prefix allowing backslash escapes, or an unterminated string), so the tokens are found by a
+        // forward scan and then reversed. This keeps both directions in agreement, which suffix matching relies on.
+        let mut tokens = Self::scan_with_parameters(query);
+        tokens.reverse();
+        tokens
+    }
+
+    fn token_offsets(query: &str) -> Vec<Range<usize>> {
+        PostgresToken::scan_without_parameters(query)
+            .into_iter()
+            .map(|(_, start, end)| start..end)
+            .collect()
+    }
+
     fn deep_eq(&self, other: &Self) -> bool {
         (self == other)
-            && match self {
-                PostgresToken::Sconst(s1)
-                | PostgresToken::Bconst(s1)
-                | PostgresToken::Bitconst(s1)
-                | PostgresToken::Iconst(s1)
-                | PostgresToken::Fconst(s1) => match other {
-                    PostgresToken::Sconst(s2)
-                    | PostgresToken::Bconst(s2)
-                    | PostgresToken::Bitconst(s2)
-                    | PostgresToken::Iconst(s2)
-                    | PostgresToken::Fconst(s2) => s1 == s2,
-                    _ => false,
-                },
+            && match (self, other) {
+                (PostgresToken::Const(s1), PostgresToken::Const(s2)) => s1 == s2,
                 _ => true,
             }
     }
 
     fn is_param_token(&self) -> bool {
-        match self {
-            PostgresToken::Sconst(_)
-            | PostgresToken::Bconst(_)
-            | PostgresToken::Bitconst(_)
-            | PostgresToken::Iconst(_)
-            | PostgresToken::Fconst(_) => true,
-            _ => false,
-        }
+        matches!(self, PostgresToken::Const(_))
     }
 
-    fn scan_forward(query: &str) -> Vec<Self> {
+    fn is_whitespace(&self) -> bool {
+        matches!(self, PostgresToken::Whitespace(_))
+    }
+}
+
+impl PostgresToken {
+    /// Scans the query into tokens without accounting for quoted or commented-out portions.
+    ///
+    /// Each token is returned along with the byte range it spans in the query.
+    fn scan_without_parameters(query: &str) -> Vec<(Self, usize, usize)> {
+        let mut iter = query.char_indices().peekable();
         let mut tokens = vec![];
-        let mut chars: Vec<char> = vec![];
-        let mut iter = query.chars().peekable();
 
-        while let Some(c) = iter.next() {
-            tokens.push(match (c, iter.peek()) {
+        while let Some((start, c)) = iter.next() {
+            let token = match (c, iter.peek().map(|(_, p)| *p)) {
                 ('-', Some('-')) => {
                     iter.next(); // consume '-'
-                    let mut comment = String::new();
-                    while let Some(c) = iter.next() {
-                        comment.push(c); // TODO: there's got to be some better way to do this?
-                    }
-                    PostgresToken::LineComment(comment)
+                    PostgresToken::LineComment
                 }
                 ('/', Some('*')) => {
                     iter.next(); // consume '*'
-                    match_block_comment(&mut iter)
-                }
-                ('\'', _) => match_apostraphe(&mut iter),
-                ('"', _) => match_identifier_quot(&mut iter),
-                ('b', Some('\'')) => {
-                    iter.next(); // consume '\''
-                    match_bconst(&mut iter)
+                    PostgresToken::BlockCommentOpen
                 }
-                ('B', Some('\'')) => {
-                    iter.next(); // consume '\''
-                    match_bitconst(&mut iter)
-                }
-                ('x', Some('\'')) => {
-                    iter.next(); // consume '\''
-                    match_iconst_x(&mut iter)
+                ('*', Some('/')) => {
+                    iter.next(); // consume '/'
+                    PostgresToken::BlockCommentClose
                 }
+                ('\'', _) => PostgresToken::SingleQuote,
+                ('"', _) => PostgresToken::DoubleQuote,
+                ('\\', _) => PostgresToken::Escape,
                 ('.', Some('0'..='9')) => match_fconst_period(&mut iter, vec!['.']),
-                ('$', Some(p)) => match_dollar_opening(&mut iter),
+                ('$', Some('0'..='9')) => match_placeholder(&mut iter),
+                ('$', Some(_)) => match_dollar_opening(&mut iter),
                 ('$', None) => PostgresToken::UnknownToken(c),
-                ('_', _) => match_kw_id(&mut iter, vec!['_']),
+                ('0', Some('x' | 'X')) => match_const_radix(&mut iter, c, 16),
+                ('0', Some('o' | 'O')) => match_const_radix(&mut iter, c, 8),
+                ('0', Some('b' | 'B')) => match_const_radix(&mut iter, c, 2),
+                ('0'..='9', _) => match_const_digit(&mut iter, vec![c]),
+                ('e' | 'E' | 'b' | 'B' | 'x' | 'X' | 'n' | 'N', Some('\'')) => {
+                    PostgresToken::StringPrefix(c.to_ascii_uppercase().to_string())
+                }
+                ('u' | 'U', Some('&')) => match_unicode_prefix(&mut iter),
+                // "SQL identifiers and key words must begin with a letter (a-z, but also letters with diacritical marks and non-Latin letters), or an underscore"
+                (c, _) if c == '_' || c.is_alphabetic() => {
+                    match_kw_id(&mut iter, vec![c.to_ascii_uppercase()])
+                }
                 (
-                    '/' | '-' | '^' | ';' | '(' | ')' | '@' | ',' | '=' | '*' | '+' | '~' | '%'
-                    | '#' | '&' | '|' | '<' | '>' | '?' | '[' | ']' | '{' | '}' | ':' | '.',
+                    '~' | '!' | '@' | '#' | '^' | '&' | '|' | '`' | '?' | '+' | '-' | '*' | '/'
+                    | '%' | '<' | '>' | '=',
                     _,
-                ) => PostgresToken::Symbol(c),
-                (' ' | '\t' | '\r' | '\n', _) => PostgresToken::Whitespace(c),
-                ('0', Some('x')) => {
-                    iter.next();
-                    match_iconst_0x(&mut iter)
-                }
-                ('0'..='9', _) => match_const_digit(&mut iter, vec![c]),
-                (c, _) => {
-                    if c.is_alphabetic() {
-                        match_kw_id(&mut iter, vec![c])
-                    // "SQL identifiers and key words must begin with a letter (a-z, but also letters with diacritical marks and non-Latin letters)
-                    // or an underscore". Rip.
-                    } else {
-                        PostgresToken::UnknownToken(c) // Not alphanumeric, and not any of the special chars we listed above: must be a Weasley
-                    }
+                ) => match_operator(&mut iter, c),
+                ('(' | ')' | ',' | ';' | '[' | ']' | '{' | '}' | ':' | '.', _) => {
+                    PostgresToken::Symbol(c)
                 }
-            });
+                (' ' | '\t' | '\r' | '\n' | '\x0c', _) => PostgresToken::Whitespace(c),
+                // Not alphanumeric, and not any of the special chars we listed above: must be a Weasley
+                (c, _) => PostgresToken::UnknownToken(c),
+            };
+
+            let end = match iter.peek() {
+                Some((idx, _)) => *idx,
+                None => query.len(),
+            };
+            tokens.push((token, start, end));
         }
 
         tokens
     }
-}
 
-fn read_until(iter: &mut std::iter::Peekable<std::str::Chars>, c: char) -> String {
-    let mut chars = vec![];
+    /// Takes any instances of quoted parameters (such as apostraphe-quoted, like 'param', or dollar-quoted, like $$param$$ or $label$param$label$) and condenses their contents into a single 'Const' token.
+    ///
+    /// This function also condenses block comments and line comments, as well as double-quoted identifiers.
+    /// It takes into account the nesting of block comments, doubled quotes and backslash escapes within escape (E'..') strings.
+    fn scan_with_parameters(query: &str) -> Vec<(Self, usize)> {
+        let tokens = PostgresToken::scan_without_parameters(query);
+        let mut norm_tokens: Vec<(Self, usize)> = Vec::new();
+        let mut layer: Option<Layer> = None;
 
-    while let Some(n) = iter.next() {
-        match n {
-            c => return chars.into_iter().collect::<String>(),
-            _ => {
-                chars.push(n);
-            }
-        };
-    }
+        let mut idx = 0;
+        while idx < tokens.len() {
+            let next_token = &tokens[idx].0;
+            let token_idx = idx;
+            idx += 1;
 
-    chars.into_iter().collect::<String>()
-}
+            let current = match layer.as_mut() {
+                Some(current) => current,
+                None => {
+                    match next_token {
+                        PostgresToken::SingleQuote => {
+                            // Backslashes are only escapes in strings prefixed with `E` (standard_conforming_strings)
+                            let is_escape_string = matches!(
+                                token_idx.checked_sub(1).map(|i| &tokens[i].0),
+                                Some(PostgresToken::StringPrefix(p)) if p == "E"
+                            );
+                            layer = Some(Layer {
+                                open_idx: token_idx,
+                                depth: 1,
+                                backslash_escapes: is_escape_string,
+                            })
+                        }
+                        PostgresToken::DoubleQuote
+                        | PostgresToken::DollarQuote(_)
+                        | PostgresToken::BlockCommentOpen
+                        | PostgresToken::LineComment => {
+                            layer = Some(Layer {
+                                open_idx: token_idx,
+                                depth: 1,
+                                backslash_escapes: false,
+                            })
+                        }
+                        _ => (),
+                    }
+                    norm_tokens.push((next_token.clone(), token_idx));
+                    continue;
+                }
+            };
 
-fn match_block_comment(iter: &mut std::iter::Peekable<std::str::Chars>) -> PostgresToken {
-    let mut chars = vec!['/', '*'];
-    let mut nested_depth = 1;
-
-    while let Some(c) = iter.next() {
-        match (c, iter.peek()) {
-            ('/', Some('*')) => {
-                nested_depth += 1; // Another nested '/*'
-                iter.next(); // Consume '*'
-                chars.push('/');
-                chars.push('*');
-            }
-            ('*', Some('/')) => {
-                if nested_depth > 1 {
-                    nested_depth -= 1;
-                    iter.next(); // Consume '/'
-                    chars.push('*');
-                    chars.push('/');
-                } else {
-                    iter.next(); // Consume '/'
-                    return PostgresToken::BlockComment(chars.into_iter().collect::<String>());
+            let is_closed = match (&tokens[current.open_idx].0, next_token) {
+                (PostgresToken::SingleQuote, PostgresToken::Escape)
+                    if current.backslash_escapes =>
+                {
+                    idx += 1; // The escaped token is part of the string's contents
+                    false
                 }
+                (PostgresToken::SingleQuote, PostgresToken::SingleQuote)
+                | (PostgresToken::DoubleQuote, PostgresToken::DoubleQuote) => {
+                    if idx < tokens.len() && tokens[idx].0 == *next_token {
+                        idx += 1; // A doubled quote is an escaped quote
+                        false
+                    } else {
+                        true
+                    }
+                }
+                (PostgresToken::DollarQuote(d1), PostgresToken::DollarQuote(d2)) => d1 == d2,
+                (PostgresToken::BlockCommentOpen, PostgresToken::BlockCommentOpen) => {
+                    current.depth += 1; // Block comments can be nested
+                    false
+                }
+                (PostgresToken::BlockCommentOpen, PostgresToken::BlockCommentClose) => {
+                    current.depth -= 1;
+                    current.depth == 0
+                }
+                (PostgresToken::LineComment, PostgresToken::Whitespace('\n' | '\r')) => true,
+                _ => false,
+            };
+
+            if is_closed {
+                let open_idx = current.open_idx;
+                let contents = query[tokens[open_idx].2..tokens[token_idx].1].to_string();
+                norm_tokens.push((Self::condense(&tokens[open_idx].0, contents), open_idx + 1));
+                norm_tokens.push((next_token.clone(), token_idx));
+                layer = None;
             }
-            _ => {
-                chars.push(c);
-            }
-        };
+        }
+
+        // If there was no closing quote or comment, then just output the Const/Identifier/Comment anyway
+        if let Some(current) = layer {
+            let open_idx = current.open_idx;
+            let contents = query[tokens[open_idx].2..].to_string();
+            norm_tokens.push((Self::condense(&tokens[open_idx].0, contents), open_idx + 1));
+        }
+
+        norm_tokens
     }
 
-    // Block comment did not have proper closing
-    PostgresToken::BlockComment(chars.into_iter().collect::<String>())
+    /// Returns the token that the contents of a quote or comment opened by `opening_token` are condensed into.
+    fn condense(opening_token: &PostgresToken, contents: String) -> PostgresToken {
+        match opening_token {
+            PostgresToken::SingleQuote | PostgresToken::DollarQuote(_) => {
+                PostgresToken::Const(contents)
+            }
+            PostgresToken::DoubleQuote => PostgresToken::Identifier(contents),
+            _ => PostgresToken::Comment(contents),
+        }
+    }
 }
 
-fn match_apostraphe(iter: &mut std::iter::Peekable<std::str::Chars>) -> PostgresToken {
-    let mut chars = vec![];
+fn match_kw_id(iter: &mut Peekable<CharIndices>, mut chars: Vec<char>) -> PostgresToken {
+    while let Some((_, p)) = iter.peek().copied() {
+        if !p.is_alphabetic() && !p.is_ascii_digit() && p != '_' && p != '$' {
+            break;
+        }
 
-    while let (Some(c), p) = (iter.next(), iter.peek()) {
-        match (c, p) {
-            ('\'' | '\\', Some('\'')) => {
-                chars.push(c);
-                chars.push('\'');
-                iter.next();
-            }
-            ('\'', _) => {
-                return PostgresToken::Sconst(chars.into_iter().collect::<String>());
+        if p == '$' {
+            // Identifiers can contain dollar signs, but the identifier ends if they begin a dollar quote (e.g. `a$$`),
+            // as otherwise the end of a dollar-quoted string like `$$...a$$` would be missed
+            let mut lookahead = iter.clone();
+            lookahead.next();
+            if match_dollar_quote_tag(&mut lookahead).is_some() {
+                break;
             }
-            _ => {
-                chars.push(c);
-            }
-        };
-    }
+        }
 
-    PostgresToken::Sconst(chars.into_iter().collect::<String>())
-}
+        chars.push(p.to_ascii_uppercase());
+        iter.next();
+    }
 
-fn match_bconst(iter: &mut std::iter::Peekable<std::str::Chars>) -> PostgresToken {
-    PostgresToken::Bconst(read_until(iter, '\''))
+    let identifier = chars.into_iter().collect::<String>();
+    match KEYWORDS.get(&identifier) {
+        Some(token) => token.clone(),
+        None => PostgresToken::Identifier(identifier),
+    }
 }
 
-fn match_bitconst(iter: &mut std::iter::Peekable<std::str::Chars>) -> PostgresToken {
-    PostgresToken::Bitconst(read_until(iter, '\''))
+/// Matches what follows a `U`, which is either a Unicode escape prefix (`U&'..'` or `U&".."`) or a keyword/identifier.
+fn match_unicode_prefix(iter: &mut Peekable<CharIndices>) -> PostgresToken {
+    let mut lookahead = iter.clone();
+    lookahead.next(); // '&'
+    match lookahead.peek() {
+        Some((_, '\'' | '"')) => {
+            iter.next(); // consume '&'
+            PostgresToken::StringPrefix("U&".to_string())
+        }
+        _ => match_kw_id(iter, vec!['U']),
+    }
 }
 
-fn match_identifier_quot(iter: &mut std::iter::Peekable<std::str::Chars>) -> PostgresToken {
-    let mut chars = vec![];
+/// Matches an operator, following the rules PostgreSQL uses to decide where an operator ends:
+/// - `--` and `/*` can't appear within an operator, as they begin comments
+/// - A multiple-character operator can only end in `+` or `-` if it contains at least one of ``~ ! @ # % ^ & | ` ?``
+fn match_operator(iter: &mut Peekable<CharIndices>, first: char) -> PostgresToken {
+    let mut chars = vec![first];
+    let mut lookahead = iter.clone();
+    while let Some((_, p)) = lookahead.peek().copied() {
+        if !matches!(
+            p,
+            '~' | '!'
+                | '@'
+                | '#'
+                | '^'
+                | '&'
+                | '|'
+                | '`'
+                | '?'
+                | '+'
+                | '-'
+                | '*'
+                | '/'
+                | '%'
+                | '<'
+                | '>'
+                | '='
+        ) {
+            break;
+        }
 
-    while let (Some(c), p) = (iter.next(), iter.peek()) {
-        match (c, p) {
-            ('"', Some('"')) => {
-                // TODO: does '\\"' count here as well?
-                chars.push(c);
-                chars.push('"');
-                iter.next();
-            }
-            ('"', _) => {
-                return PostgresToken::Identifier(chars.into_iter().collect::<String>());
-            }
-            _ => {
-                chars.push(c);
+        lookahead.next();
+        match (chars.last(), p, lookahead.peek()) {
+            (Some('-'), '-', _) | (Some('/'), '*', _) => {
+                chars.pop(); // The comment began with the previous character
+                break;
             }
-        };
+            (_, '-', Some((_, '-'))) | (_, '/', Some((_, '*'))) => break,
+            _ => chars.push(p),
+        }
     }
 
-    PostgresToken::Sconst(chars.into_iter().collect::<String>())
-}
+    if chars.is_empty() {
+        // Only possible if the operator's first character began a comment, which the caller already checks for
+        return PostgresToken::Symbol(first);
+    }
 
-fn match_kw_id(
-    iter: &mut std::iter::Peekable<std::str::Chars>,
-    mut chars: Vec<char>,
-) -> PostgresToken {
-    while let Some(p) = iter.peek() {
-        if !p.is_alphabetic() && !p.is_ascii_digit() && *p != '_' && *p != '$' {
-            break;
+    if chars.len() > 1
+        && !chars
+            .iter()
+            .any(|c| matches!(c, '~' | '!' | '@' | '#' | '%' | '^' | '&' | '|' | '`' | '?'))
+    {
+        while chars.len() > 1 && matches!(chars.last(), Some('+' | '-')) {
+            chars.pop();
         }
-        chars.push(p.to_ascii_uppercase());
+    }
+
+    // The first character was already consumed by the caller
+    for _ in 1..chars.len() {
         iter.next();
     }
 
-    let identifier = chars.into_iter().collect::<String>();
-    match KEYWORDS.get(&identifier) {
-        Some(token) => token.clone(),
-        None => PostgresToken::Identifier(identifier),
+    match chars.len() {
+        1 => PostgresToken::Symbol(first),
+        _ => PostgresToken::Operator(chars.into_iter().collect::<String>()),
     }
 }
 
-fn match_iconst(
-    iter: &mut std::iter::Peekable<std::str::Chars>,
-    mut chars: Vec<char>,
-) -> PostgresToken {
-    while let Some(p @ '0'..='9') = iter.peek() {
-        chars.push(p.clone());
-        iter.next();
+fn match_const_radix(iter: &mut Peekable<CharIndices>, first: char, radix: u32) -> PostgresToken {
+    let mut chars = vec![first];
+    if let Some((_, prefix)) = iter.next() {
+        chars.push(prefix);
     }
 
-    PostgresToken::Iconst(chars.into_iter().collect::<String>())
-}
+    while let Some((_, p)) = iter.peek() {
+        if !p.is_digit(radix) && *p != '_' {
+            break;
+        }
+        chars.push(*p);
+        iter.next();
+    }
 
-fn match_iconst_x(iter: &mut std::iter::Peekable<std::str::Chars>) -> PostgresToken {
-    PostgresToken::Iconst(read_until(iter, '\'')) // iconst x isn't supposed to escape \' or ''
+    PostgresToken::Const(chars.into_iter().collect::<String>())
 }
 
-fn match_iconst_0x(iter: &mut std::iter::Peekable<std::str::Chars>) -> PostgresToken {
-    let mut chars = vec![];
-
-    while let Some(n @ ('0'..='9' | 'a'..='f' | 'A'..='F')) = iter.peek() {
-        chars.push(n.clone());
+fn match_fconst_period(iter: &mut Peekable<CharIndices>, mut chars: Vec<char>) -> PostgresToken {
+    while let Some((_, p @ ('0'..='9' | '_'))) = iter.peek() {
+        chars.push(*p);
         iter.next();
     }
 
-    PostgresToken::Iconst(chars.into_iter().collect::<String>())
+    match_fconst_e(iter, chars)
 }
 
-fn match_fconst_period(
-    iter: &mut std::iter::Peekable<std::str::Chars>,
-    mut chars: Vec<char>,
-) -> PostgresToken {
-    while let Some(p) = iter.peek().copied() {
-        match p {
-            '0'..='9' => {
-                chars.push(p);
+fn match_fconst_e(iter: &mut Peekable<CharIndices>, mut chars: Vec<char>) -> PostgresToken {
+    if let Some((_, e @ ('e' | 'E'))) = iter.peek().copied() {
+        let mut lookahead = iter.clone();
+        lookahead.next();
+        let sign = match lookahead.peek() {
+            Some((_, s @ ('+' | '-'))) => {
+                let s = *s;
+                lookahead.next();
+                Some(s)
+            }
+            _ => None,
+        };
+
+        // Only an exponent if digits follow (otherwise the 'e' is the start of something else)
+        if let Some((_, '0'..='9')) = lookahead.peek() {
+            chars.push(e);
+            iter.next();
+            if let Some(s) = sign {
+                chars.push(s);
                 iter.next();
             }
-            'e' => {
-                chars.push(p);
+            while let Some((_, p @ '0'..='9')) = iter.peek() {
+                chars.push(*p);
                 iter.next();
-                match iter.peek() {
-                    Some(sign @ ('+' | '-')) => {
-                        chars.push(sign.clone());
-                        iter.next();
-                        return match_fconst_e(iter, chars);
-                    }
-                    Some('0'..='9') => return match_fconst_e(iter, chars),
-                    Some(_) | None => break,
-                };
             }
-            _ => break,
-        };
+        }
     }
 
-    PostgresToken::Fconst(chars.into_iter().collect::<String>())
+    PostgresToken::Const(chars.into_iter().collect::<String>())
 }
 
-fn match_fconst_e(
-    iter: &mut std::iter::Peekable<std::str::Chars>,
-    mut chars: Vec<char>,
-) -> PostgresToken {
-    while let Some(p @ '0'..='9') = iter.peek() {
-        chars.push(p.clone());
+fn match_const_digit(iter: &mut Peekable<CharIndices>, mut chars: Vec<char>) -> PostgresToken {
+    while let Some((_, p @ ('0'..='9' | '_'))) = iter.peek() {
+        chars.push(*p);
         iter.next();
     }
 
-    PostgresToken::Fconst(chars.into_iter().collect::<String>())
+    let mut lookahead = iter.clone();
+    match (lookahead.next(), lookahead.peek()) {
+        // `1..10` is an integer followed by the `..` range operator (used in PL/pgSQL), not a float
+        (Some((_, '.')), Some((_, '.'))) => PostgresToken::Const(chars.into_iter().collect()),
+        (Some((_, '.')), _) => {
+            chars.push('.');
+            iter.next();
+            match_fconst_period(iter, chars)
+        }
+        _ => match_fconst_e(iter, chars),
+    }
 }
 
-fn match_placeholder(iter: &mut std::iter::Peekable<std::str::Chars>) -> PostgresToken {
+fn match_placeholder(iter: &mut Peekable<CharIndices>) -> PostgresToken {
     let mut chars: Vec<char> = vec![];
 
-    while let Some(p @ ('0'..='9')) = iter.peek() {
-        chars.push(p.clone());
+    while let Some((_, p @ '0'..='9')) = iter.peek() {
+        chars.push(*p);
         iter.next();
     }
 
     PostgresToken::Placeholder(chars.into_iter().collect::<String>())
 }
 
-fn match_dollar_opening(iter: &mut std::iter::Peekable<std::str::Chars>) -> PostgresToken {
-    let mut ident = vec![];
-    while let Some(p @ ('a'..='z' | 'A'..='Z' | '0'..='9' | '_')) = iter.peek() {
-        ident.push(p.clone());
-        iter.next();
+fn match_dollar_opening(iter: &mut Peekable<CharIndices>) -> PostgresToken {
+    match match_dollar_quote_tag(iter) {
+        Some(tag) => PostgresToken::DollarQuote(tag),
+        None => PostgresToken::UnknownToken('$'),
     }
+}
 
-    match iter.peek() {
-        Some('$') => {
-            iter.next();
-            match_dollar_sconst(iter, ident.into_iter().collect::<String>())
+/// Matches the tag of a dollar quote (the `tag$` of `$tag$`) that follows an opening `$`, only advancing `iter` if one is found.
+fn match_dollar_quote_tag(iter: &mut Peekable<CharIndices>) -> Option<String> {
+    // Dollar-quote tags follow the same rules as identifiers, except that they can't contain a dollar sign
+    let mut lookahead = iter.clone();
+    let mut tag = vec![];
+    while let Some((_, p)) = lookahead.peek() {
+        if !p.is_alphabetic() && !p.is_ascii_digit() && *p != '_' {
+            break;
+        }
+        tag.push(*p);
+        lookahead.next();
+    }
+
+    match lookahead.peek() {
+        Some((_, '$')) if !matches!(tag.first(), Some('0'..='9')) => {
+            lookahead.next();
+            *iter = lookahead;
+            Some(tag.into_iter().collect::<String>())
         }
-        _ => PostgresToken::Placeholder(ident.into_iter().collect::<String>()), // TODO: this is an invalid placeholder
+        _ => None,
     }
 }
 
-fn match_dollar_sconst(
-    iter: &mut std::iter::Peekable<std::str::Chars>,
-    mut identifier: String,
-) -> PostgresToken {
-    let mut chars: Vec<char> = vec![];
-    let mut id_index = None;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    identifier.push('$');
+    /// Asserts that a reverse scan finds the same tokens (and indices) as a forward scan, in reverse order.
+    fn assert_scans_agree(query: &str) {
+        let forward = PostgresToken::scan_forward(query);
+        let mut reverse = PostgresToken::scan_reverse(query);
+        reverse.reverse();
+        assert_eq!(forward.len(), reverse.len(), "{query:?}");
+        for ((f, f_idx), (r, r_idx)) in forward.iter().zip(&reverse) {
+            assert!(f.deep_eq(r) && f_idx == r_idx, "{query:?}: {f:?} != {r:?}");
+        }
+    }
 
-    while let Some(p @ ('a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '$')) = iter.peek() {
-        match id_index {
-            Some(i) => match chars.get(i) {
-                Some(c) => id_index = if p == c { Some(i + 1) } else { None },
-                None => {
-                    return PostgresToken::Sconst(
-                        chars[..chars.len() - i].iter().collect::<String>(),
-                    )
-                }
-            },
-            None => id_index = if *p == '$' { Some(0) } else { None },
-        };
+    /// Returns the contents of each string constant found by a forward scan.
+    fn strings(query: &str) -> Vec<String> {
+        let tokens = PostgresToken::scan_forward(query);
+        tokens
+            .windows(2)
+            .filter_map(|pair| match pair {
+                [(PostgresToken::SingleQuote, _), (PostgresToken::Const(s), _)] => Some(s.clone()),
+                _ => None,
+            })
+            .collect()
+    }
 
-        chars.push(p.clone());
-        iter.next();
+    #[test]
+    fn backslashes_only_escape_in_escape_strings() {
+        for query in [
+            "SELECT 'it\\'s'",
+            "SELECT * FROM t WHERE a LIKE '%\\_%' ESCAPE '\\'",
+            "SELECT 'C:\\' FROM t",
+            "SELECT E'it\\'s' FROM t",
+            "SELECT e'C:\\\\', 'C:\\' FROM t",
+        ] {
+            assert_scans_agree(query);
+        }
+
+        assert_eq!(strings("SELECT 'C:\\' FROM t"), vec!["C:\\"]);
+        assert_eq!(
+            strings("SELECT a LIKE '%\\_%' ESCAPE '\\'"),
+            vec!["%\\_%", "\\"]
+        );
+        assert_eq!(strings("SELECT E'it\\'s', 'b'"), vec!["it\\'s", "b"]);
     }
 
-    PostgresToken::Sconst(chars.into_iter().collect::<String>())
-}
+    #[test]
+    fn quotes_in_line_comments_do_not_open_strings() {
+        assert_scans_agree("SELECT 1 -- it's\nFROM t WHERE a = 'b'");
+        assert_scans_agree("SELECT 1 -- 'x");
+        assert_eq!(strings("SELECT 1 -- it's\nFROM t WHERE a = 'b'"), vec!["b"]);
+    }
 
-fn match_const_digit(
-    iter: &mut std::iter::Peekable<std::str::Chars>,
-    mut chars: Vec<char>,
-) -> PostgresToken {
-    while let Some(p @ '0'..='9') = iter.peek() {
-        chars.push(p.clone());
-        iter.next();
+    #[test]
+    fn block_comments_nest() {
+        let query = "SELECT /* a /* b */ c */ 1";
+        assert_scans_agree(query);
+        let tokens = PostgresToken::scan_forward(query);
+        assert!(tokens[3]
+            .0
+            .deep_eq(&PostgresToken::Comment(" a /* b */ c ".to_string())));
     }
 
-    return match iter.peek() {
-        Some('.') => {
-            chars.push('.');
-            match_fconst_period(iter, chars)
+    #[test]
+    fn unterminated_quotes_and_comments() {
+        for query in [
+            "SELECT 'abc",
+            "SELECT \"abc",
+            "SELECT $$abc",
+            "SELECT 1 /* a /* b */",
+            "SELECT 'a' /* 'b",
+            "SELECT E'abc\\'",
+        ] {
+            assert_scans_agree(query);
         }
-        Some(_) | None => PostgresToken::Iconst(chars.into_iter().collect::<String>()),
-    };
+    }
+
+    #[test]
+    fn doubled_quotes_and_dollar_quotes() {
+        assert_scans_agree("SELECT 'it''s', \"a\"\"b\", $x$ it's $$ $x$ FROM t");
+        assert_eq!(strings("SELECT 'it''s'"), vec!["it''s"]);
+    }
 }
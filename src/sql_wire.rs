@@ -5,7 +5,7 @@ mod mysql_packet;
 mod postgres_packet;
 mod wire_reader;
 
-use std::io;
+use std::{cmp, io};
 
 // Requests and responses have a 1-to-1 mapping
 
@@ -14,18 +14,29 @@ pub struct PacketInfo {
     pub username: Option<String>,
     /// If set, indicates that the given database should be used for subsequent SQL queries.
     pub database: Option<String>,
-    /// If set, indicates that the message contains the given SQL query that will be executed by the SQL server
+    /// If set, indicates that the message contains the given SQL query that will be executed by the SQL server.
+    /// A query may be carried by a message that is not itself a request (such as a Postgres `Parse` message);
+    /// in that case, the query's outcome is reported in the result of the next message that has `is_request` set.
     pub query: Option<String>,
     /// If true, indicates that the message is requesting information from the other side that should be met with a corresponding 'result' message.
+    /// Any messages received since the last request (and not themselves requests) form part of this request.
     pub is_request: bool,
+    /// If true, the message is complete in and of itself: it neither expects a result nor forms part of the next request
+    /// (such as a MySQL `COM_STMT_CLOSE` command, or a packet exchanged during authentication).
+    pub is_standalone: bool,
     /// If set, indicates that the message is a definitive result for a corresponding request in the message stream with either a successful (true) or failed (false) outcome.
     pub result: Option<bool>,
+    /// If true, the message reports an error that will be followed by the result of the request it belongs to
+    /// (such as a Postgres `ErrorResponse`, which precedes `ReadyForQuery`).
+    pub is_error: bool,
     /// If true, the given packet is attempting to indicate or request SSL encryption support with the other side.
     pub ssl_requested: bool,
     /// If true, the given packet is attempting to indicate or request GSSAPI encryption support with the other side.
     pub gssenc_requested: bool,
     /// If true, the protocol requested by the given packet is not supported by the current version of this library.
     pub unsupported_version: bool,
+    /// If true, the message ends the session (such as a Postgres `FATAL` error), so the connection is closed once it has been sent.
+    pub ends_session: bool,
 }
 
 impl PacketInfo {
@@ -36,14 +47,38 @@ impl PacketInfo {
             database: None,
             query: None,
             is_request: false,
+            is_standalone: false,
             result: None,
+            is_error: false,
             ssl_requested: false,
             gssenc_requested: false,
             unsupported_version: false,
+            ends_session: false,
         }
     }
 }
 
+/// The contents of an error reported to the frontend.
+#[derive(Clone)]
+pub struct ErrorFields {
+    /// The severity of the error (reported by Postgres only)
+    pub severity: String,
+    pub sql_state: [u8; 5],
+    /// The error number (reported by MySQL only)
+    pub code: u16,
+    pub message: String,
+    pub detail: Option<String>,
+    pub hint: Option<String>,
+}
+
+impl ErrorFields {
+    /// Returns `true` if the error ends the client's session (as `FATAL` and `PANIC` errors do in Postgres), in which
+    /// case the connection is closed once the error has been sent.
+    pub fn ends_session(&self) -> bool {
+        self.severity != "ERROR"
+    }
+}
+
 pub trait ClientPacket {
     fn get_basic_info<'a>(&'a self) -> &'a PacketInfo;
 
@@ -116,15 +151,27 @@ pub trait Proxy<C: io::Read + io::Write, S: io::Read + io::Write> {
 
     fn get_frontend_io_ref(&self) -> &S;
 
+    fn get_backend_io_mut(&mut self) -> &mut C;
+
+    fn get_frontend_io_mut(&mut self) -> &mut S;
+
     fn frontend_downgrade_ssl(
         &mut self,
         ssl_request: &mut Self::RequestType,
     ) -> Option<Self::ResponseType>;
 
-    fn backend_downgrade_ssl(
+    /// Accepts the frontend's request to encrypt the session, returning the response that tells it to begin a TLS handshake.
+    fn frontend_accept_ssl(
         &mut self,
-        ssl_response: &mut Self::ResponseType,
-    ) -> Option<Self::RequestType>;
+        ssl_request: &mut Self::RequestType,
+    ) -> io::Result<Self::ResponseType>;
+
+    /// Creates the request asking the backend to encrypt the session, which is sent before any request from the frontend.
+    fn backend_request_ssl(&mut self) -> io::Result<Self::RequestType>;
+
+    /// Receives the backend's answer to the request created by `backend_request_ssl()`, returning `true` if it
+    /// agreed to begin a TLS handshake.
+    fn backend_receive_ssl_response(&mut self) -> io::Result<bool>;
 
     fn frontend_downgrade_gssenc(
         &mut self,
@@ -136,5 +183,116 @@ pub trait Proxy<C: io::Read + io::Write, S: io::Read + io::Write> {
         proto_request: &mut Self::ResponseType,
     ) -> Option<Self::RequestType>;
 
-    fn error_response(&mut self) -> Self::ResponseType;
+    /// If the backend is within a transaction block, creates a request that fails without side effects. Forwarding it
+    /// in place of a blocked request fails the backend's transaction, just as the frontend is told by `error_response()`.
+    fn transaction_abort_request(&mut self) -> Option<Self::RequestType>;
+
+    /// Creates a message that fails the multi-message request being forwarded (such as a Postgres extended-query
+    /// batch) without side effects of its own. Sent ahead of the message that completes a blocked request whose
+    /// earlier messages already reached the backend, it makes the backend undo them rather than commit them.
+    fn batch_abort_request(&mut self) -> Option<Self::RequestType>;
+
+    /// Creates the error sent to the frontend in place of a blocked request's result. Fields that the wire protocol
+    /// has no place for are either folded into the message or left out.
+    fn error_response(&mut self, error: &ErrorFields) -> Self::ResponseType;
+}
+
+/// Reads from `io` until the first `pkt_len` bytes of `buf` are filled, growing `buf` as needed.
+/// `buf_len` tracks how many bytes have been read so far, so that an interrupted read (such as by `WouldBlock`) may be resumed.
+fn read_packet<'a, T: io::Read>(
+    io: &mut T,
+    buf: &'a mut Vec<u8>,
+    buf_len: &mut usize, // 0 <= buf_len <= buf.len()
+    pkt_len: usize,      // Could be *anything*--passed in from client
+) -> io::Result<&'a mut [u8]> {
+    let mut buffer_length = buf.len();
+    let mut truncated_end_idx = cmp::min(pkt_len, buffer_length);
+
+    if *buf_len > buf.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Internal error while reading in packet--write index exceeded buffer length",
+        ));
+    } // Thus, *buf_len <= buf.len()
+
+    loop {
+        while let Some(remaining_buffer) = buf.get_mut(*buf_len..truncated_end_idx) {
+            if remaining_buffer.len() == 0 {
+                break;
+            }
+
+            match io.read(remaining_buffer) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        "I/O device unexpectedly closed while reading data",
+                    ))
+                }
+                Ok(len) => {
+                    *buf_len += len; // Invariant: *buf_len + len <= buf.len() because we're reading into a buffer of size (buf.len() - *buf_len) or less
+                    log::debug!("Read {} bytes into buffer", len);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        if *buf_len >= pkt_len {
+            break;
+        } else {
+            log::debug!("Packet contents exceeded available space--increasing buffer size");
+            buf.extend(std::iter::repeat(0).take(cmp::min(buffer_length, pkt_len - buffer_length))); // We need more buffer--at most double the current one
+
+            buffer_length = buf.len();
+            truncated_end_idx = cmp::min(pkt_len, buffer_length);
+        }
+    }
+
+    return Ok(&mut buf[..pkt_len]); // Invariant: pkt_len <= *buf_len <= buf.len(), so this will never index out of bounds
+}
+
+fn advance_up_to<'a, T>(buf: &'a [T], amount: usize) -> &'a [T] {
+    match buf.get(amount..) {
+        Some(b) => b,
+        None => &mut [],
+    }
+}
+
+/// Writes out the given packet, starting from the offset stored in `start` (if any).
+/// If the write is interrupted (such as by `WouldBlock`), `start` records how much has been written so that the write may be resumed.
+fn write_packet<T: io::Write>(
+    io: &mut T,
+    packet: &[u8],
+    start: &mut Option<usize>,
+) -> io::Result<()> {
+    let mut total_written = start.unwrap_or(0);
+
+    let mut buf = match packet.get(total_written..) {
+        Some(b) => b,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unexpected packet sent out of order (invalid length)",
+            ))
+        }
+    };
+
+    while !buf.is_empty() {
+        let num_written = match io.write(buf) {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "I/O device unexpectedly closed while writing data",
+                ))
+            }
+            Ok(num_written) => num_written,
+            Err(e) => return Err(e),
+        };
+
+        total_written += num_written;
+        *start = Some(total_written);
+        buf = advance_up_to(buf, num_written);
+    }
+
+    *start = None;
+    Ok(())
 }
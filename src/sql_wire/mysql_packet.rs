@@ -1,668 +1,557 @@
-use std::collections::HashMap;
+use std::borrow::Cow;
 use std::cmp;
-use std::ops;
+use std::collections::HashMap;
 
 use super::wire_reader::WireReader;
 
+/// The number of bytes in the header of every MySQL packet (3-byte payload length followed by a 1-byte sequence ID)
+pub const HEADER_LEN: usize = 4;
+/// The largest payload a single packet can carry. A packet with a payload of exactly this length is continued in the next packet.
+pub const MAX_PAYLOAD_LEN: usize = 0xFFFFFF;
+
 pub enum RequestPacket<'a> {
+    /// The client's response to the server's initial handshake (CLIENT_PROTOCOL_41).
     ///
-    /// 
-    /// Fields: capability_flags, max_packet_size, charset, username, auth_response, database, auth_plugin_name, client_connect_attrs
-    HandshakeResponse41(u32, u32, u8,&'a str, &'a [u8], Option<&'a str>, Option<&'a str>, Option<HashMap<&'a str, &'a str>>, u8),
+    /// Fields: capability_flags, max_packet_size, charset, username, auth_response, database, auth_plugin_name, client_connect_attrs, zstd_compression_level
+    HandshakeResponse41(u32, u32, u8, &'a str, &'a [u8], Option<&'a str>, Option<&'a str>, Option<HashMap<&'a str, &'a str>>, Option<u8>),
+    /// The client's response to the server's initial handshake (pre-4.1 protocol).
     ///
-    /// 
     /// Fields: capability_flags, max_packet_size, username, auth_response, database
     HandshakeResponse320(u32, u32, &'a str, &'a [u8], Option<&'a str>),
+    /// A truncated handshake response that requests the rest of the session be encrypted with SSL.
     ///
-    /// 
     /// Fields: capability flags (CLIENT_SSL set), max_packet_size, charset
     SSLRequest(u32, u32, Option<u8>),
-    ///
-    /// 
-    /// Fields: auth_plugin_response
-    AuthSwitchResponse(&'a [u8]),
-    ///
-    /// 
-    /// Fields: plugin_name, plugin_data
-    AuthNextFactor(&'a str, &'a [u8]),
-    ///
-    /// 
     /// Fields: query, (parameter_type_and_flag, parameter_name, parameter_value)
-    ComQuery(&'a str, Vec<(u16, &'a str, &'a [u8])>),
-    ///
+    ComQuery(Cow<'a, str>, Vec<(u16, &'a str, Option<&'a [u8]>)>),
     ComQuit,
-    ///
-    /// 
     /// Fields: schema_name
     ComInitDb(&'a str),
-    ///
-    /// 
     /// Fields: table, wildcard
-    ComFieldList(&'a str, &'a str),
+    ComFieldList(&'a str, &'a [u8]),
+    /// Fields: sub_command
     ComRefresh(u8),
     ComStatistics,
     ComProcessInfo,
-    ///
-    /// 
     /// Fields: connection_id
     ComProcessKill(u32),
     ComDebug,
     ComPing,
-    ///
-    /// 
-    /// Fields: username, auth_plugin_data, database, charset, auth_plugin_name connection_attrs // TODO: are connection_attrs strings or bytes?
-    ComChangeUser(&'a str, &'a [u8], &'a str, Option<u16>, Option<&'a str>, HashMap<&'a str, &'a str>),
+    /// Fields: username, auth_plugin_data, database
+    ComChangeUser(&'a str, &'a [u8], Option<&'a str>),
     ComResetConnection,
-    ///
-    /// 
     /// Fields: option_operation
     ComSetOption(u16),
-    ///
-    /// 
     /// Fields: query
-    ComStmtPrepare(&'a str),
+    ComStmtPrepare(Cow<'a, str>),
+    /// The parameters of the statement aren't parsed, as their layout depends on the prepared statement's metadata.
+    ///
+    /// Fields: statement_id, flags, iteration_count
+    ComStmtExecute(u32, u8, u32),
+    /// Fields: statement_id, param_id, data
+    ComStmtSendLongData(u32, u16, &'a [u8]),
+    /// Fields: statement_id
+    ComStmtClose(u32),
+    /// Fields: statement_id
+    ComStmtReset(u32),
+    /// Fields: statement_id, num_rows
+    ComStmtFetch(u32, u32),
+    /// Replication commands, which switch the connection to a stream of binlog events.
     ///
-    /// 
-    /// Fields: statement_id, flags, iteration_count, (null_bit, parameter_type, parameter_name, parameter_value)
-    ComStmtExecute(u32, u8, u32, Vec<(bool, u16, &'a str, &'a [u8])>),
+    /// Fields: command
+    ComBinlog(u8),
+    /// Deprecated or internal commands that the server answers with a single OK or ERR packet.
+    ///
+    /// Fields: command
+    ComOther(u8),
 }
 
 pub enum ResponsePacket<'a> {
-    ///
-    /// 
-    /// Fields: server_version, connection_id, auth_plugin_data_part_1, capability_flags_1, charset, status_flags, capability_flags_2, auth_plugin_data_part_2, auth_plugin_name
+    /// Fields: server_version, connection_id, auth_plugin_data_part_1, capability_flags, charset, status_flags, auth_plugin_data_part_2, auth_plugin_name
     HandshakeV10(&'a str, u32, &'a [u8; 8], u32, u8, u16, &'a [u8], Option<&'a str>),
-    ///
-    /// 
-    /// Fields: server_version, connection_id, auth_data_stramble
+    /// Fields: server_version, connection_id, auth_data_scramble
     HandshakeV9(&'a str, u32, &'a str),
-    ///
-    /// 
     /// Fields: plugin_name, auth_plugin_data
     AuthSwitchRequest(&'a str, &'a [u8]),
     OldAuthSwitchRequest,
-    ///
-    /// 
     /// Fields: additional_authentication_data
     AuthMoreData(&'a [u8]),
-    ///
-    /// 
-    /// Fields: plugin_name, plugin_data
-    AuthNextFactor(&'a str, &'a [u8]),
-    ///
-    /// 
-    /// Fields: affected_rows, last_insert_id, status_flags, warnings, status_info, session_state_info
-    OKPacket(u64, u64, Option<u16>, Option<u16>, &'a str, Option<&'a str>),
-    ///
-    /// 
-    /// Fields: error_code, (sql_state_marker, sql_state), error_message
-    ERRPacket(u16, Option<(&'a str, &'a str)>, &'a str),
-    ///
-    /// 
-    /// Fields: catalog, schema, table, org_table, name, org_name, charset, column_length, column_type, flags, max_decimal_digits
-    ColumnDefinition41(&'a str, &'a str, &'a str, &'a str, &'a str, &'a str, u16, u32, u8, u16, u8),
-    ///
-    /// 
-    /// Fields: table, name, column_type, flags, decimals, default_values
-    ColumnDefinition320(&'a str, &'a str, u8, u16, u8, Option<&'a str>),
-    ///
-    /// 
-    /// Fields: statement_id, num_columns, num_params, warning_count, metadata_follows
-    ComStmtPrepareOk(u32, u16, u16, Option<u16>, Option<bool>),
+    /// Fields: affected_rows, last_insert_id, status_flags, warnings, info
+    OKPacket(u64, u64, Option<u16>, Option<u16>, &'a [u8]),
+    /// Fields: error_code, sql_state, error_message
+    ERRPacket(u16, Option<&'a str>, Cow<'a, str>),
+    /// Fields: warnings, status_flags
+    EOFPacket(Option<u16>, Option<u16>),
+    /// Fields: filename
+    LocalInfileRequest(&'a [u8]),
+    /// Fields: statement_id, num_columns, num_params, warning_count
+    ComStmtPrepareOk(u32, u16, u16, Option<u16>),
+}
+
+#[allow(dead_code)]
+pub mod capabilities {
+    pub const LONG_PASSWORD: u32 = 1 << 0;
+    pub const FOUND_ROWS: u32 = 1 << 1;
+    pub const LONG_FLAG: u32 = 1 << 2;
+    pub const CONNECT_WITH_DB: u32 = 1 << 3;
+    pub const NO_SCHEMA: u32 = 1 << 4;
+    pub const COMPRESS: u32 = 1 << 5;
+    pub const ODBC: u32 = 1 << 6;
+    pub const LOCAL_FILES: u32 = 1 << 7;
+    pub const IGNORE_SPACE: u32 = 1 << 8;
+    pub const PROTOCOL_41: u32 = 1 << 9;
+    pub const INTERACTIVE: u32 = 1 << 10;
+    pub const SSL: u32 = 1 << 11;
+    pub const IGNORE_SIGPIPE: u32 = 1 << 12;
+    pub const TRANSACTIONS: u32 = 1 << 13;
+    pub const RESERVED: u32 = 1 << 14;
+    pub const SECURE_CONNECTION: u32 = 1 << 15;
+    pub const MULTI_STATEMENTS: u32 = 1 << 16;
+    pub const MULTI_RESULTS: u32 = 1 << 17;
+    pub const PS_MULTI_RESULTS: u32 = 1 << 18;
+    pub const PLUGIN_AUTH: u32 = 1 << 19;
+    pub const CONNECT_ATTRS: u32 = 1 << 20;
+    pub const PLUGIN_AUTH_LENENC_CLIENT_DATA: u32 = 1 << 21;
+    pub const CAN_HANDLE_EXPIRED_PASSWORDS: u32 = 1 << 22;
+    pub const SESSION_TRACK: u32 = 1 << 23;
+    pub const DEPRECATE_EOF: u32 = 1 << 24;
+    pub const OPTIONAL_RESULTSET_METADATA: u32 = 1 << 25;
+    pub const ZSTD_COMPRESSION_ALGORITHM: u32 = 1 << 26;
+    pub const QUERY_ATTRIBUTES: u32 = 1 << 27;
+    pub const MULTI_FACTOR_AUTHENTICATION: u32 = 1 << 28;
+    pub const CAPABILITY_EXTENSION: u32 = 1 << 29;
+    pub const SSL_VERIFY_SERVER_CERT: u32 = 1 << 30;
+    pub const REMEMBER_OPTIONS: u32 = 1 << 31;
 }
 
-pub mod ClientCapabilities {
-pub const LONG_PASSWORD: u32 = 1 << 0;
-pub const FOUND_ROWS: u32 = 1 << 1;
-pub const LONG_FLAG:u32 = 1 << 2;
-pub const CONNECT_WITH_DB: u32 = 1 << 3;
-pub const NO_SCHEMA: u32 = 1 << 4;
-pub const COMPRESS: u32 = 1 << 5;
-pub const ODBC: u32 = 1 << 6;
-pub const LOCAL_FILES: u32 = 1 << 7;
-pub const IGNORE_SPACE: u32 = 1 << 8;
-pub const PROTOCOL_41: u32 = 1 << 9;
-pub const INTERACTIVE: u32 = 1 << 10;
-pub const SSL: u32 = 1 << 11;
-pub const IGNORE_SIGPIPE: u32 = 1 << 12;
-pub const TRANSACTIONS: u32 = 1 << 13;
-pub const RESERVED: u32 = 1 << 14;
-pub const RESERVED2: u32 = 1 << 15;
-pub const MULTI_STATEMENTS: u32 = 1 << 16;
-pub const MULTI_RESULTS: u32 = 1 << 17;
-pub const PS_MULTI_RESULTS: u32 = 1 << 18;
-pub const PLUGIN_AUTH: u32 = 1 << 19;
-pub const CONNECT_ATTRS: u32 = 1 << 20;
-pub const PLUGIN_AUTH_LENENC_CLIENT_DATA: u32 = 1 << 21;
-pub const CAN_HANDLE_EXPIRED_PASSWORDS: u32 = 1 << 22;
-pub const SESSION_TRACK: u32 = 1 << 23;
-pub const DEPRECATE_EOF: u32 = 1 << 24;
-pub const OPTIONAL_RESULTSET_METADATA: u32 = 1 << 25;
-pub const ZSTD_COMPRESSION_ALGORITHM: u32 = 1 << 26;
-pub const QUERY_ATTRIBUTES: u32 = 1 << 27;
-pub const MULTI_FACTOR_AUTHENTICATION: u32 = 1 << 28;
-pub const CAPABILITY_EXTENSION: u32 = 1 << 29;
-pub const SSL_VERIFY_SERVER_CERT: u32 = 1 << 30;
-pub const REMEMBER_OPTIONS: u32 = 1 << 31;
+#[allow(dead_code)]
+pub mod server_status {
+    pub const IN_TRANS: u16 = 1 << 0;
+    pub const AUTOCOMMIT: u16 = 1 << 1;
+    pub const MORE_RESULTS_EXISTS: u16 = 1 << 3;
+    pub const CURSOR_EXISTS: u16 = 1 << 6;
+    pub const LAST_ROW_SENT: u16 = 1 << 7;
+    pub const IN_TRANS_READONLY: u16 = 1 << 13;
+    pub const SESSION_STATE_CHANGED: u16 = 1 << 14;
 }
 
-#[derive(PartialEq, Eq)]
-pub enum TransactionStatus {
-    /// Not in a transaction block
-    Idle,
-    /// Currently in a transaction block
-    Transaction,
-    /// In a failed transaction block (queries will be rejected until block is ended)
-    FailedTransaction,
+/// Returns the payload length and sequence ID found in the header of a packet.
+pub fn read_header(buffer: &[u8]) -> Result<(usize, u8), &'static str> {
+    let mut reader = WireReader::new(buffer);
+    Ok((reader.read_u32_3byte()? as usize, reader.read()?))
+}
+
+/// Writes a packet header containing the given payload length and sequence ID into `buffer`.
+pub fn write_header(buffer: &mut [u8; HEADER_LEN], payload_len: usize, sequence_id: u8) {
+    buffer[..3].copy_from_slice(&(payload_len as u32).to_le_bytes()[..3]);
+    buffer[3] = sequence_id;
 }
 
 pub fn read_lenenc_integer<'a>(reader: &mut WireReader<'a>) -> Result<u64, &'static str> {
     let first_byte: u8 = reader.read()?;
     match first_byte {
-        0..=0xFB => Ok(first_byte as u64),
-        OxFC => Ok(reader.read::<u16>()? as u64),
+        0..=0xFA => Ok(first_byte as u64),
+        0xFC => Ok(reader.read_le::<u16>()? as u64),
         0xFD => Ok(reader.read_u32_3byte()? as u64),
-        0xFE => Ok(reader.read::<u64>()?),
-        0xFF => Err("invalid length-encoded integer 0xFF")
+        0xFE => Ok(reader.read_le::<u64>()?),
+        _ => Err("invalid length-encoded integer"),
     }
 }
 
-pub fn read_header<'a>(
-    mut reader: WireReader<'a>,
-) -> Result<(u32, u8), &'static str> {
-    Ok((reader.read_u32_3byte()?, reader.read()?))
+pub fn read_lenenc_bytes<'a>(reader: &mut WireReader<'a>) -> Result<&'a [u8], &'static str> {
+    let length = read_lenenc_integer(reader)?;
+    match usize::try_from(length) {
+        Ok(l) => reader.read_bytes(l),
+        Err(_) => Err("length-encoded string exceeded maximum supported length"),
+    }
 }
 
-pub fn parse_server_handshake<'a>(buffer: &'a [u8]) -> Result<ResponsePacket, &'static str> {
-    let mut reader = WireReader::new(buffer);
-    reader.advance_up_to(4);
+fn read_lenenc_str<'a>(reader: &mut WireReader<'a>) -> Result<&'a str, &'static str> {
+    std::str::from_utf8(read_lenenc_bytes(reader)?)
+        .or(Err("invalid UTF-8 characters detected in field"))
+}
 
-    
-    let protocol_version = reader.read()?;
-    let server_version = reader.read_str()?;
-    let thread_id = reader.read()?;
+/// Reads a NUL-terminated string, or the remainder of the packet if no terminator is present
+/// (some server versions omit the terminator on the final field of a packet).
+fn read_str_or_remaining<'a>(reader: &mut WireReader<'a>) -> Result<&'a str, &'static str> {
+    match reader.read_bytes_term() {
+        Ok(bytes) => std::str::from_utf8(bytes),
+        Err(_) => std::str::from_utf8(reader.read_remaining_bytes()),
+    }
+    .or(Err("invalid UTF-8 characters detected in field"))
+}
 
+pub fn parse_server_handshake<'a>(payload: &'a [u8]) -> Result<ResponsePacket<'a>, &'static str> {
+    let mut reader = WireReader::new(payload);
+
+    let protocol_version: u8 = reader.read()?;
     match protocol_version {
-        9u8 => Ok(ResponsePacket::HandshakeV9(server_version, thread_id, reader.read_str_and_finalize()?)),
-        10u8 => {
-            let auth_data_part1 = reader.read_bytearray()?;
-            reader.advance_up_to(1); // empty 0x00 byte
-            let capabilities_flags_1: u32 = reader.read::<u16>()? as u32;
-            let charset = reader.read()?;
-            let status_flags = reader.read()?;
-            let capabilities_flags = (capabilities_flags_1) & ((reader.read::<u16>()? as u32) << 16);
-            
-            let auth_data_len: u8 = if (capabilities_flags & ClientCapabilities::PLUGIN_AUTH) != 0 {
-                reader.read()?
-            } else {
-                reader.advance_up_to(1);
-                0
-            };
-            
-            reader.advance_up_to(10); // Reserved field
-            let adjusted_auth_len = match auth_data_len.checked_sub(8) {
-                Some(l) => cmp::max(13, l as usize),
-                None => 13
-            };
+        0xFF => return Err("server refused connection during handshake"),
+        9 | 10 => (),
+        _ => return Err("unsupported protocol version in server handshake packet"),
+    }
 
-            let auth_data_part2 = reader.read_bytes(adjusted_auth_len)?;
-            let auth_plugin_name = if (capabilities_flags & ClientCapabilities::PLUGIN_AUTH) != 0 {
-                Some(reader.read_str()?)
-            } else {
-                None
-            };
-            reader.finalize()?;
+    let server_version = reader.read_str()?;
+    let connection_id = reader.read_le()?;
 
-            Ok(ResponsePacket::HandshakeV10(server_version, thread_id, auth_data_part1, capabilities_flags, charset, status_flags, auth_data_part2, auth_plugin_name))
-        },
-        _ => Err("unsupported protocol version in startup packet")
+    if protocol_version == 9 {
+        return Ok(ResponsePacket::HandshakeV9(server_version, connection_id, read_str_or_remaining(&mut reader)?));
     }
-}
 
-fn parse_client_handshake<'a>(buffer: &'a [u8]) -> Result<RequestPacket, &'static str> {
-    let mut reader = WireReader::new(buffer);
-    reader.advance_up_to(4);
+    let auth_data_part1 = reader.read_bytearray()?;
+    reader.advance_up_to(1); // filler byte
+    let capability_flags_1 = reader.read_le::<u16>()? as u32;
+    if reader.empty() {
+        // Older servers may end the packet here
+        return Ok(ResponsePacket::HandshakeV10(server_version, connection_id, auth_data_part1, capability_flags_1, 0, 0, &[], None));
+    }
 
-    let client_flags1: u32 = reader.read::<u16>()? as u32;
-    if (client_flags1 & ClientCapabilities::SSL) != 0 {
-        if (client_flags1 & ClientCapabilities::PROTOCOL_41) != 0 {
-            let capabilities_flags = (client_flags1) & ((reader.read::<u16>()? as u32) << 16);
-            Ok(RequestPacket::SSLRequest(capabilities_flags, reader.read()?, Some(reader.read_and_finalize()?)))
-        } else {
-            Ok(RequestPacket::SSLRequest(client_flags1, reader.read_u32_3byte_and_finalize()?, None))
-        }
-    } else if (client_flags1 & ClientCapabilities::PROTOCOL_41) != 0 {
-        let client_flags = (client_flags1) | ((reader.read::<u16>()? as u32) << 16);
-        let max_packet_size = reader.read::<u32>()?;
-        let charset = reader.read()?;
-        reader.advance_up_to(23);
-        let username = reader.read_str()?;
-        let resp_len = if (client_flags & ClientCapabilities::PLUGIN_AUTH_LENENC_CLIENT_DATA) != 0 {
-            read_lenenc_integer(&mut reader)?
-        } else {
-            reader.read::<u8>()? as u64
-        };
-        let auth_response = reader.read_bytes(resp_len as usize)?;
+    let charset = reader.read()?;
+    let status_flags = reader.read_le()?;
+    let capability_flags = capability_flags_1 | ((reader.read_le::<u16>()? as u32) << 16);
+    let auth_data_len: u8 = reader.read()?;
+    reader.advance_up_to(10); // Reserved field
 
-        let database = if (client_flags & ClientCapabilities::CONNECT_WITH_DB) != 0 {
-            Some(reader.read_str()?)
-        } else {
-            None
-        };
+    let auth_data_part2 = if (capability_flags & capabilities::SECURE_CONNECTION) != 0 {
+        let adjusted_auth_len = cmp::max(13, auth_data_len.saturating_sub(8) as usize);
+        reader.read_bytes(adjusted_auth_len)?
+    } else {
+        &[]
+    };
 
-        let client_plugin_name = if (client_flags & ClientCapabilities::PLUGIN_AUTH) != 0 {
-            Some(reader.read_str()?)
-        } else {
-            None
-        };
+    let auth_plugin_name = if (capability_flags & capabilities::PLUGIN_AUTH) != 0 {
+        Some(read_str_or_remaining(&mut reader)?)
+    } else {
+        None
+    };
 
-        let connect_attrs = if (client_flags & ClientCapabilities::CONNECT_ATTRS) != 0 {
-            let map_len = read_lenenc_integer(&mut reader)? as usize;
-            Some(reader.read_str_str_map_sized(map_len)?)
-        } else {
-            None
-        };
+    Ok(ResponsePacket::HandshakeV10(server_version, connection_id, auth_data_part1, capability_flags, charset, status_flags, auth_data_part2, auth_plugin_name))
+}
 
-        let zstd_level = reader.read_and_finalize()?;
-        Ok(RequestPacket::HandshakeResponse41(client_flags, max_packet_size, charset, username, auth_response, database, client_plugin_name, connect_attrs, zstd_level))
+pub fn parse_client_handshake<'a>(payload: &'a [u8]) -> Result<RequestPacket<'a>, &'static str> {
+    let mut reader = WireReader::new(payload);
 
-    } else { // CLIENT_PROTOCOL_320
+    let client_flags_1 = reader.read_le::<u16>()? as u32;
+    if (client_flags_1 & capabilities::PROTOCOL_41) == 0 {
+        // CLIENT_PROTOCOL_320
         let max_packet_size = reader.read_u32_3byte()?;
+        if (client_flags_1 & capabilities::SSL) != 0 && reader.empty() {
+            return Ok(RequestPacket::SSLRequest(client_flags_1, max_packet_size, None));
+        }
+
         let username = reader.read_str()?;
-        let (auth_response, database) = if (client_flags1 & ClientCapabilities::CONNECT_WITH_DB) != 0 {
-            (reader.read_bytes_term()?, Some(reader.read_str_and_finalize()?))
+        let (auth_response, database) = if (client_flags_1 & capabilities::CONNECT_WITH_DB) != 0 {
+            (reader.read_bytes_term()?, Some(read_str_or_remaining(&mut reader)?))
         } else {
-            (reader.read_bytes_term_and_finalize()?, None)
+            (reader.read_remaining_bytes(), None)
         };
-        Ok(RequestPacket::HandshakeResponse320(client_flags1, max_packet_size, username, auth_response, database))
+        return Ok(RequestPacket::HandshakeResponse320(client_flags_1, max_packet_size, username, auth_response, database));
     }
-}
-
-/* 
-pub fn parse_startup_req_packet<'a>(buffer: &'a [u8]) -> Result<RequestPacket, &'static str> {
-    let packet_length = read_startup_packet_len(buffer)?;
-    if buffer.len() != packet_length {
-        return Err("startup packet length field mismatch (internal error)");
-    }
-
-    let mut reader = WireReader::new(buffer);
-    reader.advance_up_to(4);
 
-    let protocol_field = reader.read_int32()?;
+    let client_flags = client_flags_1 | ((reader.read_le::<u16>()? as u32) << 16);
+    let max_packet_size = reader.read_le()?;
+    let charset = reader.read()?;
+    reader.read_bytes(23)?; // Filler
 
-    if (protocol_field == 80877103 || protocol_field == 80877104) && !reader.empty() {
-        return Err("startup packet contained more data than expected");
+    if (client_flags & capabilities::SSL) != 0 && reader.empty() {
+        return Ok(RequestPacket::SSLRequest(client_flags, max_packet_size, Some(charset)));
     }
 
-    let protocol_version = match protocol_field {
-        196608 => PostgresWireVersion::V3_0, // TODO: allow all minor versions to use this major version as well...?
-        80877102 => {
-            return Ok(RequestPacket::CancelRequest(
-                reader.read_int32()?,
-                reader.read_int32_and_finalize()?,
-            ))
-        },
-        80877103 => return Ok(RequestPacket::SSLRequest),
-        80877104 => return Ok(RequestPacket::GSSENCRequest),
-        _ => return Err("startup packet contained unrecognized protocol version"),
+    let username = reader.read_str()?;
+    let auth_response = if (client_flags & capabilities::PLUGIN_AUTH_LENENC_CLIENT_DATA) != 0 {
+        read_lenenc_bytes(&mut reader)?
+    } else if (client_flags & capabilities::SECURE_CONNECTION) != 0 {
+        let resp_len: u8 = reader.read()?;
+        reader.read_bytes(resp_len as usize)?
+    } else {
+        reader.read_bytes_term()?
     };
 
-    let params = reader.read_utf8_string_string_map()?;
-
-    if let Some(user) = params.get("user") {
-        Ok(RequestPacket::StartupMessage(
-            protocol_version,
-            user,
-            params,
-        ))
+    let database = if (client_flags & capabilities::CONNECT_WITH_DB) != 0 && !reader.empty() {
+        Some(reader.read_str()?)
     } else {
-        Err("startup packet missing required 'user' parameter")
-    }
-}
+        None
+    };
 
-pub fn parse_standard_req_packet<'a>(buffer: &'a [u8]) -> Result<RequestPacket, &'static str> {
-    let (packet_identifier, packet_length) = read_standard_packet_len(buffer)?;
-    if buffer.len() - 1 != packet_length {
-        return Err("request packet length field mismatch (internal error)");
-    }
+    let client_plugin_name = if (client_flags & capabilities::PLUGIN_AUTH) != 0 && !reader.empty() {
+        Some(read_str_or_remaining(&mut reader)?)
+    } else {
+        None
+    };
 
-    let mut reader = WireReader::new(buffer);
-    reader.advance_up_to(5);
+    let connect_attrs = if (client_flags & capabilities::CONNECT_ATTRS) != 0 && !reader.empty() {
+        let mut attrs_reader = WireReader::new(read_lenenc_bytes(&mut reader)?);
+        let mut attrs = HashMap::new();
+        while !attrs_reader.empty() {
+            let key = read_lenenc_str(&mut attrs_reader)?;
+            let value = read_lenenc_str(&mut attrs_reader)?;
+            attrs.insert(key, value);
+        }
+        Some(attrs)
+    } else {
+        None
+    };
 
-    match packet_identifier {
-        b'c' | b'H' | b'S' => reader.finalize()?,
-        _ => (),
-    }
+    let zstd_level = if (client_flags & capabilities::ZSTD_COMPRESSION_ALGORITHM) != 0 && !reader.empty() {
+        Some(reader.read()?)
+    } else {
+        None
+    };
+    reader.finalize()?;
 
-    match packet_identifier {
-        b'B' => parse_bind_packet(reader),
-        b'C' => match reader.read_byte()? {
-            b'S' => Ok(RequestPacket::ClosePrepared(
-                reader.read_utf8_c_str_and_finalize()?,
-            )),
-            b'P' => Ok(RequestPacket::ClosePortal(
-                reader.read_utf8_c_str_and_finalize()?,
-            )),
-            _ => Err("packet contained invalid Close type parameter"),
-        },
-        b'd' => Ok(RequestPacket::CopyData(reader.read_remaining_bytes())),
-        b'c' => Ok(RequestPacket::CopyDone),
-        b'f' => Ok(RequestPacket::CopyFail(
-            reader.read_utf8_c_str_and_finalize()?,
-        )),
-        b'D' => match reader.read_byte()? {
-            b'S' => Ok(RequestPacket::DescribePrepared(
-                reader.read_utf8_c_str_and_finalize()?,
-            )),
-            b'P' => Ok(RequestPacket::DescribePortal(
-                reader.read_utf8_c_str_and_finalize()?,
-            )),
-            _ => Err("packet contained invalid Describe type parameter"),
-        },
-        b'E' => Ok(RequestPacket::Execute(
-            reader.read_utf8_c_str()?,
-            reader.read_int32_and_finalize()?,
-        )),
-        b'H' => Ok(RequestPacket::Flush),
-        b'F' => parse_function_req_packet(reader),
-        b'p' => Ok(RequestPacket::AuthDataResponse(
-            reader.read_remaining_bytes(),
-        )),
-        b'P' => {
-            let prepared_stmt_name = reader.read_utf8_c_str()?;
-            let query_name = reader.read_utf8_c_str()?;
-            let parameters_cnt = reader.read_int16_length()?;
-            Ok(RequestPacket::Parse(
-                prepared_stmt_name,
-                query_name,
-                reader.read_int32_list_and_finalize(parameters_cnt)?,
-            ))
-        }
-        b'Q' => Ok(RequestPacket::Query(reader.read_utf8_c_str_and_finalize()?)),
-        b'S' => Ok(RequestPacket::Sync),
-        b'X' => Ok(RequestPacket::Terminate),
-        _ => Err("packet contained unrecognized packet identifier"),
-    }
+    Ok(RequestPacket::HandshakeResponse41(client_flags, max_packet_size, charset, username, auth_response, database, client_plugin_name, connect_attrs, zstd_level))
 }
 
-pub fn parse_standard_resp_packet<'a>(buffer: &'a [u8]) -> Result<ResponsePacket, &'static str> {
-    let (packet_identifier, packet_length) = read_standard_packet_len(buffer)?;
-    if buffer.len() - 1 != packet_length {
-        return Err("response packet length field mismatch (internal error)");
-    }
-    let mut reader = WireReader::new(buffer);
-    reader.advance_up_to(5);
+/// Parses a packet sent by the client during the command phase, using the capabilities negotiated during the handshake.
+pub fn parse_command<'a>(payload: &'a [u8], capability_flags: u32) -> Result<RequestPacket<'a>, &'static str> {
+    let mut reader = WireReader::new(payload);
+    let command: u8 = reader.read()?;
 
-    match packet_identifier {
-        b'2' | b'3' | b'c' | b'I' | b'n' | b'1' | b's' => reader.finalize()?,
+    match command {
+        0x01 | 0x09 | 0x0A | 0x0D | 0x0E | 0x1F => reader.finalize()?,
         _ => (),
     }
 
-    match packet_identifier {
-        b'K' => Ok(ResponsePacket::BackendKeyData(
-            reader.read_int32()?,
-            reader.read_int32_and_finalize()?,
-        )),
-        b'R' => parse_auth_resp_packet(reader),
-        b'2' => Ok(ResponsePacket::BindComplete),
-        b'3' => Ok(ResponsePacket::CloseComplete),
-        b'C' => Ok(ResponsePacket::CommandComplete(
-            reader.read_utf8_c_str_and_finalize()?,
-        )),
-        b'd' => Ok(ResponsePacket::CopyData(reader.read_remaining_bytes())),
-        b'c' => Ok(ResponsePacket::CopyDone),
-        b'G' => parse_copyin_response_packet(reader),
-        b'H' => parse_copyout_response_packet(reader),
-        b'W' => parse_copyboth_response_packet(reader),
-        b'D' => parse_datarow_response_packet(reader),
-        b'I' => Ok(ResponsePacket::EmptyQueryResponse),
-        b'E' => Ok(ResponsePacket::ErrorResponse(
-            reader.read_term_utf8_byte_string_map_and_finalize()?,
-        )),
-        b'V' => Ok(ResponsePacket::FunctionCallResponse(
-            match reader.read_nullable_int32_length()? {
-                Some(length) => Some(reader.read_bytes_and_finalize(length)?),
-                None => None,
-            },
-        )),
-        b'v' => {
-            let newest_minor_proto = reader.read_int32()?;
-            let options_cnt = reader.read_int32_length()?;
-            Ok(ResponsePacket::NegotiateProtocolVersion(
-                newest_minor_proto,
-                reader.read_utf8_c_strs_and_finalize(options_cnt)?,
-            ))
+    match command {
+        0x01 => Ok(RequestPacket::ComQuit),
+        0x02 => Ok(RequestPacket::ComInitDb(read_str_or_remaining(&mut reader)?)),
+        0x03 => parse_query_packet(reader, capability_flags),
+        0x04 => Ok(RequestPacket::ComFieldList(reader.read_str()?, reader.read_remaining_bytes())),
+        0x07 => Ok(RequestPacket::ComRefresh(reader.read_and_finalize()?)),
+        0x09 => Ok(RequestPacket::ComStatistics),
+        0x0A => Ok(RequestPacket::ComProcessInfo),
+        0x0C => {
+            let connection_id = reader.read_le()?;
+            reader.finalize()?;
+            Ok(RequestPacket::ComProcessKill(connection_id))
+        }
+        0x0D => Ok(RequestPacket::ComDebug),
+        0x0E => Ok(RequestPacket::ComPing),
+        0x11 => {
+            let username = reader.read_str()?;
+            let auth_data = if (capability_flags & capabilities::SECURE_CONNECTION) != 0 {
+                let auth_len: u8 = reader.read()?;
+                reader.read_bytes(auth_len as usize)?
+            } else {
+                reader.read_bytes_term()?
+            };
+            let database = if reader.empty() {
+                None
+            } else {
+                Some(read_str_or_remaining(&mut reader)?)
+            };
+            // Any remaining fields (charset, auth plugin name, connection attributes) aren't needed
+            Ok(RequestPacket::ComChangeUser(username, auth_data, database))
         }
-        b'n' => Ok(ResponsePacket::NoData),
-        b'N' => Ok(ResponsePacket::NoticeResponse(
-            reader.read_term_utf8_byte_string_map_and_finalize()?,
-        )),
-        b'A' => Ok(ResponsePacket::NotificationResponse(
-            reader.read_int32()?,
-            reader.read_utf8_c_str()?,
-            reader.read_utf8_c_str_and_finalize()?,
-        )),
-        b't' => {
-            let parameters_cnt = reader.read_int16_length()?;
-            Ok(ResponsePacket::ParameterDescription(
-                reader.read_int32_list_and_finalize(parameters_cnt)?,
-            ))
+        0x12 | 0x1E => Ok(RequestPacket::ComBinlog(command)),
+        0x16 => Ok(RequestPacket::ComStmtPrepare(String::from_utf8_lossy(reader.read_remaining_bytes()))),
+        0x17 => Ok(RequestPacket::ComStmtExecute(reader.read_le()?, reader.read()?, reader.read_le()?)),
+        0x18 => Ok(RequestPacket::ComStmtSendLongData(reader.read_le()?, reader.read_le()?, reader.read_remaining_bytes())),
+        0x19 => {
+            let statement_id = reader.read_le()?;
+            reader.finalize()?;
+            Ok(RequestPacket::ComStmtClose(statement_id))
         }
-        b'S' => Ok(ResponsePacket::ParameterStatus(
-            reader.read_utf8_c_str()?,
-            reader.read_utf8_c_str_and_finalize()?,
-        )),
-        b'1' => Ok(ResponsePacket::ParseComplete),
-        b's' => Ok(ResponsePacket::PortalSuspended),
-        b'Z' => Ok(ResponsePacket::ReadyForQuery(match reader.read_byte()? {
-            b'I' => TransactionStatus::Idle,
-            b'T' => TransactionStatus::Transaction,
-            b'E' => TransactionStatus::FailedTransaction,
-            _ => return Err("packet contained unrecognized transaction status indicator"),
-        })),
-        b'T' => parse_rowdescription_resp_packet(reader),
-        _ => Err("packet contained unrecognized packet identifier"),
+        0x1A => {
+            let statement_id = reader.read_le()?;
+            reader.finalize()?;
+            Ok(RequestPacket::ComStmtReset(statement_id))
+        }
+        0x1B => {
+            let option = reader.read_le()?;
+            reader.finalize()?;
+            Ok(RequestPacket::ComSetOption(option))
+        }
+        0x1C => {
+            let statement_id = reader.read_le()?;
+            let num_rows = reader.read_le()?;
+            reader.finalize()?;
+            Ok(RequestPacket::ComStmtFetch(statement_id, num_rows))
+        }
+        0x1F => Ok(RequestPacket::ComResetConnection),
+        0x00 | 0x05 | 0x06 | 0x08 | 0x0B | 0x0F | 0x10 | 0x13 | 0x14 | 0x15 | 0x1D => Ok(RequestPacket::ComOther(command)),
+        _ => Err("packet contained unrecognized command"),
     }
 }
 
-fn parse_rowdescription_resp_packet<'a>(
-    mut reader: WireReader<'a>,
-) -> Result<ResponsePacket, &'static str> {
-    let field_cnt = reader.read_int16_length()?;
-    let mut fields = Vec::new();
-    for _ in 0..field_cnt {
-        let field_name = reader.read_utf8_c_str()?;
-        let table_object_id = reader.read_int32()?;
-        let attribute_number = reader.read_int16()?;
-        let data_type_id = reader.read_int32()?;
-        let data_type_size = reader.read_int16()?;
-        let type_modifier = reader.read_int32()?;
-        let format_code = match reader.read_int16()? {
-            1 => true,
-            0 => false,
-            _ => return Err("packet contained invalid boolean value for format code"),
+fn parse_query_packet<'a>(mut reader: WireReader<'a>, capability_flags: u32) -> Result<RequestPacket<'a>, &'static str> {
+    let mut parameters = Vec::new();
+
+    if (capability_flags & capabilities::QUERY_ATTRIBUTES) != 0 {
+        let parameter_cnt = match usize::try_from(read_lenenc_integer(&mut reader)?) {
+            Ok(c) => c,
+            Err(_) => return Err("query packet contained too many parameters"),
         };
-        fields.push((
-            field_name,
-            table_object_id,
-            attribute_number,
-            data_type_id,
-            data_type_size,
-            type_modifier,
-            format_code,
-        ));
+        let _parameter_set_cnt = read_lenenc_integer(&mut reader)?; // Always 1
+
+        if parameter_cnt > 0 {
+            let null_bitmap = reader.read_bytes(parameter_cnt.div_ceil(8))?;
+            let new_params_bind_flag: u8 = reader.read()?;
+            if new_params_bind_flag != 1 {
+                return Err("query packet contained parameters without their types");
+            }
+
+            let mut types_and_names = Vec::new();
+            for _ in 0..parameter_cnt {
+                let parameter_type: u16 = reader.read_le()?;
+                types_and_names.push((parameter_type, read_lenenc_str(&mut reader)?));
+            }
+
+            for (idx, (parameter_type, name)) in types_and_names.into_iter().enumerate() {
+                let is_null = (null_bitmap[idx / 8] & (1 << (idx % 8))) != 0;
+                let value = if is_null {
+                    None
+                } else {
+                    Some(read_binary_value(&mut reader, parameter_type)?)
+                };
+                parameters.push((parameter_type, name, value));
+            }
+        }
     }
 
-    Ok(ResponsePacket::RowDescription(fields))
+    Ok(RequestPacket::ComQuery(String::from_utf8_lossy(reader.read_remaining_bytes()), parameters))
 }
 
-fn parse_function_req_packet<'a>(
-    mut reader: WireReader<'a>,
-) -> Result<RequestPacket, &'static str> {
-    let object_id = reader.read_int32()?;
-    let arg_format_code_cnt = reader.read_int16_length()?;
-    let mut arg_format_codes = Vec::new();
-    for _ in 0..arg_format_code_cnt {
-        arg_format_codes.push(match reader.read_int16()? {
-            1 => true,
-            0 => false,
-            _ => return Err("packet contains invalid value for boolean format code field"),
-        });
-    }
-
-    let argument_cnt = reader.read_int16_length()?;
-    let mut arguments = Vec::new();
-    for _ in 0..argument_cnt {
-        arguments.push(match reader.read_nullable_int32_length()? {
-            Some(param_length) => Some(reader.read_bytes(param_length)?),
-            None => None,
-        });
+/// Reads a value encoded in the binary protocol for the given field type (the low byte of `parameter_type`).
+fn read_binary_value<'a>(reader: &mut WireReader<'a>, parameter_type: u16) -> Result<&'a [u8], &'static str> {
+    match parameter_type & 0xFF {
+        0x06 => Ok(&[]), // MYSQL_TYPE_NULL
+        0x01 => reader.read_bytes(1), // MYSQL_TYPE_TINY
+        0x02 | 0x0D => reader.read_bytes(2), // MYSQL_TYPE_SHORT, MYSQL_TYPE_YEAR
+        0x03 | 0x04 | 0x09 => reader.read_bytes(4), // MYSQL_TYPE_LONG, MYSQL_TYPE_FLOAT, MYSQL_TYPE_INT24
+        0x05 | 0x08 => reader.read_bytes(8), // MYSQL_TYPE_DOUBLE, MYSQL_TYPE_LONGLONG
+        0x07 | 0x0A | 0x0B | 0x0C => {
+            // MYSQL_TYPE_TIMESTAMP, MYSQL_TYPE_DATE, MYSQL_TYPE_TIME, MYSQL_TYPE_DATETIME
+            let value_len: u8 = reader.read()?;
+            reader.read_bytes(value_len as usize)
+        }
+        _ => read_lenenc_bytes(reader), // Strings, decimals, blobs, JSON, etc.
     }
+}
 
-    let function_result_format = match reader.read_int16_and_finalize()? {
-        1 => true,
-        0 => false,
-        _ => return Err("packet contains invalid value for boolean format code field"),
-    };
+/// Returns true if the given payload is an OK packet (as opposed to the first packet of a result set).
+pub fn is_ok_packet(payload: &[u8]) -> bool {
+    payload.first() == Some(&0x00) && payload.len() >= 7
+}
 
-    Ok(RequestPacket::FunctionCall(
-        object_id,
-        arg_format_codes,
-        arguments,
-        function_result_format,
-    ))
+pub fn is_err_packet(payload: &[u8]) -> bool {
+    payload.first() == Some(&0xFF)
 }
 
-fn parse_datarow_response_packet<'a>(
-    mut reader: WireReader<'a>,
-) -> Result<ResponsePacket, &'static str> {
-    let column_cnt = reader.read_int16_length()?;
-    let mut column_values = Vec::new();
-    for _ in 0..column_cnt {
-        column_values.push(match reader.read_nullable_int32_length()? {
-            Some(column_length) => Some(reader.read_bytes(column_length)?),
-            None => None,
-        });
+/// Returns true if the given payload terminates a sequence of packets, as an EOF packet (or an OK packet with an EOF header if CLIENT_DEPRECATE_EOF is set) would.
+pub fn is_eof_packet(payload: &[u8], capability_flags: u32) -> bool {
+    if payload.first() != Some(&0xFE) {
+        return false;
     }
-    reader.finalize()?;
 
-    Ok(ResponsePacket::DataRow(column_values))
+    if (capability_flags & capabilities::DEPRECATE_EOF) != 0 {
+        payload.len() < MAX_PAYLOAD_LEN
+    } else {
+        payload.len() < 9
+    }
 }
 
-fn parse_copyin_response_packet<'a>(
-    reader: WireReader<'a>,
-) -> Result<ResponsePacket, &'static str> {
-    let (is_binary, format_codes) = parse_copy_response_fields(reader)?;
-    Ok(ResponsePacket::CopyInResponse(is_binary, format_codes))
-}
+pub fn parse_ok_packet<'a>(payload: &'a [u8], capability_flags: u32) -> Result<ResponsePacket<'a>, &'static str> {
+    let mut reader = WireReader::new(payload);
+    reader.advance_up_to(1); // 0x00 or 0xFE header
 
-fn parse_copyout_response_packet<'a>(
-    reader: WireReader<'a>,
-) -> Result<ResponsePacket, &'static str> {
-    let (is_binary, format_codes) = parse_copy_response_fields(reader)?;
-    Ok(ResponsePacket::CopyOutResponse(is_binary, format_codes))
-}
+    let affected_rows = read_lenenc_integer(&mut reader)?;
+    let last_insert_id = read_lenenc_integer(&mut reader)?;
 
-fn parse_copyboth_response_packet<'a>(
-    reader: WireReader<'a>,
-) -> Result<ResponsePacket, &'static str> {
-    let (is_binary, format_codes) = parse_copy_response_fields(reader)?;
-    Ok(ResponsePacket::CopyBothResponse(is_binary, format_codes))
+    let (status_flags, warnings) = if (capability_flags & capabilities::PROTOCOL_41) != 0 {
+        (Some(reader.read_le()?), Some(reader.read_le()?))
+    } else if (capability_flags & capabilities::TRANSACTIONS) != 0 {
+        (Some(reader.read_le()?), None)
+    } else {
+        (None, None)
+    };
+
+    Ok(ResponsePacket::OKPacket(affected_rows, last_insert_id, status_flags, warnings, reader.read_remaining_bytes()))
 }
 
-fn parse_copy_response_fields<'a>(
-    mut reader: WireReader<'a>,
-) -> Result<(bool, Vec<bool>), &'static str> {
-    let is_binary = match reader.read_byte()? {
-        b'1' => true,
-        b'0' => false,
-        _ => return Err("packet contains invalid value for boolean copy format field"),
+pub fn parse_err_packet<'a>(payload: &'a [u8]) -> Result<ResponsePacket<'a>, &'static str> {
+    let mut reader = WireReader::new(payload);
+    reader.advance_up_to(1); // 0xFF header
+
+    let error_code = reader.read_le()?;
+    let sql_state = if payload.get(3) == Some(&b'#') {
+        reader.advance_up_to(1);
+        Some(reader.read_fixed_str(5)?)
+    } else {
+        None
     };
 
-    let format_codes_cnt = reader.read_int16_length()?;
-    let mut format_codes = Vec::new();
-    for _ in 0..format_codes_cnt {
-        format_codes.push(match reader.read_int16()? {
-            1 => true,
-            0 => false,
-            _ => return Err("packet contains invalid value for boolean format code field"),
-        });
-    }
-    reader.finalize()?;
-    Ok((is_binary, format_codes))
+    Ok(ResponsePacket::ERRPacket(error_code, sql_state, String::from_utf8_lossy(reader.read_remaining_bytes())))
 }
 
-fn parse_auth_resp_packet<'a>(mut reader: WireReader<'a>) -> Result<ResponsePacket, &'static str> {
-    let auth_mechanism = reader.read_int32()?;
-    match auth_mechanism {
-        0 | 2 | 6 | 7 | 9 => reader.finalize()?,
-        _ => (),
+pub fn parse_eof_packet<'a>(payload: &'a [u8], capability_flags: u32) -> Result<ResponsePacket<'a>, &'static str> {
+    if (capability_flags & capabilities::DEPRECATE_EOF) != 0 {
+        return parse_ok_packet(payload, capability_flags);
     }
 
-    match auth_mechanism {
-        0 => Ok(ResponsePacket::AuthenticationOk),
-        2 => Ok(ResponsePacket::AuthenticationKerberosV5),
-        3 => Ok(ResponsePacket::AuthenticationCleartextPassword),
-        5 => Ok(ResponsePacket::AuthenticationMD5Password(
-            reader.read_4_bytes_and_finalize()?,
-        )),
-        6 => Ok(ResponsePacket::AuthenticationSCMCredential),
-        7 => Ok(ResponsePacket::AuthenticationGSS),
-        8 => Ok(ResponsePacket::AuthenticationGSSContinue(
-            reader.read_remaining_bytes(),
-        )),
-        9 => Ok(ResponsePacket::AuthenticationSSPI),
-        10 => Ok(ResponsePacket::AuthenticationSASL(
-            reader.read_term_utf8_c_strs_and_finalize()?,
-        )),
-        11 => Ok(ResponsePacket::AuthenticationSASLContinue(
-            reader.read_remaining_bytes(),
-        )),
-        12 => Ok(ResponsePacket::AuthenticationSASLFinal(
-            reader.read_remaining_bytes(),
-        )),
-        _ => Err(""),
+    let mut reader = WireReader::new(payload);
+    reader.advance_up_to(1); // 0xFE header
+
+    if (capability_flags & capabilities::PROTOCOL_41) != 0 {
+        let warnings = reader.read_le()?;
+        let status_flags = reader.read_le()?;
+        Ok(ResponsePacket::EOFPacket(Some(warnings), Some(status_flags)))
+    } else {
+        Ok(ResponsePacket::EOFPacket(None, None))
     }
 }
 
-fn parse_bind_packet<'a>(mut reader: WireReader<'a>) -> Result<RequestPacket, &'static str> {
-    let dest_portal = reader.read_utf8_c_str()?;
-    let prepared_stmt = reader.read_utf8_c_str()?;
-    let format_codes_cnt = reader.read_int16_length()?;
-    let mut format_codes = Vec::new();
-    for _ in 0..format_codes_cnt {
-        format_codes.push(match reader.read_int16()? {
-            0 => false,
-            1 => true,
-            _ => return Err("packet contained invalid parameter format code"),
-        });
+/// Parses a packet received during authentication (after the client's handshake response or a COM_CHANGE_USER).
+pub fn parse_auth_packet<'a>(payload: &'a [u8], capability_flags: u32) -> Result<ResponsePacket<'a>, &'static str> {
+    let mut reader = WireReader::new(payload);
+    match payload.first() {
+        Some(0x00) => parse_ok_packet(payload, capability_flags),
+        Some(0xFF) => parse_err_packet(payload),
+        Some(0x01) => {
+            reader.advance_up_to(1);
+            Ok(ResponsePacket::AuthMoreData(reader.read_remaining_bytes()))
+        }
+        Some(0xFE) if payload.len() == 1 => Ok(ResponsePacket::OldAuthSwitchRequest),
+        Some(0xFE) => {
+            reader.advance_up_to(1);
+            let plugin_name = reader.read_str()?;
+            Ok(ResponsePacket::AuthSwitchRequest(plugin_name, reader.read_remaining_bytes()))
+        }
+        _ => Err("unexpected packet received during authentication"),
     }
+}
 
-    let parameters_cnt = reader.read_int16_length()?;
-    let mut parameters = Vec::new();
-    for _ in 0..parameters_cnt {
-        parameters.push(match reader.read_nullable_int32_length()? {
-            Some(param_length) => Some(reader.read_bytes(param_length)?),
-            None => None,
-        });
-    }
+pub fn parse_stmt_prepare_ok<'a>(payload: &'a [u8]) -> Result<ResponsePacket<'a>, &'static str> {
+    let mut reader = WireReader::new(payload);
+    reader.advance_up_to(1); // 0x00 header
 
-    let result_format_codes_cnt = reader.read_int16_length()?;
-    let mut result_format_codes = Vec::new();
-    for _ in 0..result_format_codes_cnt {
-        result_format_codes.push(match reader.read_int16()? {
-            1 => true,
-            0 => false,
-            _ => return Err("packet contained invalid result-column format code"),
-        });
-    }
+    let statement_id = reader.read_le()?;
+    let num_columns = reader.read_le()?;
+    let num_params = reader.read_le()?;
+    reader.advance_up_to(1); // Reserved
+    let warning_count = if reader.empty() {
+        None
+    } else {
+        Some(reader.read_le()?)
+    };
+    // Any optional metadata_follows flag is ignored, as CLIENT_OPTIONAL_RESULTSET_METADATA is never negotiated
 
-    Ok(RequestPacket::Bind(
-        dest_portal,
-        prepared_stmt,
-        format_codes,
-        parameters,
-        result_format_codes,
-    ))
+    Ok(ResponsePacket::ComStmtPrepareOk(statement_id, num_columns, num_params, warning_count))
+}
+
+/// Parses the packet that begins a response to COM_QUERY or COM_STMT_EXECUTE, returning either
+/// the number of columns in the result set that follows or the packet itself if it isn't a result set.
+pub fn parse_query_response<'a>(payload: &'a [u8], capability_flags: u32) -> Result<Result<u64, ResponsePacket<'a>>, &'static str> {
+    match payload.first() {
+        Some(0x00) if is_ok_packet(payload) => Ok(Err(parse_ok_packet(payload, capability_flags)?)),
+        Some(0xFF) => Ok(Err(parse_err_packet(payload)?)),
+        Some(0xFB) => Ok(Err(ResponsePacket::LocalInfileRequest(&payload[1..]))),
+        Some(_) => {
+            let mut reader = WireReader::new(payload);
+            let column_cnt = read_lenenc_integer(&mut reader)?;
+            reader.finalize()?;
+            Ok(Ok(column_cnt))
+        }
+        None => Err("empty packet received in response to query"),
+    }
 }
-*/
\ No newline at end of file
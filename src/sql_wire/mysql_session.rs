@@ -32,78 +32,1084 @@
 // ^ only applies if CLIENT_DEPRECATE_EOF flag is sent by client
 //
 
-/*
+use std::borrow::Cow;
+use std::cmp;
+use std::collections::VecDeque;
+use std::io;
 
+use super::mysql_packet::*;
 
-pub struct MysqlWire {
-    startup_packet_read: bool,
-    protocol_version: u32,
-    user: String,
-    database: String,
+use crate::sql_wire::*;
+
+const DEFAULT_REQ_RESP_BUFLEN: usize = 1024; // 1KB
+
+/// Capabilities removed from the server's initial handshake, as they would prevent the proxy from inspecting the packets exchanged.
+const UNSUPPORTED_CAPABILITIES: u32 = capabilities::SSL
+    | capabilities::COMPRESS
+    | capabilities::ZSTD_COMPRESSION_ALGORITHM
+    | capabilities::OPTIONAL_RESULTSET_METADATA;
+
+const SSL_ERROR_CODE: u16 = 1043; // ER_HANDSHAKE_ERROR
+const SSL_SQL_STATE: &[u8; 5] = b"08S01";
+const SSL_MESSAGE: &[u8] = b"SSL connections are not supported by SQLFortify";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SessionPhase {
+    /// Waiting for the server to send its initial handshake
+    ServerHandshake,
+    /// Waiting for the client to respond to the server's handshake
+    ClientHandshake,
+    /// Authentication packets are being exchanged (either after the handshake or after a COM_CHANGE_USER)
+    Authentication,
+    Command,
+    /// The client is sending the contents of a file in response to a LOCAL INFILE request
+    LocalInfile,
+}
+
+/// The packets still expected from the server in response to a request.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ResponseState {
+    /// Authentication packets, ending with an OK or ERR packet
+    Authentication,
+    /// An OK, ERR or LOCAL INFILE packet, or the column count of a result set
+    QueryResult,
+    /// Fields: the number of column definitions remaining
+    ColumnDefinitions(u64),
+    /// The EOF packet that follows column definitions
+    ColumnsEof,
+    /// Rows of a result set, ending with an EOF (or OK) packet
+    Rows,
+    /// A COM_STMT_PREPARE_OK or ERR packet
+    PrepareResult,
+    /// Fields: the number of parameter definitions, column definitions and EOF packets remaining
+    PrepareDefinitions(u32),
+    /// A single packet (such as an OK or ERR packet)
+    Single,
+}
+
+enum ResponseProgress {
+    Continue(ResponseState),
+    /// Fields: whether the request was successful
+    Complete(bool),
+}
+
+pub struct MysqlRequest {
+    basic_info: PacketInfo,
+    is_valid: bool,
+    data: Vec<u8>,
+    pkt_len: usize,
+    /// The packets that the server will send in response to this request, if any
+    response_state: Option<ResponseState>,
+}
+
+impl MysqlRequest {
+    fn new() -> Self {
+        MysqlRequest {
+            basic_info: PacketInfo::new(),
+            is_valid: false,
+            data: Vec::from([0; DEFAULT_REQ_RESP_BUFLEN]),
+            pkt_len: 0,
+            response_state: None,
+        }
+    }
 }
 
-impl MysqlWire {
+impl ClientPacket for MysqlRequest {
+    fn get_basic_info(&self) -> &PacketInfo {
+        &self.basic_info
+    }
 
+    fn as_slice(&self) -> &[u8] {
+        &self.data[..self.pkt_len]
+    }
+
+    fn is_valid(&self) -> bool {
+        self.is_valid
+    }
 }
 
-impl SqlWire for MysqlWire {
+pub struct MysqlResponse {
+    basic_info: PacketInfo,
+    is_valid: bool,
+    data: Vec<u8>,
+    pkt_len: usize,
+}
+
+impl MysqlResponse {
     fn new() -> Self {
-        MysqlWire {
-            user: String::new(),
-            database: String::new(),
-            startup_packet_read: false,
-            protocol_version: 0,
+        MysqlResponse {
+            basic_info: PacketInfo::new(),
+            is_valid: false,
+            data: Vec::from([0; DEFAULT_REQ_RESP_BUFLEN]),
+            pkt_len: 0,
         }
     }
 
-    fn read_client_packet(&mut self, wire: &[u8]) -> Result<Packet, WireError> {
-        /*
-        if !self.startup_packet_read {
-            self.read_startup_header(wire)
-        } else {
-            Err(WireError::Truncated(9))
+    /// Creates an ERR packet with the given contents.
+    fn error(sequence_id: u8, capability_flags: u32, code: u16, sql_state: &[u8; 5], message: &[u8]) -> Self {
+        let mut payload = Vec::from([0xFF]);
+        payload.extend_from_slice(&code.to_le_bytes());
+        if (capability_flags & capabilities::PROTOCOL_41) != 0 {
+            payload.push(b'#');
+            payload.extend_from_slice(sql_state);
+        }
+        payload.extend_from_slice(message);
+
+        let mut header = [0; HEADER_LEN];
+        write_header(&mut header, payload.len(), sequence_id);
+        let mut data = Vec::from(header);
+        data.extend_from_slice(&payload);
+
+        MysqlResponse {
+            basic_info: PacketInfo::new(),
+            is_valid: true,
+            pkt_len: data.len(),
+            data,
         }
-        */
+    }
+}
 
-        Err(WireError::Truncated(9))
+impl ServerPacket for MysqlResponse {
+    fn get_basic_info(&self) -> &PacketInfo {
+        &self.basic_info
     }
 
-    fn read_server_packet(&mut self, wire: &[u8]) -> Result<Packet, WireError> {
-        /*
-        if !self.startup_packet_read {
-            self.read_startup_header(wire)
-        } else {
-            Err(WireError::Truncated(9))
+    fn as_slice(&self) -> &[u8] {
+        &self.data[..self.pkt_len]
+    }
+
+    fn is_valid(&self) -> bool {
+        self.is_valid
+    }
+}
+
+pub struct MysqlProxySession<C: io::Read + io::Write, S: io::Read + io::Write> {
+    /// The capabilities shared by the client and server (only those of the server until the client has responded to the handshake)
+    capabilities: u32,
+    client_io: C,
+    /// The packets expected from the server for each request forwarded to it, in the order they were forwarded
+    pending_responses: VecDeque<ResponseState>,
+    phase: SessionPhase,
+    recycled_requests: VecDeque<MysqlRequest>,
+    recycled_responses: VecDeque<MysqlResponse>,
+    request_part_idx: Option<usize>,
+    response_part_idx: Option<usize>,
+    /// The sequence ID that a response to the most recently received request would start with
+    response_sequence_id: u8,
+    server_io: S,
+}
+
+impl<C: io::Read + io::Write, S: io::Read + io::Write> Proxy<C, S> for MysqlProxySession<C, S> {
+    type RequestType = MysqlRequest;
+    type ResponseType = MysqlResponse;
+
+    fn new(client_io: C, server_io: S) -> Self {
+        MysqlProxySession {
+            capabilities: 0,
+            client_io,
+            pending_responses: VecDeque::new(),
+            phase: SessionPhase::ServerHandshake,
+            recycled_requests: VecDeque::new(),
+            recycled_responses: VecDeque::new(),
+            request_part_idx: None,
+            response_part_idx: None,
+            response_sequence_id: 1,
+            server_io,
+        }
+    }
+
+    fn frontend_receive_request(&mut self) -> io::Result<Self::RequestType> {
+        let mut request = match self.recycled_requests.pop_front() {
+            Some(req) => req,
+            None => MysqlRequest::new(),
+        };
+
+        log::debug!("Receiving request...");
+        match receive_request(
+            &mut self.server_io,
+            &mut request,
+            &mut self.phase,
+            &mut self.capabilities,
+            &mut self.response_sequence_id,
+        ) {
+            Ok(()) => {
+                log::debug!("Request received");
+                Ok(request)
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                log::debug!("Request needs more bytes to be received");
+                self.recycled_requests.push_front(request);
+                Err(e)
+            }
+            Err(e) => {
+                log::debug!(
+                    "Receiving request failed with other error: {}",
+                    e
+                );
+                Err(e)
+            }
         }
-        */
+    }
 
-        Err(WireError::Truncated(9))
+    fn frontend_send_response(&mut self, response: &Self::ResponseType) -> io::Result<()> {
+        send_response(&mut self.server_io, response, &mut self.response_part_idx)
     }
 
-    fn write_server_error(&self, buffer: &mut [u8]) -> Result<(), usize> {
+    fn backend_receive_response(&mut self) -> io::Result<Self::ResponseType> {
+        let mut response = match self.recycled_responses.pop_front() {
+            Some(resp) => resp,
+            None => MysqlResponse::new(),
+        };
 
+        log::debug!("Receiving response...");
+        match receive_response(
+            &mut self.client_io,
+            &mut response,
+            &mut self.phase,
+            &mut self.capabilities,
+            &mut self.pending_responses,
+        ) {
+            Ok(()) => {
+                log::debug!("Response received");
+                Ok(response)
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                log::debug!("Response needs more bytes to be received");
+                self.recycled_responses.push_front(response);
+                Err(e)
+            }
+            Err(e) => {
+                log::debug!(
+                    "Receiving response failed with other error: {}",
+                    e
+                );
+                Err(e)
+            }
+        }
+    }
+
+    fn backend_send_request(&mut self, request: &Self::RequestType) -> io::Result<()> {
+        send_request(&mut self.client_io, request, &mut self.request_part_idx)?;
+        if let Some(response_state) = request.response_state {
+            self.pending_responses.push_back(response_state);
+        }
         Ok(())
     }
 
-    /*
-    fn read_header(&mut self, wire: &[u8]) -> Result<Header, WireError> {
+    /// Safely reuses the allocated structures and buffers of the given request, thereby resulting in fewer repeated allocations of large buffers
+    fn recycle_request(&mut self, mut request: Self::RequestType) {
+        request.basic_info = PacketInfo::new();
+        request.pkt_len = 0;
+        request.is_valid = false;
+        request.response_state = None;
+        self.recycled_requests.push_back(request);
+    }
+
+    /// Safely reuses the allocated structures and buffers of the given response, thereby resulting in fewer repeated allocations of large buffers
+    fn recycle_response(&mut self, mut response: Self::ResponseType) {
+        response.basic_info = PacketInfo::new();
+        response.pkt_len = 0;
+        response.is_valid = false;
+        self.recycled_responses.push_back(response);
+    }
+
+    fn get_backend_io_ref(&self) -> &C {
+        &self.client_io
+    }
+
+    fn get_frontend_io_ref(&self) -> &S {
+        &self.server_io
+    }
+
+    fn get_backend_io_mut(&mut self) -> &mut C {
+        &mut self.client_io
+    }
+
+    fn get_frontend_io_mut(&mut self) -> &mut S {
+        &mut self.server_io
+    }
+
+    fn frontend_downgrade_ssl(
+        &mut self,
+        ssl_request: &mut Self::RequestType,
+    ) -> Option<Self::ResponseType> {
+        // MySQL clients can't fall back to an unencrypted session once they've requested SSL, so the handshake is refused instead.
+        // Clients will only request SSL here if they require it, as the capability is removed from the server's handshake.
+        ssl_request.is_valid = false;
+
+        Some(MysqlResponse::error(
+            self.response_sequence_id,
+            self.capabilities,
+            SSL_ERROR_CODE,
+            SSL_SQL_STATE,
+            SSL_MESSAGE,
+        ))
+    }
+
+    // SSL is negotiated partway through the MySQL handshake (rather than before it), which isn't supported
+    fn frontend_accept_ssl(
+        &mut self,
+        _ssl_request: &mut Self::RequestType,
+    ) -> io::Result<Self::ResponseType> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "TLS isn't supported for MySQL connections",
+        ))
+    }
+
+    fn backend_request_ssl(&mut self) -> io::Result<Self::RequestType> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "TLS isn't supported for MySQL connections",
+        ))
+    }
+
+    fn backend_receive_ssl_response(&mut self) -> io::Result<bool> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "TLS isn't supported for MySQL connections",
+        ))
+    }
+
+    fn frontend_downgrade_gssenc(
+        &mut self,
+        _gssenc_request: &mut Self::RequestType,
+    ) -> Option<Self::ResponseType> {
+        None // GSSAPI encryption doesn't exist in the MySQL protocol
+    }
+
+    fn backend_downgrade_protocol(
+        &mut self,
+        _proto_request: &mut Self::ResponseType,
+    ) -> Option<Self::RequestType> {
+        None
+    }
+
+    fn transaction_abort_request(&mut self) -> Option<Self::RequestType> {
+        None // Errors don't affect the state of MySQL transactions
+    }
+
+    fn batch_abort_request(&mut self) -> Option<Self::RequestType> {
+        None // Every MySQL command is answered on its own, so none is ever partially forwarded
+    }
+
+    fn error_response(&mut self, error: &ErrorFields) -> Self::ResponseType {
+        // ERR packets only carry a message, so the detail and hint are appended to it
+        let mut message = Vec::from(error.message.as_bytes());
+        if let Some(detail) = error.detail.as_ref() {
+            message.extend_from_slice(b" (");
+            message.extend_from_slice(detail.as_bytes());
+            message.push(b')');
+        }
+        if let Some(hint) = error.hint.as_ref() {
+            message.extend_from_slice(b" Hint: ");
+            message.extend_from_slice(hint.as_bytes());
+        }
+
+        let mut response = MysqlResponse::error(
+            self.response_sequence_id,
+            self.capabilities,
+            error.code,
+            &error.sql_state,
+            &message,
+        );
+        response.basic_info.result = Some(false);
+        response.basic_info.ends_session = error.ends_session();
+        response
+    }
+}
+
+/// Reads in a complete packet, including any continuation packets that follow it if its payload exceeds `MAX_PAYLOAD_LEN`.
+/// Returns the total length of the packet(s) and the sequence ID of the last packet read.
+fn read_logical_packet<T: io::Read>(
+    io: &mut T,
+    buf: &mut Vec<u8>,
+    buf_len: &mut usize,
+) -> io::Result<(usize, u8)> {
+    // Packets already read in are re-parsed if a previous call was interrupted (e.g. by `WouldBlock`)
+    let mut packet_start = 0;
+    loop {
+        let header_end = packet_start + HEADER_LEN;
+        read_packet(io, buf, buf_len, header_end)?;
+        let (payload_len, sequence_id) = match read_header(&buf[packet_start..header_end]) {
+            Ok(h) => h,
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        };
+
+        let packet_end = header_end + payload_len;
+        read_packet(io, buf, buf_len, packet_end)?;
+        if payload_len < MAX_PAYLOAD_LEN {
+            return Ok((packet_end, sequence_id));
+        }
+
+        log::debug!("Packet payload continues in the next packet");
+        packet_start = packet_end;
+    }
+}
+
+/// Returns the payload of the given packet(s) with packet headers removed.
+fn packet_payload(packet: &[u8]) -> Cow<'_, [u8]> {
+    if packet.len() < HEADER_LEN + MAX_PAYLOAD_LEN {
+        return Cow::Borrowed(advance_up_to(packet, HEADER_LEN));
+    }
+
+    let mut payload = Vec::new();
+    let mut remaining = packet;
+    while remaining.len() >= HEADER_LEN {
+        let payload_len = cmp::min(MAX_PAYLOAD_LEN, remaining.len() - HEADER_LEN);
+        payload.extend_from_slice(&remaining[HEADER_LEN..HEADER_LEN + payload_len]);
+        remaining = advance_up_to(remaining, HEADER_LEN + payload_len);
+    }
+    Cow::Owned(payload)
+}
+
+/// Clears any unsupported capabilities from the server's initial handshake packet, given the offset of its lower capability flags.
+fn strip_unsupported_capabilities(packet: &mut [u8], capabilities_idx: usize) {
+    let mask = (!UNSUPPORTED_CAPABILITIES).to_le_bytes();
+    // The lower two bytes of the capability flags are followed by the charset and status flags, and then the upper two bytes
+    let flag_indices = [
+        capabilities_idx,
+        capabilities_idx + 1,
+        capabilities_idx + 5,
+        capabilities_idx + 6,
+    ];
+
+    for (idx, mask_byte) in flag_indices.iter().zip(mask.iter()) {
+        if let Some(b) = packet.get_mut(*idx) {
+            *b &= mask_byte;
+        }
+    }
+}
+
+fn receive_request<T: io::Read>(
+    io: &mut T,
+    request: &mut MysqlRequest,
+    phase: &mut SessionPhase,
+    capability_flags: &mut u32,
+    response_sequence_id: &mut u8,
+) -> io::Result<()> {
+    log::debug!("Reading request packet...");
+    let (pkt_len, sequence_id) = read_logical_packet(io, &mut request.data, &mut request.pkt_len)?;
+    log::debug!("Request packet read.");
+
+    if *phase == SessionPhase::ServerHandshake {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "client sent data before the server's initial handshake",
+        ));
+    }
+
+    request.is_valid = true;
+    *response_sequence_id = sequence_id.wrapping_add(1);
+    let payload = packet_payload(&request.data[..pkt_len]);
+
+    match phase {
+        SessionPhase::ClientHandshake => {
+            let (client_flags, username, database) = match parse_client_handshake(&payload) {
+                Ok(RequestPacket::SSLRequest(_, _, _)) => {
+                    request.basic_info.ssl_requested = true;
+                    return Ok(());
+                }
+                Ok(RequestPacket::HandshakeResponse41(flags, _, _, username, _, database, _, _, _)) => {
+                    (flags, username, database)
+                }
+                Ok(RequestPacket::HandshakeResponse320(flags, _, username, _, database)) => {
+                    (flags, username, database)
+                }
+                Ok(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "unexpected packet received during connection handshake",
+                    ))
+                }
+                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+            };
+
+            request.basic_info.username = Some(username.to_string());
+            request.basic_info.database = database.map(|db| db.to_string());
+            request.response_state = Some(ResponseState::Authentication);
+            *capability_flags &= client_flags;
+            *phase = SessionPhase::Authentication;
+        }
+        SessionPhase::Authentication => request.basic_info.is_standalone = true,
+        SessionPhase::LocalInfile => {
+            request.basic_info.is_standalone = true;
+            if payload.is_empty() {
+                // An empty packet marks the end of the file's contents
+                *phase = SessionPhase::Command;
+            }
+        }
+        SessionPhase::Command => match parse_command(&payload, *capability_flags) {
+            Ok(RequestPacket::ComQuery(query, _)) => {
+                request.basic_info.query = Some(query.into_owned());
+                request.response_state = Some(ResponseState::QueryResult);
+            }
+            Ok(RequestPacket::ComStmtPrepare(query)) => {
+                request.basic_info.query = Some(query.into_owned());
+                request.response_state = Some(ResponseState::PrepareResult);
+            }
+            Ok(RequestPacket::ComStmtExecute(_, _, _) | RequestPacket::ComProcessInfo) => {
+                request.response_state = Some(ResponseState::QueryResult)
+            }
+            Ok(RequestPacket::ComStmtFetch(_, _) | RequestPacket::ComFieldList(_, _)) => {
+                request.response_state = Some(ResponseState::Rows)
+            }
+            Ok(RequestPacket::ComInitDb(database)) => {
+                request.basic_info.database = Some(database.to_string());
+                request.response_state = Some(ResponseState::Single);
+            }
+            Ok(RequestPacket::ComChangeUser(username, _, database)) => {
+                request.basic_info.username = Some(username.to_string());
+                request.basic_info.database = database.map(|db| db.to_string());
+                request.response_state = Some(ResponseState::Authentication);
+                *phase = SessionPhase::Authentication;
+            }
+            Ok(
+                RequestPacket::ComQuit
+                | RequestPacket::ComStmtClose(_)
+                | RequestPacket::ComStmtSendLongData(_, _, _),
+            ) => request.basic_info.is_standalone = true, // The server doesn't respond to these commands
+            Ok(RequestPacket::ComBinlog(_)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "binlog replication commands are not supported",
+                ))
+            }
+            Ok(_) => request.response_state = Some(ResponseState::Single),
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        },
+        SessionPhase::ServerHandshake => (), // Handled above
+    }
+
+    request.basic_info.is_request = request.response_state.is_some();
+    Ok(())
+}
+
+fn receive_response<T: io::Read>(
+    io: &mut T,
+    response: &mut MysqlResponse,
+    phase: &mut SessionPhase,
+    capability_flags: &mut u32,
+    pending_responses: &mut VecDeque<ResponseState>,
+) -> io::Result<()> {
+    log::debug!("Reading response packet...");
+    let (pkt_len, _) = read_logical_packet(io, &mut response.data, &mut response.pkt_len)?;
+    log::debug!("Response packet read.");
+
+    response.is_valid = true;
+
+    if *phase == SessionPhase::ServerHandshake {
+        let payload = advance_up_to(&response.data[..pkt_len], HEADER_LEN);
+        if is_err_packet(payload) {
+            log::warn!("Server refused connection during initial handshake");
+            return Ok(());
+        }
+
+        let (capabilities_idx, server_flags) = match parse_server_handshake(payload) {
+            Ok(ResponsePacket::HandshakeV10(server_version, _, _, flags, _, _, _, _)) => {
+                // protocol version (1) + server version (NUL-terminated) + connection id (4) + auth data (8) + filler (1)
+                (HEADER_LEN + server_version.len() + 15, flags)
+            }
+            Ok(ResponsePacket::HandshakeV9(_, _, _)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "server requested unsupported protocol version 9",
+                ))
+            }
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "unexpected packet received during connection handshake",
+                ))
+            }
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        };
+
+        strip_unsupported_capabilities(&mut response.data[..pkt_len], capabilities_idx);
+        *capability_flags = server_flags & !UNSUPPORTED_CAPABILITIES;
+        *phase = SessionPhase::ClientHandshake;
+        return Ok(());
+    }
+
+    let response_state = match pending_responses.front_mut() {
+        Some(s) => s,
+        None => {
+            // Servers may send an ERR packet without prompting before closing a connection (such as on timeout)
+            log::debug!("Response packet received with no outstanding request");
+            return Ok(());
+        }
+    };
+
+    let payload = packet_payload(&response.data[..pkt_len]);
+    match advance_response(*response_state, &payload, *capability_flags, phase) {
+        Ok(ResponseProgress::Continue(next_state)) => *response_state = next_state,
+        Ok(ResponseProgress::Complete(was_successful)) => {
+            response.basic_info.result = Some(was_successful);
+            pending_responses.pop_front();
+        }
+        Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+    }
+
+    Ok(())
+}
+
+/// Determines what (if anything) the server will send next in response to a request, given the packet it just sent.
+fn advance_response(
+    state: ResponseState,
+    payload: &[u8],
+    capability_flags: u32,
+    phase: &mut SessionPhase,
+) -> Result<ResponseProgress, &'static str> {
+    let deprecate_eof = (capability_flags & capabilities::DEPRECATE_EOF) != 0;
+
+    match state {
+        ResponseState::Authentication => match parse_auth_packet(payload, capability_flags)? {
+            ResponsePacket::OKPacket(_, _, _, _, _) => {
+                *phase = SessionPhase::Command;
+                Ok(ResponseProgress::Complete(true))
+            }
+            ResponsePacket::ERRPacket(_, _, _) => {
+                *phase = SessionPhase::Command;
+                Ok(ResponseProgress::Complete(false))
+            }
+            _ => Ok(ResponseProgress::Continue(state)),
+        },
+        ResponseState::QueryResult => match parse_query_response(payload, capability_flags)? {
+            Ok(0) => Err("result set contained no columns"),
+            Ok(column_cnt) => Ok(ResponseProgress::Continue(
+                ResponseState::ColumnDefinitions(column_cnt),
+            )),
+            Err(ResponsePacket::LocalInfileRequest(_)) => {
+                // The client sends the file's contents, after which the server responds as it would to any other query
+                *phase = SessionPhase::LocalInfile;
+                Ok(ResponseProgress::Continue(state))
+            }
+            Err(ResponsePacket::ERRPacket(_, _, _)) => Ok(ResponseProgress::Complete(false)),
+            Err(packet) => Ok(end_of_result(&packet)),
+        },
+        ResponseState::ColumnDefinitions(remaining) if remaining > 1 => Ok(
+            ResponseProgress::Continue(ResponseState::ColumnDefinitions(remaining - 1)),
+        ),
+        ResponseState::ColumnDefinitions(_) if deprecate_eof => {
+            Ok(ResponseProgress::Continue(ResponseState::Rows))
+        }
+        ResponseState::ColumnDefinitions(_) => {
+            Ok(ResponseProgress::Continue(ResponseState::ColumnsEof))
+        }
+        ResponseState::ColumnsEof => {
+            if !is_eof_packet(payload, capability_flags) {
+                return Err("expected EOF packet following column definitions");
+            }
+
+            match parse_eof_packet(payload, capability_flags)? {
+                // A cursor was opened for the result set, so its rows will only be sent on COM_STMT_FETCH
+                ResponsePacket::EOFPacket(_, Some(status))
+                    if (status & server_status::CURSOR_EXISTS) != 0 =>
+                {
+                    Ok(ResponseProgress::Complete(true))
+                }
+                _ => Ok(ResponseProgress::Continue(ResponseState::Rows)),
+            }
+        }
+        ResponseState::Rows => {
+            if is_err_packet(payload) {
+                Ok(ResponseProgress::Complete(false))
+            } else if is_eof_packet(payload, capability_flags) {
+                Ok(end_of_result(&parse_eof_packet(payload, capability_flags)?))
+            } else {
+                Ok(ResponseProgress::Continue(state))
+            }
+        }
+        ResponseState::PrepareResult => {
+            if is_err_packet(payload) {
+                return Ok(ResponseProgress::Complete(false));
+            }
+
+            let (num_columns, num_params) = match parse_stmt_prepare_ok(payload)? {
+                ResponsePacket::ComStmtPrepareOk(_, num_columns, num_params, _) => {
+                    (num_columns as u32, num_params as u32)
+                }
+                _ => return Err("unexpected packet received in response to statement preparation"),
+            };
+
+            let mut remaining = num_columns + num_params;
+            if !deprecate_eof {
+                // Parameter and column definitions are each followed by an EOF packet (if there are any)
+                remaining += (num_params > 0) as u32 + (num_columns > 0) as u32;
+            }
+
+            if remaining == 0 {
+                Ok(ResponseProgress::Complete(true))
+            } else {
+                Ok(ResponseProgress::Continue(ResponseState::PrepareDefinitions(remaining)))
+            }
+        }
+        ResponseState::PrepareDefinitions(remaining) if remaining > 1 => Ok(
+            ResponseProgress::Continue(ResponseState::PrepareDefinitions(remaining - 1)),
+        ),
+        ResponseState::PrepareDefinitions(_) => Ok(ResponseProgress::Complete(true)),
+        ResponseState::Single => Ok(ResponseProgress::Complete(!is_err_packet(payload))),
+    }
+}
 
+/// Determines whether a result is complete given the OK or EOF packet that ended it, as more results follow if the server indicates so.
+fn end_of_result(packet: &ResponsePacket) -> ResponseProgress {
+    let status_flags = match packet {
+        ResponsePacket::OKPacket(_, _, status, _, _) | ResponsePacket::EOFPacket(_, status) => *status,
+        _ => None,
+    };
+
+    match status_flags {
+        Some(status) if (status & server_status::MORE_RESULTS_EXISTS) != 0 => {
+            ResponseProgress::Continue(ResponseState::QueryResult)
+        }
+        _ => ResponseProgress::Complete(true),
     }
+}
+
+fn send_request<T: io::Write>(
+    io: &mut T,
+    request: &MysqlRequest,
+    start: &mut Option<usize>,
+) -> io::Result<()> {
+    log::debug!("Writing request...");
 
-    fn parse_query(&mut self, wire: &[u8]) -> Result<String, WireError> {
-        Err(WireError::Truncated)
+    if !request.is_valid {
+        log::error!("Invalid request sent")
     }
-    */
 
-    // read_client() and read_server()?
+    write_packet(io, request.as_slice(), start)?;
+    log::debug!("Successfully wrote request.");
+    Ok(())
 }
 
-*/
+fn send_response<T: io::Write>(
+    io: &mut T,
+    response: &MysqlResponse,
+    start: &mut Option<usize>,
+) -> io::Result<()> {
+    log::debug!("Writing response...");
+
+    if !response.is_valid {
+        log::error!("Invalid response sent")
+    }
+
+    write_packet(io, response.as_slice(), start)?;
+    log::debug!("Successfully wrote response.");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One side of a connection held in memory: reads drain `incoming` (failing with `WouldBlock` once it is empty,
+    /// as a nonblocking socket would) and writes are collected in `outgoing`.
+    #[derive(Default)]
+    struct Wire {
+        incoming: VecDeque<u8>,
+        outgoing: Vec<u8>,
+    }
+
+    impl io::Read for Wire {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.incoming.is_empty() {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            self.incoming.read(buf)
+        }
+    }
 
-// Simple Query
-// Client -- 'Q' --> Server (Single Query message)
-// Server -- <Response message> --> Client (one or more)
-// Server -- 'E' <ErrorMessage> --> Client (potential)
-// Server -- 'Z' <ReadyForQuery> --> Client (indicates next command)
+    impl io::Write for Wire {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.outgoing.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    type Session = MysqlProxySession<Wire, Wire>;
+
+    const SERVER_CAPABILITIES: u32 = capabilities::LONG_PASSWORD
+        | capabilities::CONNECT_WITH_DB
+        | capabilities::PROTOCOL_41
+        | capabilities::TRANSACTIONS
+        | capabilities::SECURE_CONNECTION
+        | capabilities::MULTI_RESULTS
+        | capabilities::PLUGIN_AUTH
+        | capabilities::DEPRECATE_EOF
+        | UNSUPPORTED_CAPABILITIES;
+
+    fn packet(sequence_id: u8, payload: &[u8]) -> Vec<u8> {
+        let mut header = [0; HEADER_LEN];
+        write_header(&mut header, payload.len(), sequence_id);
+        let mut data = header.to_vec();
+        data.extend_from_slice(payload);
+        data
+    }
+
+    fn server_handshake(flags: u32) -> Vec<u8> {
+        let mut payload = b"\x0a8.0.36\x00".to_vec();
+        payload.extend_from_slice(&7_u32.to_le_bytes()); // Connection ID
+        payload.extend_from_slice(b"abcdefgh\x00"); // Auth data (part 1), filler
+        payload.extend_from_slice(&(flags as u16).to_le_bytes());
+        payload.push(0xff); // Charset
+        payload.extend_from_slice(&server_status::AUTOCOMMIT.to_le_bytes());
+        payload.extend_from_slice(&((flags >> 16) as u16).to_le_bytes());
+        payload.push(21); // Auth data length
+        payload.extend_from_slice(&[0; 10]);
+        payload.extend_from_slice(b"ijklmnopqrst\x00"); // Auth data (part 2)
+        payload.extend_from_slice(b"mysql_native_password\x00");
+        packet(0, &payload)
+    }
 
-// Exception to return: 22018 | invalid_character_value_for_cast
+    fn client_handshake(flags: u32) -> Vec<u8> {
+        let mut payload = flags.to_le_bytes().to_vec();
+        payload.extend_from_slice(&(1_u32 << 24).to_le_bytes()); // Max packet size
+        payload.push(0xff); // Charset
+        payload.extend_from_slice(&[0; 23]);
+        payload.extend_from_slice(b"app\x00");
+        payload.push(0); // Empty auth response
+        payload.extend_from_slice(b"shop\x00");
+        packet(1, &payload)
+    }
+
+    fn ok_packet(sequence_id: u8, header: u8, status: u16) -> Vec<u8> {
+        let mut payload = vec![header, 0, 0]; // Affected rows, last insert ID
+        payload.extend_from_slice(&status.to_le_bytes());
+        payload.extend_from_slice(&0_u16.to_le_bytes()); // Warnings
+        packet(sequence_id, &payload)
+    }
+
+    fn eof_packet(sequence_id: u8, status: u16) -> Vec<u8> {
+        let mut payload = vec![0xfe, 0, 0]; // Warnings
+        payload.extend_from_slice(&status.to_le_bytes());
+        packet(sequence_id, &payload)
+    }
+
+    /// Passes the data to the session as if sent by the client, returning the requests it was split into.
+    fn client_sends(session: &mut Session, data: &[u8]) -> Vec<MysqlRequest> {
+        session.get_frontend_io_mut().incoming.extend(data);
+        let mut requests = Vec::new();
+        loop {
+            match session.frontend_receive_request() {
+                Ok(request) => requests.push(request),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return requests,
+                Err(e) => panic!("receiving request failed: {}", e),
+            }
+        }
+    }
+
+    /// Passes the data to the session as if sent by the server, returning the result of each response it was split
+    /// into (which is `None` unless the response completes the server's response to a request).
+    fn server_sends(session: &mut Session, data: &[u8]) -> Vec<Option<bool>> {
+        session.get_backend_io_mut().incoming.extend(data);
+        let mut results = Vec::new();
+        loop {
+            match session.backend_receive_response() {
+                Ok(response) => results.push(response.basic_info.result),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return results,
+                Err(e) => panic!("receiving response failed: {}", e),
+            }
+        }
+    }
+
+    /// Receives the client's request and forwards it to the server.
+    fn forward(session: &mut Session, data: &[u8]) -> PacketInfo {
+        let mut requests = client_sends(session, data);
+        assert_eq!(requests.len(), 1);
+        let request = requests.pop().unwrap();
+        session.backend_send_request(&request).unwrap();
+        request.basic_info
+    }
+
+    /// Returns a session that has completed the handshake, with the client supporting the given capabilities.
+    fn connected_session(client_flags: u32) -> Session {
+        let mut session = Session::new(Wire::default(), Wire::default());
+        server_sends(&mut session, &server_handshake(SERVER_CAPABILITIES));
+        forward(&mut session, &client_handshake(client_flags));
+        assert_eq!(
+            server_sends(&mut session, &ok_packet(2, 0, 0)),
+            [Some(true)]
+        );
+        session
+    }
+
+    fn query(text: &str) -> Vec<u8> {
+        let mut payload = vec![0x03];
+        payload.extend_from_slice(text.as_bytes());
+        packet(0, &payload)
+    }
+
+    fn error_fields() -> ErrorFields {
+        ErrorFields {
+            severity: "ERROR".to_string(),
+            sql_state: *b"42000",
+            code: 1064,
+            message: "blocked".to_string(),
+            detail: None,
+            hint: None,
+        }
+    }
+
+    #[test]
+    fn unsupported_capabilities_are_stripped_from_the_server_handshake() {
+        let mut session = Session::new(Wire::default(), Wire::default());
+        let handshake = server_handshake(SERVER_CAPABILITIES);
+        session.get_backend_io_mut().incoming.extend(&handshake);
+        let response = session.backend_receive_response().unwrap();
+        let forwarded = response.as_slice();
+
+        let flags = match parse_server_handshake(&forwarded[HEADER_LEN..]) {
+            Ok(ResponsePacket::HandshakeV10(_, _, _, flags, ..)) => flags,
+            _ => panic!("forwarded handshake wasn't parsed"),
+        };
+        assert_eq!(flags, SERVER_CAPABILITIES & !UNSUPPORTED_CAPABILITIES);
+        assert_eq!(session.capabilities, flags);
+        // Nothing but the capability flags is changed
+        let changed: Vec<usize> = (0..handshake.len())
+            .filter(|i| handshake[*i] != forwarded[*i])
+            .collect();
+        let capabilities_idx = HEADER_LEN + b"\x0a8.0.36\x00".len() + 13; // After the connection ID, auth data and filler
+        assert_eq!(
+            changed,
+            [capabilities_idx, capabilities_idx + 1, capabilities_idx + 6]
+        );
+
+        // The capabilities used from then on are those shared with the client
+        let client_flags = capabilities::PROTOCOL_41
+            | capabilities::SECURE_CONNECTION
+            | capabilities::CONNECT_WITH_DB
+            | capabilities::QUERY_ATTRIBUTES;
+        let info = forward(&mut session, &client_handshake(client_flags));
+        assert_eq!(info.username.as_deref(), Some("app"));
+        assert_eq!(info.database.as_deref(), Some("shop"));
+        assert_eq!(
+            session.capabilities,
+            client_flags & SERVER_CAPABILITIES & !capabilities::QUERY_ATTRIBUTES
+        );
+    }
+
+    #[test]
+    fn ssl_request_is_refused_with_the_next_sequence_id() {
+        let mut session = Session::new(Wire::default(), Wire::default());
+        server_sends(&mut session, &server_handshake(SERVER_CAPABILITIES));
+        let mut ssl_request = (capabilities::PROTOCOL_41 | capabilities::SSL)
+            .to_le_bytes()
+            .to_vec();
+        ssl_request.extend_from_slice(&(1_u32 << 24).to_le_bytes());
+        ssl_request.push(0xff);
+        ssl_request.extend_from_slice(&[0; 23]);
+
+        let mut requests = client_sends(&mut session, &packet(1, &ssl_request));
+        assert!(requests[0].basic_info.ssl_requested);
+        let response = session.frontend_downgrade_ssl(&mut requests[0]).unwrap();
+        assert!(!requests[0].is_valid());
+        assert_eq!(&response.as_slice()[3..6], b"\x02\xff\x13"); // Sequence ID 2, ERR packet, code 1043
+    }
+
+    #[test]
+    fn continued_packets_form_a_single_request() {
+        for text_len in [MAX_PAYLOAD_LEN + 10, MAX_PAYLOAD_LEN - 1] {
+            let mut session = connected_session(SERVER_CAPABILITIES);
+            let text = format!("SELECT '{}'", "a".repeat(text_len - 9));
+            let mut payload = vec![0x03];
+            payload.extend_from_slice(text.as_bytes());
+
+            // A payload of exactly the maximum length is followed by an empty packet
+            let mut wire = packet(0, &payload[..MAX_PAYLOAD_LEN]);
+            wire.extend(packet(1, &payload[MAX_PAYLOAD_LEN..]));
+            let requests = client_sends(&mut session, &wire);
+
+            assert_eq!(requests.len(), 1);
+            assert_eq!(requests[0].as_slice(), wire);
+            assert_eq!(requests[0].basic_info.query.as_deref(), Some(text.as_str()));
+            let error = session.error_response(&error_fields());
+            assert_eq!(error.as_slice()[3], 2); // Follows the last packet of the request
+        }
+    }
+
+    #[test]
+    fn error_for_a_blocked_request_follows_its_sequence_id() {
+        let mut session = connected_session(SERVER_CAPABILITIES);
+        client_sends(&mut session, &query("SELECT 1"));
+
+        let error = session.error_response(&error_fields());
+        let data = error.as_slice();
+        assert_eq!(read_header(data), Ok((data.len() - HEADER_LEN, 1)));
+        assert_eq!(&data[HEADER_LEN..], b"\xff\x28\x04#42000blocked");
+        assert_eq!(error.basic_info.result, Some(false));
+        assert!(!error.basic_info.ends_session);
+    }
+
+    #[test]
+    fn result_set_ends_with_eof_packets() {
+        let mut session = connected_session(SERVER_CAPABILITIES & !capabilities::DEPRECATE_EOF);
+        forward(&mut session, &query("SELECT a, b FROM t"));
+
+        let mut wire = packet(1, b"\x02"); // Column count
+        wire.extend(packet(2, b"column a"));
+        wire.extend(packet(3, b"column b"));
+        // Column definitions are followed by an EOF packet, which is where rows would start otherwise
+        wire.extend(eof_packet(4, 0));
+        wire.extend(packet(5, b"\x011\x012"));
+        wire.extend(packet(6, b"\x013\xfb"));
+        wire.extend(eof_packet(7, 0));
+
+        let results = server_sends(&mut session, &wire);
+        assert_eq!(results.len(), 7);
+        assert!(results[..6].iter().all(|r| r.is_none()));
+        assert_eq!(results[6], Some(true));
+        assert!(session.pending_responses.is_empty());
+    }
+
+    #[test]
+    fn result_set_ends_with_ok_packet_when_eof_is_deprecated() {
+        let mut session = connected_session(SERVER_CAPABILITIES);
+        forward(&mut session, &query("SELECT a FROM t"));
+
+        let mut wire = packet(1, b"\x01");
+        wire.extend(packet(2, b"column a"));
+        wire.extend(packet(3, b"\x011"));
+        wire.extend(ok_packet(4, 0xfe, 0));
+
+        assert_eq!(
+            server_sends(&mut session, &wire),
+            [None, None, None, Some(true)]
+        );
+    }
+
+    #[test]
+    fn further_results_are_awaited_while_more_results_exist() {
+        let mut session = connected_session(SERVER_CAPABILITIES & !capabilities::DEPRECATE_EOF);
+        forward(&mut session, &query("CALL p()"));
+
+        let mut wire = packet(1, b"\x01");
+        wire.extend(packet(2, b"column a"));
+        wire.extend(eof_packet(3, 0));
+        wire.extend(packet(4, b"\x011"));
+        wire.extend(eof_packet(5, server_status::MORE_RESULTS_EXISTS));
+        wire.extend(ok_packet(6, 0, server_status::MORE_RESULTS_EXISTS));
+        wire.extend(ok_packet(7, 0, 0));
+
+        let results = server_sends(&mut session, &wire);
+        assert_eq!(results.len(), 7);
+        assert!(results[..6].iter().all(|r| r.is_none()));
+        assert_eq!(results[6], Some(true));
+    }
+
+    #[test]
+    fn pipelined_requests_are_answered_in_order() {
+        let mut session = connected_session(SERVER_CAPABILITIES);
+        forward(&mut session, &query("SELECT a FROM t"));
+        forward(&mut session, &query("DELETE FROM t"));
+        assert_eq!(session.pending_responses.len(), 2);
+
+        let mut wire = packet(1, b"\xff\x7a\x04#42S02Table 't' doesn't exist");
+        wire.extend(ok_packet(1, 0, 0));
+        assert_eq!(server_sends(&mut session, &wire), [Some(false), Some(true)]);
+        assert!(session.pending_responses.is_empty());
+    }
+}
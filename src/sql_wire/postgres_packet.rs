@@ -151,7 +151,7 @@ pub enum ResponsePacket<'a> {
     RowDescription(Vec<(&'a str, i32, i16, i32, i16, i32, bool)>),
 }
 
-#[derive(PartialEq, Eq)]
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub enum TransactionStatus {
     /// Not in a transaction block
     Idle,
@@ -221,7 +221,7 @@ pub fn parse_startup_req_packet<'a>(buffer: &'a [u8]) -> Result<RequestPacket, &
 
 pub fn parse_standard_req_packet<'a>(buffer: &'a [u8]) -> Result<RequestPacket, &'static str> {
     let (packet_identifier, packet_length) = read_standard_packet_len(buffer)?;
-    if buffer.len() - 1 != packet_length {
+    if buffer.len() != packet_length {
         return Err("request packet length field mismatch (internal error)");
     }
 
@@ -286,7 +286,7 @@ pub fn parse_standard_req_packet<'a>(buffer: &'a [u8]) -> Result<RequestPacket,
 
 pub fn parse_standard_resp_packet<'a>(buffer: &'a [u8]) -> Result<ResponsePacket, &'static str> {
     let (packet_identifier, packet_length) = read_standard_packet_len(buffer)?;
-    if buffer.len() - 1 != packet_length {
+    if buffer.len() != packet_length {
         return Err("response packet length field mismatch (internal error)");
     }
     let mut reader = WireReader::new(buffer);
@@ -376,7 +376,7 @@ fn parse_rowdescription_resp_packet<'a>(
         let data_type_id = reader.read()?;
         let data_type_size = reader.read()?;
         let type_modifier = reader.read()?;
-        let format_code = match reader.read()? {
+        let format_code = match reader.read::<i16>()? {
             1 => true,
             0 => false,
             _ => return Err("packet contained invalid boolean value for format code"),
@@ -402,7 +402,7 @@ fn parse_function_req_packet<'a>(
     let arg_format_code_cnt = reader.read_length::<i16>()?;
     let mut arg_format_codes = Vec::new();
     for _ in 0..arg_format_code_cnt {
-        arg_format_codes.push(match reader.read()? {
+        arg_format_codes.push(match reader.read::<i16>()? {
             1 => true,
             0 => false,
             _ => return Err("packet contains invalid value for boolean format code field"),
@@ -418,7 +418,7 @@ fn parse_function_req_packet<'a>(
         });
     }
 
-    let function_result_format = match reader.read_and_finalize()? {
+    let function_result_format = match reader.read_and_finalize::<i16>()? {
         1 => true,
         0 => false,
         _ => return Err("packet contains invalid value for boolean format code field"),
@@ -472,16 +472,16 @@ fn parse_copyboth_response_packet<'a>(
 fn parse_copy_response_fields<'a>(
     mut reader: WireReader<'a>,
 ) -> Result<(bool, Vec<bool>), &'static str> {
-    let is_binary = match reader.read()? {
-        b'1' => true,
-        b'0' => false,
+    let is_binary = match reader.read::<i8>()? {
+        1 => true,
+        0 => false,
         _ => return Err("packet contains invalid value for boolean copy format field"),
     };
 
     let format_codes_cnt = reader.read_length::<i16>()?;
     let mut format_codes = Vec::new();
     for _ in 0..format_codes_cnt {
-        format_codes.push(match reader.read()? {
+        format_codes.push(match reader.read::<i16>()? {
             1 => true,
             0 => false,
             _ => return Err("packet contains invalid value for boolean format code field"),
@@ -530,7 +530,7 @@ fn parse_bind_packet<'a>(mut reader: WireReader<'a>) -> Result<RequestPacket, &'
     let format_codes_cnt = reader.read_length::<i16>()?;
     let mut format_codes = Vec::new();
     for _ in 0..format_codes_cnt {
-        format_codes.push(match reader.read()? {
+        format_codes.push(match reader.read::<i16>()? {
             0 => false,
             1 => true,
             _ => return Err("packet contained invalid parameter format code"),
@@ -549,7 +549,7 @@ fn parse_bind_packet<'a>(mut reader: WireReader<'a>) -> Result<RequestPacket, &'
     let result_format_codes_cnt = reader.read_length::<i16>()?;
     let mut result_format_codes = Vec::new();
     for _ in 0..result_format_codes_cnt {
-        result_format_codes.push(match reader.read()? {
+        result_format_codes.push(match reader.read::<i16>()? {
             1 => true,
             0 => false,
             _ => return Err("packet contained invalid result-column format code"),
@@ -564,3 +564,128 @@ fn parse_bind_packet<'a>(mut reader: WireReader<'a>) -> Result<RequestPacket, &'
         result_format_codes,
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Frames a message body with its type byte and length.
+    fn message(kind: u8, body: &[u8]) -> Vec<u8> {
+        let mut msg = vec![kind];
+        msg.extend_from_slice(&((body.len() + 4) as i32).to_be_bytes());
+        msg.extend_from_slice(body);
+        msg
+    }
+
+    #[test]
+    fn length_covers_all_but_the_type_byte() {
+        let sync = message(b'S', b"");
+        assert_eq!(read_standard_packet_len(&sync), Ok((b'S', 5)));
+        assert!(matches!(
+            parse_standard_req_packet(&sync),
+            Ok(RequestPacket::Sync)
+        ));
+        let complete = message(b'1', b"");
+        assert!(matches!(
+            parse_standard_resp_packet(&complete),
+            Ok(ResponsePacket::ParseComplete)
+        ));
+
+        let mut too_long = sync.clone();
+        too_long.push(0);
+        assert!(parse_standard_req_packet(&too_long).is_err());
+        assert!(parse_standard_req_packet(&sync[..4]).is_err());
+        let mut too_long = complete.clone();
+        too_long.push(0);
+        assert!(parse_standard_resp_packet(&too_long).is_err());
+    }
+
+    #[test]
+    fn bind_format_codes_are_int16() {
+        let mut body = b"portal\x00stmt\x00".to_vec();
+        body.extend_from_slice(&2_i16.to_be_bytes()); // Parameter format codes
+        body.extend_from_slice(&1_i16.to_be_bytes());
+        body.extend_from_slice(&0_i16.to_be_bytes());
+        body.extend_from_slice(&2_i16.to_be_bytes()); // Parameters
+        body.extend_from_slice(&4_i32.to_be_bytes());
+        body.extend_from_slice(&42_i32.to_be_bytes());
+        body.extend_from_slice(&(-1_i32).to_be_bytes()); // NULL
+        body.extend_from_slice(&1_i16.to_be_bytes()); // Result-column format codes
+        body.extend_from_slice(&1_i16.to_be_bytes());
+
+        match parse_standard_req_packet(&message(b'B', &body)) {
+            Ok(RequestPacket::Bind(portal, statement, formats, params, result_formats)) => {
+                assert_eq!((portal, statement), ("portal", "stmt"));
+                assert_eq!(formats, [true, false]);
+                assert_eq!(params, [Some(&42_i32.to_be_bytes()[..]), None]);
+                assert_eq!(result_formats, [true]);
+            }
+            _ => panic!("Bind wasn't parsed"),
+        }
+    }
+
+    #[test]
+    fn function_call_format_codes_are_int16() {
+        let mut body = 1234_i32.to_be_bytes().to_vec(); // Function OID
+        body.extend_from_slice(&1_i16.to_be_bytes()); // Argument format codes
+        body.extend_from_slice(&1_i16.to_be_bytes());
+        body.extend_from_slice(&1_i16.to_be_bytes()); // Arguments
+        body.extend_from_slice(&1_i32.to_be_bytes());
+        body.push(7);
+        body.extend_from_slice(&0_i16.to_be_bytes()); // Result format
+
+        match parse_standard_req_packet(&message(b'F', &body)) {
+            Ok(RequestPacket::FunctionCall(oid, formats, args, result_binary)) => {
+                assert_eq!(oid, 1234);
+                assert_eq!(formats, [true]);
+                assert_eq!(args, [Some(&[7_u8][..])]);
+                assert!(!result_binary);
+            }
+            _ => panic!("FunctionCall wasn't parsed"),
+        }
+    }
+
+    #[test]
+    fn row_description_format_codes_are_int16() {
+        let mut body = 1_i16.to_be_bytes().to_vec(); // Fields
+        body.extend_from_slice(b"id\x00");
+        body.extend_from_slice(&16384_i32.to_be_bytes()); // Table OID
+        body.extend_from_slice(&1_i16.to_be_bytes()); // Column number
+        body.extend_from_slice(&23_i32.to_be_bytes()); // Type OID
+        body.extend_from_slice(&4_i16.to_be_bytes()); // Type size
+        body.extend_from_slice(&(-1_i32).to_be_bytes()); // Type modifier
+        body.extend_from_slice(&1_i16.to_be_bytes()); // Format code
+
+        match parse_standard_resp_packet(&message(b'T', &body)) {
+            Ok(ResponsePacket::RowDescription(fields)) => {
+                assert_eq!(fields, [("id", 16384, 1, 23, 4, -1, true)]);
+            }
+            _ => panic!("RowDescription wasn't parsed"),
+        }
+    }
+
+    #[test]
+    fn copy_format_is_a_binary_int8() {
+        let mut body = vec![1]; // Overall format
+        body.extend_from_slice(&2_i16.to_be_bytes()); // Column format codes
+        body.extend_from_slice(&1_i16.to_be_bytes());
+        body.extend_from_slice(&0_i16.to_be_bytes());
+
+        match parse_standard_resp_packet(&message(b'G', &body)) {
+            Ok(ResponsePacket::CopyInResponse(is_binary, formats)) => {
+                assert!(is_binary);
+                assert_eq!(formats, [true, false]);
+            }
+            _ => panic!("CopyInResponse wasn't parsed"),
+        }
+
+        body[0] = 0;
+        assert!(matches!(
+            parse_standard_resp_packet(&message(b'H', &body)),
+            Ok(ResponsePacket::CopyOutResponse(false, formats)) if formats == [true, false]
+        ));
+
+        body[0] = b'1';
+        assert!(parse_standard_resp_packet(&message(b'W', &body)).is_err());
+    }
+}
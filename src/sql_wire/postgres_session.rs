@@ -1,5 +1,5 @@
 use std::collections::VecDeque;
-use std::{cmp, io};
+use std::io;
 
 use super::postgres_packet::*;
 
@@ -12,13 +12,12 @@ const SSL_RESPONSE_PKT_HDRLEN: usize = 1;
 
 const DEFAULT_REQ_RESP_BUFLEN: usize = 1 * 1024; // 1KB
 
-// ErrorResponse packet is 58 bytes long: \x00\x00\x00\x3A
-const ERROR_PACKET_BYTES: &'static [u8] = b"E\x00\x00\x00\x3ASERROR\x00C42000\x00MMalformed input blocked by SQLFortify\x00\x00Z\x00\x00\x00\x05I";
-// TODO: this always assumes not in a transaction block. Will that cause issues???
-// I => idle (not in Transaction block)
-// T => in transaction block
-// E => in failed transaction block
-// Realistically, we'd only ever be in 'I' or 'E'. It seems like always returning 'I' would be preferable to always returning 'E'?
+// ReadyForQuery, excluding its transaction status indicator
+const READY_FOR_QUERY_HEADER: &[u8] = b"Z\x00\x00\x00\x05";
+// A statement that fails to parse, and so does nothing beyond failing the transaction block it is sent in
+const TRANSACTION_ABORT_QUERY: &[u8] = b"SQLFORTIFY BLOCKED QUERY\x00";
+// SSLRequest: the packet length (8) followed by the SSL request code (80877103)
+const SSL_REQUEST_BYTES: &[u8] = b"\x00\x00\x00\x08\x04\xd2\x16\x2f";
 
 #[derive(Clone, Copy, PartialEq, Eq)]
 enum SessionState {
@@ -101,6 +100,7 @@ pub struct PostgresClientSession<T: io::Read + io::Write> {
     recycled_responses: VecDeque<PostgresResponse>,
     request_failure: Option<bool>,
     request_part_idx: Option<usize>,
+    transaction_status: TransactionStatus,
 }
 
 impl<T: io::Read + io::Write> Client<T> for PostgresClientSession<T> {
@@ -114,6 +114,7 @@ impl<T: io::Read + io::Write> Client<T> for PostgresClientSession<T> {
             recycled_responses: VecDeque::new(),
             request_failure: None,
             request_part_idx: None,
+            transaction_status: TransactionStatus::Idle,
         }
     }
 
@@ -138,6 +139,7 @@ impl<T: io::Read + io::Write> Client<T> for PostgresClientSession<T> {
             &mut response,
             &mut self.state,
             &mut self.request_failure,
+            &mut self.transaction_status,
         ) {
             Ok(()) => Ok(response),
             Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
@@ -222,6 +224,8 @@ pub struct PostgresProxySession<C: io::Read + io::Write, S: io::Read + io::Write
     response_part_idx: Option<usize>,
     server_io: S,
     state: SessionState,
+    /// The transaction status reported by the backend's most recent `ReadyForQuery`
+    transaction_status: TransactionStatus,
 }
 
 impl<C: io::Read + io::Write, S: io::Read + io::Write> Proxy<C, S> for PostgresProxySession<C, S> {
@@ -238,6 +242,7 @@ impl<C: io::Read + io::Write, S: io::Read + io::Write> Proxy<C, S> for PostgresP
             request_failure: None,
             server_io: server_io,
             state: SessionState::Startup,
+            transaction_status: TransactionStatus::Idle,
         }
     }
 
@@ -290,6 +295,7 @@ impl<C: io::Read + io::Write, S: io::Read + io::Write> Proxy<C, S> for PostgresP
             &mut response,
             &mut self.state,
             &mut self.request_failure,
+            &mut self.transaction_status,
         ) {
             Ok(()) => {
                 log::debug!("Response received");
@@ -343,6 +349,14 @@ impl<C: io::Read + io::Write, S: io::Read + io::Write> Proxy<C, S> for PostgresP
         &self.server_io
     }
 
+    fn get_backend_io_mut(&mut self) -> &mut C {
+        &mut self.client_io
+    }
+
+    fn get_frontend_io_mut(&mut self) -> &mut S {
+        &mut self.server_io
+    }
+
     fn frontend_downgrade_ssl(
         &mut self,
         ssl_request: &mut Self::RequestType,
@@ -362,11 +376,61 @@ impl<C: io::Read + io::Write, S: io::Read + io::Write> Proxy<C, S> for PostgresP
         })
     }
 
-    fn backend_downgrade_ssl(
+    fn frontend_accept_ssl(
         &mut self,
-        _ssl_response: &mut Self::ResponseType,
-    ) -> Option<Self::RequestType> {
-        todo!() // TODO: stub
+        ssl_request: &mut Self::RequestType,
+    ) -> io::Result<Self::ResponseType> {
+        // The frontend sends its startup message once the TLS handshake completes
+        ssl_request.is_valid = false;
+        if self.state != SessionState::SslRequested {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "SSL request accepted outside of connection startup",
+            ));
+        }
+        self.state = SessionState::Startup;
+
+        Ok(PostgresResponse {
+            basic_info: PacketInfo::new(),
+            is_valid: true,
+            raw_data: Vec::from([b'S']),
+            pkt_len: SSL_RESPONSE_PKT_HDRLEN,
+        })
+    }
+
+    fn backend_request_ssl(&mut self) -> io::Result<Self::RequestType> {
+        let mut request = match self.recycled_requests.pop_front() {
+            Some(req) => req,
+            None => PostgresRequest::new(),
+        };
+
+        if request.data.len() < SSL_REQUEST_BYTES.len() {
+            request.data.resize(SSL_REQUEST_BYTES.len(), 0);
+        }
+        request.data[..SSL_REQUEST_BYTES.len()].copy_from_slice(SSL_REQUEST_BYTES);
+        request.pkt_len = SSL_REQUEST_BYTES.len();
+        request.is_valid = true;
+        request.basic_info.ssl_requested = true;
+        Ok(request)
+    }
+
+    fn backend_receive_ssl_response(&mut self) -> io::Result<bool> {
+        // The response is a single byte, so it can't be partially read
+        let mut response = [0; SSL_RESPONSE_PKT_HDRLEN];
+        match self.client_io.read(&mut response)? {
+            0 => Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "I/O device unexpectedly closed while reading data",
+            )),
+            _ => match response[0] {
+                b'S' => Ok(true),
+                b'N' => Ok(false),
+                _ => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "unexpected response to SSL request received from backend",
+                )),
+            },
+        }
     }
 
     fn frontend_downgrade_gssenc(
@@ -395,68 +459,91 @@ impl<C: io::Read + io::Write, S: io::Read + io::Write> Proxy<C, S> for PostgresP
         todo!() // TODO: stub
     }
 
-    fn error_response(&mut self) -> Self::ResponseType {
+    fn transaction_abort_request(&mut self) -> Option<Self::RequestType> {
+        if self.transaction_status != TransactionStatus::Transaction {
+            return None; // Outside of a transaction block (or in one that has already failed), errors leave nothing to undo
+        }
+
+        let mut request = self.new_request(b'Q', &[TRANSACTION_ABORT_QUERY]);
+        request.basic_info.is_request = true;
+        Some(request)
+    }
+
+    fn batch_abort_request(&mut self) -> Option<Self::RequestType> {
+        // An unnamed statement that fails to parse fails the batch's transaction (whether implicit or a transaction
+        // block), and the backend skips the rest of the batch up to its Sync
+        Some(self.new_request(b'P', &[b"\x00", TRANSACTION_ABORT_QUERY, b"\x00\x00"]))
+    }
+
+    fn error_response(&mut self, error: &ErrorFields) -> Self::ResponseType {
         let mut basic_info = PacketInfo::new();
         basic_info.result = Some(false);
+        basic_info.ends_session = error.ends_session();
+
+        // Each field is a type byte followed by a null-terminated string
+        let mut fields = Vec::new();
+        let mut push_field = |field_type: u8, value: &[u8]| {
+            fields.push(field_type);
+            fields.extend(value.iter().filter(|b| **b != 0)); // Null bytes would end the field early
+            fields.push(0);
+        };
+        push_field(b'S', error.severity.as_bytes());
+        push_field(b'V', error.severity.as_bytes());
+        push_field(b'C', &error.sql_state);
+        push_field(b'M', error.message.as_bytes());
+        if let Some(detail) = error.detail.as_ref() {
+            push_field(b'D', detail.as_bytes());
+        }
+        if let Some(hint) = error.hint.as_ref() {
+            push_field(b'H', hint.as_bytes());
+        }
+        fields.push(0); // Terminates the list of fields
+
+        let mut raw_data = Vec::from([b'E']);
+        raw_data.extend_from_slice(&(fields.len() as u32 + 4).to_be_bytes());
+        raw_data.extend_from_slice(&fields);
+        // A session-ending error is the last message the frontend receives, so it isn't told to send another query
+        if !basic_info.ends_session {
+            raw_data.extend_from_slice(READY_FOR_QUERY_HEADER);
+            // An error within a transaction block fails the transaction, which the frontend must be told so that it rolls back
+            raw_data.push(match self.transaction_status {
+                TransactionStatus::Idle => b'I',
+                TransactionStatus::Transaction | TransactionStatus::FailedTransaction => b'E',
+            });
+        }
 
         PostgresResponse {
             basic_info: basic_info,
             is_valid: true,
-            raw_data: Vec::from(ERROR_PACKET_BYTES),
-            pkt_len: ERROR_PACKET_BYTES.len(),
+            pkt_len: raw_data.len(),
+            raw_data,
         }
     }
 }
 
-fn read_packet<'a, T: io::Read>(
-    io: &mut T,
-    buf: &'a mut Vec<u8>,
-    buf_len: &mut usize, // 0 <= buf_len <= buf.len()
-    pkt_len: usize,      // Could be *anything*--passed in from client
-) -> io::Result<&'a mut [u8]> {
-    let mut buffer_length = buf.len();
-    let mut truncated_end_idx = cmp::min(pkt_len, buffer_length);
-
-    if *buf_len > buf.len() {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            "Internal error while reading in packet--write index exceeded buffer length",
-        ));
-    } // Thus, *buf_len <= buf.len()
-
-    loop {
-        while let Some(remaining_buffer) = buf.get_mut(*buf_len..truncated_end_idx) {
-            if remaining_buffer.len() == 0 {
-                break;
-            }
+impl<C: io::Read + io::Write, S: io::Read + io::Write> PostgresProxySession<C, S> {
+    /// Creates a request of the given message type, whose body is the concatenation of `body_parts`.
+    fn new_request(&mut self, kind: u8, body_parts: &[&[u8]]) -> PostgresRequest {
+        let mut request = match self.recycled_requests.pop_front() {
+            Some(req) => req,
+            None => PostgresRequest::new(),
+        };
 
-            match io.read(remaining_buffer) {
-                Ok(0) => {
-                    return Err(io::Error::new(
-                        io::ErrorKind::ConnectionAborted,
-                        "I/O device unexpectedly closed while reading data",
-                    ))
-                }
-                Ok(len) => {
-                    *buf_len += len; // Invariant: *buf_len + len <= buf.len() because we're reading into a buffer of size (buf.len() - *buf_len) or less
-                    log::debug!("Read {} bytes into buffer", len);
-                }
-                Err(e) => return Err(e),
-            }
+        let body_len: usize = body_parts.iter().map(|part| part.len()).sum();
+        let mut data = Vec::from([kind]);
+        data.extend_from_slice(&(body_len as u32 + 4).to_be_bytes());
+        for part in body_parts {
+            data.extend_from_slice(part);
         }
 
-        if *buf_len >= pkt_len {
-            break;
-        } else {
-            log::debug!("Packet contents exceeded available space--increasing buffer size");
-            buf.extend(std::iter::repeat(0).take(cmp::min(buffer_length, pkt_len - buffer_length))); // We need more buffer--at most double the current one
-
-            buffer_length = buf.len();
-            truncated_end_idx = cmp::min(pkt_len, buffer_length);
+        if request.data.len() < data.len() {
+            request.data.resize(data.len(), 0);
         }
+        request.data[..data.len()].copy_from_slice(&data);
+        request.pkt_len = data.len();
+        request.is_valid = true;
+        request
     }
-
-    return Ok(&mut buf[..pkt_len]); // Invariant: pkt_len <= *buf_len <= buf.len(), so this will never index out of bounds
 }
 
 fn receive_request<T: io::Read>(
@@ -537,12 +624,19 @@ fn receive_request<T: io::Read>(
             log::debug!("Request body read.");
 
             match (*state, parse_standard_req_packet(pkt)) {
-                (SessionState::Normal, Ok(RequestPacket::Query(q))) => {
+                // A simple query may be pipelined straight after an extended-query batch (before the backend's ReadyForQuery
+                // for its Sync arrives), and must be checked just the same
+                (SessionState::Normal | SessionState::ExtendedQuery, Ok(RequestPacket::Query(q))) => {
                     request.basic_info.query = Some(q.to_string());
                     request.basic_info.is_request = true;
                 },
-                (SessionState::Normal, Ok(RequestPacket::FunctionCall(_,_,_,_))) => request.basic_info.is_request = true,
-                (SessionState::Normal, Ok(RequestPacket::Parse(_,_,_) | RequestPacket::Bind(_,_,_,_,_) | RequestPacket::Execute(_,_) | RequestPacket::DescribePortal(_) | RequestPacket::DescribePrepared(_) | RequestPacket::ClosePortal(_) | RequestPacket::ClosePrepared(_) | RequestPacket::Flush)) => *state = SessionState::ExtendedQuery,
+                (SessionState::Normal | SessionState::ExtendedQuery, Ok(RequestPacket::FunctionCall(_,_,_,_))) => request.basic_info.is_request = true,
+                (SessionState::Normal | SessionState::ExtendedQuery, Ok(RequestPacket::Parse(_, q, _))) => {
+                    // Parse isn't itself answered with a result--its outcome is reported along with the rest of the batch on Sync
+                    request.basic_info.query = Some(q.to_string());
+                    *state = SessionState::ExtendedQuery;
+                },
+                (SessionState::Normal, Ok(RequestPacket::Bind(_,_,_,_,_) | RequestPacket::Execute(_,_) | RequestPacket::DescribePortal(_) | RequestPacket::DescribePrepared(_) | RequestPacket::ClosePortal(_) | RequestPacket::ClosePrepared(_) | RequestPacket::Flush)) => *state = SessionState::ExtendedQuery,
                 (SessionState::Normal, Ok(RequestPacket::Sync)) => {
                     request.basic_info.is_request = true;
                     *state = SessionState::ExtendedQuery;
@@ -572,6 +666,7 @@ fn receive_response<T: io::Read>(
     response: &mut PostgresResponse,
     state: &mut SessionState,
     request_failure: &mut Option<bool>,
+    transaction_status: &mut TransactionStatus,
 ) -> io::Result<()> {
     log::debug!("Reading response header...");
     let header_bytes = read_packet(
@@ -594,10 +689,14 @@ fn receive_response<T: io::Read>(
     log::debug!("Response body read.");
 
     match (*state, parse_standard_resp_packet(pkt)) {
-        (_, Ok(ResponsePacket::ErrorResponse(_))) => *request_failure = Some(true),
-        (SessionState::Normal, Ok(ResponsePacket::ReadyForQuery(_))) => {
+        (_, Ok(ResponsePacket::ErrorResponse(_))) => {
+            response.basic_info.is_error = true;
+            *request_failure = Some(true);
+        }
+        (SessionState::Normal, Ok(ResponsePacket::ReadyForQuery(status))) => {
             response.basic_info.result = Some(!request_failure.unwrap_or(false));
             *request_failure = None;
+            *transaction_status = status;
         }
         (
             SessionState::Normal,
@@ -605,10 +704,11 @@ fn receive_response<T: io::Read>(
         ) => *state = SessionState::CopyIn,
         (SessionState::Normal, Ok(_)) => (),
 
-        (SessionState::ExtendedQuery, Ok(ResponsePacket::ReadyForQuery(_))) => {
+        (SessionState::ExtendedQuery, Ok(ResponsePacket::ReadyForQuery(status))) => {
             *state = SessionState::Normal;
             response.basic_info.result = Some(!request_failure.unwrap_or(false));
             *request_failure = None;
+            *transaction_status = status;
         }
         (
             SessionState::ExtendedQuery,
@@ -621,29 +721,20 @@ fn receive_response<T: io::Read>(
     Ok(())
 }
 
-fn advance_up_to<'a, T>(buf: &'a [T], amount: usize) -> &'a [T] {
-    match buf.get(amount..) {
-        Some(b) => b,
-        None => &mut [],
-    }
-}
-
 fn send_request<T: io::Write>(
     io: &mut T,
     request: &PostgresRequest,
     _state: &mut SessionState,
     start: &mut Option<usize>,
 ) -> io::Result<()> {
-    let mut total_written = start.unwrap_or(0);
-
     log::debug!("Writing request...");
 
     if !request.is_valid {
         log::error!("Invalid request sent")
     }
 
-    let mut buf = match request.as_slice().get(total_written..request.pkt_len) {
-        Some(b) => b,
+    let packet = match request.as_slice().get(..request.pkt_len) {
+        Some(p) => p,
         None => {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidData,
@@ -652,23 +743,7 @@ fn send_request<T: io::Write>(
         }
     };
 
-    while buf.len() > 0 {
-        let num_written = match io.write(buf) {
-            Ok(0) => {
-                return Err(io::Error::new(
-                    io::ErrorKind::ConnectionAborted,
-                    "I/O device unexpectedly closed while writing data",
-                ))
-            }
-            Ok(num_written) => num_written,
-            Err(e) => return Err(e),
-        };
-
-        total_written += num_written;
-        *start = Some(total_written);
-        buf = advance_up_to(buf, num_written);
-    }
-
+    write_packet(io, packet, start)?;
     log::debug!("Successfully wrote request.");
     return Ok(());
 }
@@ -679,16 +754,14 @@ fn send_response<T: io::Write>(
     _state: &mut SessionState,
     start: &mut Option<usize>,
 ) -> io::Result<()> {
-    let mut total_written = start.unwrap_or(0);
-
     log::debug!("Writing response...");
 
     if !response.is_valid {
         log::error!("Invalid response sent")
     }
 
-    let mut buf = match response.as_slice().get(total_written..response.pkt_len) {
-        Some(buf) => buf,
+    let packet = match response.as_slice().get(..response.pkt_len) {
+        Some(p) => p,
         None => {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidData,
@@ -697,23 +770,108 @@ fn send_response<T: io::Write>(
         }
     };
 
-    while buf.len() > 0 {
-        let num_written = match io.write(buf) {
-            Ok(0) => {
-                return Err(io::Error::new(
-                    io::ErrorKind::ConnectionAborted,
-                    "I/O device unexpectedly closed while writing data",
-                ))
-            }
-            Ok(num_written) => num_written,
-            Err(e) => return Err(e),
-        };
+    write_packet(io, packet, start)?;
+    log::debug!("Successfully wrote response.");
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        total_written += num_written;
-        *start = Some(total_written);
-        buf = advance_up_to(buf, num_written);
+    /// Frames a message body with its type byte and length.
+    fn message(kind: u8, body: &[u8]) -> Vec<u8> {
+        let mut msg = vec![kind];
+        msg.extend_from_slice(&((body.len() + 4) as i32).to_be_bytes());
+        msg.extend_from_slice(body);
+        msg
     }
 
-    log::debug!("Successfully wrote response.");
-    return Ok(());
+    fn parse(query: &str) -> Vec<u8> {
+        let mut body = b"\x00".to_vec(); // Unnamed statement
+        body.extend_from_slice(query.as_bytes());
+        body.extend_from_slice(b"\x00\x00\x00"); // No parameter types
+        message(b'P', &body)
+    }
+
+    fn query(query: &str) -> Vec<u8> {
+        let mut body = query.as_bytes().to_vec();
+        body.push(0);
+        message(b'Q', &body)
+    }
+
+    fn receive_all(wire: Vec<u8>, state: &mut SessionState) -> Vec<PacketInfo> {
+        let mut io = io::Cursor::new(wire);
+        let mut infos = Vec::new();
+        while (io.position() as usize) < io.get_ref().len() {
+            let mut request = PostgresRequest::new();
+            receive_request(&mut io, &mut request, state).unwrap();
+            infos.push(request.basic_info);
+        }
+        infos
+    }
+
+    #[test]
+    fn query_pipelined_after_extended_batch_is_checked() {
+        let mut wire = parse("SELECT 1");
+        wire.extend(message(b'S', b""));
+        wire.extend(query("SELECT 1; DROP TABLE t"));
+
+        let mut state = SessionState::Normal;
+        let infos = receive_all(wire, &mut state);
+
+        assert_eq!(infos[0].query.as_deref(), Some("SELECT 1"));
+        assert!(!infos[0].is_request);
+        assert!(infos[1].is_request);
+        assert_eq!(infos[2].query.as_deref(), Some("SELECT 1; DROP TABLE t"));
+        assert!(infos[2].is_request);
+        // The backend has yet to answer the Sync, so the batch is still outstanding
+        assert!(state == SessionState::ExtendedQuery);
+    }
+
+    #[test]
+    fn function_call_pipelined_after_extended_batch_is_a_request() {
+        let mut wire = parse("SELECT 1");
+        wire.extend(message(b'S', b""));
+        // Function OID, no argument formats, no arguments, text result
+        wire.extend(message(b'F', b"\x00\x00\x04\xd2\x00\x00\x00\x00\x00\x00"));
+
+        let mut state = SessionState::Normal;
+        let infos = receive_all(wire, &mut state);
+
+        assert!(infos[2].is_request);
+    }
+
+    fn error_fields(severity: &str) -> ErrorFields {
+        ErrorFields {
+            severity: severity.to_string(),
+            sql_state: *b"42000",
+            code: 1064,
+            message: "blocked".to_string(),
+            detail: None,
+            hint: None,
+        }
+    }
+
+    #[test]
+    fn only_errors_that_keep_the_session_are_followed_by_ready_for_query() {
+        let mut session =
+            PostgresProxySession::new(io::Cursor::new(vec![]), io::Cursor::new(vec![]));
+
+        let response = session.error_response(&error_fields("ERROR"));
+        assert!(!response.basic_info.ends_session);
+        assert!(response.as_slice()[..response.pkt_len].ends_with(b"Z\x00\x00\x00\x05I"));
+
+        for severity in ["FATAL", "PANIC"] {
+            let response = session.error_response(&error_fields(severity));
+            let packet = &response.as_slice()[..response.pkt_len];
+            assert!(response.basic_info.ends_session);
+            assert_eq!(packet[0], b'E');
+            // The ErrorResponse is the whole of the response
+            assert_eq!(
+                u32::from_be_bytes(packet[1..5].try_into().unwrap()) as usize,
+                packet.len() - 1
+            );
+        }
+    }
 }
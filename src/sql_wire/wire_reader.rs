@@ -64,7 +64,7 @@ impl<'a> EndianConvert<'a> for $int {
     }
 
     fn from_le(bytes: Self::Bytes) -> Self {
-        <$int>::from_be_bytes(*bytes)
+        <$int>::from_le_bytes(*bytes)
     }
 
     fn zero() -> Self {
@@ -237,17 +237,18 @@ impl<'a> WireReader<'a> {
         Ok(ret)
     }
 
+    /// Reads a 3-byte little-endian integer (as used by the MySQL wire protocol).
     pub fn read_u32_3byte(&mut self) -> Result<u32, &'static str> {
         let (int32_bytes, remaining_bytes) = try_split_at(self.bytes, 3);
         let mut buffer = [0u8; 4];
 
         match int32_bytes.get(..3) {
-            Some(b) => {
+            Some(b) if b.len() == 3 => {
                 buffer[..3].copy_from_slice(b);
                 self.bytes = remaining_bytes;
-                Ok(u32::from_be_bytes(buffer)) // Network Byte order is big-endian
+                Ok(u32::from_le_bytes(buffer))
             }
-            None => Err(INSUFFICIENT_DATA_ERROR),
+            _ => Err(INSUFFICIENT_DATA_ERROR),
         }
     }
 
@@ -306,6 +307,21 @@ impl<'a> WireReader<'a> {
         Ok(ret)
     }
 
+    /// Reads an integer in little-endian byte order (as used by the MySQL wire protocol).
+    pub fn read_le<T>(&mut self) -> Result<T, &'static str> 
+    where T: EndianConvert<'a> {
+        let (int_bytes, remaining_bytes) = try_split_at(self.bytes, size_of::<T>());
+
+        let res: Result<T::Bytes, _> = std::convert::TryInto::try_into(int_bytes);
+        match res {
+            Ok(b) => {
+                self.bytes = remaining_bytes;
+                Ok(T::from_le(b))
+            }
+            Err(_) => Err(INSUFFICIENT_DATA_ERROR),
+        }
+    }
+
     
     pub fn read_length<T>(&mut self) -> Result<usize, &'static str>
     where T: EndianConvert<'a>  {
@@ -442,8 +458,8 @@ fn split_at<T, const S: usize>(buffer: &[T; S], index: usize) -> Result<(&[T; S]
 
 /// Splits the slice into two slices at the first instance of `value`, or returns `None` if `value` is not in the slice.
 fn try_split_once<T: Eq>(buffer: &[T], value: T) -> Option<(&[T], &[T])> {
-    match buffer.split(|t| *t == value).next() {
-        Some(split_buf) => Some((split_buf, buffer.get(split_buf.len() + 1..).unwrap_or(&[]))),
+    match buffer.iter().position(|t| *t == value) {
+        Some(idx) => Some((&buffer[..idx], &buffer[idx + 1..])),
         None => None,
     }
 }
@@ -0,0 +1,450 @@
+use rustls_pemfile::Item;
+use serde::Deserialize;
+use socket2::Socket;
+use std::io::{BufReader, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::{error, fmt, fs, io, net};
+
+/// Whether TLS is used for one side of a proxied connection, much like libpq's `sslmode`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    /// Connections are never encrypted
+    Disable,
+    /// Connections are encrypted if the other side supports it, and are otherwise left unencrypted
+    Prefer,
+    /// Connections that can't be encrypted are refused
+    Require,
+}
+
+/// TLS offered to clients connecting to the proxy.
+#[derive(Clone)]
+pub struct FrontendParameters {
+    /// Whether clients that don't request TLS are refused
+    pub required: bool,
+    pub config: Arc<rustls::ServerConfig>,
+}
+
+/// TLS used to connect to the database.
+#[derive(Clone)]
+pub struct BackendParameters {
+    /// Whether the connection is aborted if the database doesn't support TLS
+    pub required: bool,
+    pub config: Arc<rustls::ClientConfig>,
+    /// The name that the database's certificate is verified against
+    pub server_name: rustls::ServerName,
+}
+
+#[derive(Clone, Default)]
+pub struct Parameters {
+    /// If `None`, clients that request TLS are told it isn't supported
+    pub frontend: Option<FrontendParameters>,
+    /// If `None`, the connection to the database is left unencrypted
+    pub backend: Option<BackendParameters>,
+}
+
+#[derive(Debug)]
+pub struct TlsError {
+    reason: String,
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", &self.reason)
+    }
+}
+
+impl error::Error for TlsError {}
+
+/// Builds the configuration used to accept TLS connections from clients, presenting the certificate chain
+/// and private key found in the given PEM files.
+pub fn server_config(
+    cert_path: &Path,
+    key_path: &Path,
+) -> Result<Arc<rustls::ServerConfig>, TlsError> {
+    let certs = read_certificates(cert_path)?;
+    if certs.is_empty() {
+        return Err(TlsError {
+            reason: format!("no certificates found in {}", cert_path.display()),
+        });
+    }
+
+    let items = match fs::File::open(key_path)
+        .and_then(|f| rustls_pemfile::read_all(&mut BufReader::new(f)))
+    {
+        Ok(i) => i,
+        Err(e) => {
+            return Err(TlsError {
+                reason: format!("couldn't read {}: {}", key_path.display(), e),
+            })
+        }
+    };
+
+    let key = items.into_iter().find_map(|item| match item {
+        Item::PKCS8Key(k) | Item::RSAKey(k) | Item::ECKey(k) => Some(rustls::PrivateKey(k)),
+        _ => None,
+    });
+    let key = match key {
+        Some(k) => k,
+        None => {
+            return Err(TlsError {
+                reason: format!("no private key found in {}", key_path.display()),
+            })
+        }
+    };
+
+    match rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+    {
+        Ok(config) => Ok(Arc::new(config)),
+        Err(e) => Err(TlsError {
+            reason: format!(
+                "certificate {} can't be used with key {}: {}",
+                cert_path.display(),
+                key_path.display(),
+                e
+            ),
+        }),
+    }
+}
+
+/// Builds the configuration used to connect to the database over TLS, trusting only the
+/// certificate authorities found in the given PEM file.
+pub fn client_config(ca_path: &Path) -> Result<Arc<rustls::ClientConfig>, TlsError> {
+    let certs = read_certificates(ca_path)?;
+
+    let mut roots = rustls::RootCertStore::empty();
+    let (added, _) =
+        roots.add_parsable_certificates(&certs.iter().map(|c| &c.0).collect::<Vec<_>>());
+    if added == 0 {
+        return Err(TlsError {
+            reason: format!("no usable CA certificates found in {}", ca_path.display()),
+        });
+    }
+
+    let config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+fn read_certificates(path: &Path) -> Result<Vec<rustls::Certificate>, TlsError> {
+    match fs::File::open(path).and_then(|f| rustls_pemfile::certs(&mut BufReader::new(f))) {
+        Ok(certs) => Ok(certs.into_iter().map(rustls::Certificate).collect()),
+        Err(e) => Err(TlsError {
+            reason: format!("couldn't read {}: {}", path.display(), e),
+        }),
+    }
+}
+
+/// A socket whose traffic may be encrypted with TLS. Streams begin unencrypted, as database wire protocols
+/// negotiate TLS in plaintext before the handshake starts.
+pub struct Stream {
+    socket: Socket,
+    tls: Option<rustls::Connection>,
+}
+
+impl Stream {
+    pub fn new(socket: Socket) -> Self {
+        Stream { socket, tls: None }
+    }
+
+    pub fn get_socket(&self) -> &Socket {
+        &self.socket
+    }
+
+    /// Returns `true` if TLS has been started on the stream (even if the handshake hasn't completed).
+    pub fn is_tls(&self) -> bool {
+        self.tls.is_some()
+    }
+
+    /// Returns `true` if encrypted data is waiting to be written out to the socket.
+    pub fn wants_write(&self) -> bool {
+        match self.tls.as_ref() {
+            Some(tls) => tls.wants_write(),
+            None => false,
+        }
+    }
+
+    /// Encrypts all further traffic on the stream with the given TLS session, whose handshake is then progressed
+    /// with `handshake()`.
+    pub fn start_tls(&mut self, tls: rustls::Connection) {
+        self.tls = Some(tls);
+    }
+
+    /// Progresses the TLS handshake as far as possible without blocking, returning `Ok(())` once it is complete.
+    /// If the handshake is interrupted by `WouldBlock`, `wants_write()` indicates whether it is waiting to write or read.
+    pub fn handshake(&mut self) -> io::Result<()> {
+        let tls = match self.tls.as_mut() {
+            Some(t) => t,
+            None => return Ok(()),
+        };
+
+        loop {
+            write_tls(tls, &mut self.socket)?;
+
+            if !tls.is_handshaking() {
+                return Ok(());
+            }
+
+            match tls.read_tls(&mut self.socket)? {
+                0 => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        "peer closed the connection during the TLS handshake",
+                    ))
+                }
+                _ => process_tls(tls, &mut self.socket)?,
+            }
+        }
+    }
+
+    /// Shuts down the given half of the socket, first telling the peer that no more data will be sent over TLS (if in use).
+    pub fn shutdown(&mut self, how: net::Shutdown) -> io::Result<()> {
+        if how != net::Shutdown::Read {
+            if let Some(tls) = self.tls.as_mut() {
+                tls.send_close_notify();
+                match write_tls(tls, &mut self.socket) {
+                    Ok(()) => (),
+                    Err(e) => log::debug!("Couldn't send TLS close_notify alert: {}", e),
+                }
+            }
+        }
+
+        self.socket.shutdown(how)
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let tls = match self.tls.as_mut() {
+            Some(t) => t,
+            None => return self.socket.read(buf),
+        };
+
+        // Only returns `WouldBlock` once both the decrypted data and the socket have been exhausted, as data left
+        // buffered here wouldn't trigger another read event from the poller
+        loop {
+            match tls.reader().read(buf) {
+                Ok(len) => return Ok(len),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                // Many clients close the socket without sending `close_notify`, which is treated as any other close
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(0),
+                Err(e) => return Err(e),
+            }
+
+            tls.read_tls(&mut self.socket)?;
+            process_tls(tls, &mut self.socket)?;
+
+            // Alerts or key updates prompted by the data read are sent now if possible, and otherwise with the next write
+            match write_tls(tls, &mut self.socket) {
+                Ok(()) => (),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let tls = match self.tls.as_mut() {
+            Some(t) => t,
+            None => return self.socket.write(buf),
+        };
+
+        // Data is only accepted once everything encrypted previously has been written, so that at most one write's
+        // worth of data is ever buffered in the TLS session
+        write_tls(tls, &mut self.socket)?;
+        let len = tls.writer().write(buf)?;
+
+        // Whatever can't be written now is written by the next call to `write()` or `flush()`
+        match write_tls(tls, &mut self.socket) {
+            Ok(()) => Ok(len),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(len),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.tls.as_mut() {
+            Some(tls) => write_tls(tls, &mut self.socket),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Writes out all encrypted data buffered in the TLS session.
+fn write_tls(tls: &mut rustls::Connection, socket: &mut Socket) -> io::Result<()> {
+    while tls.wants_write() {
+        if tls.write_tls(socket)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "I/O device unexpectedly closed while writing data",
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Decrypts the data most recently read into the TLS session. If it is invalid, the alert describing why is sent to the peer.
+fn process_tls(tls: &mut rustls::Connection, socket: &mut Socket) -> io::Result<()> {
+    match tls.process_new_packets() {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            let _ = write_tls(tls, socket);
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("TLS error: {}", e),
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// Returns the path of a file in `testdata/tls`, which holds a test CA and a certificate it issued for `localhost`.
+    fn test_file(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata/tls")
+            .join(name)
+    }
+
+    fn test_server_config() -> Arc<rustls::ServerConfig> {
+        server_config(&test_file("server.pem"), &test_file("server.key")).unwrap()
+    }
+
+    fn test_client_connection() -> rustls::Connection {
+        let config = client_config(&test_file("ca.pem")).unwrap();
+        let server_name = rustls::ServerName::try_from("localhost").unwrap();
+        rustls::Connection::Client(rustls::ClientConnection::new(config, server_name).unwrap())
+    }
+
+    /// Returns both ends of a nonblocking TCP connection over the loopback interface.
+    fn stream_pair() -> (Stream, Stream) {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let connecting = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+        let (client, server) = (Socket::from(connecting), Socket::from(accepted));
+        client.set_nonblocking(true).unwrap();
+        server.set_nonblocking(true).unwrap();
+        (Stream::new(client), Stream::new(server))
+    }
+
+    /// Progresses the handshakes of both streams in turn until both have completed.
+    fn handshake(client: &mut Stream, server: &mut Stream) {
+        let (mut client_done, mut server_done) = (false, false);
+        for _ in 0..100 {
+            for (stream, done) in [
+                (&mut *client, &mut client_done),
+                (&mut *server, &mut server_done),
+            ] {
+                match stream.handshake() {
+                    Ok(()) => *done = true,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                    Err(e) => panic!("handshake failed: {}", e),
+                }
+            }
+            if client_done && server_done {
+                return;
+            }
+        }
+        panic!("handshake didn't complete");
+    }
+
+    fn read_all(stream: &mut Stream) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        let mut buf = [0; 4096];
+        loop {
+            match stream.read(&mut buf) {
+                Ok(0) => return Ok(data),
+                Ok(n) => data.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(data),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    #[test]
+    fn loads_configurations() {
+        test_server_config();
+        client_config(&test_file("ca.pem")).unwrap();
+    }
+
+    #[test]
+    fn rejects_unusable_files() {
+        let missing = test_file("missing.pem");
+        let cert = test_file("server.pem");
+        let key = test_file("server.key");
+
+        let error = server_config(&missing, &key).unwrap_err();
+        assert!(error.to_string().starts_with("couldn't read "));
+        let error = server_config(&key, &key).unwrap_err();
+        assert!(error.to_string().starts_with("no certificates found in "));
+        let error = server_config(&cert, &cert).unwrap_err();
+        assert!(error.to_string().starts_with("no private key found in "));
+        let error = server_config(&cert, &missing).unwrap_err();
+        assert!(error.to_string().starts_with("couldn't read "));
+        let error = client_config(&key).unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("no usable CA certificates found in "));
+    }
+
+    #[test]
+    fn exchanges_data_after_handshake() {
+        let (mut client, mut server) = stream_pair();
+        assert!(!client.is_tls());
+        client.start_tls(test_client_connection());
+        let session = rustls::ServerConnection::new(test_server_config()).unwrap();
+        server.start_tls(rustls::Connection::Server(session));
+        assert!(client.is_tls() && server.is_tls());
+
+        handshake(&mut client, &mut server);
+
+        client.write_all(b"request").unwrap();
+        client.flush().unwrap();
+        assert_eq!(read_all(&mut server).unwrap(), b"request");
+        server.write_all(b"response").unwrap();
+        server.flush().unwrap();
+        assert_eq!(read_all(&mut client).unwrap(), b"response");
+
+        // The peer sees a clean close once the other side shuts down
+        client.shutdown(net::Shutdown::Write).unwrap();
+        assert_eq!(server.read(&mut [0; 16]).unwrap(), 0);
+    }
+
+    #[test]
+    fn rejects_untrusted_certificates() {
+        let (mut client, mut server) = stream_pair();
+        let server_name = rustls::ServerName::try_from("example.com").unwrap();
+        let config = client_config(&test_file("ca.pem")).unwrap();
+        let session = rustls::ClientConnection::new(config, server_name).unwrap();
+        client.start_tls(rustls::Connection::Client(session));
+        let session = rustls::ServerConnection::new(test_server_config()).unwrap();
+        server.start_tls(rustls::Connection::Server(session));
+
+        let mut client_error = None;
+        for _ in 0..100 {
+            let _ = server.handshake();
+            match client.handshake() {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                result => {
+                    client_error = result.err();
+                    break;
+                }
+            }
+        }
+        assert_eq!(
+            client_error.map(|e| e.kind()),
+            Some(io::ErrorKind::InvalidData)
+        );
+    }
+}
@@ -0,0 +1,575 @@
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+use std::{error, fmt, fs, io};
+
+use crate::dialect;
+use crate::matcher::BiTrie;
+use crate::snapshot;
+use crate::sql;
+use crate::sql::Token as SqlToken;
+
+pub const USAGE: &str =
+    "sqlfortify train --db-type <db_type> --output <snapshot_file> [--format <format>] [--replace] <log_file>...";
+
+/// The layout of a file that queries are learned from.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// One query per line, exactly as it is sent by the client
+    Plain,
+    /// A PostgreSQL server log written with `log_statement` enabled (using the default `stderr` log destination)
+    PostgresLog,
+    /// A CSV export of the `pg_stat_statements` view that includes its `query` column
+    PgStatStatements,
+    /// A MySQL general query log
+    MysqlGeneralLog,
+}
+
+impl LogFormat {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "plain" => Some(LogFormat::Plain),
+            "postgres_log" => Some(LogFormat::PostgresLog),
+            "pg_stat_statements" => Some(LogFormat::PgStatStatements),
+            "mysql_general_log" => Some(LogFormat::MysqlGeneralLog),
+            _ => None,
+        }
+    }
+}
+
+/// The settings of a single `sqlfortify train` run.
+pub struct Options {
+    pub dialect: &'static dialect::Dialect,
+    pub format: LogFormat,
+    pub inputs: Vec<PathBuf>,
+    pub output: PathBuf,
+    /// Whether to discard the patterns already saved in the output snapshot rather than adding to them
+    pub replace: bool,
+}
+
+#[derive(Debug)]
+pub struct TrainError {
+    reason: String,
+}
+
+impl fmt::Display for TrainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", &self.reason)
+    }
+}
+
+impl error::Error for TrainError {}
+
+/// Parses the arguments that follow `sqlfortify train`.
+pub fn parse_args(args: &[String]) -> Result<Options, TrainError> {
+    let mut dialect = None;
+    let mut format = LogFormat::Plain;
+    let mut output = None;
+    let mut replace = false;
+    let mut inputs = Vec::new();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = |option: &str| match args.next() {
+            Some(v) => Ok(v),
+            None => Err(TrainError {
+                reason: format!("missing value for {}", option),
+            }),
+        };
+
+        match arg.as_str() {
+            "--db-type" => match dialect::lookup(value(arg)?) {
+                Ok(d) => dialect = Some(d),
+                Err(e) => {
+                    return Err(TrainError {
+                        reason: e.to_string(),
+                    })
+                }
+            },
+            "--format" => {
+                let name = value(arg)?;
+                format = match LogFormat::from_name(name) {
+                    Some(f) => f,
+                    None => return Err(TrainError {
+                        reason: format!(
+                            "unknown log format '{}' (expected one of \"plain\", \"postgres_log\", \"pg_stat_statements\" or \"mysql_general_log\")",
+                            name
+                        ),
+                    }),
+                }
+            }
+            "--output" => output = Some(PathBuf::from(value(arg)?)),
+            "--replace" => replace = true,
+            _ if arg.starts_with("--") => {
+                return Err(TrainError {
+                    reason: format!("unknown option '{}'", arg),
+                })
+            }
+            _ => inputs.push(PathBuf::from(arg)),
+        }
+    }
+
+    let dialect = match dialect {
+        Some(d) => d,
+        None => {
+            return Err(TrainError {
+                reason: "--db-type must be specified".to_string(),
+            })
+        }
+    };
+
+    let output = match output {
+        Some(o) => o,
+        None => {
+            return Err(TrainError {
+                reason: "--output must be specified".to_string(),
+            })
+        }
+    };
+
+    if inputs.is_empty() {
+        return Err(TrainError {
+            reason: "no log files to learn from were specified".to_string(),
+        });
+    }
+
+    Ok(Options {
+        dialect,
+        format,
+        inputs,
+        output,
+        replace,
+    })
+}
+
+/// Learns every query found in the input files and saves the resulting patterns to the output snapshot,
+/// returning the number of queries learned.
+pub fn train<D: sql::Detector>(options: &Options) -> Result<usize, TrainError> {
+    let snapshot_params = snapshot::Parameters {
+        path: options.output.clone(),
+        interval: None,
+        dialect: options.dialect.name,
+    };
+
+    let mut trie = if options.replace {
+        BiTrie::<D>::new()
+    } else {
+        match snapshot::load::<D>(&snapshot_params) {
+            Ok(Some(trie)) => {
+                log::info!(
+                    "Adding to the patterns already saved in {}",
+                    options.output.display()
+                );
+                trie
+            }
+            Ok(None) => BiTrie::new(),
+            Err(e) => {
+                return Err(TrainError {
+                    reason: format!("failed to load existing snapshot: {}", e),
+                })
+            }
+        }
+    };
+
+    let mut total = 0;
+    for input in options.inputs.iter() {
+        let file = match fs::File::open(input) {
+            Ok(f) => f,
+            Err(e) => {
+                return Err(TrainError {
+                    reason: format!("couldn't open {}: {}", input.display(), e),
+                })
+            }
+        };
+
+        let mut count = 0;
+        let mut learn = |query: &str| {
+            let query = query.trim();
+            if !query.is_empty() {
+                log::debug!("Learning query: {}", query);
+                trie.insert(D::Token::scan_forward(query));
+                count += 1;
+            }
+        };
+
+        let reader = BufReader::new(file);
+        let result = match options.format {
+            LogFormat::Plain => read_plain(reader, &mut learn),
+            LogFormat::PostgresLog => read_postgres_log(reader, &mut learn),
+            LogFormat::PgStatStatements => read_pg_stat_statements(reader, &mut learn),
+            LogFormat::MysqlGeneralLog => read_mysql_general_log(reader, &mut learn),
+        };
+
+        match result {
+            Ok(()) => log::info!("Learned {} queries from {}", count, input.display()),
+            Err(e) => {
+                return Err(TrainError {
+                    reason: format!("couldn't read {}: {}", input.display(), e),
+                })
+            }
+        }
+
+        total += count;
+    }
+
+    match snapshot::save(&trie, &snapshot_params) {
+        Ok(()) => Ok(total),
+        Err(e) => Err(TrainError {
+            reason: format!("failed to save snapshot: {}", e),
+        }),
+    }
+}
+
+/// Calls `handle_line` with each line of the reader (minus its line ending). Logs aren't guaranteed
+/// to be valid UTF-8, so any invalid sequences are replaced rather than failing the whole file.
+fn for_each_line<R: BufRead>(mut reader: R, mut handle_line: impl FnMut(&str)) -> io::Result<()> {
+    let mut buf = Vec::new();
+    loop {
+        buf.clear();
+        if reader.read_until(b'\n', &mut buf)? == 0 {
+            return Ok(());
+        }
+
+        let line = String::from_utf8_lossy(&buf);
+        handle_line(line.trim_end_matches(['\n', '\r']));
+    }
+}
+
+fn read_plain<R: BufRead>(reader: R, learn: &mut dyn FnMut(&str)) -> io::Result<()> {
+    for_each_line(reader, |line| learn(line))
+}
+
+// Each log entry begins with `log_line_prefix`, followed by the severity and message. Statements
+// spanning multiple lines are continued on lines beginning with a tab.
+//
+// 2023-04-01 12:00:00.000 UTC [1234] LOG:  statement: SELECT * FROM users
+// 2023-04-01 12:00:00.000 UTC [1234] LOG:  execute <unnamed>: SELECT * FROM users WHERE id = $1
+// 2023-04-01 12:00:00.000 UTC [1234] LOG:  duration: 0.123 ms  statement: SELECT 1
+//
+// `log_statement` logs each statement before it runs, so a statement that fails is followed by an
+// ERROR (and then a STATEMENT line repeating it) from the same backend. Each statement is held back
+// until its backend's next entry shows whether it failed, which relies on `log_line_prefix`
+// including the process ID (`[%p]`, as the default does); without it, entries from concurrent
+// backends are all treated as coming from one. Statements logged with their duration have already
+// completed, so they're learned straight away.
+fn read_postgres_log<R: BufRead>(reader: R, learn: &mut dyn FnMut(&str)) -> io::Result<()> {
+    // The statement last logged by each backend, until it's known not to have failed
+    let mut pending: HashMap<String, String> = HashMap::new();
+    // The statement logged by the last entry, which any continuation lines belong to
+    let mut last_entry: Option<LoggedStatement> = None;
+
+    for_each_line(reader, |line| {
+        if let Some(continued) = line.strip_prefix('\t') {
+            if let Some(entry) = last_entry.as_mut() {
+                entry.statement.push('\n');
+                entry.statement.push_str(continued);
+            }
+            return;
+        }
+
+        end_postgres_log_entry(last_entry.take(), &mut pending, learn);
+
+        let (pid, severity, mut message) = match parse_postgres_log_entry(line) {
+            Some(entry) => entry,
+            None => return,
+        };
+
+        match severity {
+            "ERROR" | "FATAL" | "PANIC" => {
+                pending.remove(pid);
+                return;
+            }
+            "LOG" => {
+                // The backend has moved on, so its last statement didn't fail
+                if let Some(statement) = pending.remove(pid) {
+                    learn(&statement);
+                }
+            }
+            _ => return, // Details, hints, notices and the like don't show whether the statement failed
+        }
+
+        let mut completed = false;
+        if message.starts_with("duration: ") {
+            message = match message.find("  ") {
+                Some(idx) => &message[idx + 2..],
+                None => return, // Durations are logged on their own when the statement was already logged
+            };
+            completed = true;
+        }
+
+        let statement = if let Some(query) = message.strip_prefix("statement: ") {
+            query
+        } else if message.starts_with("execute ") {
+            match message.find(": ") {
+                Some(idx) => &message[idx + 2..],
+                None => return,
+            }
+        } else {
+            return;
+        };
+
+        last_entry = Some(LoggedStatement {
+            pid: pid.to_string(),
+            statement: statement.to_string(),
+            completed,
+        });
+    })?;
+
+    end_postgres_log_entry(last_entry, &mut pending, learn);
+    // The log ended before these backends logged anything else
+    for statement in pending.values() {
+        learn(statement);
+    }
+
+    Ok(())
+}
+
+/// A statement logged by a PostgreSQL backend, along with whether it's known to have completed.
+struct LoggedStatement {
+    pid: String,
+    statement: String,
+    completed: bool,
+}
+
+fn end_postgres_log_entry(
+    entry: Option<LoggedStatement>,
+    pending: &mut HashMap<String, String>,
+    learn: &mut dyn FnMut(&str),
+) {
+    match entry {
+        Some(entry) if entry.completed => learn(&entry.statement),
+        Some(entry) => {
+            pending.insert(entry.pid, entry.statement);
+        }
+        None => (),
+    }
+}
+
+/// Splits a PostgreSQL log entry into the process ID from its prefix (if any), its severity and its message.
+fn parse_postgres_log_entry(line: &str) -> Option<(&str, &str, &str)> {
+    // The severity is the word before the first `:  `, which can't appear in the timestamp
+    let separator = line.find(":  ")?;
+    let severity_start = line[..separator]
+        .rfind(|c: char| c.is_whitespace() || c == ']')
+        .map_or(0, |idx| idx + 1);
+    let prefix = &line[..severity_start];
+
+    let pid = prefix
+        .match_indices('[')
+        .find_map(|(idx, _)| {
+            let rest = &prefix[idx + 1..];
+            let end = rest.find(']')?;
+            let digits = &rest[..end];
+            (!digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit())).then_some(digits)
+        })
+        .unwrap_or("");
+
+    Some((
+        pid,
+        &line[severity_start..separator],
+        &line[separator + 3..],
+    ))
+}
+
+// `pg_stat_statements` replaces the constants in each query with placeholders (`$1`, `$2`, ...),
+// so the patterns learned from it only match clients that send parameterized queries.
+fn read_pg_stat_statements<R: BufRead>(reader: R, learn: &mut dyn FnMut(&str)) -> io::Result<()> {
+    let mut query_column = None;
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+
+    let mut end_record = |record: &mut Vec<String>| {
+        match query_column {
+            None => {
+                query_column = record.iter().position(|name| name.trim() == "query");
+                if query_column.is_none() {
+                    log::warn!("CSV header has no 'query' column--no queries will be learned");
+                    query_column = Some(usize::MAX);
+                }
+            }
+            Some(idx) => {
+                if let Some(query) = record.get(idx) {
+                    learn(query);
+                }
+            }
+        }
+        record.clear();
+    };
+
+    // Quoted fields may contain commas, doubled quotes and line breaks
+    for_each_line(reader, |line| {
+        let mut chars = line.chars().peekable();
+        while let Some(c) = chars.next() {
+            match (c, in_quotes) {
+                ('"', true) if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                ('"', _) => in_quotes = !in_quotes,
+                (',', false) => record.push(std::mem::take(&mut field)),
+                (c, _) => field.push(c),
+            }
+        }
+
+        if in_quotes {
+            field.push('\n');
+        } else {
+            record.push(std::mem::take(&mut field));
+            end_record(&mut record);
+        }
+    })?;
+
+    if !record.is_empty() || !field.is_empty() {
+        record.push(field);
+        end_record(&mut record);
+    }
+
+    Ok(())
+}
+
+// Each entry is made up of a timestamp (left blank by older versions when unchanged), the connection
+// ID, the command and its argument. Queries spanning multiple lines are continued without a prefix.
+//
+// 2023-04-01T12:00:00.000000Z	    8 Query	SELECT * FROM users
+// 2023-04-01T12:00:00.000000Z	    8 Execute	SELECT * FROM users WHERE id = 5
+fn read_mysql_general_log<R: BufRead>(reader: R, learn: &mut dyn FnMut(&str)) -> io::Result<()> {
+    let mut query: Option<String> = None;
+
+    for_each_line(reader, |line| {
+        let entry = parse_mysql_log_entry(line);
+
+        if entry.is_none() && !is_mysql_log_header(line) {
+            if let Some(q) = query.as_mut() {
+                q.push('\n');
+                q.push_str(line);
+            }
+            return;
+        }
+
+        if let Some(q) = query.take() {
+            learn(&q);
+        }
+
+        match entry {
+            Some(("Query", argument)) | Some(("Execute", argument)) => {
+                query = Some(argument.to_string())
+            }
+            _ => (),
+        }
+    })?;
+
+    if let Some(q) = query {
+        learn(&q);
+    }
+
+    Ok(())
+}
+
+/// Splits a MySQL general log entry into its command and argument.
+fn parse_mysql_log_entry(line: &str) -> Option<(&str, &str)> {
+    let (_timestamp, rest) = line.split_once('\t')?;
+    let rest = rest.trim_start();
+
+    let id_len = rest.find(|c: char| !c.is_ascii_digit())?;
+    if id_len == 0 {
+        return None;
+    }
+
+    let rest = rest[id_len..].strip_prefix(' ')?;
+    match rest.split_once('\t') {
+        Some((command, argument)) => Some((command.trim(), argument)),
+        None => Some((rest.trim(), "")),
+    }
+}
+
+/// Whether the line is part of the header written each time the server (re)opens the log.
+fn is_mysql_log_header(line: &str) -> bool {
+    line.contains(", Version: ")
+        || line.starts_with("Tcp port: ")
+        || (line.starts_with("Time ") && line.contains(" Id Command"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type ReadLog = fn(&'static [u8], &mut dyn FnMut(&str)) -> io::Result<()>;
+
+    fn read(read_log: ReadLog, log: &'static str) -> Vec<String> {
+        let mut queries = Vec::new();
+        read_log(log.as_bytes(), &mut |query| queries.push(query.to_string())).unwrap();
+        queries.sort();
+        queries
+    }
+
+    #[test]
+    fn reads_plain_queries() {
+        let queries = read(read_plain, include_str!("../testdata/train/plain.txt"));
+        assert_eq!(
+            queries,
+            vec![
+                "SELECT * FROM users WHERE id = 1",
+                "UPDATE users SET name = 'a' WHERE id = 2",
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_postgres_log_without_failed_statements() {
+        let queries = read(
+            read_postgres_log,
+            include_str!("../testdata/train/postgresql.log"),
+        );
+        assert_eq!(
+            queries,
+            vec![
+                "DELETE FROM sessions WHERE expires < now()",
+                "SELECT * FROM users WHERE id = 1",
+                "SELECT 1",
+                "SELECT name\nFROM users\nWHERE id = $1",
+                "UPDATE users SET name = 'b' WHERE id = 3",
+            ]
+        );
+    }
+
+    #[test]
+    fn postgres_log_without_process_ids() {
+        let log = "LOG:  statement: SELECT 1\n\
+                   LOG:  statement: SELECT 2\n\
+                   ERROR:  division by zero\n\
+                   STATEMENT:  SELECT 2\n";
+        assert_eq!(read(read_postgres_log, log), vec!["SELECT 1"]);
+    }
+
+    #[test]
+    fn reads_pg_stat_statements_export() {
+        let queries = read(
+            read_pg_stat_statements,
+            include_str!("../testdata/train/pg_stat_statements.csv"),
+        );
+        assert_eq!(
+            queries,
+            vec![
+                "SELECT * FROM users WHERE id = $1",
+                "SELECT name, \"email\"\nFROM users\nWHERE id = $1",
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_mysql_general_log() {
+        let queries = read(
+            read_mysql_general_log,
+            include_str!("../testdata/train/mysql_general.log"),
+        );
+        assert_eq!(
+            queries,
+            vec![
+                "SELECT * FROM users WHERE id = 1",
+                "SELECT * FROM users WHERE id = 5",
+                "SELECT name\nFROM users\nWHERE id = 2",
+            ]
+        );
+    }
+}
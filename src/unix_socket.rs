@@ -0,0 +1,122 @@
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+
+/// Marks an address as the path of a Unix domain socket (e.g. `unix:/var/run/postgresql/.s.PGSQL.5432`) rather than a host.
+pub const ADDRESS_PREFIX: &str = "unix:";
+
+/// The address reported for clients connected over a Unix domain socket, which have no address of their own.
+pub const CLIENT_ADDRESS: &str = "[local]";
+
+/// The socket file created for a proxy instance that listens on a Unix domain socket.
+#[derive(Clone)]
+pub struct Parameters {
+    pub path: PathBuf,
+    /// The permissions given to the socket file; if `None`, they're left as determined by the umask
+    pub mode: Option<u32>,
+    /// The user that the socket file is given to; if `None`, it's owned by the user running SQLFortify
+    pub owner: Option<libc::uid_t>,
+    /// The group that the socket file is given to; if `None`, it's left as the user's primary group
+    pub group: Option<libc::gid_t>,
+}
+
+/// Returns the socket path of an address given as `unix:<path>`, or `None` if the address is a host.
+pub fn parse_address(address: &str) -> Option<&Path> {
+    address.strip_prefix(ADDRESS_PREFIX).map(Path::new)
+}
+
+/// Removes a socket file left behind by a proxy that didn't shut down cleanly, so that the path can be bound again.
+/// Fails if another process is still listening on the socket, or if the path is something other than a socket.
+pub fn remove_stale(path: &Path) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(m) => m,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "path exists and is not a socket",
+        ));
+    }
+
+    match UnixStream::connect(path) {
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            "another process is listening on the socket",
+        )),
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+            log::info!("Removing stale socket file {}", path.display());
+            fs::remove_file(path)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Applies the configured permissions and ownership to a newly-bound socket file.
+pub fn set_permissions(params: &Parameters) -> io::Result<()> {
+    if let Some(mode) = params.mode {
+        fs::set_permissions(&params.path, fs::Permissions::from_mode(mode))?;
+    }
+
+    if params.owner.is_some() || params.group.is_some() {
+        let path = match CString::new(params.path.as_os_str().as_bytes()) {
+            Ok(p) => p,
+            Err(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "socket path contains a null byte",
+                ))
+            }
+        };
+
+        // An ID of -1 leaves the owner or group unchanged
+        let owner = params.owner.unwrap_or(libc::uid_t::MAX);
+        let group = params.group.unwrap_or(libc::gid_t::MAX);
+        if unsafe { libc::chown(path.as_ptr(), owner, group) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+/// Looks up the ID of the given user, which may be a name or a numeric ID.
+/// Only called while loading the configuration, as the lookup isn't thread-safe.
+pub fn lookup_user(user: &str) -> Result<libc::uid_t, String> {
+    if let Ok(uid) = user.parse::<libc::uid_t>() {
+        return Ok(uid);
+    }
+
+    let name = match CString::new(user) {
+        Ok(n) => n,
+        Err(_) => return Err(format!("'{}' is not a valid user name", user)),
+    };
+    let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+    if passwd.is_null() {
+        return Err(format!("no user named '{}' exists", user));
+    }
+    Ok(unsafe { (*passwd).pw_uid })
+}
+
+/// Looks up the ID of the given group, which may be a name or a numeric ID.
+/// Only called while loading the configuration, as the lookup isn't thread-safe.
+pub fn lookup_group(group: &str) -> Result<libc::gid_t, String> {
+    if let Ok(gid) = group.parse::<libc::gid_t>() {
+        return Ok(gid);
+    }
+
+    let name = match CString::new(group) {
+        Ok(n) => n,
+        Err(_) => return Err(format!("'{}' is not a valid group name", group)),
+    };
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    if entry.is_null() {
+        return Err(format!("no group named '{}' exists", group));
+    }
+    Ok(unsafe { (*entry).gr_gid })
+}
@@ -1,11 +1,89 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
 use super::matcher::BiTrie;
+use crate::snapshot;
 use crate::sql;
 use crate::sql::Token as SqlToken;
 
+/// What happens to queries that are detected as SQL injection.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    /// Detected queries are blocked, and an error is returned to the client in their place
+    Enforce,
+    /// Detected queries are reported, but still forwarded to the database
+    Monitor,
+}
+
+/// How much of a query matched previously-learned queries, which determines the detection rules that apply to it.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchPath {
+    /// The query has been seen before, so it's accepted without further checks
+    ExactMatch,
+    /// The beginning of the query matched a pattern whose parameter was previously found to be vulnerable
+    VulnerablePrefix,
+    /// The beginning and end of the query matched a known pattern
+    PrefixSuffix,
+    /// Only the beginning of the query matched a known pattern
+    Prefix,
+    /// The query matched no known pattern
+    NoPattern,
+}
+
+/// The outcome of checking a single query for SQL injection, along with how it was reached.
+#[derive(Clone, Debug, Serialize)]
+pub struct Verdict {
+    pub path: MatchPath,
+    /// The index of the first token after the learned prefix that the query matched (if any)
+    pub prefix_index: Option<usize>,
+    /// The index of the last token before the learned suffix that the query matched (if any)
+    pub suffix_index: Option<usize>,
+    /// The indices of the tokens that lie between the matched prefix and suffix (i.e. where an injected parameter would be)
+    pub suspect_tokens: Option<Range<usize>>,
+    /// If set, the query was detected as SQL injection
+    pub detection: Option<Finding>,
+}
+
+/// The detection rule that a query matched, and where in the query it matched.
+#[derive(Clone, Debug, Serialize)]
+pub struct Finding {
+    pub rule: sql::Rule,
+    /// The index of the token that triggered the rule
+    pub token_index: usize,
+    /// The byte range of the query spanned by that token
+    pub offsets: Range<usize>,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} at bytes {}..{}",
+            self.rule, self.offsets.start, self.offsets.end
+        )
+    }
+}
+
+/// A period at the start of a proxy instance's life during which it only monitors queries, after which it enforces.
+/// The window ends as soon as either of its limits is reached.
+#[derive(Clone, Copy)]
+pub struct LearningWindow {
+    pub duration: Option<Duration>,
+    pub queries: Option<u64>,
+}
+
+#[derive(Clone)]
 pub struct Parameters {
     pub detector_nopattern: sql::Parameters,
     pub detector_prefix: sql::Parameters,
     pub detector_prefix_suffix: sql::Parameters,
+    pub mode: Mode,
+    /// If set, `mode` only takes effect once the window has ended (queries are monitored until then)
+    pub learning_window: Option<LearningWindow>,
 }
 
 impl Parameters {
@@ -14,6 +92,8 @@ impl Parameters {
             detector_nopattern: sql::Parameters::default_nopattern(),
             detector_prefix: sql::Parameters::default_prefix(),
             detector_prefix_suffix: sql::Parameters::default_prefix_suffix(),
+            mode: Mode::Enforce,
+            learning_window: None,
         }
     }
 }
@@ -21,35 +101,161 @@ impl Parameters {
 pub struct SqlValidator<D: sql::Detector> {
     matcher: BiTrie<D>,
     params: Parameters,
+    /// The mode currently in effect (which differs from `params.mode` while the learning window is open)
+    mode: Mode,
+    started: Instant,
+    queries_checked: u64,
 }
 
 // TODO: should there just be `check_query()` with a closure passed in?
 impl<D: sql::Detector> SqlValidator<D> {
     pub fn new(config_parameters: Parameters) -> Self {
+        let mode = match config_parameters.learning_window {
+            Some(_) => Mode::Monitor,
+            None => config_parameters.mode,
+        };
+
         SqlValidator {
             matcher: BiTrie::new(),
             params: config_parameters,
+            mode,
+            started: Instant::now(),
+            queries_checked: 0,
+        }
+    }
+
+    /// Switches to the given detection settings (such as after the configuration is reloaded), keeping every
+    /// query pattern learned so far. A learning window that has already ended isn't reopened.
+    pub fn set_parameters(&mut self, params: Parameters) {
+        let window_ended = self.params.learning_window.is_none();
+        self.params = params;
+        if window_ended {
+            self.params.learning_window = None;
+        }
+
+        self.mode = match self.params.learning_window {
+            Some(_) => Mode::Monitor,
+            None => self.params.mode,
+        };
+        // Closes the window straight away if its new limits have already been reached
+        self.update_learning_window();
+    }
+
+    /// Returns whether queries that fail `check_query()` should be blocked (rather than only reported).
+    pub fn is_enforcing(&self) -> bool {
+        self.mode == Mode::Enforce
+    }
+
+    /// Closes the learning window (switching to the configured mode) once either of its limits has been reached.
+    fn update_learning_window(&mut self) {
+        let window = match self.params.learning_window {
+            Some(w) => w,
+            None => return,
+        };
+
+        let elapsed = self.started.elapsed();
+        let time_expired = match window.duration {
+            Some(duration) => elapsed >= duration,
+            None => false,
+        };
+        let queries_expired = match window.queries {
+            Some(queries) => self.queries_checked >= queries,
+            None => false,
+        };
+
+        if time_expired || queries_expired {
+            log::warn!(
+                "Learning window ended after {} seconds and {} queries--SQL injection attempts will now be blocked",
+                elapsed.as_secs(),
+                self.queries_checked
+            );
+            self.params.learning_window = None;
+            self.mode = self.params.mode;
+        }
+    }
+
+    /// Returns the number of nodes used to store learned query patterns.
+    pub fn pattern_node_count(&self) -> usize {
+        self.matcher.node_count()
+    }
+
+    /// Replaces the learned query patterns with those saved in a snapshot (if one has been saved).
+    pub fn load_patterns(
+        &mut self,
+        snapshot_params: &snapshot::Parameters,
+    ) -> Result<bool, snapshot::SnapshotError> {
+        match snapshot::load(snapshot_params)? {
+            Some(matcher) => {
+                self.matcher = matcher;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Saves the learned query patterns to a snapshot, returning `false` if nothing was learned since the last save.
+    pub fn save_patterns(
+        &mut self,
+        snapshot_params: &snapshot::Parameters,
+    ) -> Result<bool, snapshot::SnapshotError> {
+        if !self.matcher.is_modified() {
+            return Ok(false);
         }
+
+        snapshot::save(&self.matcher, snapshot_params)?;
+        self.matcher.mark_saved();
+        Ok(true)
     }
 
-    // Ok(s) => go ahead and send the query through to the SQL server
-    // Err(s) => send the following error back through to the client
-    pub fn check_query(&mut self, query: &str) -> Result<(), &'static str> {
+    /// Checks whether the query is an attempt at SQL injection. If `detection` is set in the result, the query
+    /// should not be sent through to the SQL server (unless only monitoring queries, in which case the query's
+    /// pattern isn't marked as vulnerable either, so that false positives aren't blocked once enforcement starts).
+    pub fn check_query(&mut self, query: &str) -> Verdict {
+        self.update_learning_window();
+        self.queries_checked += 1;
+
         let tokens = D::Token::scan_forward(query);
 
         println!("Tokenized query into: {:?}", &tokens);
 
+        let end_of_query = match tokens.last() {
+            Some((_, abs_idx)) => abs_idx + 1,
+            None => 0,
+        };
+
         let prefix = self.matcher.match_prefix(tokens.as_ref());
 
         if let Some(prefix_info) = prefix {
+            let mut verdict = Verdict {
+                path: MatchPath::Prefix,
+                prefix_index: Some(prefix_info.absolute_index),
+                suffix_index: None,
+                suspect_tokens: Some(prefix_info.absolute_index..end_of_query),
+                detection: None,
+            };
+
             // First, accept queries that have been seen before (even if a parameter is later detected to be vulnerable)
             if prefix_info.is_exact_match {
-                return Ok(());
+                return Verdict {
+                    path: MatchPath::ExactMatch,
+                    prefix_index: None,
+                    suffix_index: None,
+                    suspect_tokens: None,
+                    detection: None,
+                };
             }
 
             // Then immediately reject queries that haven't been seen, but have a vulnerable parameter
             if prefix_info.has_vuln_prefix {
-                return Err("vulnerable prefix detected for new query");
+                verdict.path = MatchPath::VulnerablePrefix;
+                verdict.detection = Some(finding::<D>(
+                    query,
+                    sql::Detection {
+                        rule: sql::Rule::VulnerablePrefix,
+                        token_index: prefix_info.absolute_index,
+                    },
+                ));
+                return verdict;
             }
 
             // And if neither of these cases fit, do another O(n) scan on the query to get suffix information
@@ -65,7 +271,7 @@ impl<D: sql::Detector> SqlValidator<D> {
             // 2a. If prefix + suffix, do full check of malicious queries
             // 2b. If prefix only, do subset of checks
             // 2c. If nothing, do subset of checks (or no checks)
-            match suffix {
+            let detection = match suffix {
                 // Prefix and suffix match: could likely be SQL injection on query we've already seen, but could also be a new query pattern
                 Some(suffix_info) => {
                     let mut middle_cnt = tokens.len() - prefix_info.directional_index;
@@ -76,38 +282,52 @@ impl<D: sql::Detector> SqlValidator<D> {
                         }
                     }
 
-                    if D::is_malicious_query(
-                        token_iter.take(middle_cnt).map(|(t, _)| t),
+                    verdict.path = MatchPath::PrefixSuffix;
+                    verdict.suffix_index = Some(suffix_info.absolute_index);
+                    verdict.suspect_tokens =
+                        Some(prefix_info.absolute_index..suffix_info.absolute_index + 1);
+
+                    D::detect_injection(
+                        token_iter.take(middle_cnt),
                         &self.params.detector_prefix_suffix,
-                    ) {
-                        self.matcher
-                            .mark_vulnerable(&tokens, Some(prefix_info.get_id()));
-                        return Err("query matched a malicious pattern");
-                    }
+                    )
                 }
                 // Prefix matches, no suffix found: either null byte injection, or pattern hasn't been seen before but happens to match some other prefix
-                None => {
-                    if D::is_malicious_query(
-                        tokens.iter().map(|(t, _)| t),
-                        &self.params.detector_prefix,
-                    ) {
-                        self.matcher
-                            .mark_vulnerable(&tokens, Some(prefix_info.get_id()));
-                        return Err("query matched a malicious pattern");
-                    }
+                None => D::detect_injection(tokens.iter(), &self.params.detector_prefix),
+            };
+
+            if let Some(detection) = detection {
+                // While monitoring, flagged queries are still sent to the database, and may well be false positives
+                // that the database runs successfully (and that are then learned as good)
+                if self.is_enforcing() {
+                    self.matcher
+                        .mark_vulnerable(&tokens, Some(prefix_info.get_id()));
                 }
+                verdict.detection = Some(finding::<D>(query, detection));
             }
-        } else if D::is_malicious_query(
-            tokens.iter().map(|(t, _)| t),
-            &self.params.detector_nopattern,
-        ) {
+
+            verdict
+        } else {
             // No prefix or suffix matches--query pattern has never been seen before
-            self.matcher.mark_vulnerable(&tokens, None);
-            return Err("query matched a malicious pattern");
-        }
+            let mut verdict = Verdict {
+                path: MatchPath::NoPattern,
+                prefix_index: None,
+                suffix_index: None,
+                suspect_tokens: None,
+                detection: None,
+            };
+
+            if let Some(detection) =
+                D::detect_injection(tokens.iter(), &self.params.detector_nopattern)
+            {
+                if self.is_enforcing() {
+                    self.matcher.mark_vulnerable(&tokens, None);
+                }
+                verdict.detection = Some(finding::<D>(query, detection));
+            }
 
-        // Now send data to the server and receive either a response or a SQL error.
-        Ok(())
+            verdict
+        }
     }
 
     pub fn update_good_query(&mut self, query: &str) {
@@ -133,3 +353,154 @@ impl<D: sql::Detector> SqlValidator<D> {
         self.matcher.mark_vulnerable(&tokens, prefix_id);
     }
 }
+
+/// Locates the token that triggered a detection rule within the original query.
+fn finding<D: sql::Detector>(query: &str, detection: sql::Detection) -> Finding {
+    // Tokens are rescanned here rather than tracked while checking, as this is only needed for the rare detected query
+    let offsets = match D::Token::token_offsets(query).get(detection.token_index) {
+        Some(range) => range.clone(),
+        None => query.len()..query.len(), // Invariant: should never happen (indices come from scanning the same query)
+    };
+
+    Finding {
+        rule: detection.rule,
+        token_index: detection.token_index,
+        offsets,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql::postgres_detector::PostgresDetector;
+
+    type Validator = SqlValidator<PostgresDetector>;
+
+    const LEARNED: &str = "SELECT name FROM users WHERE id = 1";
+    const LEARNED_OTHER_ID: &str = "SELECT name FROM users WHERE id = 2";
+    const INJECTED: &str = "SELECT name FROM users WHERE id = 1 AND pg_sleep(5) IS NOT NULL";
+    const UNSEEN: &str = "SELECT name FROM users WHERE id = 3 AND active";
+
+    fn parameters(mode: Mode, learning_window: Option<LearningWindow>) -> Parameters {
+        Parameters {
+            mode,
+            learning_window,
+            ..Parameters::default()
+        }
+    }
+
+    /// Learns two queries that differ only in `id`, which makes `id` a parameter of the learned pattern
+    fn learn_pattern(validator: &mut Validator) {
+        validator.update_good_query(LEARNED);
+        validator.update_good_query(LEARNED_OTHER_ID);
+    }
+
+    fn window(duration: Option<Duration>, queries: Option<u64>) -> Option<LearningWindow> {
+        Some(LearningWindow { duration, queries })
+    }
+
+    #[test]
+    fn flagged_queries_mark_their_prefix_vulnerable_when_enforcing() {
+        let mut validator = Validator::new(parameters(Mode::Enforce, None));
+        learn_pattern(&mut validator);
+
+        assert!(validator.check_query(INJECTED).detection.is_some());
+        let verdict = validator.check_query(UNSEEN);
+        assert_eq!(verdict.path, MatchPath::VulnerablePrefix);
+        assert!(verdict.detection.is_some());
+        assert_eq!(validator.check_query(LEARNED).path, MatchPath::ExactMatch);
+    }
+
+    #[test]
+    fn monitoring_flags_queries_without_marking_their_prefix_vulnerable() {
+        let mut validator = Validator::new(parameters(Mode::Monitor, None));
+        learn_pattern(&mut validator);
+
+        assert!(!validator.is_enforcing());
+        assert!(validator.check_query(INJECTED).detection.is_some());
+        let verdict = validator.check_query(UNSEEN);
+        assert_ne!(verdict.path, MatchPath::VulnerablePrefix);
+        assert!(verdict.detection.is_none());
+
+        // A flagged query that succeeds is learned like any other
+        validator.update_good_query(INJECTED);
+        assert_eq!(validator.check_query(INJECTED).path, MatchPath::ExactMatch);
+    }
+
+    #[test]
+    fn learning_window_ends_after_its_query_limit() {
+        let mut validator = Validator::new(parameters(Mode::Enforce, window(None, Some(2))));
+        learn_pattern(&mut validator);
+
+        assert!(!validator.is_enforcing());
+        assert!(validator.check_query(INJECTED).detection.is_some());
+        assert!(validator.check_query(UNSEEN).detection.is_none());
+        assert!(!validator.is_enforcing());
+
+        // The window closes as the third query is checked, so that query is the first one enforced
+        assert!(validator.check_query(INJECTED).detection.is_some());
+        assert!(validator.is_enforcing());
+        assert_eq!(
+            validator.check_query(UNSEEN).path,
+            MatchPath::VulnerablePrefix
+        );
+    }
+
+    #[test]
+    fn learning_window_ends_after_its_duration() {
+        let mut validator = Validator::new(parameters(
+            Mode::Enforce,
+            window(Some(Duration::from_secs(3600)), None),
+        ));
+        validator.check_query(LEARNED);
+        assert!(!validator.is_enforcing());
+
+        let mut validator = Validator::new(parameters(
+            Mode::Enforce,
+            window(Some(Duration::ZERO), None),
+        ));
+        assert!(!validator.is_enforcing());
+        validator.check_query(LEARNED);
+        assert!(validator.is_enforcing());
+    }
+
+    #[test]
+    fn set_parameters_keeps_learned_patterns() {
+        let mut validator = Validator::new(parameters(Mode::Enforce, None));
+        learn_pattern(&mut validator);
+
+        validator.set_parameters(parameters(Mode::Monitor, None));
+        assert!(!validator.is_enforcing());
+        assert_eq!(validator.check_query(LEARNED).path, MatchPath::ExactMatch);
+
+        validator.set_parameters(parameters(Mode::Enforce, None));
+        assert!(validator.is_enforcing());
+    }
+
+    #[test]
+    fn set_parameters_does_not_reopen_an_ended_learning_window() {
+        let mut validator = Validator::new(parameters(Mode::Enforce, window(None, Some(1))));
+        validator.check_query(LEARNED);
+        validator.check_query(LEARNED);
+        assert!(validator.is_enforcing());
+
+        validator.set_parameters(parameters(Mode::Enforce, window(None, Some(100))));
+        assert!(validator.is_enforcing());
+        assert!(validator.params.learning_window.is_none());
+    }
+
+    #[test]
+    fn set_parameters_applies_new_limits_to_an_open_learning_window() {
+        let mut validator = Validator::new(parameters(Mode::Enforce, window(None, Some(100))));
+        validator.check_query(LEARNED);
+        validator.check_query(LEARNED);
+
+        // Raising the limit keeps the window open
+        validator.set_parameters(parameters(Mode::Enforce, window(None, Some(200))));
+        assert!(!validator.is_enforcing());
+
+        // Lowering it below the queries already checked closes the window straight away
+        validator.set_parameters(parameters(Mode::Enforce, window(None, Some(2))));
+        assert!(validator.is_enforcing());
+    }
+}